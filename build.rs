@@ -1,5 +1,5 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::configure().build_server(false).compile(
+    tonic_build::configure().build_server(true).compile(
         &[
             "etcd-api-protos/etcd/api/etcdserverpb/rpc.proto",
             "etcd-api-protos/etcd/api/authpb/auth.proto",
@@ -0,0 +1,18 @@
+//! Send traces to a Jaeger agent.
+//!
+//! `JAEGER_AGENT_ENDPOINT=localhost:6831 cargo run --example jaeger --features jaeger`
+
+use opentelemetry_tracing_utils::LoggingSetupBuilder;
+use tracing::{info, info_span};
+
+fn main() -> anyhow::Result<()> {
+    LoggingSetupBuilder::new().build()?;
+
+    info_span!("jaeger example span").in_scope(|| {
+        info!("this span should be exported to the Jaeger agent");
+    });
+
+    opentelemetry_tracing_utils::shutdown_tracer_provider();
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+//! Configuration for the batch span processor used by the OTLP exporter.
+//!
+//! The queue size and export interval come from [BatchConfig::default], which reads the standard
+//! `OTEL_BSP_*` environment variables (e.g. `OTEL_BSP_MAX_QUEUE_SIZE` and
+//! `OTEL_BSP_SCHEDULE_DELAY`). If the queue fills up, spans are dropped, and OpenTelemetry reports
+//! an error for each one. [warn_on_dropped_spans] turns those into a single warning, so that gaps
+//! in traces aren't a mystery.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use opentelemetry::global;
+use opentelemetry::trace::TraceError;
+use opentelemetry_sdk::trace::BatchConfig;
+
+/// Overrides for the batch span processor's [BatchConfig]. Anything that isn't set comes from the
+/// `OTEL_BSP_*` environment variables, or the SDK's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchProcessorSettings {
+    /// Maximum number of spans buffered before they start being dropped
+    pub max_queue_size: Option<usize>,
+    /// Delay between consecutive exports
+    pub schedule_delay: Option<Duration>,
+    /// Maximum number of spans sent in a single export
+    pub max_export_batch_size: Option<usize>,
+}
+impl BatchProcessorSettings {
+    pub fn batch_config(&self) -> BatchConfig {
+        let mut batch_config = BatchConfig::default();
+        if let Some(max_queue_size) = self.max_queue_size {
+            batch_config = batch_config.with_max_queue_size(max_queue_size);
+        }
+        if let Some(schedule_delay) = self.schedule_delay {
+            batch_config = batch_config.with_scheduled_delay(schedule_delay);
+        }
+        if let Some(max_export_batch_size) = self.max_export_batch_size {
+            batch_config = batch_config.with_max_export_batch_size(max_export_batch_size);
+        }
+        batch_config
+    }
+}
+
+static DROPPED_SPANS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the global OpenTelemetry error handler, so that the first span dropped because the batch
+/// queue is full logs a warning. Other errors are printed to stderr, like the default handler.
+pub fn warn_on_dropped_spans() -> Result<(), global::Error> {
+    global::set_error_handler(|error| {
+        if !is_dropped_span(&error) {
+            eprintln!("OpenTelemetry error occurred. {error}");
+        } else if DROPPED_SPANS.fetch_add(1, Ordering::Relaxed) == 0 {
+            tracing::warn!(
+                "the OTLP batch span queue is full, so spans are being dropped. Increase \
+                 OTEL_BSP_MAX_QUEUE_SIZE or reduce OTEL_BSP_SCHEDULE_DELAY"
+            );
+        }
+    })
+}
+
+/// The number of spans dropped because the batch queue was full, since [warn_on_dropped_spans]
+pub fn dropped_spans() -> usize {
+    DROPPED_SPANS.load(Ordering::Relaxed)
+}
+
+/// Whether the error is from a span that couldn't be added to the full batch queue
+fn is_dropped_span(error: &global::Error) -> bool {
+    matches!(
+        error,
+        global::Error::Trace(TraceError::Other(error))
+            if error.to_string().contains("channel is full")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use opentelemetry::trace::{Span, Tracer, TracerProvider as _};
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry_sdk::trace::TracerProvider;
+
+    use super::*;
+    use crate::{LoggingSetupBuilder, SpanProcessorKind};
+
+    /// Takes a while to export each batch, so that spans back up in the queue
+    #[derive(Debug)]
+    struct SlowExporter;
+    impl SpanExporter for SlowExporter {
+        fn export(
+            &mut self,
+            _batch: Vec<SpanData>,
+        ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ExportResult> + Send>> {
+            std::thread::sleep(Duration::from_millis(200));
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[test]
+    fn spans_dropped_when_the_configured_queue_is_full() {
+        warn_on_dropped_spans().unwrap();
+        let dropped_before = dropped_spans();
+
+        let builder = LoggingSetupBuilder {
+            span_processor: SpanProcessorKind::Batch,
+            batch_processor: BatchProcessorSettings {
+                max_queue_size: Some(1),
+                max_export_batch_size: Some(1),
+                ..Default::default()
+            },
+            ..LoggingSetupBuilder::new()
+        };
+        let provider = builder
+            .with_otlp_span_processor(TracerProvider::builder(), SlowExporter)
+            .build();
+        let tracer = provider.tracer("test");
+
+        for _ in 0..20 {
+            tracer.start("span").end();
+        }
+
+        assert!(dropped_spans() > dropped_before);
+    }
+
+    #[test]
+    fn other_errors_are_not_dropped_spans() {
+        assert!(!is_dropped_span(&global::Error::Trace(
+            "exporter timed out".to_owned().into()
+        )));
+        assert!(is_dropped_span(&global::Error::Trace(
+            "send failed because channel is full".to_owned().into()
+        )));
+    }
+}
@@ -0,0 +1,41 @@
+//! Export traces directly to a Jaeger agent, for setups without an OTLP collector.
+
+use opentelemetry::trace::TraceError;
+use opentelemetry_sdk::trace::Tracer;
+
+use crate::SpanProcessorKind;
+
+/// Install a Jaeger agent pipeline, sending spans to `agent_endpoint` (e.g. "localhost:6831") with
+/// the given span processor.
+pub fn install_pipeline(
+    agent_endpoint: &str,
+    span_processor: SpanProcessorKind,
+) -> Result<Tracer, TraceError> {
+    let pipeline = opentelemetry_jaeger::new_agent_pipeline()
+        .with_endpoint(agent_endpoint)
+        .with_trace_config(crate::trace_config());
+
+    match span_processor {
+        SpanProcessorKind::Batch => {
+            pipeline.install_batch(opentelemetry_sdk::runtime::TokioCurrentThread)
+        }
+        SpanProcessorKind::Simple => pipeline.install_simple(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jaeger_pipeline_is_constructed() {
+        // The agent exporter uses UDP, so nothing needs to be listening for this to work
+        for span_processor in [SpanProcessorKind::Batch, SpanProcessorKind::Simple] {
+            let tracer = install_pipeline("127.0.0.1:6831", span_processor);
+
+            assert!(tracer.is_ok(), "{span_processor:?}");
+        }
+
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
@@ -1,17 +1,38 @@
 //! Utilities for tracing and logging.
 //!
 //! Some fairly opinionated!
+//!
+//! The commonly needed items can be imported together from [prelude].
 
 use anyhow::Result;
-use std::str::FromStr;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{PoisonError, RwLock},
+    time::Duration,
+};
 use tracing_opentelemetry::OpenTelemetryLayer;
 
 // tracing
-use opentelemetry::{global, trace::TracerProvider as _};
-use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::TracerProvider};
+use opentelemetry::trace::TraceError;
+use opentelemetry::{
+    baggage::BaggageExt,
+    global,
+    propagation::{TextMapCompositePropagator, TextMapPropagator},
+    trace::{SpanContext, TraceContextExt, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::{
+    Compression, SpanExporterBuilder, TonicExporterBuilder, WithExportConfig,
+};
+use opentelemetry_sdk::{
+    export::trace::SpanExporter,
+    propagation::{BaggagePropagator, TraceContextPropagator},
+    trace::{BatchSpanProcessor, Sampler, Tracer, TracerProvider},
+};
 pub use opentelemetry_semantic_conventions as semcov;
 use tonic::{metadata::MetadataKey, service::Interceptor};
-use tracing::Span;
+use tracing::{instrument::Instrumented, Instrument, Level, Span};
 pub use tracing_opentelemetry::OpenTelemetrySpanExt;
 use tracing_subscriber::{
     fmt::{self, format::FmtSpan, TestWriter},
@@ -20,11 +41,56 @@ use tracing_subscriber::{
     EnvFilter, Layer,
 };
 
-use self::trace_output_fmt::JsonWithTraceId;
+use self::batch_config::BatchProcessorSettings;
+use self::request_id::RequestIdPropagator;
+use self::sampling::SamplingOverrideSampler;
+use self::trace_output_fmt::{JsonWithTraceId, LogfmtWithTraceId};
 
+pub mod batch_config;
+#[cfg(feature = "jaeger")]
+pub mod jaeger;
+pub mod prelude;
+pub mod request_id;
+pub mod sampling;
 pub mod trace_output_fmt;
 
-pub use opentelemetry::global::shutdown_tracer_provider;
+/// The provider of the tracer used by the tracing layer, kept so that its spans can be flushed
+/// on demand. [global] only hands out tracers, not the provider itself.
+static TRACER_PROVIDER: RwLock<Option<TracerProvider>> = RwLock::new(None);
+
+/// Export any finished spans that haven't been exported yet, without shutting down the tracer
+/// provider, e.g. at the end of an integration test. Does nothing if logging hasn't been set up.
+pub fn force_flush_traces() -> Result<(), TraceError> {
+    let provider = TRACER_PROVIDER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    match provider.as_ref() {
+        Some(provider) => provider.force_flush().into_iter().collect(),
+        None => Ok(()),
+    }
+}
+
+/// Shut down the tracer provider, exporting any spans that haven't been exported yet
+pub fn shutdown_tracer_provider() {
+    // Drop the retained provider first, so that the global one is the last reference and
+    // dropping it shuts down the span processors
+    TRACER_PROVIDER
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+
+    global::shutdown_tracer_provider();
+}
+
+/// Filter directive used when `RUST_LOG` isn't set: `info` for everything, except dependencies
+/// that log every connection or request at `info`, which only log warnings.
+///
+/// Syntax for directives is here:
+/// <https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives>
+pub const DEFAULT_LOG_DIRECTIVE: &str = "info,h2=warn,hyper=warn,tower=warn,tonic=warn,\
+    rustls=warn,reqwest=warn,aws_config=warn,aws_smithy_client=warn,aws_smithy_http_tower=warn,\
+    aws_credential_types=warn,opentelemetry=warn";
 
 /// Set up an OTEL pipeline when the OTLP endpoint is set. Otherwise just set up tokio tracing
 /// support.
@@ -36,7 +102,40 @@ pub fn set_up_logging() -> Result<()> {
 pub struct LoggingSetupBuilder {
     pub otlp_output_enabled: bool,
     pub pretty_logs: bool,
+    /// Write logs as logfmt (`key=value` pairs) rather than JSON or pretty printed. Set with
+    /// `LOG_FORMAT=logfmt`. Takes precedence over `pretty_logs`.
+    pub logfmt_logs: bool,
     pub use_test_writer: bool,
+    /// Whether OTLP spans are exported in batches or one at a time. Set with
+    /// `OTEL_SPAN_PROCESSOR=simple`, defaults to batch.
+    pub span_processor: SpanProcessorKind,
+    /// Queue size and export interval for the OTLP batch span processor, overriding the
+    /// `OTEL_BSP_*` environment variables
+    pub batch_processor: BatchProcessorSettings,
+    /// Compress OTLP export payloads. Set with `OTEL_EXPORTER_OTLP_COMPRESSION=gzip`, defaults to
+    /// no compression.
+    pub otlp_compression: Option<Compression>,
+    /// Give up on each OTLP export after this long, so that a slow collector doesn't back up the
+    /// batch queue. Set with `OTEL_EXPORTER_OTLP_TIMEOUT` in milliseconds, defaults to the
+    /// exporter's default (10 seconds).
+    pub otlp_timeout: Option<Duration>,
+    /// Longest formatted value logged with [trace_output_fmt::Truncated], e.g. request headers.
+    /// Set with `LOG_BODY_MAX_LEN`, defaults to
+    /// [DEFAULT_LOG_BODY_MAX_LEN](trace_output_fmt::DEFAULT_LOG_BODY_MAX_LEN).
+    pub log_body_max_len: usize,
+    /// Also write spans to stdout as JSON when OTLP output is enabled, e.g. to see them with
+    /// `kubectl logs`. Set with `STDOUT_SPANS=1`.
+    pub stdout_spans: bool,
+    /// Filter directive used when `RUST_LOG` isn't set (or isn't valid), defaults to
+    /// [DEFAULT_LOG_DIRECTIVE]
+    pub default_log_directive: String,
+    /// Header carrying a request id to propagate, see [request_id]. Set with `REQUEST_ID_HEADER`,
+    /// defaults to [DEFAULT_REQUEST_ID_HEADER](request_id::DEFAULT_REQUEST_ID_HEADER).
+    pub request_id_header: String,
+    /// Export to this Jaeger agent instead of an OTLP collector. Only used if OTLP output is
+    /// enabled.
+    #[cfg(feature = "jaeger")]
+    pub jaeger_agent_endpoint: Option<String>,
 }
 impl Default for LoggingSetupBuilder {
     fn default() -> Self {
@@ -53,7 +152,33 @@ impl Default for LoggingSetupBuilder {
         Self {
             otlp_output_enabled: otlp_enabled,
             pretty_logs,
+            logfmt_logs: std::env::var("LOG_FORMAT")
+                .is_ok_and(|e| e.trim().eq_ignore_ascii_case("logfmt")),
             use_test_writer: false,
+            span_processor: parse_span_processor(
+                std::env::var("OTEL_SPAN_PROCESSOR").ok().as_deref(),
+            ),
+            batch_processor: BatchProcessorSettings::default(),
+            otlp_compression: parse_compression(
+                std::env::var("OTEL_EXPORTER_OTLP_COMPRESSION")
+                    .ok()
+                    .as_deref(),
+            ),
+            otlp_timeout: parse_timeout(
+                std::env::var("OTEL_EXPORTER_OTLP_TIMEOUT").ok().as_deref(),
+            ),
+            log_body_max_len: std::env::var("LOG_BODY_MAX_LEN")
+                .ok()
+                .and_then(|e| e.trim().parse().ok())
+                .unwrap_or(trace_output_fmt::DEFAULT_LOG_BODY_MAX_LEN),
+            stdout_spans: std::env::var("STDOUT_SPANS").is_ok_and(|e| e == "1"),
+            default_log_directive: DEFAULT_LOG_DIRECTIVE.to_owned(),
+            request_id_header: std::env::var("REQUEST_ID_HEADER")
+                .ok()
+                .filter(|header| !header.trim().is_empty())
+                .unwrap_or_else(|| request_id::DEFAULT_REQUEST_ID_HEADER.to_owned()),
+            #[cfg(feature = "jaeger")]
+            jaeger_agent_endpoint: std::env::var("JAEGER_AGENT_ENDPOINT").ok(),
         }
     }
 }
@@ -65,27 +190,77 @@ impl LoggingSetupBuilder {
     pub fn build(&self) -> Result<()> {
         let otlp_enabled = self.otlp_output_enabled;
 
-        global::set_text_map_propagator(TraceContextPropagator::new());
+        global::set_text_map_propagator(text_map_propagator(&self.request_id_header));
+        trace_output_fmt::set_log_body_max_len(self.log_body_max_len);
 
-        let provider = TracerProvider::builder()
-            .with_simple_exporter(opentelemetry_stdout::SpanExporter::default())
-            .build();
-        let basic_no_otlp_tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        #[cfg(feature = "jaeger")]
+        let jaeger_tracer = self
+            .jaeger_agent_endpoint
+            .as_deref()
+            .map(|agent_endpoint| jaeger::install_pipeline(agent_endpoint, self.span_processor))
+            .transpose()?;
+        #[cfg(not(feature = "jaeger"))]
+        let jaeger_tracer: Option<opentelemetry_sdk::trace::Tracer> = None;
 
         // Install a new OpenTelemetry trace pipeline
-        let otlp_tracer = opentelemetry_otlp::new_pipeline()
-            .tracing()
-            // trace config. Collects service.name etc.
-            .with_trace_config(opentelemetry_sdk::trace::config())
-            .with_exporter(opentelemetry_otlp::new_exporter().tonic())
-            .install_batch(opentelemetry_sdk::runtime::TokioCurrentThread)?;
-
-        let tracer = match otlp_enabled {
-            true => otlp_tracer,
-            // BUG: the non-otlp tracer isn't correctly setting context/linking ids
-            false => basic_no_otlp_tracer,
+        let otlp_tracer = match jaeger_tracer {
+            Some(jaeger_tracer) => Ok(jaeger_tracer),
+            None => self.install_otlp_pipeline(),
         };
 
+        let (tracer, otlp_install_error) = self.choose_tracer(otlp_tracer);
+        *TRACER_PROVIDER
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = tracer.provider();
+
+        self.subscriber(tracer).try_init()?;
+
+        if let Some(error) = otlp_install_error {
+            tracing::warn!(
+                %error,
+                "failed to install the OTLP pipeline, so spans are only written to stdout"
+            );
+        }
+
+        if otlp_enabled && self.span_processor == SpanProcessorKind::Batch {
+            batch_config::warn_on_dropped_spans()?;
+        }
+
+        Ok(())
+    }
+
+    /// The tracer for the tracing layer: the OTLP (or Jaeger) tracer if OTLP output is enabled,
+    /// otherwise one that writes spans to stdout.
+    ///
+    /// If the OTLP pipeline failed to install, e.g. because the endpoint isn't a valid URI, this
+    /// falls back to the stdout tracer so that the app still runs, and returns the error to be
+    /// logged once logging is set up. Spans aren't exported to OTLP until the app is restarted.
+    ///
+    /// An unreachable collector isn't an install error, as the exporter only connects when it
+    /// first exports. The OTLP tracer is still used, and each failed export is reported to the
+    /// OpenTelemetry error handler rather than falling back to stdout.
+    fn choose_tracer(
+        &self,
+        otlp_tracer: Result<Tracer, TraceError>,
+    ) -> (Tracer, Option<TraceError>) {
+        let basic_no_otlp_tracer = || {
+            TracerProvider::builder()
+                .with_config(trace_config())
+                .with_simple_exporter(opentelemetry_stdout::SpanExporter::default())
+                .build()
+                .tracer(env!("CARGO_PKG_NAME"))
+        };
+
+        match (self.span_outputs().otlp, otlp_tracer) {
+            (true, Ok(otlp_tracer)) => (otlp_tracer, None),
+            (true, Err(error)) => (basic_no_otlp_tracer(), Some(error)),
+            // BUG: the non-otlp tracer isn't correctly setting context/linking ids
+            (false, _) => (basic_no_otlp_tracer(), None),
+        }
+    }
+
+    /// The subscriber with the tracing layer for `tracer` and the log output layer
+    fn subscriber(&self, tracer: Tracer) -> impl tracing::Subscriber + Send + Sync + 'static {
         // Create a tracing layer with the configured tracer
         let opentelemetry: OpenTelemetryLayer<_, _> = tracing_opentelemetry::layer()
             .with_error_fields_to_exceptions(true)
@@ -109,9 +284,18 @@ impl LoggingSetupBuilder {
 
         // Include an option for when there is no otlp endpoint available. In this case, pretty print
         // events, as the data doesn't need to be nicely formatted json for analysis.
-        let format_layers = match pretty_logs {
+        let format_layers = match (self.logfmt_logs, pretty_logs) {
+            // logfmt layer, for log aggregators that prefer it to json
+            (true, _) => match base_layer {
+                MaybeTestWriterLayer::NoTestWriter(layer) => {
+                    layer.event_format(LogfmtWithTraceId).boxed()
+                }
+                MaybeTestWriterLayer::WithTestWriter(layer) => {
+                    layer.event_format(LogfmtWithTraceId).boxed()
+                }
+            },
             // json fmt layer
-            false => match base_layer {
+            (false, false) => match base_layer {
                 MaybeTestWriterLayer::NoTestWriter(layer) => {
                     layer.json().event_format(JsonWithTraceId).boxed()
                 }
@@ -120,7 +304,7 @@ impl LoggingSetupBuilder {
                 }
             },
             // pretty fmt layer
-            true => match base_layer {
+            (false, true) => match base_layer {
                 MaybeTestWriterLayer::NoTestWriter(layer) => {
                     layer.pretty().with_span_events(FmtSpan::NONE).boxed()
                 }
@@ -134,22 +318,271 @@ impl LoggingSetupBuilder {
 
         let tracing_registry = tracing_subscriber::registry()
             // Add a filter to the layers so that they only observe the spans that I want
-            .with(layers.with_filter(
-                // Parse env filter from RUST_LOG, setting a default directive if that fails.
-                // Syntax for directives is here: https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
-                EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                    // e.g. "RUST_LOG=hello_rust_backend,warn" would do everything from hello_rust_backend, and only "warn" level or higher from elsewhere
-                    EnvFilter::try_new("info")
-                        .expect("hard-coded default directive should be valid")
-                }),
-            ));
+            .with(layers.with_filter(self.env_filter(std::env::var("RUST_LOG").ok().as_deref())));
 
         #[cfg(feature = "tokio-console")]
         let tracing_registry = tracing_registry.with(console_subscriber::spawn());
 
-        tracing_registry.try_init()?;
+        tracing_registry
+    }
 
-        Ok(())
+    /// Parse the env filter from `rust_log` (the `RUST_LOG` env var), using the default directive if
+    /// it isn't set or isn't valid.
+    ///
+    /// e.g. "RUST_LOG=hello_rust_backend,warn" would do everything from hello_rust_backend, and
+    /// only "warn" level or higher from elsewhere
+    fn env_filter(&self, rust_log: Option<&str>) -> EnvFilter {
+        rust_log
+            .and_then(|directives| EnvFilter::try_new(directives).ok())
+            .or_else(|| EnvFilter::try_new(&self.default_log_directive).ok())
+            .unwrap_or_else(|| {
+                EnvFilter::try_new(DEFAULT_LOG_DIRECTIVE)
+                    .expect("hard-coded default directive should be valid")
+            })
+    }
+
+    /// Where spans are exported to
+    fn span_outputs(&self) -> SpanOutputs {
+        SpanOutputs {
+            otlp: self.otlp_output_enabled,
+            stdout: !self.otlp_output_enabled || self.stdout_spans,
+        }
+    }
+
+    /// Export spans to the OTLP collector, and also to stdout if `stdout_spans` is set
+    fn install_otlp_pipeline(&self) -> Result<Tracer, TraceError> {
+        let otlp_exporter =
+            SpanExporterBuilder::from(self.otlp_exporter()).build_span_exporter()?;
+
+        let provider =
+            self.otlp_tracer_provider(otlp_exporter, opentelemetry_stdout::SpanExporter::default());
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+
+        global::set_tracer_provider(provider);
+
+        Ok(tracer)
+    }
+
+    /// A tracer provider exporting to `otlp_exporter`, and also to `stdout_exporter` if
+    /// [SpanOutputs::stdout]. Both exporters share the provider, so the trace and span ids are the
+    /// same in each output.
+    fn otlp_tracer_provider(
+        &self,
+        otlp_exporter: impl SpanExporter + 'static,
+        stdout_exporter: impl SpanExporter + 'static,
+    ) -> TracerProvider {
+        let provider = self.with_otlp_span_processor(
+            TracerProvider::builder().with_config(trace_config()),
+            otlp_exporter,
+        );
+
+        match self.span_outputs().stdout {
+            true => provider.with_simple_exporter(stdout_exporter),
+            false => provider,
+        }
+        .build()
+    }
+
+    /// Add the span processor for OTLP exports, see [SpanProcessorKind]
+    fn with_otlp_span_processor(
+        &self,
+        provider: opentelemetry_sdk::trace::Builder,
+        exporter: impl SpanExporter + 'static,
+    ) -> opentelemetry_sdk::trace::Builder {
+        match self.span_processor {
+            SpanProcessorKind::Batch => provider.with_span_processor(
+                BatchSpanProcessor::builder(
+                    exporter,
+                    opentelemetry_sdk::runtime::TokioCurrentThread,
+                )
+                .with_batch_config(self.batch_processor.batch_config())
+                .build(),
+            ),
+            SpanProcessorKind::Simple => provider.with_simple_exporter(exporter),
+        }
+    }
+
+    fn otlp_exporter(&self) -> TonicExporterBuilder {
+        let exporter = opentelemetry_otlp::new_exporter().tonic();
+
+        let exporter = match self.otlp_compression {
+            Some(compression) => exporter.with_compression(compression),
+            None => exporter,
+        };
+
+        match self.otlp_timeout {
+            Some(timeout) => exporter.with_timeout(timeout),
+            None => exporter,
+        }
+    }
+}
+
+/// How spans are handed to the OTLP exporter
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SpanProcessorKind {
+    /// Export spans in the background, in batches. Spans that haven't been exported yet are lost
+    /// if the process exits without shutting down the tracer provider.
+    #[default]
+    Batch,
+    /// Export each span as soon as it ends, blocking until the export completes. Useful for
+    /// debugging, or for short-lived processes, but much slower.
+    Simple,
+}
+
+/// Parse an `OTEL_SPAN_PROCESSOR` value. Anything other than "simple" means batch.
+fn parse_span_processor(value: Option<&str>) -> SpanProcessorKind {
+    match value.map(str::trim) {
+        Some(value) if value.eq_ignore_ascii_case("simple") => SpanProcessorKind::Simple,
+        _ => SpanProcessorKind::Batch,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SpanOutputs {
+    otlp: bool,
+    stdout: bool,
+}
+
+/// Parse an `OTEL_EXPORTER_OTLP_COMPRESSION` value. Anything other than "gzip" (including "none")
+/// means no compression.
+fn parse_compression(value: Option<&str>) -> Option<Compression> {
+    match value.map(str::trim) {
+        Some(value) if value.eq_ignore_ascii_case("gzip") => Some(Compression::Gzip),
+        _ => None,
+    }
+}
+
+/// Parse an `OTEL_EXPORTER_OTLP_TIMEOUT` value, a number of milliseconds. Invalid values are
+/// ignored, so that the default is used.
+fn parse_timeout(value: Option<&str>) -> Option<Duration> {
+    value?.trim().parse().ok().map(Duration::from_millis)
+}
+
+/// Trace config shared by all of the exporters. Collects service.name etc.
+fn trace_config() -> opentelemetry_sdk::trace::Config {
+    opentelemetry_sdk::trace::config().with_sampler(default_sampler())
+}
+
+/// The SDK default sampler (parent based, always on), but respecting per-span
+/// [sampling::SamplingOverride]s.
+fn default_sampler() -> SamplingOverrideSampler<Sampler> {
+    SamplingOverrideSampler::new(Sampler::ParentBased(Box::new(Sampler::AlwaysOn)))
+}
+
+/// Propagates the trace context, baggage (e.g. a `tenant.id` used for filtering traces) and the
+/// request id in `request_id_header`
+fn text_map_propagator(request_id_header: &str) -> TextMapCompositePropagator {
+    TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(BaggagePropagator::new()),
+        Box::new(RequestIdPropagator::new(request_id_header)),
+    ])
+}
+
+/// Add a baggage entry to a new span, so that it is propagated with the span's (and its
+/// children's) outgoing requests.
+///
+/// `span` should be a child of the current span that hasn't been entered yet, as its parent is
+/// set to the current span's context with the added entry.
+///
+/// ```
+/// # use opentelemetry_tracing_utils::set_baggage_entry;
+/// let span = tracing::info_span!("sync job");
+/// set_baggage_entry(&span, "tenant.id", "tenant-a");
+/// ```
+pub fn set_baggage_entry(span: &Span, key: &'static str, value: impl Into<String>) {
+    let context = Span::current()
+        .context()
+        .with_baggage(vec![KeyValue::new(key, value.into())]);
+
+    span.set_parent(context);
+}
+
+/// The W3C `traceparent` of the current span, e.g. to put in an error message or a database record
+/// so that the trace can be found later. `None` if there is no valid current span.
+pub fn current_traceparent() -> Option<String> {
+    let context = Span::current().context();
+
+    if !context.span().span_context().is_valid() {
+        return None;
+    }
+
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&context, &mut carrier);
+
+    carrier.remove("traceparent")
+}
+
+/// Links each span in a sequence (e.g. the iterations of a long-running loop) to the one before it
+/// with an OpenTelemetry span link, so that the sequence can be followed in the trace graph.
+///
+/// Only the previous span's context is kept, not the span itself, as a span isn't exported until
+/// every handle to it is dropped.
+#[derive(Debug, Default)]
+pub struct SpanChain {
+    previous: Option<SpanContext>,
+}
+
+impl SpanChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Link `span` to the previous span in the chain, and make it the one the next span links to
+    pub fn link(&mut self, span: &Span) {
+        if let Some(previous) = self.previous.take() {
+            span.add_link(previous);
+        }
+
+        self.previous = Some(span.context().span().span_context().clone());
+    }
+}
+
+/// A span with no parent, so it starts a new trace rather than joining the current one. The span is
+/// called "root span", with `name` used as its OpenTelemetry name.
+pub fn new_root_span(name: &str, level: Level) -> Span {
+    if level == Level::ERROR {
+        tracing::error_span!(parent: None, "root span", otel.name = name)
+    } else if level == Level::WARN {
+        tracing::warn_span!(parent: None, "root span", otel.name = name)
+    } else if level == Level::INFO {
+        tracing::info_span!(parent: None, "root span", otel.name = name)
+    } else if level == Level::DEBUG {
+        tracing::debug_span!(parent: None, "root span", otel.name = name)
+    } else {
+        tracing::trace_span!(parent: None, "root span", otel.name = name)
+    }
+}
+
+/// Run `future` in a new root span (see [new_root_span]), for starting a new unit of work that
+/// shouldn't be part of the current trace.
+///
+/// ```
+/// # use opentelemetry_tracing_utils::in_new_root_span;
+/// # async fn sync_job() {}
+/// let job = in_new_root_span("sync job", tracing::Level::INFO, sync_job());
+/// ```
+pub fn in_new_root_span<F: std::future::Future>(
+    name: &str,
+    level: Level,
+    future: F,
+) -> Instrumented<F> {
+    future.instrument(new_root_span(name, level))
+}
+
+/// Record an error and each of its causes (e.g. the layers of context on an [anyhow::Error]) as
+/// error events on `span`, outermost first. Each event has the cause as `exception.message` and
+/// its position in the chain as `exception.cause_depth`, so the whole chain shows up in the trace
+/// rather than just the outermost message.
+pub fn record_error_chain(span: &Span, error: &anyhow::Error) {
+    for (depth, cause) in error.chain().enumerate() {
+        let message = if depth == 0 { "error" } else { "caused by" };
+        tracing::error!(
+            parent: span,
+            exception.message = %cause,
+            exception.cause_depth = depth,
+            "{message}"
+        );
     }
 }
 
@@ -198,9 +631,11 @@ pub mod tower_tracing {
         propagation::{Extractor, Injector},
     };
     use tower::{Layer, Service};
-    use tracing::trace;
+    use tracing::{instrument::Instrumented, trace, Instrument};
     use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+    use crate::trace_output_fmt::Truncated;
+
     pub struct TracingLayer;
 
     impl<S> Layer<S> for TracingLayer {
@@ -211,7 +646,9 @@ pub mod tower_tracing {
         }
     }
 
-    /// A middleware that sorts tracing propagation to a client
+    /// A middleware that sorts tracing propagation to a client. Each request gets an "outgoing
+    /// request" span, with the propagated request id (see [crate::request_id]) as its
+    /// `request_id` field.
     #[derive(Clone, Debug)]
     pub struct TracingService<S> {
         service: S,
@@ -224,7 +661,7 @@ pub mod tower_tracing {
     {
         type Response = S::Response;
         type Error = S::Error;
-        type Future = S::Future;
+        type Future = Instrumented<S::Future>;
 
         fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
             self.service.poll_ready(cx)
@@ -233,7 +670,15 @@ pub mod tower_tracing {
         fn call(&mut self, mut request: Request<BodyType>) -> Self::Future {
             let old_headers = request.headers().clone();
 
-            let context = tracing::Span::current().context();
+            let span = tracing::info_span!(
+                "outgoing request",
+                http.method = %request.method(),
+                request_id = tracing::field::Empty,
+            );
+            let context = span.context();
+            if let Some(request_id) = crate::request_id::request_id(&context) {
+                span.record("request_id", request_id.as_str());
+            }
 
             global::get_text_map_propagator(|propagator| {
                 propagator.inject_context(&context, &mut HeaderInjector(request.headers_mut()))
@@ -247,16 +692,22 @@ old headers:
 new headers:
 {:#?}
 -----------------------------------------------",
-                old_headers,
-                request.headers()
+                Truncated::new(&old_headers),
+                Truncated::new(request.headers())
             );
 
-            self.service.call(request)
+            span.in_scope(|| self.service.call(request))
+                .instrument(span)
         }
     }
 
     /// Trace context propagation: associate the current span with the OTel trace of the given request,
     /// if any and valid.
+    ///
+    /// The request id header (see [crate::request_id]) is also extracted. It is recorded as the
+    /// current span's `request_id` field, which the span must declare (e.g. with
+    /// `request_id = tracing::field::Empty`) for it to be kept, and is added to the spans of
+    /// outgoing requests made through [TracingService].
     pub fn extract_trace_context<BodyType>(request: Request<BodyType>) -> Request<BodyType>
     where
         BodyType: std::fmt::Debug,
@@ -265,7 +716,13 @@ new headers:
         let parent_context = global::get_text_map_propagator(|propagator| {
             propagator.extract(&HeaderExtractor(request.headers()))
         });
-        trace!("parent context (extraction): {:#?}", parent_context);
+        trace!(
+            "parent context (extraction): {:#?}",
+            Truncated::new(&parent_context)
+        );
+        if let Some(request_id) = crate::request_id::request_id(&parent_context) {
+            tracing::Span::current().record("request_id", request_id.as_str());
+        }
         tracing::Span::current().set_parent(parent_context);
 
         request
@@ -306,3 +763,604 @@ new headers:
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_from_env_value() {
+        assert!(matches!(
+            parse_compression(Some("gzip")),
+            Some(Compression::Gzip)
+        ));
+        assert!(matches!(
+            parse_compression(Some(" GZIP ")),
+            Some(Compression::Gzip)
+        ));
+        assert!(parse_compression(Some("none")).is_none());
+        assert!(parse_compression(None).is_none());
+    }
+
+    #[test]
+    fn default_filter_quietens_noisy_dependencies() {
+        /// Records the target of every event that passes the filter
+        #[derive(Clone, Default)]
+        struct TargetCollector(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl<S: tracing::Subscriber> Layer<S> for TargetCollector {
+            fn on_event(
+                &self,
+                event: &tracing::Event<'_>,
+                _ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(event.metadata().target().to_owned());
+            }
+        }
+
+        let emit_events = || {
+            tracing::info!(target: "hyper::proto::h1::conn", "incoming body completed");
+            tracing::warn!(target: "hyper::client", "connection error");
+            tracing::info!(target: "h2::codec", "frame received");
+            tracing::info!(target: "hello_rust_backend", "sync job finished");
+        };
+
+        let builder = LoggingSetupBuilder::new();
+        let collector = TargetCollector::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(collector.clone().with_filter(builder.env_filter(None)));
+        tracing::subscriber::with_default(subscriber, emit_events);
+        assert_eq!(
+            vec!["hyper::client", "hello_rust_backend"],
+            *collector.0.lock().unwrap()
+        );
+
+        // RUST_LOG takes precedence over the default
+        let collector = TargetCollector::default();
+        let subscriber = tracing_subscriber::registry().with(
+            collector
+                .clone()
+                .with_filter(builder.env_filter(Some("info"))),
+        );
+        tracing::subscriber::with_default(subscriber, emit_events);
+        assert_eq!(4, collector.0.lock().unwrap().len());
+    }
+
+    #[test]
+    fn failed_otlp_install_falls_back_to_stdout_tracer() {
+        let builder = LoggingSetupBuilder {
+            otlp_output_enabled: true,
+            use_test_writer: true,
+            ..LoggingSetupBuilder::new()
+        };
+
+        let (tracer, error) =
+            builder.choose_tracer(Err(TraceError::Other("invalid endpoint".into())));
+        assert!(error.is_some_and(|error| error.to_string().contains("invalid endpoint")));
+
+        tracing::subscriber::with_default(builder.subscriber(tracer), || {
+            let span = tracing::info_span!("sync");
+            assert!(span.context().span().span_context().is_valid());
+            span.in_scope(|| tracing::info!("still logging"));
+        });
+    }
+
+    #[test]
+    fn flush_exports_pending_spans() {
+        /// Counts the exported spans
+        #[derive(Debug, Clone, Default)]
+        struct CountingExporter(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+        impl SpanExporter for CountingExporter {
+            fn export(
+                &mut self,
+                batch: Vec<opentelemetry_sdk::export::trace::SpanData>,
+            ) -> std::pin::Pin<
+                Box<
+                    dyn std::future::Future<Output = opentelemetry_sdk::export::trace::ExportResult>
+                        + Send,
+                >,
+            > {
+                self.0
+                    .fetch_add(batch.len(), std::sync::atomic::Ordering::SeqCst);
+                Box::pin(std::future::ready(Ok(())))
+            }
+        }
+
+        let exporter = CountingExporter::default();
+        let exported = exporter.0.clone();
+        let provider = TracerProvider::builder()
+            .with_config(opentelemetry_sdk::trace::config().with_sampler(Sampler::AlwaysOn))
+            .with_span_processor(
+                BatchSpanProcessor::builder(
+                    exporter,
+                    opentelemetry_sdk::runtime::TokioCurrentThread,
+                )
+                .build(),
+            )
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        *TRACER_PROVIDER.write().unwrap() = Some(provider);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("first").in_scope(|| {});
+            tracing::info_span!("second").in_scope(|| {});
+        });
+        // the batch is only exported every few seconds by itself
+        assert_eq!(0, exported.load(std::sync::atomic::Ordering::SeqCst));
+
+        force_flush_traces().unwrap();
+
+        assert_eq!(2, exported.load(std::sync::atomic::Ordering::SeqCst));
+
+        TRACER_PROVIDER.write().unwrap().take();
+    }
+
+    /// Keeps the exported spans
+    #[derive(Debug, Clone, Default)]
+    struct CollectingExporter(
+        std::sync::Arc<std::sync::Mutex<Vec<opentelemetry_sdk::export::trace::SpanData>>>,
+    );
+    impl SpanExporter for CollectingExporter {
+        fn export(
+            &mut self,
+            batch: Vec<opentelemetry_sdk::export::trace::SpanData>,
+        ) -> std::pin::Pin<
+            Box<
+                dyn std::future::Future<Output = opentelemetry_sdk::export::trace::ExportResult>
+                    + Send,
+            >,
+        > {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[test]
+    fn error_chain_recorded_as_events() {
+        let exporter = CollectingExporter::default();
+        let exported = exporter.0.clone();
+        let provider = TracerProvider::builder()
+            .with_config(opentelemetry_sdk::trace::config().with_sampler(Sampler::AlwaysOn))
+            .with_simple_exporter(exporter)
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let error = anyhow::anyhow!("connection refused")
+            .context("fetching calendar events")
+            .context("syncing user");
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("sync job");
+            record_error_chain(&span, &error);
+        });
+
+        let exported = exported.lock().unwrap();
+        let span = exported
+            .iter()
+            .find(|span| span.name == "sync job")
+            .expect("span should have been exported");
+        let attribute = |event: &opentelemetry::trace::Event, key: &str| {
+            event
+                .attributes
+                .iter()
+                .find(|attribute| attribute.key.as_str() == key)
+                .map(|attribute| attribute.value.to_string())
+        };
+        let causes: Vec<_> = span
+            .events
+            .iter()
+            .map(|event| {
+                (
+                    event.name.to_string(),
+                    attribute(event, "exception.message"),
+                    attribute(event, "exception.cause_depth"),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            vec![
+                (
+                    "error".to_owned(),
+                    Some("syncing user".to_owned()),
+                    Some("0".to_owned())
+                ),
+                (
+                    "caused by".to_owned(),
+                    Some("fetching calendar events".to_owned()),
+                    Some("1".to_owned())
+                ),
+                (
+                    "caused by".to_owned(),
+                    Some("connection refused".to_owned()),
+                    Some("2".to_owned())
+                ),
+            ],
+            causes
+        );
+    }
+
+    #[test]
+    fn span_chain_links_consecutive_spans() {
+        let exporter = CollectingExporter::default();
+        let exported = exporter.0.clone();
+        let provider = TracerProvider::builder()
+            .with_config(opentelemetry_sdk::trace::config().with_sampler(Sampler::AlwaysOn))
+            .with_span_processor(
+                BatchSpanProcessor::builder(
+                    exporter,
+                    opentelemetry_sdk::runtime::TokioCurrentThread,
+                )
+                .build(),
+            )
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut chain = SpanChain::new();
+            let first = tracing::info_span!("first");
+            chain.link(&first);
+            drop(first);
+            let second = tracing::info_span!("second");
+            chain.link(&second);
+        });
+        for result in provider.force_flush() {
+            result.unwrap();
+        }
+
+        let exported = exported.lock().unwrap();
+        let span_named = |name: &str| {
+            exported
+                .iter()
+                .find(|span| span.name == name)
+                .expect("span should have been exported")
+        };
+        let (first, second) = (span_named("first"), span_named("second"));
+
+        assert_eq!(0, first.links.iter().count());
+        let links: Vec<_> = second.links.iter().collect();
+        assert_eq!(1, links.len());
+        assert_eq!(first.span_context, links[0].span_context);
+    }
+
+    #[test]
+    fn traceparent_inside_sampled_span() {
+        let provider = TracerProvider::builder()
+            .with_config(opentelemetry_sdk::trace::config().with_sampler(Sampler::AlwaysOn))
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!(None, current_traceparent());
+
+            tracing::info_span!("sampled span").in_scope(|| {
+                let traceparent = current_traceparent().expect("should be a valid span");
+                let parts: Vec<_> = traceparent.split('-').collect();
+
+                assert_eq!(4, parts.len(), "{traceparent}");
+                assert_eq!("00", parts[0]);
+                assert_eq!(32, parts[1].len());
+                assert_eq!(16, parts[2].len());
+                // sampled flag
+                assert_eq!("01", parts[3]);
+            });
+        });
+    }
+
+    #[test]
+    fn root_span_starts_a_new_trace() {
+        /// Records whether each new span has a parent
+        #[derive(Clone, Default)]
+        struct CaptureRoots(std::sync::Arc<std::sync::Mutex<Vec<bool>>>);
+        impl<S: tracing::Subscriber> Layer<S> for CaptureRoots {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                _id: &tracing::span::Id,
+                ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                let has_parent = attrs.parent().is_some()
+                    || (attrs.is_contextual() && ctx.current_span().id().is_some());
+                self.0.lock().unwrap().push(has_parent);
+            }
+        }
+
+        let provider = TracerProvider::builder()
+            .with_config(opentelemetry_sdk::trace::config().with_sampler(Sampler::AlwaysOn))
+            .build();
+        let roots = CaptureRoots::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(roots.clone())
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let trace_id = |span: &Span| span.context().span().span_context().trace_id();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("outer");
+            outer.in_scope(|| {
+                let root = new_root_span("new unit of work", Level::INFO);
+
+                assert_ne!(trace_id(&outer), trace_id(&root));
+                // spans inside the root span are part of its trace
+                let child = root.in_scope(|| tracing::info_span!("child"));
+                assert_eq!(trace_id(&root), trace_id(&child));
+            });
+        });
+
+        // outer, root, child
+        assert_eq!(vec![false, false, true], *roots.0.lock().unwrap());
+    }
+
+    #[cfg(feature = "tower")]
+    #[test]
+    fn request_id_recorded_and_forwarded() {
+        use std::sync::{Arc, Mutex};
+
+        use tower::{Layer as _, Service as _};
+
+        /// Records the `request_id` field of every span
+        #[derive(Clone, Default)]
+        struct CaptureRequestIds(Arc<Mutex<Vec<String>>>);
+        impl tracing::field::Visit for CaptureRequestIds {
+            fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                if field.name() == "request_id" {
+                    self.0.lock().unwrap().push(value.to_owned());
+                }
+            }
+
+            fn record_debug(
+                &mut self,
+                _field: &tracing::field::Field,
+                _value: &dyn std::fmt::Debug,
+            ) {
+            }
+        }
+        impl<S: tracing::Subscriber> Layer<S> for CaptureRequestIds {
+            fn on_record(
+                &self,
+                _span: &tracing::span::Id,
+                values: &tracing::span::Record<'_>,
+                _ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                values.record(&mut self.clone());
+            }
+        }
+
+        /// Keeps the headers of the outgoing request
+        #[derive(Clone, Default)]
+        struct CaptureHeaders(Arc<Mutex<Option<http::HeaderMap>>>);
+        impl tower::Service<http::Request<()>> for CaptureHeaders {
+            type Response = ();
+            type Error = std::convert::Infallible;
+            type Future = std::future::Ready<Result<(), Self::Error>>;
+
+            fn poll_ready(
+                &mut self,
+                _cx: &mut std::task::Context<'_>,
+            ) -> std::task::Poll<Result<(), Self::Error>> {
+                std::task::Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, request: http::Request<()>) -> Self::Future {
+                *self.0.lock().unwrap() = Some(request.headers().clone());
+                std::future::ready(Ok(()))
+            }
+        }
+
+        global::set_text_map_propagator(text_map_propagator(request_id::DEFAULT_REQUEST_ID_HEADER));
+
+        let provider = TracerProvider::builder()
+            .with_config(opentelemetry_sdk::trace::config().with_sampler(Sampler::AlwaysOn))
+            .build();
+        let request_ids = CaptureRequestIds::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(request_ids.clone())
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let outgoing = CaptureHeaders::default();
+        tracing::subscriber::with_default(subscriber, || {
+            // a span without a `request_id` field
+            tracing::info_span!("incoming request").in_scope(|| {
+                let incoming = http::Request::builder()
+                    .header("X-Request-Id", "req-42")
+                    .body(())
+                    .unwrap();
+                extract_trace_context(incoming);
+
+                tracing::info_span!("child").in_scope(|| {
+                    let _ = TracingLayer
+                        .layer(outgoing.clone())
+                        .call(http::Request::new(()));
+
+                    let request = GrpcInterceptor.call(tonic::Request::new(())).unwrap();
+                    assert_eq!("req-42", request.metadata()["x-request-id"]);
+                    assert!(request.metadata().get("baggage").is_none());
+                });
+            });
+        });
+
+        // recorded on the outgoing request's span, as the incoming one has no such field
+        assert_eq!(vec!["req-42"], *request_ids.0.lock().unwrap());
+        let headers = outgoing.0.lock().unwrap().take().unwrap();
+        assert_eq!("req-42", headers["x-request-id"]);
+        assert!(headers.get("traceparent").is_some());
+        // only sent in its own header
+        assert!(headers.get("baggage").is_none());
+    }
+
+    #[test]
+    fn baggage_injected_into_grpc_metadata() {
+        global::set_text_map_propagator(text_map_propagator(request_id::DEFAULT_REQUEST_ID_HEADER));
+
+        let provider = TracerProvider::builder()
+            .with_config(opentelemetry_sdk::trace::config().with_sampler(Sampler::AlwaysOn))
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("parent").in_scope(|| {
+                let span = tracing::info_span!("with baggage");
+                set_baggage_entry(&span, "tenant.id", "tenant-a");
+
+                span.in_scope(|| {
+                    // baggage is inherited by child spans
+                    tracing::info_span!("child").in_scope(|| {
+                        let request = GrpcInterceptor.call(tonic::Request::new(())).unwrap();
+
+                        assert_eq!("tenant.id=tenant-a", request.metadata()["baggage"]);
+                        assert!(request.metadata().get("traceparent").is_some());
+                    });
+                });
+            });
+        });
+    }
+
+    #[test]
+    fn stdout_and_otlp_span_outputs() {
+        let builder = LoggingSetupBuilder {
+            otlp_output_enabled: true,
+            stdout_spans: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            SpanOutputs {
+                otlp: true,
+                stdout: true
+            },
+            builder.span_outputs()
+        );
+
+        let builder = LoggingSetupBuilder {
+            otlp_output_enabled: true,
+            stdout_spans: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            SpanOutputs {
+                otlp: true,
+                stdout: false
+            },
+            builder.span_outputs()
+        );
+
+        // stdout is used on its own when OTLP is disabled
+        let builder = LoggingSetupBuilder {
+            otlp_output_enabled: false,
+            stdout_spans: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            SpanOutputs {
+                otlp: false,
+                stdout: true
+            },
+            builder.span_outputs()
+        );
+    }
+
+    #[test]
+    fn spans_exported_to_stdout_as_well_as_otlp() {
+        let exported_names = |stdout_spans| {
+            let builder = LoggingSetupBuilder {
+                otlp_output_enabled: true,
+                stdout_spans,
+                span_processor: SpanProcessorKind::Simple,
+                ..Default::default()
+            };
+            let (otlp, stdout) = (CollectingExporter::default(), CollectingExporter::default());
+            let (otlp_spans, stdout_spans) = (otlp.0.clone(), stdout.0.clone());
+            let provider = builder.otlp_tracer_provider(otlp, stdout);
+
+            let subscriber = tracing_subscriber::registry()
+                .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+            tracing::subscriber::with_default(subscriber, || {
+                tracing::info_span!("sync").in_scope(|| {});
+            });
+
+            let names =
+                |spans: &std::sync::Mutex<Vec<opentelemetry_sdk::export::trace::SpanData>>| {
+                    spans
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|span| span.name.to_string())
+                        .collect::<Vec<_>>()
+                };
+            (names(&otlp_spans), names(&stdout_spans))
+        };
+
+        assert_eq!(
+            (vec!["sync".to_owned()], vec!["sync".to_owned()]),
+            exported_names(true)
+        );
+        assert_eq!((vec!["sync".to_owned()], vec![]), exported_names(false));
+    }
+
+    #[test]
+    fn chosen_span_processor_installed() {
+        assert_eq!(
+            SpanProcessorKind::Simple,
+            parse_span_processor(Some("simple"))
+        );
+        assert_eq!(
+            SpanProcessorKind::Batch,
+            parse_span_processor(Some("batch"))
+        );
+        assert_eq!(SpanProcessorKind::Batch, parse_span_processor(None));
+
+        // the number of spans exported as soon as a span ends, without a flush
+        let exported_on_end = |span_processor| {
+            use opentelemetry::trace::{Span as _, Tracer as _};
+
+            let builder = LoggingSetupBuilder {
+                span_processor,
+                ..Default::default()
+            };
+            let exporter = CollectingExporter::default();
+            let exported = exporter.0.clone();
+            let provider = builder
+                .with_otlp_span_processor(
+                    TracerProvider::builder().with_config(
+                        opentelemetry_sdk::trace::config().with_sampler(Sampler::AlwaysOn),
+                    ),
+                    exporter,
+                )
+                .build();
+
+            provider.tracer("test").start("sync").end();
+            exported.lock().map(|spans| spans.len()).unwrap()
+        };
+
+        assert_eq!(1, exported_on_end(SpanProcessorKind::Simple));
+        // batched until the scheduled delay (or a flush)
+        assert_eq!(0, exported_on_end(SpanProcessorKind::Batch));
+    }
+
+    #[test]
+    fn exporter_uses_configured_timeout() {
+        assert_eq!(
+            Some(Duration::from_millis(2500)),
+            parse_timeout(Some("2500"))
+        );
+        assert_eq!(None, parse_timeout(Some("2.5s")));
+        assert_eq!(None, parse_timeout(None));
+
+        use opentelemetry_otlp::HasExportConfig;
+
+        let builder = LoggingSetupBuilder {
+            otlp_timeout: Some(Duration::from_millis(2500)),
+            ..Default::default()
+        };
+        assert_eq!(
+            Duration::from_millis(2500),
+            builder.otlp_exporter().export_config().timeout
+        );
+    }
+}
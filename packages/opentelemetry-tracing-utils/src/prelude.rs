@@ -0,0 +1,32 @@
+//! The items most apps need, to import all at once.
+//!
+//! ```no_run
+//! use opentelemetry_tracing_utils::prelude::*;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! set_up_logging()?;
+//!
+//! // gRPC requests on this channel carry the trace context of the current span
+//! let channel = tonic::transport::Endpoint::from_static("http://localhost:2379").connect_lazy();
+//! let _service: InterceptedGrpcService =
+//!     tonic::codegen::InterceptedService::new(channel, GrpcInterceptor);
+//!
+//! let span = tracing::info_span!("request");
+//! set_baggage_entry(&span, "tenant.id", "tenant-1");
+//! let _context = span.context();
+//!
+//! shutdown_tracer_provider();
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::request_id::RequestIdPropagator;
+pub use crate::trace_output_fmt::Truncated;
+pub use crate::{
+    current_traceparent, force_flush_traces, in_new_root_span, new_root_span, record_error_chain,
+    set_baggage_entry, set_up_logging, shutdown_tracer_provider, GrpcInterceptor,
+    InterceptedGrpcService, LoggingSetupBuilder, OpenTelemetrySpanExt, SpanChain,
+};
+
+#[cfg(feature = "tower")]
+pub use crate::tower_tracing::{extract_trace_context, TracingLayer, TracingService};
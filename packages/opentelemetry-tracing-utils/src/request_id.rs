@@ -0,0 +1,101 @@
+//! Propagation of a request id header (e.g. `X-Request-Id`, set by a frontend or API gateway), so
+//! that a request can be correlated across services.
+//!
+//! The id is kept as a value of the extracted context, so it is carried by every span in the
+//! request's trace and forwarded in the same header on outgoing requests by
+//! [crate::GrpcInterceptor] and [crate::TracingService]. It isn't baggage, so that it isn't also
+//! sent in the `baggage` header.
+
+use opentelemetry::{
+    propagation::{text_map_propagator::FieldIter, Extractor, Injector, TextMapPropagator},
+    Context,
+};
+
+/// Header used by [crate::LoggingSetupBuilder] unless `REQUEST_ID_HEADER` is set
+pub const DEFAULT_REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The request id, as stored in a [Context]
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+/// Extracts the request id from a header into the context, and injects it back into the same
+/// header
+#[derive(Debug)]
+pub struct RequestIdPropagator {
+    /// Header names are lowercase, as gRPC metadata keys must be
+    fields: [String; 1],
+}
+
+impl RequestIdPropagator {
+    pub fn new(header: &str) -> Self {
+        Self {
+            fields: [header.to_ascii_lowercase()],
+        }
+    }
+
+    fn header(&self) -> &str {
+        &self.fields[0]
+    }
+}
+
+impl Default for RequestIdPropagator {
+    fn default() -> Self {
+        Self::new(DEFAULT_REQUEST_ID_HEADER)
+    }
+}
+
+impl TextMapPropagator for RequestIdPropagator {
+    fn inject_context(&self, cx: &Context, injector: &mut dyn Injector) {
+        if let Some(request_id) = request_id(cx) {
+            injector.set(self.header(), request_id);
+        }
+    }
+
+    fn extract_with_context(&self, cx: &Context, extractor: &dyn Extractor) -> Context {
+        match extractor.get(self.header()).map(str::trim) {
+            Some(request_id) if !request_id.is_empty() => {
+                cx.with_value(RequestId(request_id.to_owned()))
+            }
+            _ => cx.clone(),
+        }
+    }
+
+    fn fields(&self) -> FieldIter<'_> {
+        FieldIter::new(&self.fields)
+    }
+}
+
+/// The request id carried by `cx`, if any
+pub fn request_id(cx: &Context) -> Option<String> {
+    cx.get::<RequestId>()
+        .map(|RequestId(request_id)| request_id.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn request_id_extracted_and_injected() {
+        let propagator = RequestIdPropagator::new("X-Correlation-Id");
+
+        let incoming = HashMap::from([("x-correlation-id".to_owned(), "req-42".to_owned())]);
+        let cx = propagator.extract(&incoming);
+        assert_eq!(Some("req-42".to_owned()), request_id(&cx));
+
+        let mut outgoing = HashMap::new();
+        propagator.inject_context(&cx, &mut outgoing);
+        assert_eq!(Some(&"req-42".to_owned()), outgoing.get("x-correlation-id"));
+
+        let cx = propagator.extract(&HashMap::from([(
+            "x-correlation-id".to_owned(),
+            " ".to_owned(),
+        )]));
+        assert_eq!(None, request_id(&cx));
+        let mut outgoing = HashMap::new();
+        propagator.inject_context(&cx, &mut outgoing);
+        assert!(outgoing.is_empty());
+    }
+}
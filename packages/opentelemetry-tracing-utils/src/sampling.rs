@@ -0,0 +1,165 @@
+//! Per-span sampling overrides.
+//!
+//! Spans can be marked as "always sample" or "never sample", independent of the sampler that would
+//! otherwise be used. This is done with a span attribute, which [SamplingOverrideSampler] checks
+//! before delegating to the wrapped sampler.
+
+use opentelemetry::{
+    trace::{Link, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceId},
+    Context, KeyValue,
+};
+use opentelemetry_sdk::trace::ShouldSample;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Attribute key used to mark a span with a [SamplingOverride]
+pub const SAMPLING_OVERRIDE_KEY: &str = "sampling.override";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplingOverride {
+    Always,
+    Never,
+}
+impl SamplingOverride {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::Never => "never",
+        }
+    }
+
+    fn from_attributes(attributes: &[KeyValue]) -> Option<Self> {
+        attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == SAMPLING_OVERRIDE_KEY)
+            .and_then(|kv| match kv.value.as_str().as_ref() {
+                "always" => Some(Self::Always),
+                "never" => Some(Self::Never),
+                _ => None,
+            })
+    }
+}
+
+/// Mark a span as always or never sampled.
+///
+/// This needs to be called straight after the span is created, before any child spans are
+/// created, as the sampling decision is made when the span's context is first needed.
+pub fn set_sampling_override(span: &Span, sampling_override: SamplingOverride) {
+    span.set_attribute(SAMPLING_OVERRIDE_KEY, sampling_override.as_str());
+}
+
+/// A sampler that respects [SamplingOverride] span attributes, and otherwise uses the wrapped
+/// sampler.
+#[derive(Debug, Clone)]
+pub struct SamplingOverrideSampler<S> {
+    inner: S,
+}
+impl<S> SamplingOverrideSampler<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S> ShouldSample for SamplingOverrideSampler<S>
+where
+    S: ShouldSample + Clone + 'static,
+{
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let decision = match SamplingOverride::from_attributes(attributes) {
+            Some(SamplingOverride::Always) => SamplingDecision::RecordAndSample,
+            Some(SamplingOverride::Never) => SamplingDecision::Drop,
+            None => {
+                return self.inner.should_sample(
+                    parent_context,
+                    trace_id,
+                    name,
+                    span_kind,
+                    attributes,
+                    links,
+                )
+            }
+        };
+
+        SamplingResult {
+            decision,
+            attributes: Vec::new(),
+            trace_state: parent_context
+                .map(|cx| cx.span().span_context().trace_state().clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_sdk::trace::Sampler;
+
+    use super::*;
+
+    fn decision_for(sampler: &impl ShouldSample, attributes: &[KeyValue]) -> SamplingDecision {
+        sampler
+            .should_sample(
+                None,
+                TraceId::from_u128(1),
+                "test span",
+                &SpanKind::Internal,
+                attributes,
+                &[],
+            )
+            .decision
+    }
+
+    #[test]
+    fn never_sample_overrides_inner_sampler() {
+        let sampler = SamplingOverrideSampler::new(Sampler::AlwaysOn);
+
+        assert_eq!(
+            SamplingDecision::Drop,
+            decision_for(
+                &sampler,
+                &[KeyValue::new(
+                    SAMPLING_OVERRIDE_KEY,
+                    SamplingOverride::Never.as_str()
+                )]
+            )
+        );
+    }
+
+    #[test]
+    fn always_sample_overrides_inner_sampler() {
+        let sampler = SamplingOverrideSampler::new(Sampler::AlwaysOff);
+
+        assert_eq!(
+            SamplingDecision::RecordAndSample,
+            decision_for(
+                &sampler,
+                &[KeyValue::new(
+                    SAMPLING_OVERRIDE_KEY,
+                    SamplingOverride::Always.as_str()
+                )]
+            )
+        );
+    }
+
+    #[test]
+    fn unmarked_spans_use_inner_sampler() {
+        let sampler = SamplingOverrideSampler::new(Sampler::AlwaysOff);
+
+        assert_eq!(SamplingDecision::Drop, decision_for(&sampler, &[]));
+        assert_eq!(
+            SamplingDecision::Drop,
+            decision_for(
+                &sampler,
+                &[KeyValue::new(SAMPLING_OVERRIDE_KEY, "sometimes")]
+            )
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use opentelemetry::trace::TraceContextExt;
 use serde::ser::{SerializeMap, Serializer as _};
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::field::{Field, Visit};
 use tracing::{Event, Subscriber};
 use tracing_serde::AsSerde;
 use tracing_subscriber::fmt::format::Writer;
@@ -114,3 +116,294 @@ where
         writeln!(writer)
     }
 }
+
+/// Formats events as [logfmt](https://brandur.org/logfmt) (`key=value` pairs), with the trace id
+/// like [JsonWithTraceId], for log aggregators that ingest logfmt more efficiently than JSON
+pub struct LogfmtWithTraceId;
+
+impl<S, N> FormatEvent<S, N> for LogfmtWithTraceId
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let meta = event.metadata();
+
+        let mut line = String::new();
+        push_logfmt_pair(&mut line, "level", &meta.level().as_str().to_lowercase());
+        push_logfmt_pair(&mut line, "target", meta.target());
+        event.record(&mut LogfmtVisitor { line: &mut line });
+
+        if let Some(ref span_ref) = ctx.lookup_current() {
+            if let Some(trace_info) = lookup_trace_info(span_ref) {
+                push_logfmt_pair(&mut line, "span_id", &trace_info.span_id);
+                push_logfmt_pair(&mut line, "trace_id", &trace_info.trace_id);
+            }
+        }
+
+        writeln!(writer, "{line}")
+    }
+}
+
+/// Adds each of an event's fields to a logfmt line
+struct LogfmtVisitor<'a> {
+    line: &'a mut String,
+}
+
+impl Visit for LogfmtVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        push_logfmt_pair(self.line, field.name(), value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        push_logfmt_pair(self.line, field.name(), &format!("{value:?}"));
+    }
+}
+
+/// Add `key=value` to the line, quoting the value if it is empty or contains spaces, quotes, `=`
+/// or control characters
+fn push_logfmt_pair(line: &mut String, key: &str, value: &str) {
+    if !line.is_empty() {
+        line.push(' ');
+    }
+    line.push_str(key);
+    line.push('=');
+
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c == ' ' || c == '=' || c == '"' || c.is_control());
+    if !needs_quotes {
+        line.push_str(value);
+        return;
+    }
+
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// Default for the longest formatted value logged with [Truncated]
+pub const DEFAULT_LOG_BODY_MAX_LEN: usize = 2048;
+
+static LOG_BODY_MAX_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_LOG_BODY_MAX_LEN);
+
+/// Set the length that [Truncated::new] clips values to. Set from
+/// [crate::LoggingSetupBuilder::log_body_max_len] when logging is set up.
+pub fn set_log_body_max_len(max_len: usize) {
+    LOG_BODY_MAX_LEN.store(max_len, Ordering::Relaxed);
+}
+
+/// Formats a value (e.g. a request or response body, or headers) with its `Debug` implementation
+/// for logging, but clipped to a maximum length in bytes with a "…(truncated)" marker, so that a
+/// large payload doesn't blow up the log volume. Use `{:#}` or `{:#?}` for pretty printing.
+///
+/// ```
+/// # use opentelemetry_tracing_utils::trace_output_fmt::Truncated;
+/// let body = "x".repeat(10);
+/// assert_eq!("\"xxxx…(truncated)", Truncated::with_max_len(&body, 5).to_string());
+/// ```
+pub struct Truncated<'a> {
+    value: &'a dyn std::fmt::Debug,
+    max_len: usize,
+}
+
+impl<'a> Truncated<'a> {
+    /// Clip to the length set with [set_log_body_max_len]
+    pub fn new(value: &'a dyn std::fmt::Debug) -> Self {
+        Self::with_max_len(value, LOG_BODY_MAX_LEN.load(Ordering::Relaxed))
+    }
+
+    pub fn with_max_len(value: &'a dyn std::fmt::Debug, max_len: usize) -> Self {
+        Self { value, max_len }
+    }
+
+    fn write(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let formatted = match f.alternate() {
+            true => format!("{:#?}", self.value),
+            false => format!("{:?}", self.value),
+        };
+        if formatted.len() <= self.max_len {
+            return f.write_str(&formatted);
+        }
+
+        let mut end = self.max_len;
+        while !formatted.is_char_boundary(end) {
+            end -= 1;
+        }
+        write!(f, "{}…(truncated)", &formatted[..end])
+    }
+}
+
+impl std::fmt::Display for Truncated<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f)
+    }
+}
+
+impl std::fmt::Debug for Truncated<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use opentelemetry::trace::TracerProvider as _;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    /// Collects everything written by the fmt layer
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Split a logfmt line into its pairs, unescaping quoted values. `None` if it isn't valid
+    /// logfmt.
+    fn parse_logfmt(line: &str) -> Option<Vec<(String, String)>> {
+        let mut pairs = vec![];
+        let mut chars = line.chars().peekable();
+
+        while chars.peek().is_some() {
+            let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+            if key.is_empty() || key.contains(' ') {
+                return None;
+            }
+
+            let mut value = String::new();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => value.push(match chars.next()? {
+                            'n' => '\n',
+                            'r' => '\r',
+                            't' => '\t',
+                            c => c,
+                        }),
+                        c => value.push(c),
+                    }
+                }
+                if chars.next().is_some_and(|c| c != ' ') {
+                    return None;
+                }
+            } else {
+                value = chars.by_ref().take_while(|c| *c != ' ').collect();
+                if value.contains('"') {
+                    return None;
+                }
+            }
+
+            pairs.push((key, value));
+        }
+
+        Some(pairs)
+    }
+
+    #[test]
+    fn logfmt_line_with_trace_id() {
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let buffer = SharedBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .event_format(LogfmtWithTraceId)
+                    .with_writer(move || writer.clone()),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("sync").in_scope(|| {
+                tracing::info!(user_id = "user 1", n_events = 3, "synced \"calendar\"");
+            });
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line = output.strip_suffix('\n').unwrap();
+        let pairs = parse_logfmt(line).unwrap();
+        let value = |key: &str| {
+            pairs
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.as_str())
+        };
+
+        assert_eq!(Some("info"), value("level"));
+        assert_eq!(Some("synced \"calendar\""), value("message"));
+        assert_eq!(Some("user 1"), value("user_id"));
+        assert_eq!(Some("3"), value("n_events"));
+        let trace_id = value("trace_id").unwrap();
+        assert_eq!(32, trace_id.len());
+        assert_ne!(opentelemetry::trace::TraceId::INVALID.to_string(), trace_id);
+    }
+
+    #[test]
+    fn long_body_truncated() {
+        let body = serde_json::json!({ "results": ["a".repeat(100), "b".repeat(100)] });
+
+        let truncated = Truncated::with_max_len(&body, 50).to_string();
+        let (kept, marker) = truncated.split_at(50);
+        assert_eq!(&format!("{body:?}")[..50], kept);
+        assert_eq!("…(truncated)", marker);
+
+        // short values and multi-byte characters at the cut
+        assert_eq!(
+            "\"short\"",
+            Truncated::with_max_len(&"short", 50).to_string()
+        );
+        assert_eq!(
+            "\"é…(truncated)",
+            Truncated::with_max_len(&"éé", 3).to_string()
+        );
+    }
+
+    #[test]
+    fn logfmt_values_quoted_when_needed() {
+        let mut line = String::new();
+        push_logfmt_pair(&mut line, "a", "plain");
+        push_logfmt_pair(&mut line, "b", "");
+        push_logfmt_pair(&mut line, "c", "x=y \\ \"z\"\n");
+
+        assert_eq!(r#"a=plain b="" c="x=y \\ \"z\"\n""#, line);
+        assert_eq!(
+            Some(vec![
+                ("a".to_owned(), "plain".to_owned()),
+                ("b".to_owned(), String::new()),
+                ("c".to_owned(), "x=y \\ \"z\"\n".to_owned()),
+            ]),
+            parse_logfmt(&line)
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! A small HTTP API for operating a running node without redeploying or signalling it: resyncing
+//! users, draining the node, checking which partitions it owns, and scraping its [metrics].
+//!
+//! Every request must have the `admin_token` from the settings as a bearer token.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::TcpListener,
+    pin::Pin,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use anyhow::anyhow;
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::warn;
+
+use crate::{
+    aws,
+    cluster_management::{current_partition_assignment, PartitionAssignment},
+    etcd::EtcdClients,
+    metrics,
+    secret::SecretString,
+    settings::Settings,
+};
+
+type OperationFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>;
+
+/// The operations behind the admin API's routes
+pub trait AdminOperations: Send + Sync {
+    /// Sync the user in the next round, see [aws::resync_user]. Returns the number of sync
+    /// records.
+    fn resync_user<'a>(&'a self, user_id: &'a str) -> OperationFuture<'a, usize>;
+    /// Force a full sync of one user, or every user if `user_id` is `None`, see
+    /// [aws::force_full_resync]. Returns the number of sync records.
+    fn force_full_resync<'a>(&'a self, user_id: Option<&'a str>) -> OperationFuture<'a, usize>;
+    /// Release all of the node's sync locks, see [crate::drain_node]. Returns the released
+    /// partitions.
+    fn drain(&self) -> OperationFuture<'_, Vec<u16>>;
+    /// The partitions this node currently owns
+    fn partitions(&self) -> OperationFuture<'_, PartitionAssignment>;
+}
+
+/// The admin operations for this node
+pub struct NodeAdmin {
+    dynamo_db_client: aws_sdk_dynamodb::Client,
+    node_name: String,
+    settings: Arc<Settings>,
+    /// `None` if the node isn't part of a cluster
+    cluster: Option<ClusterAdmin>,
+}
+
+struct ClusterAdmin {
+    etcd_clients: EtcdClients,
+    draining: Arc<AtomicBool>,
+}
+
+impl NodeAdmin {
+    pub fn clustered(
+        dynamo_db_client: aws_sdk_dynamodb::Client,
+        node_name: String,
+        settings: Arc<Settings>,
+        etcd_clients: EtcdClients,
+        draining: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            dynamo_db_client,
+            node_name,
+            settings,
+            cluster: Some(ClusterAdmin {
+                etcd_clients,
+                draining,
+            }),
+        }
+    }
+
+    pub fn single_node(
+        dynamo_db_client: aws_sdk_dynamodb::Client,
+        node_name: String,
+        settings: Arc<Settings>,
+    ) -> Self {
+        Self {
+            dynamo_db_client,
+            node_name,
+            settings,
+            cluster: None,
+        }
+    }
+}
+
+impl AdminOperations for NodeAdmin {
+    fn resync_user<'a>(&'a self, user_id: &'a str) -> OperationFuture<'a, usize> {
+        Box::pin(async move { Ok(aws::resync_user(&self.dynamo_db_client, user_id).await?) })
+    }
+
+    fn force_full_resync<'a>(&'a self, user_id: Option<&'a str>) -> OperationFuture<'a, usize> {
+        Box::pin(async move {
+            Ok(match user_id {
+                Some(user_id) => aws::force_full_resync(&self.dynamo_db_client, user_id).await?,
+                None => aws::force_full_resync_all_users(&self.dynamo_db_client).await?,
+            })
+        })
+    }
+
+    fn drain(&self) -> OperationFuture<'_, Vec<u16>> {
+        Box::pin(async move {
+            let cluster = self.cluster.as_ref().ok_or(anyhow!(
+                "not clustered, so there are no sync locks to release"
+            ))?;
+            Ok(
+                crate::drain_node(&cluster.etcd_clients, &self.node_name, &cluster.draining)
+                    .await?,
+            )
+        })
+    }
+
+    fn partitions(&self) -> OperationFuture<'_, PartitionAssignment> {
+        Box::pin(async move {
+            Ok(match &self.cluster {
+                Some(cluster) => {
+                    current_partition_assignment(
+                        &mut cluster.etcd_clients.kv.clone(),
+                        &self.node_name,
+                    )
+                    .await?
+                }
+                None => PartitionAssignment::single_node(
+                    self.settings.partition_allowlist.as_deref(),
+                    self.settings.max_partitions_per_node,
+                ),
+            })
+        })
+    }
+}
+
+/// Serve the admin API on `listener` until `shutdown` completes
+pub async fn serve(
+    listener: TcpListener,
+    token: SecretString,
+    operations: Arc<dyn AdminOperations>,
+    shutdown: impl Future<Output = ()>,
+) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let token = token.clone();
+        let operations = operations.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(request, token.clone(), operations.clone())
+            }))
+        }
+    });
+
+    Server::from_tcp(listener)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// Body of the resync routes
+#[derive(Deserialize, Debug, Default)]
+struct UserRequest {
+    user_id: Option<String>,
+}
+
+async fn handle(
+    request: Request<Body>,
+    token: SecretString,
+    operations: Arc<dyn AdminOperations>,
+) -> Result<Response<Body>, Infallible> {
+    if !is_authorised(&request, &token) {
+        return Ok(json_response(
+            StatusCode::UNAUTHORIZED,
+            json!({ "error": "missing or invalid admin token" }),
+        ));
+    }
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let result = match (method, path.as_str()) {
+        (Method::POST, "/resync_user") => match user_request(request).await {
+            Some(UserRequest {
+                user_id: Some(user_id),
+            }) => operations
+                .resync_user(&user_id)
+                .await
+                .map(|sync_records| json!({ "sync_records": sync_records })),
+            _ => return Ok(bad_request("expected a JSON body with a user_id")),
+        },
+        (Method::POST, "/force_full_resync") => match user_request(request).await {
+            Some(UserRequest { user_id }) => operations
+                .force_full_resync(user_id.as_deref())
+                .await
+                .map(|sync_records| json!({ "sync_records": sync_records })),
+            None => return Ok(bad_request("expected an empty body or a JSON body")),
+        },
+        (Method::POST, "/drain") => operations
+            .drain()
+            .await
+            .map(|released| json!({ "released": released })),
+        (Method::GET, "/partitions") => operations.partitions().await.map(|assignment| {
+            json!({
+                "owned": assignment.owned,
+                "total": assignment.total,
+                "workers": assignment.workers,
+            })
+        }),
+        (Method::GET, "/metrics") => {
+            return Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(metrics::render()))
+                .expect("response should be valid"))
+        }
+        _ => {
+            return Ok(json_response(
+                StatusCode::NOT_FOUND,
+                json!({ "error": "not found" }),
+            ))
+        }
+    };
+
+    Ok(match result {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(error) => {
+            warn!(?error, path = path.as_str(), "admin operation failed");
+            json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": format!("{error:#}") }),
+            )
+        }
+    })
+}
+
+fn is_authorised(request: &Request<Body>, token: &SecretString) -> bool {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| constant_time_eq(value.as_bytes(), token.expose_secret().as_bytes()))
+}
+
+/// Compare every byte rather than stopping at the first difference, so that the response time
+/// doesn't reveal how much of a guessed token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+
+    std::hint::black_box(difference) == 0
+}
+
+/// The request's JSON body, or the default if it is empty. `None` if it isn't valid.
+async fn user_request(request: Request<Body>) -> Option<UserRequest> {
+    let body = hyper::body::to_bytes(request.into_body()).await.ok()?;
+    if body.is_empty() {
+        return Some(UserRequest::default());
+    }
+    serde_json::from_slice(&body).ok()
+}
+
+fn bad_request(message: &str) -> Response<Body> {
+    json_response(StatusCode::BAD_REQUEST, json!({ "error": message }))
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("response should be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeOperations {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl FakeOperations {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl AdminOperations for FakeOperations {
+        fn resync_user<'a>(&'a self, user_id: &'a str) -> OperationFuture<'a, usize> {
+            self.record(format!("resync_user {user_id}"));
+            Box::pin(async { Ok(2) })
+        }
+
+        fn force_full_resync<'a>(&'a self, user_id: Option<&'a str>) -> OperationFuture<'a, usize> {
+            self.record(format!("force_full_resync {user_id:?}"));
+            Box::pin(async { Ok(5) })
+        }
+
+        fn drain(&self) -> OperationFuture<'_, Vec<u16>> {
+            self.record("drain".to_owned());
+            Box::pin(async { Err(anyhow!("etcd unavailable")) })
+        }
+
+        fn partitions(&self) -> OperationFuture<'_, PartitionAssignment> {
+            self.record("partitions".to_owned());
+            Box::pin(async {
+                Ok(PartitionAssignment {
+                    owned: vec![1, 2],
+                    released: vec![],
+                    total: 100,
+                    workers: 3,
+                })
+            })
+        }
+    }
+
+    /// Serve the admin API with `operations` on a free port, returning its base URL
+    fn start_server(operations: Arc<FakeOperations>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            SecretString::new("admin-token"),
+            operations,
+            std::future::pending(),
+        ));
+        format!("http://{address}")
+    }
+
+    async fn send(request: reqwest::RequestBuilder) -> (StatusCode, serde_json::Value) {
+        let response = request.bearer_auth("admin-token").send().await.unwrap();
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+        (status, response.json().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn admin_routes() {
+        let operations = Arc::new(FakeOperations::default());
+        let url = start_server(operations.clone());
+        let client = reqwest::Client::new();
+
+        let (status, body) = send(
+            client
+                .post(format!("{url}/resync_user"))
+                .json(&json!({ "user_id": "user-1" })),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(json!({ "sync_records": 2 }), body);
+
+        let (status, body) = send(client.post(format!("{url}/force_full_resync"))).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(json!({ "sync_records": 5 }), body);
+
+        let (status, body) = send(
+            client
+                .post(format!("{url}/force_full_resync"))
+                .json(&json!({ "user_id": "user-2" })),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(json!({ "sync_records": 5 }), body);
+
+        let (status, body) = send(client.post(format!("{url}/drain"))).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+        assert_eq!(json!({ "error": "etcd unavailable" }), body);
+
+        let (status, body) = send(client.get(format!("{url}/partitions"))).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(json!({ "owned": [1, 2], "total": 100, "workers": 3 }), body);
+
+        let (status, _) = send(client.post(format!("{url}/resync_user"))).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+
+        let (status, _) = send(client.get(format!("{url}/drain"))).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        let metrics = client
+            .get(format!("{url}/metrics"))
+            .bearer_auth("admin-token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, metrics.status().as_u16());
+        assert!(metrics
+            .text()
+            .await
+            .unwrap()
+            .contains("# TYPE owned_partitions gauge"));
+
+        assert_eq!(
+            vec![
+                "resync_user user-1",
+                "force_full_resync None",
+                "force_full_resync Some(\"user-2\")",
+                "drain",
+                "partitions",
+            ],
+            *operations.calls.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_token_required() {
+        let operations = Arc::new(FakeOperations::default());
+        let url = start_server(operations.clone());
+        let client = reqwest::Client::new();
+
+        let response = client.post(format!("{url}/drain")).send().await.unwrap();
+        assert_eq!(401, response.status().as_u16());
+
+        let response = client
+            .post(format!("{url}/drain"))
+            .bearer_auth("wrong-token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(401, response.status().as_u16());
+
+        // the same length as the right token
+        let response = client
+            .post(format!("{url}/drain"))
+            .bearer_auth("admin-tokeN")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(401, response.status().as_u16());
+
+        assert!(operations.calls.lock().unwrap().is_empty());
+    }
+}
@@ -1,21 +1,165 @@
 use std::{collections::HashMap, time::Duration};
 
 use anyhow::Result;
-use aws_sdk_dynamodb::{model::AttributeValue, types::SdkError, Client};
-use serde::{Deserialize, Serialize};
-use serde_dynamo::{from_item, from_items};
+use aws_sdk_dynamodb::{
+    error::{GetItemError, QueryError, UpdateItemError},
+    model::{AttributeValue, ReturnConsumedCapacity, Select},
+    types::SdkError,
+    Client,
+};
+use aws_smithy_client::{conns, http_connector::ConnectorSettings, hyper_ext};
+use base64::Engine;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_dynamo::from_item;
 use thiserror::Error;
-use tokio::task::JoinSet;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    task::JoinSet,
+};
 use tokio_stream::StreamExt;
-use tracing::{trace, Instrument};
+use tracing::{trace, warn, Instrument};
 use typeshare::typeshare;
 
-use crate::{do_with_retries, RetryConfig};
+use crate::{do_with_retries, do_with_retries_while, secret::SecretString, RetryConfig};
 
 #[tracing::instrument(ret)]
 pub async fn load_client() -> Client {
-    let config = aws_config::load_from_env().await;
-    aws_sdk_dynamodb::Client::new(&config)
+    load_client_with(None, None, ConnectionPoolSettings::default()).await
+}
+
+/// Settings for the HTTP connection pool used by the DynamoDB client. Anything left as `None`
+/// uses the AWS SDK (and hyper) default.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConnectionPoolSettings {
+    /// Maximum number of idle connections kept open to each host
+    pub max_idle_per_host: Option<usize>,
+    /// How long an idle connection is kept open for
+    pub idle_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+}
+
+/// Load a DynamoDB client, optionally overriding the region and endpoint that would otherwise be
+/// loaded from the environment. Overriding the endpoint is useful for pointing at DynamoDB Local.
+#[tracing::instrument(ret)]
+pub async fn load_client_with(
+    region: Option<String>,
+    endpoint_url: Option<String>,
+    connection_pool: ConnectionPoolSettings,
+) -> Client {
+    let mut config_loader = aws_config::from_env();
+
+    if let Some(region) = region {
+        config_loader = config_loader.region(aws_sdk_dynamodb::Region::new(region));
+    }
+    if let Some(endpoint_url) = endpoint_url {
+        config_loader = config_loader.endpoint_url(endpoint_url);
+    }
+
+    let config = config_loader.load().await;
+    client_from_config(&config, &connection_pool)
+}
+
+/// Load a DynamoDB client that assumes `role_arn` (e.g. a cross-account role), using the
+/// credentials from the environment to call STS `AssumeRole`. The assumed role's credentials are
+/// refreshed before they expire. The client uses the same connection pool settings as
+/// [load_client_with].
+#[tracing::instrument(ret, err)]
+pub async fn load_client_assuming_role(
+    role_arn: &str,
+    session_name: &str,
+    connection_pool: ConnectionPoolSettings,
+) -> Result<Client, LoadClientError> {
+    let base_config = aws_config::load_from_env().await;
+
+    let config = aws_config::from_env()
+        .credentials_provider(assume_role_provider(
+            role_arn,
+            session_name,
+            &base_config,
+            None,
+        )?)
+        .load()
+        .await;
+
+    Ok(client_from_config(&config, &connection_pool))
+}
+
+/// A credentials provider for `role_arn`, calling STS with the credentials from `base_config`.
+/// STS requests go through `sts_connection`, or the default HTTPS connection if it's `None`.
+fn assume_role_provider(
+    role_arn: &str,
+    session_name: &str,
+    base_config: &aws_config::SdkConfig,
+    sts_connection: Option<aws_smithy_client::erase::DynConnector>,
+) -> Result<aws_config::sts::AssumeRoleProvider, LoadClientError> {
+    let base_credentials =
+        base_config
+            .credentials_provider()
+            .ok_or_else(|| LoadClientError::NoBaseCredentials {
+                role_arn: role_arn.to_owned(),
+            })?;
+
+    let mut builder =
+        aws_config::sts::AssumeRoleProvider::builder(role_arn).session_name(session_name);
+    if let Some(region) = base_config.region() {
+        builder = builder.region(region.clone());
+    }
+    if let Some(sts_connection) = sts_connection {
+        builder = builder.connection(sts_connection);
+    }
+
+    Ok(builder.build(base_credentials.clone()))
+}
+
+/// An STS role session name for a node, so that its requests can be identified in CloudTrail.
+/// Characters that aren't allowed in a session name are replaced, and it is truncated to the
+/// maximum length of 64.
+pub fn role_session_name(node_name: &str) -> String {
+    format!("hello-rust-{node_name}")
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '+' | '=' | ',' | '.' | '@' | '-' => c,
+            _ => '-',
+        })
+        .take(64)
+        .collect()
+}
+
+fn client_from_config(
+    config: &aws_config::SdkConfig,
+    connection_pool: &ConnectionPoolSettings,
+) -> Client {
+    if connection_pool == &ConnectionPoolSettings::default() {
+        return Client::new(config);
+    }
+
+    let mut hyper_builder = hyper::Client::builder();
+    if let Some(max_idle_per_host) = connection_pool.max_idle_per_host {
+        hyper_builder.pool_max_idle_per_host(max_idle_per_host);
+    }
+    if let Some(idle_timeout) = connection_pool.idle_timeout {
+        hyper_builder.pool_idle_timeout(idle_timeout);
+    }
+
+    let mut connector_settings = ConnectorSettings::builder();
+    connector_settings
+        .set_connect_timeout(connection_pool.connect_timeout)
+        .set_read_timeout(connection_pool.read_timeout);
+
+    let connector = hyper_ext::Adapter::builder()
+        .hyper_builder(hyper_builder)
+        .connector_settings(connector_settings.build())
+        .build(conns::https());
+
+    Client::from_conf_conn(config.into(), connector)
+}
+
+/// Load a KMS client from the environment, for [decrypt_credentials]
+#[tracing::instrument(ret)]
+pub async fn load_kms_client() -> aws_sdk_kms::Client {
+    aws_sdk_kms::Client::new(&aws_config::load_from_env().await)
 }
 
 /// Get all users from the DynamoDB table
@@ -32,39 +176,104 @@ pub async fn get_users(client: &Client) -> Result<Vec<UserRecord>, DatabaseReque
         .key_condition_expression("#t = :partKey")
         .expression_attribute_names("#t", "type")
         .expression_attribute_values(":partKey", AttributeValue::S("userDetails".to_string()))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
         .into_paginator()
         .items()
         .send();
 
     let items = paginator.collect::<Result<Vec<_>, _>>().await?;
 
-    let users = from_items(items)?;
+    let users = from_items_skipping_malformed(items);
 
     Ok(users)
 }
 
-#[tracing::instrument(err)]
+/// Get a user's details, retrying transient errors (see [DatabaseRequestError::is_transient])
+///
+/// With `consistent_read`, the read reflects every write that succeeded before it, e.g. credentials
+/// the user has just updated, at twice the read capacity cost.
+///
+/// The user's credentials are decrypted with `kms_client` if it is set, see [decrypt_credentials].
+#[tracing::instrument(err, skip(kms_client))]
 pub async fn get_single_user(
     client: &Client,
     user_id: String,
+    consistent_read: bool,
+    kms_client: Option<&aws_sdk_kms::Client>,
+) -> Result<UserRecord, DatabaseRequestError> {
+    let user = do_with_retries_while(
+        || get_single_user_once(client, &user_id, consistent_read),
+        database_retry_config(),
+        DatabaseRequestError::is_transient,
+    )
+    .await?;
+
+    Ok(decrypt_credentials(user, kms_client).await?)
+}
+
+async fn get_single_user_once(
+    client: &Client,
+    user_id: &str,
+    consistent_read: bool,
 ) -> Result<UserRecord, DatabaseRequestError> {
     let item = client
         .get_item()
         .table_name("tasks")
+        .consistent_read(consistent_read)
         .set_key(Some(HashMap::from([
-            ("userId".to_owned(), AttributeValue::S(user_id)),
+            ("userId".to_owned(), AttributeValue::S(user_id.to_owned())),
             ("SK".to_owned(), AttributeValue::S("userDetails".to_owned())),
         ])))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
         .send()
         .await?;
 
-    let item = item.item().unwrap();
+    let item = item
+        .item()
+        .ok_or_else(|| DatabaseRequestError::UserNotFound {
+            user_id: user_id.to_owned(),
+        })?;
+    check_user_details_shape(user_id, item)?;
 
     let user = from_item(item.to_owned())?;
 
     Ok(user)
 }
 
+/// Check that a user details item has the keys and string attributes that [UserRecord] needs, so
+/// that an item written in a different shape (e.g. by a newer frontend) is reported as a
+/// [DatabaseRequestError::SchemaMismatch] rather than a deserialization error
+fn check_user_details_shape(
+    user_id: &str,
+    item: &HashMap<String, AttributeValue>,
+) -> Result<(), DatabaseRequestError> {
+    let schema_mismatch = |detail: String| DatabaseRequestError::SchemaMismatch {
+        user_id: user_id.to_owned(),
+        detail,
+    };
+
+    for key in ["SK", "type"] {
+        match item.get(key) {
+            Some(AttributeValue::S(value)) if value == "userDetails" => {}
+            Some(value) => {
+                return Err(schema_mismatch(format!(
+                    "expected {key} to be \"userDetails\", found {value:?}"
+                )))
+            }
+            None => return Err(schema_mismatch(format!("missing {key}"))),
+        }
+    }
+    for attribute in ["userId", "data"] {
+        if !matches!(item.get(attribute), Some(AttributeValue::S(_))) {
+            return Err(schema_mismatch(format!(
+                "missing string attribute {attribute}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
 #[typeshare]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserRecord {
@@ -74,7 +283,7 @@ pub struct UserRecord {
     record_type: String,
     pub data: String,
     #[serde(rename = "googleRefreshToken")]
-    pub google_refresh_token: Option<String>,
+    pub google_refresh_token: Option<SecretString>,
     #[serde(flatten)]
     pub notion_data: Option<UserRecordNotionData>,
 }
@@ -85,7 +294,50 @@ pub struct UserRecordNotionData {
     #[serde(rename = "notionBotId")]
     pub notion_bot_id: String,
     #[serde(rename = "notionAccessToken")]
-    pub notion_access_token: String,
+    pub notion_access_token: SecretString,
+}
+
+/// Decrypt the user's Google refresh token and Notion access token with KMS. Each is stored as the
+/// base64 encoded KMS ciphertext, which includes the key it was encrypted with.
+///
+/// Without a `kms_client`, the credentials are assumed to be plaintext and returned unchanged.
+pub async fn decrypt_credentials(
+    mut user: UserRecord,
+    kms_client: Option<&aws_sdk_kms::Client>,
+) -> Result<UserRecord, CredentialDecryptionError> {
+    let Some(kms_client) = kms_client else {
+        return Ok(user);
+    };
+
+    if let Some(google_refresh_token) = &user.google_refresh_token {
+        user.google_refresh_token =
+            Some(decrypt_credential(kms_client, google_refresh_token).await?);
+    }
+    if let Some(notion_data) = &mut user.notion_data {
+        notion_data.notion_access_token =
+            decrypt_credential(kms_client, &notion_data.notion_access_token).await?;
+    }
+
+    Ok(user)
+}
+
+async fn decrypt_credential(
+    kms_client: &aws_sdk_kms::Client,
+    ciphertext: &SecretString,
+) -> Result<SecretString, CredentialDecryptionError> {
+    let ciphertext =
+        base64::engine::general_purpose::STANDARD.decode(ciphertext.expose_secret())?;
+
+    let response = kms_client
+        .decrypt()
+        .ciphertext_blob(aws_sdk_kms::types::Blob::new(ciphertext))
+        .send()
+        .await?;
+    let plaintext = response
+        .plaintext()
+        .ok_or(CredentialDecryptionError::MissingPlaintext)?;
+
+    Ok(String::from_utf8(plaintext.as_ref().to_vec())?.into())
 }
 
 #[tracing::instrument(err)]
@@ -99,71 +351,238 @@ pub async fn get_sync_record(
         .key_condition_expression("userId = :partKey and begins_with(SK, :sk)")
         .expression_attribute_values(":partKey", AttributeValue::S(user_id.to_string()))
         .expression_attribute_values(":sk", AttributeValue::S("sync#".to_string()))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
         .into_paginator()
         .items()
         .send();
 
     let items = paginator.collect::<Result<Vec<_>, _>>().await?;
 
-    let sync_records = from_items(items)?;
+    let sync_records = from_items_skipping_malformed(items);
 
     Ok(sync_records)
 }
 
+/// Get every sync record, whatever its status. A separate query is made for each sync partition,
+/// one at a time.
 #[tracing::instrument(err)]
 pub async fn get_sync_records(client: &Client) -> Result<Vec<SyncRecord>, DatabaseRequestError> {
-    let paginator = client
+    let mut sync_records = Vec::new();
+
+    for partition in all_partitions() {
+        let paginator = whole_partition_query(client, partition)
+            .into_paginator()
+            .items()
+            .send();
+        let items = paginator.collect::<Result<Vec<_>, _>>().await?;
+
+        sync_records.extend(from_items_skipping_malformed(items));
+    }
+
+    Ok(sync_records)
+}
+
+/// Every sync partition number
+fn all_partitions() -> impl Iterator<Item = u16> {
+    (0..crate::cluster_management::TOTAL_NUMBER_OF_SYNC_PARTITIONS)
+        .map(|partition| u16::try_from(partition).expect("partition numbers should fit in a u16"))
+}
+
+/// Query one sync partition of the `type-data-index` for records with any status
+fn whole_partition_query(
+    client: &Client,
+    partition: u16,
+) -> aws_sdk_dynamodb::client::fluent_builders::Query {
+    client
         .query()
         .table_name("tasks")
         .index_name("type-data-index")
         .key_condition_expression("#t = :partKey")
         .expression_attribute_names("#t", "type")
-        .expression_attribute_values(":partKey", AttributeValue::S("sync".to_string()))
+        .expression_attribute_values(":partKey", AttributeValue::S(partition_type_key(partition)))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+}
+
+/// Write every sync record to `writer` as a line of JSON, e.g. for a backup. Each sync partition
+/// is queried in turn, and records are written a page at a time, so the whole table is never held
+/// in memory. Returns the number of records written.
+#[tracing::instrument(skip(writer), err)]
+pub async fn export_sync_records<W: AsyncWrite + Unpin>(
+    client: &Client,
+    mut writer: W,
+) -> Result<usize, ExportError> {
+    let mut n_sync_records = 0;
+
+    for partition in all_partitions() {
+        let mut pages = whole_partition_query(client, partition)
+            .into_paginator()
+            .send();
+
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(DatabaseRequestError::from)?;
+            let items = page.items().unwrap_or_default().to_vec();
+
+            for sync_record in from_items_skipping_malformed::<SyncRecord>(items) {
+                let mut line = serde_json::to_vec(&sync_record)?;
+                line.push(b'\n');
+                writer.write_all(&line).await?;
+                n_sync_records += 1;
+            }
+        }
+    }
+    writer.flush().await?;
+
+    Ok(n_sync_records)
+}
+
+/// Deserialize items one at a time, so that one malformed item (e.g. a sync record missing its
+/// `notionDBProps`) doesn't fail the whole query. Malformed items are logged with their key and
+/// skipped.
+fn from_items_skipping_malformed<T: DeserializeOwned>(
+    items: Vec<HashMap<String, AttributeValue>>,
+) -> Vec<T> {
+    items
+        .into_iter()
+        .filter_map(|item| {
+            let key_attribute = |name: &str| match item.get(name) {
+                Some(AttributeValue::S(value)) => value.clone(),
+                _ => String::new(),
+            };
+            let user_id = key_attribute("userId");
+            let sort_key = key_attribute("SK");
+
+            from_item(item)
+                .map_err(|error| {
+                    warn!(user_id, sort_key, %error, "skipping malformed DynamoDB item");
+                })
+                .ok()
+        })
+        .collect()
+}
+
+/// Prefix of a sync record's `type` (the `type-data-index` partition key), which is followed by
+/// the record's sync partition number. This must match how the frontend writes the records.
+const PARTITION_TYPE_PREFIX: &str = "sync#";
+
+/// The `type` of the sync records in a partition, e.g. "sync#7"
+pub fn partition_type_key(partition: u16) -> String {
+    format!("{PARTITION_TYPE_PREFIX}{partition}")
+}
+
+/// Get the sync partition from a sync record's `type`. Returns `None` if it isn't a sync partition
+/// key.
+pub fn parse_partition_type_key(key: &str) -> Option<u16> {
+    key.strip_prefix(PARTITION_TYPE_PREFIX)
+        .filter(|partition| partition.bytes().all(|byte| byte.is_ascii_digit()))?
+        .parse()
+        .ok()
+}
+
+/// The sync record status that is processed if none is configured
+pub const DEFAULT_SYNC_STATUS: &str = "SCHEDULED";
+
+#[tracing::instrument(
+    level = "trace",
+    ret,
+    err,
+    fields(n_sync_records, consumed_capacity_units)
+)]
+async fn get_sync_records_for_one_partition(
+    client: &Client,
+    partition: u16,
+    status: &str,
+    page_size: Option<i32>,
+) -> Result<Vec<SyncRecord>, DatabaseRequestError> {
+    // Pages rather than items, so that the consumed capacity of each page can be read
+    let paginator = partition_query(client, partition, status)
+        // DynamoDB's default page size is used if this is `None`
+        .set_limit(page_size)
         .into_paginator()
-        .items()
         .send();
 
-    let items = paginator.collect::<Result<Vec<_>, _>>().await?;
+    let pages = paginator.collect::<Result<Vec<_>, _>>().await?;
 
-    let sync_records = from_items(items)?;
+    let consumed_capacity_units: f64 = pages
+        .iter()
+        .filter_map(|page| page.consumed_capacity()?.capacity_units())
+        .sum();
+    let items: Vec<_> = pages
+        .iter()
+        .flat_map(|page| page.items().unwrap_or_default().iter().cloned())
+        .collect();
+
+    let sync_records = from_items_skipping_malformed(items);
+
+    // Record the number of sync records and the capacity used as part of the current span.
+    tracing::Span::current().record("n_sync_records", sync_records.len());
+    tracing::Span::current().record("consumed_capacity_units", consumed_capacity_units);
 
     Ok(sync_records)
 }
 
-#[tracing::instrument(level = "trace", ret, err, fields(n_sync_records))]
-async fn get_sync_records_for_one_partition(
+/// Count the scheduled sync records in a partition, without fetching them, e.g. for capacity
+/// planning
+#[tracing::instrument(level = "trace", ret, err)]
+pub async fn count_scheduled_in_partition(
     client: &Client,
     partition: u16,
-) -> Result<Vec<SyncRecord>, DatabaseRequestError> {
-    let partition_string = "sync#".to_string() + &partition.to_string();
+) -> Result<i64, DatabaseRequestError> {
+    // A count query is still paginated if it reads more than 1MB of items
+    let pages = partition_query(client, partition, DEFAULT_SYNC_STATUS)
+        .select(Select::Count)
+        .into_paginator()
+        .send()
+        .collect::<Result<Vec<_>, _>>()
+        .await?;
 
-    let paginator = client
+    Ok(pages.iter().map(|page| i64::from(page.count())).sum())
+}
+
+/// Query one sync partition of the `type-data-index` for records with a status
+fn partition_query(
+    client: &Client,
+    partition: u16,
+    status: &str,
+) -> aws_sdk_dynamodb::client::fluent_builders::Query {
+    client
         .query()
         .table_name("tasks")
         .index_name("type-data-index")
         .key_condition_expression("#t = :partKey and begins_with(#s, :sortKeyValue)")
         .expression_attribute_names("#t", "type")
         .expression_attribute_names("#s", "data")
-        .expression_attribute_values(":partKey", AttributeValue::S(partition_string))
-        .expression_attribute_values(":sortKeyValue", AttributeValue::S("SCHEDULED".to_string()))
-        .into_paginator()
-        .items()
-        .send();
-
-    let items = paginator.collect::<Result<Vec<_>, _>>().await?;
-
-    let sync_records = from_items(items)?;
-
-    // Record the number of sync records as part of the current span.
-    tracing::Span::current().record("n_sync_records", sync_records.len());
+        .set_expression_attribute_values(Some(partition_query_expression_values(partition, status)))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+}
 
-    Ok(sync_records)
+/// Expression attribute values for querying one sync partition for records with a status
+fn partition_query_expression_values(
+    partition: u16,
+    status: &str,
+) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        (
+            ":partKey".to_owned(),
+            AttributeValue::S(partition_type_key(partition)),
+        ),
+        (
+            ":sortKeyValue".to_owned(),
+            AttributeValue::S(status.to_owned()),
+        ),
+    ])
 }
 
+/// Get the sync records in each partition that have one of the given statuses. A separate query
+/// is made for each partition and status, fetching at most `page_size` items per page.
+///
+/// The queries are started in the order of `partitions`, and the sync records are returned in
+/// that order too, so that the first partitions are processed first.
 #[tracing::instrument(ret, err, fields(n_sync_records))]
 pub async fn get_sync_records_for_partitions(
     client: Client,
     partitions: Vec<u16>,
+    statuses: &[String],
+    page_size: Option<i32>,
     // ) -> Result<Vec<SyncRecord>, DynamoClientError> {
 ) -> Result<Vec<SyncRecord>, DatabaseRequestError> {
     let mut set = JoinSet::new();
@@ -173,34 +592,39 @@ pub async fn get_sync_records_for_partitions(
     // error after that limit.
 
     let mut interval = tokio::time::interval(Duration::from_millis(20)); // see note below about this
-    for i in partitions {
+    for (i, status) in partitions
+        .into_iter()
+        .flat_map(|i| statuses.iter().map(move |status| (i, status.clone())))
+    {
         // add a small delay before successive task spawns, to avoid overloading DynamoDB capacity
         interval.tick().await; // ticks immediately on the first time
 
         let client = client.clone();
+        let query_index = set.len();
         set.spawn(
             async move {
-                do_with_retries(
-                    || get_sync_records_for_one_partition(&client, i),
-                    RetryConfig {
-                        maximum_backoff: Duration::from_secs(10),
-                        maximum_n_tries: Some(10),
-                        ..Default::default()
-                    },
+                let result = do_with_retries(
+                    || get_sync_records_for_one_partition(&client, i, &status, page_size),
+                    database_retry_config(),
                 )
-                .await
+                .await;
+                (query_index, result)
             }
             .in_current_span(),
         );
     }
 
-    let mut sync_records = vec![];
+    // the queries finish in any order, so put the results back in the order they were started
+    let mut results: Vec<Vec<SyncRecord>> =
+        std::iter::repeat_with(Vec::new).take(set.len()).collect();
 
     while let Some(res) = set.join_next().await {
-        let mut result = res.unwrap()?;
-        sync_records.append(&mut result);
+        let (query_index, result) = res.unwrap();
+        results[query_index] = result?;
     }
 
+    let sync_records: Vec<_> = results.into_iter().flatten().collect();
+
     trace!("{:#?}", &sync_records);
 
     // Record the number of sync records as part of the current span.
@@ -209,24 +633,340 @@ pub async fn get_sync_records_for_partitions(
     Ok(sync_records)
 }
 
+/// Retries for a DynamoDB request, bounded so that a struggling table fails the request rather
+/// than holding it up indefinitely
+fn database_retry_config() -> RetryConfig {
+    RetryConfig {
+        maximum_backoff: Duration::from_secs(10),
+        maximum_n_tries: Some(10),
+        ..Default::default()
+    }
+}
+
 #[typeshare]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SyncRecord {
     #[serde(rename = "userId")]
     pub user_id: String,
+    #[serde(rename = "SK")]
+    pub sort_key: String,
     #[serde(rename = "type")]
     record_type: String,
-    /// includes next sync timestamp
+    /// The status and next sync timestamp, e.g. "SCHEDULED#2007-04-05T14:30Z". See
+    /// [Self::next_sync_time].
     pub data: String,
     #[serde(rename = "lastSync")]
     pub last_sync: Option<String>,
+    /// Google Calendar sync token from a sync before each calendar had its own (see
+    /// [Self::google_sync_tokens]). Only used while the record has a single calendar.
+    #[serde(rename = "googleSyncToken")]
+    pub google_sync_token: Option<String>,
+    /// Google Calendar sync tokens from the previous sync, keyed by calendar id, for fetching only
+    /// the events that changed since then. All of a calendar's events are fetched if it has none.
+    #[serde(
+        rename = "googleSyncTokens",
+        default,
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub google_sync_tokens: HashMap<String, String>,
+    /// Don't sync again until after this time, after the sync has been failing
+    #[serde(rename = "nextRetryAfter")]
+    pub next_retry_after: Option<String>,
+    /// Number of sync failures since the last success
+    #[serde(rename = "consecutiveFailures", default)]
+    pub consecutive_failures: u32,
     #[serde(rename = "notionDBProps")]
     pub notion_db_props: NotionDBPropertyOptions,
-    #[serde(rename = "googleCalendar")]
-    pub google_calendar: String,
+    /// The Google Calendars synced with the Notion database. Older records have a single calendar
+    /// id rather than a list.
+    #[serde(rename = "googleCalendar", deserialize_with = "one_or_many")]
+    pub google_calendars: Vec<String>,
     #[serde(rename = "notionDatabase")]
     pub notion_database: String,
 }
+/// Deserialize either a single string or a list of strings
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+impl SyncRecord {
+    /// The sync token from the previous sync of one of [Self::google_calendars]
+    pub fn google_sync_token(&self, google_calendar_id: &str) -> Option<&str> {
+        match (
+            self.google_sync_tokens.get(google_calendar_id),
+            self.google_calendars.as_slice(),
+        ) {
+            (Some(sync_token), _) => Some(sync_token),
+            // a token from before each calendar had its own, which can only be for the one
+            (None, [only_calendar]) if only_calendar == google_calendar_id => {
+                self.google_sync_token.as_deref()
+            }
+            (None, _) => None,
+        }
+    }
+
+    /// The sync partition that this record is in, from its `type` (e.g. "sync#7")
+    pub fn partition(&self) -> Option<u16> {
+        parse_partition_type_key(&self.record_type)
+    }
+
+    /// When the record was last synced. `None` if it has never been synced, or if `lastSync` isn't
+    /// an RFC 3339 timestamp.
+    pub fn last_sync_time(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.last_sync.as_deref()?).ok()
+    }
+
+    /// When the record is next due to be synced, from the timestamp after the status in `data`.
+    /// The timestamp is RFC 3339, or the same without seconds (e.g. "2007-04-05T14:30Z").
+    ///
+    /// # Errors
+    ///
+    /// If `data` has no timestamp, or it isn't valid.
+    pub fn next_sync_time(&self) -> Result<DateTime<Utc>, chrono::ParseError> {
+        let timestamp = self
+            .data
+            .split_once('#')
+            .map_or("", |(_, timestamp)| timestamp);
+
+        DateTime::parse_from_rfc3339(timestamp)
+            .map(|time| time.with_timezone(&Utc))
+            .or_else(|error| {
+                NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%MZ")
+                    .map(|time| Utc.from_utc_datetime(&time))
+                    // the RFC 3339 error is more useful, as that is the usual format
+                    .map_err(|_| error)
+            })
+    }
+
+    /// When the record can next be retried after failing. `None` if it isn't backing off.
+    pub fn next_retry_after_time(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.next_retry_after.as_deref()?).ok()
+    }
+
+    fn key(&self) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            ("userId".to_owned(), AttributeValue::S(self.user_id.clone())),
+            ("SK".to_owned(), AttributeValue::S(self.sort_key.clone())),
+        ])
+    }
+}
+
+/// Record a failed sync, so that the record isn't retried until `next_retry_after`
+#[tracing::instrument(skip(sync_record), fields(user_id = %sync_record.user_id), err)]
+pub async fn record_sync_failure(
+    client: &Client,
+    sync_record: &SyncRecord,
+    next_retry_after: DateTime<Utc>,
+) -> Result<(), DatabaseRequestError> {
+    client
+        .update_item()
+        .table_name("tasks")
+        .set_key(Some(sync_record.key()))
+        .update_expression(
+            "SET nextRetryAfter = :nextRetryAfter, \
+            consecutiveFailures = if_not_exists(consecutiveFailures, :zero) + :one",
+        )
+        .set_expression_attribute_values(Some(sync_failure_expression_values(next_retry_after)))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+fn sync_failure_expression_values(
+    next_retry_after: DateTime<Utc>,
+) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        (
+            ":nextRetryAfter".to_owned(),
+            AttributeValue::S(next_retry_after.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        ),
+        (":zero".to_owned(), AttributeValue::N("0".to_owned())),
+        (":one".to_owned(), AttributeValue::N("1".to_owned())),
+    ])
+}
+
+/// Clear the failure backoff after a successful sync
+#[tracing::instrument(skip(sync_record), fields(user_id = %sync_record.user_id), err)]
+pub async fn clear_sync_failures(
+    client: &Client,
+    sync_record: &SyncRecord,
+) -> Result<(), DatabaseRequestError> {
+    client
+        .update_item()
+        .table_name("tasks")
+        .set_key(Some(sync_record.key()))
+        .update_expression("REMOVE nextRetryAfter, consecutiveFailures")
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+/// Record when a sync last succeeded, so that the next sync only acts on what changed since then,
+/// and isn't started again within the debounce
+#[tracing::instrument(skip(sync_record), fields(user_id = %sync_record.user_id), err)]
+pub async fn record_successful_sync(
+    client: &Client,
+    sync_record: &SyncRecord,
+    last_sync: DateTime<Utc>,
+) -> Result<(), DatabaseRequestError> {
+    client
+        .update_item()
+        .table_name("tasks")
+        .set_key(Some(sync_record.key()))
+        .update_expression("SET lastSync = :lastSync")
+        .set_expression_attribute_values(Some(HashMap::from([(
+            ":lastSync".to_owned(),
+            // not truncated to the second, which could put it before the changes that the
+            // sync itself made
+            AttributeValue::S(last_sync.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        )])))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+/// Store the sync token from the latest sync of each of the record's Google Calendars, in place of
+/// any from before. A calendar without one has all of its events fetched next time.
+#[tracing::instrument(
+    skip(sync_record, google_sync_tokens),
+    fields(user_id = %sync_record.user_id),
+    err
+)]
+pub async fn record_google_sync_tokens(
+    client: &Client,
+    sync_record: &SyncRecord,
+    google_sync_tokens: &HashMap<String, String>,
+) -> Result<(), DatabaseRequestError> {
+    client
+        .update_item()
+        .table_name("tasks")
+        .set_key(Some(sync_record.key()))
+        .update_expression("SET googleSyncTokens = :googleSyncTokens REMOVE googleSyncToken")
+        .set_expression_attribute_values(Some(HashMap::from([(
+            ":googleSyncTokens".to_owned(),
+            AttributeValue::M(
+                google_sync_tokens
+                    .iter()
+                    .map(|(google_calendar_id, sync_token)| {
+                        (
+                            google_calendar_id.clone(),
+                            AttributeValue::S(sync_token.clone()),
+                        )
+                    })
+                    .collect(),
+            ),
+        )])))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+/// Remove a user's Google refresh token, once Google has said it will never work again (e.g. the
+/// user revoked access), so that their Google Calendar isn't synced until they reconnect it
+#[tracing::instrument(skip(client), err)]
+pub async fn clear_google_refresh_token(
+    client: &Client,
+    user_id: &str,
+) -> Result<(), DatabaseRequestError> {
+    client
+        .update_item()
+        .table_name("tasks")
+        .set_key(Some(HashMap::from([
+            ("userId".to_owned(), AttributeValue::S(user_id.to_owned())),
+            ("SK".to_owned(), AttributeValue::S("userDetails".to_owned())),
+        ])))
+        .update_expression("REMOVE googleRefreshToken")
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+/// Force a full (non-incremental) sync of each of the user's sync records next time, e.g. after the
+/// compare logic changes, by removing their Google sync token and last sync time
+#[tracing::instrument(err)]
+pub async fn force_full_resync(
+    client: &Client,
+    user_id: &str,
+) -> Result<usize, DatabaseRequestError> {
+    let sync_records = get_sync_record(client, user_id).await?;
+
+    for sync_record in &sync_records {
+        force_full_resync_of(client, sync_record).await?;
+    }
+
+    Ok(sync_records.len())
+}
+
+/// Sync each of the user's sync records in the next round, rather than waiting for the debounce
+/// or a retry backoff, by removing their last sync time and failure state
+#[tracing::instrument(err)]
+pub async fn resync_user(client: &Client, user_id: &str) -> Result<usize, DatabaseRequestError> {
+    let sync_records = get_sync_record(client, user_id).await?;
+
+    for sync_record in &sync_records {
+        client
+            .update_item()
+            .table_name("tasks")
+            .set_key(Some(sync_record.key()))
+            .update_expression(RESYNC_EXPRESSION)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+    }
+
+    Ok(sync_records.len())
+}
+
+const RESYNC_EXPRESSION: &str = "REMOVE lastSync, nextRetryAfter, consecutiveFailures";
+
+/// Force a full sync of every user's sync records. See [force_full_resync].
+#[tracing::instrument(err)]
+pub async fn force_full_resync_all_users(client: &Client) -> Result<usize, DatabaseRequestError> {
+    let sync_records = get_sync_records(client).await?;
+
+    for sync_record in &sync_records {
+        force_full_resync_of(client, sync_record).await?;
+    }
+
+    Ok(sync_records.len())
+}
+
+#[tracing::instrument(skip(sync_record), fields(user_id = %sync_record.user_id), err)]
+async fn force_full_resync_of(
+    client: &Client,
+    sync_record: &SyncRecord,
+) -> Result<(), DatabaseRequestError> {
+    client
+        .update_item()
+        .table_name("tasks")
+        .set_key(Some(sync_record.key()))
+        .update_expression("REMOVE googleSyncToken, googleSyncTokens, lastSync")
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await?;
+
+    Ok(())
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NotionDBPropertyOptions {
@@ -246,6 +986,45 @@ pub enum DatabaseRequestError {
         #[from]
         source: serde_dynamo::Error,
     },
+    #[error("Error decrypting the user's credentials")]
+    CredentialDecryption(#[from] CredentialDecryptionError),
+    /// The item doesn't have the expected keys or attributes, e.g. because the schema has drifted
+    #[error("User {user_id}'s details don't match the expected schema: {detail}")]
+    SchemaMismatch { user_id: String, detail: String },
+    /// There's no user details item for the user, e.g. because they have deleted their account
+    #[error("User {user_id} not found")]
+    UserNotFound { user_id: String },
+}
+
+/// Error decrypting a user's credentials, see [decrypt_credentials]
+#[derive(Debug, Error)]
+pub enum CredentialDecryptionError {
+    #[error("{0:?}")]
+    Kms(#[from] aws_sdk_kms::types::SdkError<aws_sdk_kms::error::DecryptError>),
+    #[error("Encrypted credential isn't valid base64")]
+    Base64(#[from] base64::DecodeError),
+    #[error("KMS didn't return the decrypted credential")]
+    MissingPlaintext,
+    #[error("Decrypted credential isn't valid UTF-8")]
+    Utf8(#[from] std::string::FromUtf8Error),
+}
+
+/// Error loading a DynamoDB client, see [load_client_assuming_role]
+#[derive(Debug, Error)]
+pub enum LoadClientError {
+    #[error("No credentials in the environment to assume the role {role_arn} with")]
+    NoBaseCredentials { role_arn: String },
+}
+
+/// Error exporting the sync records, see [export_sync_records]
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("Error reading the sync records")]
+    Database(#[from] DatabaseRequestError),
+    #[error("Error serializing a sync record")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Error writing the export")]
+    Write(#[from] std::io::Error),
 }
 
 /// Error deriving from the DynamoDB client
@@ -255,6 +1034,54 @@ pub enum DynamoClientError {
     QueryError(#[from] SdkError<aws_sdk_dynamodb::error::QueryError>),
     #[error("{0:?}")]
     GetItemError(#[from] SdkError<aws_sdk_dynamodb::error::GetItemError>),
+    #[error("{0:?}")]
+    UpdateItemError(#[from] SdkError<aws_sdk_dynamodb::error::UpdateItemError>),
+}
+
+impl DatabaseRequestError {
+    /// Whether the request may succeed if it is retried: DynamoDB throttled it or had an internal
+    /// error, or there was no response at all (e.g. a timeout)
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::DatabaseError(DynamoClientError::QueryError(error)) => {
+                sdk_error_is_transient(error, |error: &QueryError| {
+                    error.is_provisioned_throughput_exceeded_exception()
+                        || error.is_request_limit_exceeded()
+                        || error.is_internal_server_error()
+                })
+            }
+            Self::DatabaseError(DynamoClientError::GetItemError(error)) => {
+                sdk_error_is_transient(error, |error: &GetItemError| {
+                    error.is_provisioned_throughput_exceeded_exception()
+                        || error.is_request_limit_exceeded()
+                        || error.is_internal_server_error()
+                })
+            }
+            Self::DatabaseError(DynamoClientError::UpdateItemError(error)) => {
+                sdk_error_is_transient(error, |error: &UpdateItemError| {
+                    error.is_provisioned_throughput_exceeded_exception()
+                        || error.is_request_limit_exceeded()
+                        || error.is_internal_server_error()
+                })
+            }
+            Self::SerdeError { .. }
+            | Self::CredentialDecryption(_)
+            | Self::SchemaMismatch { .. }
+            | Self::UserNotFound { .. } => false,
+        }
+    }
+}
+
+/// A service error (the source of the [SdkError]) is transient if `is_transient_service_error`
+/// says so. Errors without a response from DynamoDB are always transient.
+fn sdk_error_is_transient<E: std::error::Error + 'static>(
+    error: &SdkError<E>,
+    is_transient_service_error: impl Fn(&E) -> bool,
+) -> bool {
+    match std::error::Error::source(error).and_then(|source| source.downcast_ref::<E>()) {
+        Some(service_error) => is_transient_service_error(service_error),
+        None => true,
+    }
 }
 
 impl<T> From<SdkError<T>> for DatabaseRequestError
@@ -265,3 +1092,1098 @@ where
         Self::DatabaseError(value.into())
     }
 }
+
+/// Mocked DynamoDB clients and items, for the tests of any module that uses the database
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::collections::HashMap;
+
+    use aws_sdk_dynamodb::{model::AttributeValue, Client};
+    use aws_smithy_client::test_connection::TestConnection;
+    use aws_smithy_http::body::SdkBody;
+    use serde_dynamo::from_item;
+
+    use super::SyncRecord;
+
+    /// A client that returns the given query response body to a single request
+    pub(crate) fn mock_client(response_body: &'static str) -> Client {
+        client_with_connection(mock_connection(response_body))
+    }
+
+    pub(crate) fn mock_connection(response_body: &'static str) -> TestConnection<&'static str> {
+        TestConnection::new(vec![(
+            http::Request::builder()
+                .uri("https://dynamodb.eu-west-2.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(200)
+                .body(response_body)
+                .unwrap(),
+        )])
+    }
+
+    /// A request and successful response pair for a [TestConnection], with `body` as the response
+    pub(crate) fn json_response(
+        body: serde_json::Value,
+    ) -> (http::Request<SdkBody>, http::Response<String>) {
+        (
+            http::Request::builder()
+                .uri("https://dynamodb.eu-west-2.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(200)
+                .body(body.to_string())
+                .unwrap(),
+        )
+    }
+
+    pub(crate) fn client_with_connection<B>(connection: TestConnection<B>) -> Client
+    where
+        B: Clone + Send + Sync + 'static,
+        SdkBody: From<B>,
+    {
+        let config = aws_sdk_dynamodb::Config::builder()
+            .region(aws_sdk_dynamodb::Region::new("eu-west-2"))
+            .credentials_provider(aws_sdk_dynamodb::Credentials::new(
+                "access_key_id",
+                "secret_access_key",
+                None,
+                None,
+                "test",
+            ))
+            .build();
+
+        Client::from_conf_conn(config, connection)
+    }
+
+    /// A sync record in partition 3 that is due to be synced
+    pub(crate) fn sync_record_item(user_id: &str) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            ("userId".to_owned(), AttributeValue::S(user_id.to_owned())),
+            ("SK".to_owned(), AttributeValue::S("sync#0".to_owned())),
+            ("type".to_owned(), AttributeValue::S("sync#3".to_owned())),
+            (
+                "data".to_owned(),
+                AttributeValue::S("SCHEDULED#2007-04-05T14:30Z".to_owned()),
+            ),
+            (
+                "notionDBProps".to_owned(),
+                AttributeValue::M(HashMap::from([
+                    (
+                        "notionTitleId".to_owned(),
+                        AttributeValue::S("title".to_owned()),
+                    ),
+                    (
+                        "notionDoneId".to_owned(),
+                        AttributeValue::S("O%7CaE".to_owned()),
+                    ),
+                ])),
+            ),
+            (
+                "googleCalendar".to_owned(),
+                AttributeValue::S("calendar".to_owned()),
+            ),
+            (
+                "notionDatabase".to_owned(),
+                AttributeValue::S("database".to_owned()),
+            ),
+        ])
+    }
+
+    /// The sync record in [sync_record_item]
+    pub(crate) fn sync_record(user_id: &str) -> SyncRecord {
+        from_item(sync_record_item(user_id)).unwrap()
+    }
+
+    /// An item in DynamoDB's JSON wire format, e.g. for a mocked query response body
+    pub(crate) fn item_json(item: &HashMap<String, AttributeValue>) -> serde_json::Value {
+        item.iter()
+            .map(|(name, value)| (name.clone(), attribute_json(value)))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    fn attribute_json(value: &AttributeValue) -> serde_json::Value {
+        match value {
+            AttributeValue::S(value) => serde_json::json!({ "S": value }),
+            AttributeValue::N(value) => serde_json::json!({ "N": value }),
+            AttributeValue::Bool(value) => serde_json::json!({ "BOOL": value }),
+            AttributeValue::Null(value) => serde_json::json!({ "NULL": value }),
+            AttributeValue::M(value) => serde_json::json!({ "M": item_json(value) }),
+            AttributeValue::L(values) => {
+                serde_json::json!({ "L": values.iter().map(attribute_json).collect::<Vec<_>>() })
+            }
+            _ => unimplemented!("{value:?} isn't used by the fixtures"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use aws_smithy_client::test_connection::TestConnection;
+    use aws_smithy_http::body::SdkBody;
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::{layer::SubscriberExt, Layer};
+
+    use super::test_support::{
+        client_with_connection, item_json, json_response, mock_client, mock_connection,
+        sync_record_item,
+    };
+    use super::*;
+
+    /// Captures the f64 values recorded on any span
+    #[derive(Clone, Default)]
+    struct CaptureSpanFloats(Arc<Mutex<HashMap<String, f64>>>);
+
+    impl Visit for CaptureSpanFloats {
+        fn record_f64(&mut self, field: &Field, value: f64) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(field.name().to_owned(), value);
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for CaptureSpanFloats {
+        fn on_record(
+            &self,
+            _span: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[test]
+    fn client_builds_with_custom_connection_pool() {
+        let config = aws_config::SdkConfig::builder()
+            .region(aws_sdk_dynamodb::Region::new("eu-west-2"))
+            .build();
+
+        let client = client_from_config(
+            &config,
+            &ConnectionPoolSettings {
+                max_idle_per_host: Some(4),
+                idle_timeout: Some(Duration::from_secs(30)),
+                connect_timeout: Some(Duration::from_secs(2)),
+                read_timeout: Some(Duration::from_secs(10)),
+            },
+        );
+
+        assert_eq!(
+            Some(&aws_sdk_dynamodb::Region::new("eu-west-2")),
+            client.conf().region()
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_pool_read_timeout_applied() {
+        // accepts connections, but never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = aws_config::SdkConfig::builder()
+            .region(aws_sdk_dynamodb::Region::new("eu-west-2"))
+            .credentials_provider(aws_types::credentials::SharedCredentialsProvider::new(
+                aws_sdk_dynamodb::Credentials::new(
+                    "access_key_id",
+                    "secret_access_key",
+                    None,
+                    None,
+                    "test",
+                ),
+            ))
+            .endpoint_resolver(aws_smithy_http::endpoint::Endpoint::immutable(
+                format!("http://{}", listener.local_addr().unwrap())
+                    .parse()
+                    .unwrap(),
+            ))
+            .retry_config(aws_config::retry::RetryConfig::disabled())
+            .build();
+
+        let client = client_from_config(
+            &config,
+            &ConnectionPoolSettings {
+                read_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+
+        let started = std::time::Instant::now();
+        let result = client.get_item().table_name("table").send().await;
+        assert!(
+            matches!(&result, Err(SdkError::DispatchFailure(error)) if error.is_timeout()),
+            "{result:?}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn consumed_capacity_recorded_on_span() {
+        let client = mock_client(
+            r#"{
+                "Count": 0,
+                "ScannedCount": 0,
+                "Items": [],
+                "ConsumedCapacity": { "TableName": "tasks", "CapacityUnits": 2.5 }
+            }"#,
+        );
+
+        let captured = CaptureSpanFloats::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(captured.clone()));
+
+        let sync_records =
+            get_sync_records_for_one_partition(&client, 3, DEFAULT_SYNC_STATUS, None)
+                .await
+                .unwrap();
+
+        assert!(sync_records.is_empty());
+        assert_eq!(
+            Some(&2.5),
+            captured.0.lock().unwrap().get("consumed_capacity_units")
+        );
+    }
+
+    #[tokio::test]
+    async fn partition_query_page_size_applied() {
+        let connection = mock_connection(r#"{ "Count": 0, "ScannedCount": 0, "Items": [] }"#);
+        let client = client_with_connection(connection.clone());
+
+        get_sync_records_for_one_partition(&client, 3, DEFAULT_SYNC_STATUS, Some(25))
+            .await
+            .unwrap();
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(Some(25), body["Limit"].as_i64());
+    }
+
+    #[tokio::test]
+    async fn full_resync_removes_sync_tokens_and_last_sync() {
+        let connection = mock_connection("{}");
+        let client = client_with_connection(connection.clone());
+
+        let mut item = sync_record_item("user");
+        item.insert(
+            "googleSyncToken".to_owned(),
+            AttributeValue::S("CPDAlvWDx70CEPDAlvWDx70CGAU=".to_owned()),
+        );
+        item.insert(
+            "googleSyncTokens".to_owned(),
+            AttributeValue::M(HashMap::from([(
+                "work".to_owned(),
+                AttributeValue::S("CKCbvpWDx70CEKCbvpWDx70CGAU=".to_owned()),
+            )])),
+        );
+        item.insert(
+            "lastSync".to_owned(),
+            AttributeValue::S("2023-05-04T10:12:00Z".to_owned()),
+        );
+        let sync_record: SyncRecord = from_item(item).unwrap();
+        assert!(sync_record.google_sync_token.is_some());
+        assert!(sync_record.google_sync_token("work").is_some());
+
+        force_full_resync_of(&client, &sync_record).await.unwrap();
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            "REMOVE googleSyncToken, googleSyncTokens, lastSync",
+            body["UpdateExpression"]
+        );
+        assert_eq!(
+            serde_json::json!({ "userId": { "S": "user" }, "SK": { "S": "sync#0" } }),
+            body["Key"]
+        );
+    }
+
+    #[tokio::test]
+    async fn successful_sync_sets_last_sync() {
+        let connection = mock_connection("{}");
+        let client = client_with_connection(connection.clone());
+        let sync_record: SyncRecord = from_item(sync_record_item("user")).unwrap();
+        let last_sync = DateTime::parse_from_rfc3339("2023-05-04T10:12:00.250Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        record_successful_sync(&client, &sync_record, last_sync)
+            .await
+            .unwrap();
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!("SET lastSync = :lastSync", body["UpdateExpression"]);
+        assert_eq!(
+            serde_json::json!({ ":lastSync": { "S": "2023-05-04T10:12:00.250Z" } }),
+            body["ExpressionAttributeValues"]
+        );
+        // read back as the time the record was last synced
+        let mut item = sync_record_item("user");
+        item.insert(
+            "lastSync".to_owned(),
+            AttributeValue::S("2023-05-04T10:12:00.250Z".to_owned()),
+        );
+        let sync_record: SyncRecord = from_item(item).unwrap();
+        assert_eq!(
+            Some(last_sync),
+            sync_record
+                .last_sync_time()
+                .map(|time| time.with_timezone(&Utc))
+        );
+    }
+
+    #[tokio::test]
+    async fn google_sync_tokens_replaced() {
+        let connection = mock_connection("{}");
+        let client = client_with_connection(connection.clone());
+        let sync_record: SyncRecord = from_item(sync_record_item("user")).unwrap();
+
+        record_google_sync_tokens(
+            &client,
+            &sync_record,
+            &HashMap::from([(
+                "calendar".to_owned(),
+                "CPDAlvWDx70CEPDAlvWDx70CGAU=".to_owned(),
+            )]),
+        )
+        .await
+        .unwrap();
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            "SET googleSyncTokens = :googleSyncTokens REMOVE googleSyncToken",
+            body["UpdateExpression"]
+        );
+        assert_eq!(
+            serde_json::json!({
+                ":googleSyncTokens": { "M": { "calendar": { "S": "CPDAlvWDx70CEPDAlvWDx70CGAU=" } } }
+            }),
+            body["ExpressionAttributeValues"]
+        );
+    }
+
+    #[tokio::test]
+    async fn full_resync_of_all_users_queries_every_partition() {
+        let n_partitions = crate::cluster_management::TOTAL_NUMBER_OF_SYNC_PARTITIONS;
+        // one sync record in partition 3, and none in the others
+        let mut responses: Vec<_> = (0..n_partitions)
+            .map(|partition| {
+                let items = match partition {
+                    3 => vec![item_json(&sync_record_item("user"))],
+                    _ => vec![],
+                };
+                json_response(serde_json::json!({ "Count": items.len(), "Items": items }))
+            })
+            .collect();
+        responses.push(json_response(serde_json::json!({})));
+        let connection = TestConnection::new(responses);
+        let client = client_with_connection(connection.clone());
+
+        assert_eq!(1, force_full_resync_all_users(&client).await.unwrap());
+
+        let bodies: Vec<serde_json::Value> = connection
+            .requests()
+            .iter()
+            .map(|request| serde_json::from_slice(request.actual.body().bytes().unwrap()).unwrap())
+            .collect();
+        assert_eq!(n_partitions + 1, bodies.len());
+        for (partition, body) in bodies[..n_partitions].iter().enumerate() {
+            assert_eq!(
+                serde_json::json!({ "S": format!("sync#{partition}") }),
+                body["ExpressionAttributeValues"][":partKey"]
+            );
+        }
+        assert_eq!(
+            "REMOVE googleSyncToken, googleSyncTokens, lastSync",
+            bodies[n_partitions]["UpdateExpression"]
+        );
+        assert_eq!(
+            serde_json::json!({ "userId": { "S": "user" }, "SK": { "S": "sync#0" } }),
+            bodies[n_partitions]["Key"]
+        );
+    }
+
+    #[tokio::test]
+    async fn revoked_google_refresh_token_removed() {
+        let connection = mock_connection("{}");
+        let client = client_with_connection(connection.clone());
+
+        clear_google_refresh_token(&client, "user").await.unwrap();
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!("REMOVE googleRefreshToken", body["UpdateExpression"]);
+        assert_eq!(
+            serde_json::json!({ "userId": { "S": "user" }, "SK": { "S": "userDetails" } }),
+            body["Key"]
+        );
+    }
+
+    #[tokio::test]
+    async fn export_writes_a_json_line_per_record() {
+        let n_partitions = crate::cluster_management::TOTAL_NUMBER_OF_SYNC_PARTITIONS;
+        let items = |user_ids: &[&str]| -> Vec<_> {
+            user_ids
+                .iter()
+                .map(|user_id| item_json(&sync_record_item(user_id)))
+                .collect()
+        };
+        // partition 0 has two pages, partition 3 has one record, and the rest are empty
+        let mut responses = vec![
+            json_response(serde_json::json!({
+                "Count": 2,
+                "Items": items(&["user-1", "user-2"]),
+                "LastEvaluatedKey": { "userId": { "S": "user-2" }, "SK": { "S": "sync#0" } },
+            })),
+            json_response(serde_json::json!({ "Count": 1, "Items": items(&["user-3"]) })),
+        ];
+        responses.extend((1..n_partitions).map(|partition| {
+            let items = match partition {
+                3 => items(&["user-4"]),
+                _ => vec![],
+            };
+            json_response(serde_json::json!({ "Count": items.len(), "Items": items }))
+        }));
+        let connection = TestConnection::new(responses);
+        let client = client_with_connection(connection.clone());
+
+        let mut buffer = Vec::new();
+        let n_sync_records = export_sync_records(&client, &mut buffer).await.unwrap();
+
+        assert_eq!(4, n_sync_records);
+        let requests = connection.requests();
+        assert_eq!(n_partitions + 1, requests.len());
+        let partition_key = |request: usize| {
+            let body: serde_json::Value =
+                serde_json::from_slice(requests[request].actual.body().bytes().unwrap()).unwrap();
+            body["ExpressionAttributeValues"][":partKey"]["S"].clone()
+        };
+        assert_eq!("sync#0", partition_key(1));
+        assert_eq!("sync#3", partition_key(4));
+        let user_ids: Vec<_> = String::from_utf8(buffer)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<SyncRecord>(line).unwrap().user_id)
+            .collect();
+        assert_eq!(vec!["user-1", "user-2", "user-3", "user-4"], user_ids);
+    }
+
+    #[tokio::test]
+    async fn assume_role_provider_uses_role_arn() {
+        use aws_types::credentials::ProvideCredentials;
+
+        let base_config = aws_config::SdkConfig::builder()
+            .region(aws_sdk_dynamodb::Region::new("eu-west-2"))
+            .credentials_provider(aws_types::credentials::SharedCredentialsProvider::new(
+                aws_sdk_dynamodb::Credentials::new(
+                    "access_key_id",
+                    "secret_access_key",
+                    None,
+                    None,
+                    "test",
+                ),
+            ))
+            .build();
+
+        let (sts, request) = aws_smithy_client::test_connection::capture_request(None);
+        let provider = assume_role_provider(
+            "arn:aws:iam::123456789012:role/hello-rust-dynamodb",
+            "hello-rust-node-a",
+            &base_config,
+            Some(aws_smithy_client::erase::DynConnector::new(sts)),
+        )
+        .unwrap();
+
+        // the empty response isn't valid, but the AssumeRole request has been sent
+        let _ = provider.provide_credentials().await;
+
+        let request = request.expect_request();
+        let body = std::str::from_utf8(request.body().bytes().unwrap()).unwrap();
+        assert!(body.contains("Action=AssumeRole"), "{body}");
+        assert!(
+            body.contains("RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fhello-rust-dynamodb"),
+            "{body}"
+        );
+        assert!(body.contains("RoleSessionName=hello-rust-node-a"), "{body}");
+    }
+
+    #[test]
+    fn assume_role_without_base_credentials_fails() {
+        let base_config = aws_config::SdkConfig::builder()
+            .region(aws_sdk_dynamodb::Region::new("eu-west-2"))
+            .build();
+
+        let result = assume_role_provider(
+            "arn:aws:iam::123456789012:role/hello-rust-dynamodb",
+            "hello-rust-node-a",
+            &base_config,
+            None,
+        );
+
+        assert!(
+            matches!(
+                &result,
+                Err(LoadClientError::NoBaseCredentials { role_arn })
+                    if role_arn == "arn:aws:iam::123456789012:role/hello-rust-dynamodb"
+            ),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn role_session_names_are_valid() {
+        assert_eq!("hello-rust-node-a", role_session_name("node-a"));
+        assert_eq!("hello-rust-node-a-b", role_session_name("node a/b"));
+        assert_eq!(64, role_session_name(&"a".repeat(100)).len());
+    }
+
+    #[test]
+    fn partition_type_key_round_trip() {
+        for partition in [0, 7, 99, u16::MAX] {
+            assert_eq!(
+                Some(partition),
+                parse_partition_type_key(&partition_type_key(partition))
+            );
+        }
+        assert_eq!("sync#0", partition_type_key(0));
+        assert_eq!("sync#65535", partition_type_key(u16::MAX));
+
+        for key in [
+            "sync#",
+            "sync#65536",
+            "sync#+5",
+            "sync#-1",
+            "userDetails",
+            "7",
+        ] {
+            assert_eq!(None, parse_partition_type_key(key), "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn throttled_get_single_user_retried() {
+        let request = || {
+            http::Request::builder()
+                .uri("https://dynamodb.eu-west-2.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap()
+        };
+        let connection = TestConnection::new(vec![
+            (
+                request(),
+                http::Response::builder()
+                    .status(400)
+                    .body(
+                        r#"{
+                            "__type": "com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException",
+                            "message": "The level of configured provisioned throughput for the table was exceeded."
+                        }"#,
+                    )
+                    .unwrap(),
+            ),
+            (
+                request(),
+                http::Response::builder()
+                    .status(200)
+                    .body(
+                        r#"{
+                            "Item": {
+                                "userId": { "S": "user" },
+                                "SK": { "S": "userDetails" },
+                                "type": { "S": "userDetails" },
+                                "data": { "S": "user@example.com" }
+                            }
+                        }"#,
+                    )
+                    .unwrap(),
+            ),
+        ]);
+        let client = client_with_connection(connection.clone());
+
+        let user = get_single_user(&client, "user".to_owned(), false, None)
+            .await
+            .unwrap();
+
+        assert_eq!("user", user.user_id);
+        assert_eq!(2, connection.requests().len());
+    }
+
+    #[tokio::test]
+    async fn single_user_read_consistently() {
+        let connection = mock_connection(
+            r#"{
+                "Item": {
+                    "userId": { "S": "user" },
+                    "SK": { "S": "userDetails" },
+                    "type": { "S": "userDetails" },
+                    "data": { "S": "user@example.com" }
+                }
+            }"#,
+        );
+        let client = client_with_connection(connection.clone());
+
+        get_single_user(&client, "user".to_owned(), true, None)
+            .await
+            .unwrap();
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(Some(true), body["ConsistentRead"].as_bool());
+        assert_eq!(
+            serde_json::json!({ "userId": { "S": "user" }, "SK": { "S": "userDetails" } }),
+            body["Key"]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_user_not_found() {
+        let connection = mock_connection("{}");
+        let client = client_with_connection(connection.clone());
+
+        let error = get_single_user(&client, "user".to_owned(), false, None)
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(&error, DatabaseRequestError::UserNotFound { user_id } if user_id == "user"),
+            "{error:?}"
+        );
+        // not retried
+        assert_eq!(1, connection.requests().len());
+    }
+
+    #[tokio::test]
+    async fn drifted_user_details_are_a_schema_mismatch() {
+        let client = mock_client(
+            r#"{
+                "Item": {
+                    "userId": { "S": "user" },
+                    "SK": { "S": "userDetails" },
+                    "googleRefreshToken": { "S": "refresh-token" }
+                }
+            }"#,
+        );
+
+        let error = get_single_user(&client, "user".to_owned(), false, None)
+            .await
+            .unwrap_err();
+
+        match error {
+            DatabaseRequestError::SchemaMismatch { user_id, detail } => {
+                assert_eq!("user", user_id);
+                assert_eq!("missing type", detail);
+            }
+            error => panic!("expected a schema mismatch, got {error:?}"),
+        }
+
+        let drifted_sort_key = HashMap::from([
+            ("userId".to_owned(), AttributeValue::S("user".to_owned())),
+            (
+                "SK".to_owned(),
+                AttributeValue::S("userDetails#v2".to_owned()),
+            ),
+            (
+                "type".to_owned(),
+                AttributeValue::S("userDetails".to_owned()),
+            ),
+            (
+                "data".to_owned(),
+                AttributeValue::S("user@example.com".to_owned()),
+            ),
+        ]);
+        assert!(matches!(
+            check_user_details_shape("user", &drifted_sort_key),
+            Err(DatabaseRequestError::SchemaMismatch { .. })
+        ));
+    }
+
+    fn user_with_credentials(google_refresh_token: &str, notion_access_token: &str) -> UserRecord {
+        UserRecord {
+            user_id: "user".to_owned(),
+            record_type: "userDetails".to_owned(),
+            data: "user@example.com".to_owned(),
+            google_refresh_token: Some(google_refresh_token.into()),
+            notion_data: Some(UserRecordNotionData {
+                notion_bot_id: "notionB#bot".to_owned(),
+                notion_access_token: notion_access_token.into(),
+            }),
+        }
+    }
+
+    fn kms_decrypt_event(plaintext: &str) -> (http::Request<SdkBody>, http::Response<String>) {
+        (
+            http::Request::builder()
+                .uri("https://kms.eu-west-2.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(200)
+                .body(
+                    serde_json::json!({
+                        "KeyId": "arn:aws:kms:eu-west-2:111122223333:key/credentials",
+                        "Plaintext": base64::engine::general_purpose::STANDARD.encode(plaintext),
+                        "EncryptionAlgorithm": "SYMMETRIC_DEFAULT",
+                    })
+                    .to_string(),
+                )
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn credentials_decrypted_with_kms() {
+        let connection = TestConnection::new(vec![
+            kms_decrypt_event("refresh-token"),
+            kms_decrypt_event("notion-token"),
+        ]);
+        let config = aws_sdk_kms::Config::builder()
+            .region(aws_sdk_kms::Region::new("eu-west-2"))
+            .credentials_provider(aws_sdk_kms::Credentials::new(
+                "access_key_id",
+                "secret_access_key",
+                None,
+                None,
+                "test",
+            ))
+            .build();
+        let kms_client = aws_sdk_kms::Client::from_conf_conn(config, connection.clone());
+
+        let user = decrypt_credentials(
+            user_with_credentials(
+                "ZW5jcnlwdGVkLXJlZnJlc2gtdG9rZW4=",
+                "ZW5jcnlwdGVkLW5vdGlvbi10b2tlbg==",
+            ),
+            Some(&kms_client),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            Some("refresh-token"),
+            user.google_refresh_token
+                .as_ref()
+                .map(SecretString::expose_secret)
+        );
+        assert_eq!(
+            "notion-token",
+            user.notion_data
+                .unwrap()
+                .notion_access_token
+                .expose_secret()
+        );
+
+        let ciphertexts: Vec<_> = connection
+            .requests()
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value =
+                    serde_json::from_slice(request.actual.body().bytes().unwrap()).unwrap();
+                body["CiphertextBlob"].as_str().unwrap().to_owned()
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "ZW5jcnlwdGVkLXJlZnJlc2gtdG9rZW4=",
+                "ZW5jcnlwdGVkLW5vdGlvbi10b2tlbg==",
+            ],
+            ciphertexts
+        );
+    }
+
+    #[tokio::test]
+    async fn credentials_unchanged_without_kms() {
+        let user =
+            decrypt_credentials(user_with_credentials("refresh-token", "notion-token"), None)
+                .await
+                .unwrap();
+
+        assert_eq!(
+            Some("refresh-token"),
+            user.google_refresh_token
+                .as_ref()
+                .map(SecretString::expose_secret)
+        );
+        assert_eq!(
+            "notion-token",
+            user.notion_data
+                .unwrap()
+                .notion_access_token
+                .expose_secret()
+        );
+    }
+
+    #[tokio::test]
+    async fn scheduled_records_counted() {
+        let connection = mock_connection(r#"{ "Count": 42, "ScannedCount": 42 }"#);
+        let client = client_with_connection(connection.clone());
+
+        assert_eq!(42, count_scheduled_in_partition(&client, 7).await.unwrap());
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!("COUNT", body["Select"]);
+        assert_eq!("sync#7", body["ExpressionAttributeValues"][":partKey"]["S"]);
+        assert_eq!(
+            DEFAULT_SYNC_STATUS,
+            body["ExpressionAttributeValues"][":sortKeyValue"]["S"]
+        );
+    }
+
+    #[test]
+    fn partition_query_uses_status() {
+        let values = partition_query_expression_values(7, "RETRY");
+
+        assert_eq!(
+            Some(&AttributeValue::S("RETRY".to_owned())),
+            values.get(":sortKeyValue")
+        );
+        assert_eq!(
+            Some(&AttributeValue::S("sync#7".to_owned())),
+            values.get(":partKey")
+        );
+    }
+
+    #[test]
+    fn sync_failure_backoff_fields() {
+        let mut item = sync_record_item("user");
+        item.insert(
+            "nextRetryAfter".to_owned(),
+            AttributeValue::S("2023-05-04T10:12:00Z".to_owned()),
+        );
+        item.insert(
+            "consecutiveFailures".to_owned(),
+            AttributeValue::N("3".to_owned()),
+        );
+
+        let sync_record: SyncRecord = from_item(item).unwrap();
+        assert_eq!(3, sync_record.consecutive_failures);
+        assert_eq!(
+            DateTime::parse_from_rfc3339("2023-05-04T10:12:00Z").ok(),
+            sync_record.next_retry_after_time()
+        );
+        assert_eq!(
+            HashMap::from([
+                ("userId".to_owned(), AttributeValue::S("user".to_owned())),
+                ("SK".to_owned(), AttributeValue::S("sync#0".to_owned())),
+            ]),
+            sync_record.key()
+        );
+
+        // records that have never failed don't have the fields
+        let sync_record: SyncRecord = from_item(sync_record_item("user")).unwrap();
+        assert_eq!(0, sync_record.consecutive_failures);
+        assert_eq!(None, sync_record.next_retry_after_time());
+
+        let next_retry_after = DateTime::parse_from_rfc3339("2023-05-04T10:12:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            Some(&AttributeValue::S("2023-05-04T10:12:00Z".to_owned())),
+            sync_failure_expression_values(next_retry_after).get(":nextRetryAfter")
+        );
+    }
+
+    #[test]
+    fn next_sync_time_parsed_from_data() {
+        let sync_record = |data: &str| -> SyncRecord {
+            let mut item = sync_record_item("user");
+            item.insert("data".to_owned(), AttributeValue::S(data.to_owned()));
+            from_item(item).unwrap()
+        };
+
+        assert_eq!(
+            Utc.with_ymd_and_hms(2007, 4, 5, 14, 30, 0).unwrap(),
+            sync_record("SCHEDULED#2007-04-05T14:30Z")
+                .next_sync_time()
+                .unwrap()
+        );
+        assert_eq!(
+            Utc.with_ymd_and_hms(2023, 5, 4, 9, 12, 0).unwrap(),
+            sync_record("RETRY#2023-05-04T10:12:00+01:00")
+                .next_sync_time()
+                .unwrap()
+        );
+
+        for data in [
+            "SCHEDULED",
+            "SCHEDULED#",
+            "SCHEDULED#tomorrow",
+            "SCHEDULED#2007-04-05",
+            "SCHEDULED#2007-13-05T14:30Z",
+        ] {
+            assert!(sync_record(data).next_sync_time().is_err(), "{data}");
+        }
+    }
+
+    #[test]
+    fn google_calendars_from_a_string_or_a_list() {
+        let sync_record = |google_calendar: AttributeValue| -> SyncRecord {
+            let mut item = sync_record_item("user");
+            item.insert("googleCalendar".to_owned(), google_calendar);
+            from_item(item).unwrap()
+        };
+
+        assert_eq!(
+            vec!["primary"],
+            sync_record(AttributeValue::S("primary".to_owned())).google_calendars
+        );
+        assert_eq!(
+            vec!["primary", "work@group.calendar.google.com"],
+            sync_record(AttributeValue::L(vec![
+                AttributeValue::S("primary".to_owned()),
+                AttributeValue::S("work@group.calendar.google.com".to_owned()),
+            ]))
+            .google_calendars
+        );
+
+        let from_json: SyncRecord = serde_json::from_value(serde_json::json!({
+            "userId": "user",
+            "SK": "sync#0",
+            "type": "sync#3",
+            "data": "SCHEDULED#2007-04-05T14:30Z",
+            "notionDBProps": { "notionTitleId": "title", "notionDoneId": "done" },
+            "googleCalendar": ["primary", "work@group.calendar.google.com"],
+            "notionDatabase": "database",
+        }))
+        .unwrap();
+        assert_eq!(2, from_json.google_calendars.len());
+    }
+
+    #[test]
+    fn google_sync_token_per_calendar() {
+        let google_sync_tokens = |sync_record: &SyncRecord| -> Vec<Option<&str>> {
+            sync_record
+                .google_calendars
+                .iter()
+                .map(|google_calendar_id| sync_record.google_sync_token(google_calendar_id))
+                .collect()
+        };
+
+        let mut item = sync_record_item("user");
+        item.insert(
+            "googleCalendar".to_owned(),
+            AttributeValue::L(vec![
+                AttributeValue::S("primary".to_owned()),
+                AttributeValue::S("work@group.calendar.google.com".to_owned()),
+            ]),
+        );
+        item.insert(
+            "googleSyncTokens".to_owned(),
+            AttributeValue::M(HashMap::from([(
+                "work@group.calendar.google.com".to_owned(),
+                AttributeValue::S("CKCbvpWDx70CEKCbvpWDx70CGAU=".to_owned()),
+            )])),
+        );
+        // from before each calendar had its own, so it isn't known which calendar it is for
+        item.insert(
+            "googleSyncToken".to_owned(),
+            AttributeValue::S("CPDAlvWDx70CEPDAlvWDx70CGAU=".to_owned()),
+        );
+        let sync_record: SyncRecord = from_item(item).unwrap();
+        assert_eq!(
+            vec![None, Some("CKCbvpWDx70CEKCbvpWDx70CGAU=")],
+            google_sync_tokens(&sync_record)
+        );
+
+        // an older record, with a single calendar and its token
+        let mut item = sync_record_item("user");
+        item.insert(
+            "googleSyncToken".to_owned(),
+            AttributeValue::S("CPDAlvWDx70CEPDAlvWDx70CGAU=".to_owned()),
+        );
+        let sync_record: SyncRecord = from_item(item).unwrap();
+        assert_eq!(
+            vec![Some("CPDAlvWDx70CEPDAlvWDx70CGAU=")],
+            google_sync_tokens(&sync_record)
+        );
+    }
+
+    #[test]
+    fn user_record_debug_redacts_tokens() {
+        let user: UserRecord = from_item(HashMap::from([
+            ("userId".to_owned(), AttributeValue::S("user-1".to_owned())),
+            (
+                "type".to_owned(),
+                AttributeValue::S("userDetails".to_owned()),
+            ),
+            ("data".to_owned(), AttributeValue::S("ACTIVE".to_owned())),
+            (
+                "googleRefreshToken".to_owned(),
+                AttributeValue::S("google_secret".to_owned()),
+            ),
+            (
+                "notionBotId".to_owned(),
+                AttributeValue::S("notionB#bot_id".to_owned()),
+            ),
+            (
+                "notionAccessToken".to_owned(),
+                AttributeValue::S("notion_secret".to_owned()),
+            ),
+        ]))
+        .unwrap();
+
+        let debug_output = format!("{user:?}");
+        assert!(!debug_output.contains("google_secret"), "{debug_output}");
+        assert!(!debug_output.contains("notion_secret"), "{debug_output}");
+        assert!(debug_output.contains("user-1"), "{debug_output}");
+
+        assert_eq!(
+            "notion_secret",
+            user.notion_data
+                .unwrap()
+                .notion_access_token
+                .expose_secret()
+        );
+    }
+
+    #[test]
+    fn malformed_items_are_skipped() {
+        let mut missing_props = sync_record_item("user-2");
+        missing_props.remove("notionDBProps");
+        let mut wrong_type = sync_record_item("user-3");
+        wrong_type.insert(
+            "googleCalendar".to_owned(),
+            AttributeValue::N("1".to_owned()),
+        );
+
+        let sync_records: Vec<SyncRecord> = from_items_skipping_malformed(vec![
+            sync_record_item("user-1"),
+            missing_props,
+            wrong_type,
+            sync_record_item("user-4"),
+        ]);
+
+        assert_eq!(
+            vec!["user-1", "user-4"],
+            sync_records
+                .iter()
+                .map(|record| record.user_id.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(3), sync_records[0].partition());
+    }
+
+    #[test]
+    fn partition_query_default_status() {
+        let values = partition_query_expression_values(0, DEFAULT_SYNC_STATUS);
+
+        assert_eq!(
+            Some(&AttributeValue::S("SCHEDULED".to_owned())),
+            values.get(":sortKeyValue")
+        );
+    }
+}
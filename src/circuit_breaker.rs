@@ -0,0 +1,308 @@
+//! A simple circuit breaker for calls to external services (Notion, Google).
+//!
+//! Once the number of consecutive failures reaches a threshold the breaker opens, and calls fail
+//! fast until the cooldown has passed. Then a single probe call is allowed through (half-open). If
+//! it succeeds the breaker closes again, otherwise it re-opens for another cooldown. A probe that
+//! never reports back (e.g. it was cancelled) is given up on after a cooldown, letting another one
+//! through.
+
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tracing::{event, Level};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls are allowed
+    Closed,
+    /// Calls fail fast until the cooldown has passed
+    Open,
+    /// A single probe call is in progress
+    HalfOpen,
+}
+
+#[derive(Error, Debug)]
+pub enum CircuitBreakerError<E> {
+    #[error("circuit breaker for {0} is open")]
+    Open(&'static str),
+    #[error(transparent)]
+    Inner(E),
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// When the current half-open probe was let through
+    probe_started_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// The external service, used in logs and errors
+    name: &'static str,
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            name,
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probe_started_at: None,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+            .lock()
+            .expect("mutex should not be poisoned")
+            .state
+    }
+
+    /// Run `f` if the breaker allows it, recording whether it succeeded.
+    ///
+    /// Only errors that `is_transient` (e.g. timeouts or 5xx responses) count as failures. Other
+    /// errors, like a 404 or a revoked token, are specific to the request, and show that the
+    /// service is up, so they count as a success.
+    pub async fn call<T, E, Fut, F>(
+        &self,
+        f: F,
+        is_transient: impl Fn(&E) -> bool,
+    ) -> Result<T, CircuitBreakerError<E>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        if !self.try_acquire(Instant::now()) {
+            return Err(CircuitBreakerError::Open(self.name));
+        }
+
+        match f().await {
+            Ok(result) => {
+                self.record_success();
+                Ok(result)
+            }
+            Err(error) => {
+                if is_transient(&error) {
+                    self.record_failure(Instant::now());
+                } else {
+                    self.record_success();
+                }
+                Err(CircuitBreakerError::Inner(error))
+            }
+        }
+    }
+
+    fn try_acquire(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().expect("mutex should not be poisoned");
+
+        match state.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                let cooldown_passed = state
+                    .opened_at
+                    .is_none_or(|opened_at| now >= opened_at + self.cooldown);
+
+                if cooldown_passed {
+                    event!(Level::INFO, circuit_breaker = self.name, "half-open");
+                    state.state = CircuitState::HalfOpen;
+                    state.probe_started_at = Some(now);
+                }
+
+                cooldown_passed
+            }
+            // only the single probe call is allowed, unless it has been so long that it must have
+            // been dropped (or panicked) without recording its result
+            CircuitState::HalfOpen => {
+                let probe_abandoned = state
+                    .probe_started_at
+                    .is_none_or(|started_at| now >= started_at + self.cooldown);
+
+                if probe_abandoned {
+                    event!(
+                        Level::WARN,
+                        circuit_breaker = self.name,
+                        "half-open probe never finished, allowing another"
+                    );
+                    state.probe_started_at = Some(now);
+                }
+
+                probe_abandoned
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().expect("mutex should not be poisoned");
+
+        if state.state != CircuitState::Closed {
+            event!(Level::INFO, circuit_breaker = self.name, "closed");
+        }
+
+        state.state = CircuitState::Closed;
+        state.consecutive_failures = 0;
+        state.opened_at = None;
+        state.probe_started_at = None;
+    }
+
+    fn record_failure(&self, now: Instant) {
+        let mut state = self.state.lock().expect("mutex should not be poisoned");
+
+        state.consecutive_failures += 1;
+
+        let should_open = match state.state {
+            CircuitState::HalfOpen => true,
+            CircuitState::Closed => state.consecutive_failures >= self.failure_threshold,
+            CircuitState::Open => false,
+        };
+
+        if should_open {
+            event!(
+                Level::WARN,
+                circuit_breaker = self.name,
+                consecutive_failures = state.consecutive_failures,
+                "opened"
+            );
+            state.state = CircuitState::Open;
+            state.opened_at = Some(now);
+            state.probe_started_at = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Error, Debug)]
+    #[error("simulated failure")]
+    struct SimulatedFailure;
+
+    async fn fail(breaker: &CircuitBreaker) -> Result<(), CircuitBreakerError<SimulatedFailure>> {
+        breaker
+            .call(|| async { Err(SimulatedFailure) }, |_| true)
+            .await
+    }
+
+    #[tokio::test]
+    async fn opens_after_threshold() {
+        let breaker = CircuitBreaker::new("test", 3, Duration::from_secs(30));
+
+        for _ in 0..2 {
+            assert!(matches!(
+                fail(&breaker).await,
+                Err(CircuitBreakerError::Inner(_))
+            ));
+            assert_eq!(CircuitState::Closed, breaker.state());
+        }
+
+        assert!(matches!(
+            fail(&breaker).await,
+            Err(CircuitBreakerError::Inner(_))
+        ));
+        assert_eq!(CircuitState::Open, breaker.state());
+
+        // fails fast without calling the function
+        let result: Result<(), CircuitBreakerError<SimulatedFailure>> = breaker
+            .call(
+                || async { panic!("should not be called while open") },
+                |_| true,
+            )
+            .await;
+        assert!(matches!(result, Err(CircuitBreakerError::Open("test"))));
+    }
+
+    #[tokio::test]
+    async fn success_resets_failure_count() {
+        let breaker = CircuitBreaker::new("test", 2, Duration::from_secs(30));
+
+        fail(&breaker).await.unwrap_err();
+        breaker
+            .call(|| async { Ok::<_, SimulatedFailure>(()) }, |_| true)
+            .await
+            .unwrap();
+        fail(&breaker).await.unwrap_err();
+
+        assert_eq!(CircuitState::Closed, breaker.state());
+    }
+
+    #[tokio::test]
+    async fn non_transient_errors_not_counted() {
+        let breaker = CircuitBreaker::new("test", 2, Duration::from_secs(30));
+
+        fail(&breaker).await.unwrap_err();
+        // e.g. a 404, which shows the service is responding
+        let result = breaker
+            .call(|| async { Err::<(), _>(SimulatedFailure) }, |_| false)
+            .await;
+        assert!(matches!(result, Err(CircuitBreakerError::Inner(_))));
+        fail(&breaker).await.unwrap_err();
+
+        assert_eq!(CircuitState::Closed, breaker.state());
+    }
+
+    #[test]
+    fn half_open_after_cooldown() {
+        let cooldown = Duration::from_secs(30);
+        let breaker = CircuitBreaker::new("test", 1, cooldown);
+        let opened_at = Instant::now();
+
+        breaker.record_failure(opened_at);
+        assert_eq!(CircuitState::Open, breaker.state());
+
+        assert!(!breaker.try_acquire(opened_at + Duration::from_secs(10)));
+        assert_eq!(CircuitState::Open, breaker.state());
+
+        // probe allowed once the cooldown has passed, but only one at a time
+        assert!(breaker.try_acquire(opened_at + cooldown));
+        assert_eq!(CircuitState::HalfOpen, breaker.state());
+        assert!(!breaker.try_acquire(opened_at + cooldown));
+
+        // failed probe re-opens the breaker for another cooldown
+        let reopened_at = opened_at + cooldown;
+        breaker.record_failure(reopened_at);
+        assert_eq!(CircuitState::Open, breaker.state());
+        assert!(!breaker.try_acquire(reopened_at + Duration::from_secs(10)));
+
+        // successful probe closes it
+        assert!(breaker.try_acquire(reopened_at + cooldown));
+        breaker.record_success();
+        assert_eq!(CircuitState::Closed, breaker.state());
+        assert!(breaker.try_acquire(reopened_at + cooldown));
+    }
+
+    #[test]
+    fn another_probe_allowed_once_probe_abandoned() {
+        let cooldown = Duration::from_secs(30);
+        let breaker = CircuitBreaker::new("test", 1, cooldown);
+        let opened_at = Instant::now();
+        breaker.record_failure(opened_at);
+
+        // the probe is dropped without recording a result
+        let probe_started_at = opened_at + cooldown;
+        assert!(breaker.try_acquire(probe_started_at));
+        assert!(!breaker.try_acquire(probe_started_at + Duration::from_secs(10)));
+
+        assert!(breaker.try_acquire(probe_started_at + cooldown));
+        assert_eq!(CircuitState::HalfOpen, breaker.state());
+        // but still only one at a time
+        assert!(!breaker.try_acquire(probe_started_at + cooldown));
+
+        breaker.record_success();
+        assert_eq!(CircuitState::Closed, breaker.state());
+    }
+}
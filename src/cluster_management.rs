@@ -1,14 +1,16 @@
 //! Clustering management using etcd. Get the number of replicas and manage leases on sync
 //! partitions.
 
+use std::collections::HashMap;
+
 use once_cell::sync::Lazy;
 use thiserror::Error;
-use tracing::{debug, error, trace, Instrument};
+use tracing::{debug, error, trace, warn, Instrument};
 
-use crate::{do_with_retries_infinite, etcd};
+use crate::{do_with_retries_while, etcd, metrics, RetryConfig};
 
 use crate::etcd::{
-    etcdserverpb::{PutResponse, RangeResponse},
+    etcdserverpb::{RangeResponse, TxnResponse},
     EtcdClients, KvClient,
 };
 
@@ -18,6 +20,70 @@ pub static REPLICA_PREFIX_RANGE_END: Lazy<String> =
 pub const SYNC_LOCK_PREFIX: &str = "/sync_locks/";
 pub static SYNC_LOCK_PREFIX_RANGE_END: Lazy<String> =
     Lazy::new(|| crate::etcd::calculate_prefix_range_end(SYNC_LOCK_PREFIX));
+pub const USER_LOCK_PREFIX: &str = "/user_locks/";
+
+/// This should be equal to the total number of sync partitions in DynamoDB.
+/// Perhaps there should be a way to calculate this automatically?! For now it is fine as a compile
+/// time constant.
+pub const TOTAL_NUMBER_OF_SYNC_PARTITIONS: usize = 100;
+
+/// The etcd key used to record membership of a node
+pub fn node_key(node_name: &str) -> String {
+    format!("{}{}", REPLICA_PREFIX, node_name)
+}
+
+/// Get the node name from an etcd node membership key. Returns `None` if the key isn't a node
+/// membership key.
+pub fn parse_node_key(key: &str) -> Option<&str> {
+    key.strip_prefix(REPLICA_PREFIX)
+}
+
+/// The etcd key used for a sync partition lock
+pub fn sync_lock_key(lock_key: &str) -> String {
+    format!("{}{}", SYNC_LOCK_PREFIX, lock_key)
+}
+
+/// An etcd key as a string. etcd keys are arbitrary bytes, so a key that isn't valid UTF-8 is
+/// logged and skipped, rather than stopping the node.
+fn key_str(key: &[u8]) -> Option<&str> {
+    std::str::from_utf8(key)
+        .map_err(|error| warn!(?key, %error, "skipping etcd key that isn't valid UTF-8"))
+        .ok()
+}
+
+/// The value of a node's membership record once it is draining, so that the other nodes stop
+/// counting it when assigning partitions
+const DRAINING_NODE_VALUE: &str = "draining";
+
+/// The names of the nodes in a `/nodes/` range response that partitions are assigned to, in key
+/// order. Draining nodes are left out, so that the other nodes take over their partitions.
+fn worker_names(worker_records: &RangeResponse) -> Vec<&str> {
+    worker_records
+        .kvs
+        .iter()
+        .filter(|element| element.value != DRAINING_NODE_VALUE.as_bytes())
+        .filter_map(|element| parse_node_key(key_str(&element.key)?))
+        .collect()
+}
+
+/// This node's position in the list of workers, which decides the partitions it claims. `None` if
+/// it isn't in the list, e.g. just after registering, before its own node record can be read back.
+fn worker_index(worker_records: &RangeResponse, node_name: &str) -> Option<usize> {
+    worker_names(worker_records)
+        .iter()
+        .position(|name| *name == node_name)
+}
+
+/// The etcd key used for a per-user sync lock
+pub fn user_lock_key(user_id: &str) -> String {
+    format!("{}{}", USER_LOCK_PREFIX, user_id)
+}
+
+/// Get the lock name (the sync partition) from an etcd sync lock key. Returns `None` if the key
+/// isn't a sync lock key.
+pub fn parse_sync_lock_key(key: &str) -> Option<&str> {
+    key.strip_prefix(SYNC_LOCK_PREFIX)
+}
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -26,49 +92,346 @@ pub enum Error {
     #[error("Missing environment variable {0}")]
     EnvVar(String),
     #[error("Error recording node cluster membership")]
-    RecordingMembershipError(#[from] tonic::Status),
+    RecordingMembershipError(#[source] tonic::Status),
+    /// The node's membership record was there when recording it, but had gone by the time it was
+    /// read back, e.g. because it expired in between. Trying again should settle it.
+    #[error("Node {0}'s existing cluster membership record disappeared while recording it")]
+    MembershipRecordChanged(String),
+    /// Another node is recorded with the same name, under a different lease. Two nodes must never
+    /// share a `node_name`.
+    #[error("Node {node_name} is already recorded as a cluster member under lease {lease}, is another node using the same name?")]
+    DuplicateNodeName { node_name: String, lease: i64 },
+    /// The lease attached to a put has expired (or was revoked), so a new lease must be granted
+    /// rather than retrying with the same one
+    #[error("etcd lease not found")]
+    LeaseNotFound(#[source] tonic::Status),
+}
+
+impl From<tonic::Status> for Error {
+    fn from(status: tonic::Status) -> Self {
+        // see ErrGRPCLeaseNotFound in etcd's api/v3rpc/rpctypes
+        if status.code() == tonic::Code::NotFound
+            && status.message().contains("requested lease not found")
+        {
+            Self::LeaseNotFound(status)
+        } else {
+            Self::RecordingMembershipError(status)
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Grant a new lease and record this node's membership under it.
+///
+/// If the node was a member before under `previous_lease` (e.g. it couldn't keep that lease
+/// alive), the previous lease is revoked first. Otherwise its `/nodes/` record would stop the node
+/// recording membership again until the previous lease expired.
 #[tracing::instrument]
 pub async fn initialise_lease_and_node_membership(
     etcd_clients: EtcdClients,
     node_name: String,
+    previous_lease: Option<i64>,
+    partition_allowlist: Option<&[u16]>,
+    max_partitions_per_node: Option<usize>,
+    membership_max_attempts: u32,
+    lease_events: Option<tokio::sync::mpsc::Sender<etcd::LeaseEvent>>,
 ) -> Result<etcd::LeaseGrantResponse> {
-    let lease =
-        do_with_retries_infinite(|| crate::etcd::create_lease(etcd_clients.lease.clone())).await;
+    if let Some(previous_lease) = previous_lease {
+        revoke_lease(
+            &etcd_clients,
+            previous_lease,
+            membership_retry_config(membership_max_attempts),
+        )
+        .await?;
+    }
+
+    let lease = do_with_retries_while(
+        || crate::etcd::create_lease(etcd_clients.lease.clone(), lease_events.clone()),
+        lease_retry_config(),
+        |error: &etcd::Error| {
+            matches!(error, etcd::Error::ResponseStatusError(status) if is_retryable_status(status))
+        },
+    )
+    .await?;
 
     trace!(etcd_lease_id = lease.id, "current lease: {:#?}", lease.id);
 
-    record_node_membership(&mut etcd_clients.clone(), lease.id, node_name.clone())
-        .await
-        .map_err(|e| {
-            error!("{:#?}", e);
-            e
-        })?;
+    let kv_client = etcd_clients.kv.clone();
+    record_membership_under_lease(
+        lease.id,
+        |lease_id| {
+            let mut kv_client = kv_client.clone();
+            let node_name = node_name.clone();
+            async move {
+                let partitions_to_claim = initial_sync_partitions_to_claim(
+                    &mut kv_client,
+                    &node_name,
+                    partition_allowlist,
+                    max_partitions_per_node,
+                )
+                .await?;
+
+                record_node_membership_and_claim_sync_locks(
+                    &mut kv_client,
+                    lease_id,
+                    node_name,
+                    &partitions_to_claim,
+                )
+                .await
+            }
+        },
+        membership_retry_config(membership_max_attempts),
+    )
+    .await
+    .map_err(|e| {
+        error!("{:#?}", e);
+        e
+    })?;
 
     Ok(lease)
 }
 
-/// Records node membership of the cluster of workers. This communicates with etcd and uses the
-/// current hostname as an identifier.
+/// Revoke a lease, deleting all of the keys attached to it. A lease that has already expired is
+/// fine.
+async fn revoke_lease(etcd_clients: &EtcdClients, lease: i64, config: RetryConfig) -> Result<()> {
+    let result = with_transient_error_retries(
+        || {
+            let mut lease_client = etcd_clients.lease.clone();
+            async move {
+                lease_client
+                    .lease_revoke(etcd::LeaseRevokeRequest { id: lease })
+                    .await?;
+                Ok(())
+            }
+        },
+        config,
+    )
+    .await;
+
+    match result {
+        Err(Error::LeaseNotFound(_)) => Ok(()),
+        result => result,
+    }
+}
+
+/// Run `record` (working out the partitions to claim and recording membership) with retries on
+/// transient errors, passing it the same lease every time rather than granting a new one
+async fn record_membership_under_lease<T, Fut, F>(
+    lease_id: i64,
+    record: F,
+    config: RetryConfig,
+) -> Result<T>
+where
+    F: Fn(i64) -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    with_transient_error_retries(|| record(lease_id), config).await
+}
+
+/// Retries for recording membership, so that a momentary etcd blip doesn't cost a whole new lease
+fn membership_retry_config(max_attempts: u32) -> RetryConfig {
+    RetryConfig {
+        maximum_backoff: std::time::Duration::from_secs(2),
+        maximum_n_tries: Some(max_attempts),
+        initial_duration: std::time::Duration::from_millis(100),
+        ..Default::default()
+    }
+}
+
+/// Retries for granting a lease, which keep going for as long as etcd is unavailable, as the node
+/// can't do anything without one
+fn lease_retry_config() -> RetryConfig {
+    RetryConfig {
+        maximum_backoff: std::time::Duration::from_secs(300),
+        initial_duration: std::time::Duration::from_secs(1),
+        ..Default::default()
+    }
+}
+
+/// Whether an etcd request that failed with this status may succeed if it is retried: etcd is
+/// unavailable or overloaded, the request timed out, or it was aborted (e.g. by a conflicting
+/// transaction). Logical errors like `InvalidArgument`, `NotFound` or `FailedPrecondition` fail
+/// the same way every time.
+pub fn is_retryable_status(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable
+            | tonic::Code::DeadlineExceeded
+            | tonic::Code::ResourceExhausted
+            | tonic::Code::Aborted
+    )
+}
+
+/// Whether an error is likely to be transient: from a gRPC status that is, see
+/// [is_retryable_status], or a membership record that changed while recording it
+fn is_transient_error(error: &Error) -> bool {
+    match error {
+        Error::RecordingMembershipError(status) => is_retryable_status(status),
+        Error::MembershipRecordChanged(_) => true,
+        Error::EtcdError(etcd::Error::ResponseStatusError(status)) => is_retryable_status(status),
+        _ => false,
+    }
+}
+
+/// Retry `f` while it fails with a transient gRPC status, see [is_transient_error]
+async fn with_transient_error_retries<T, Fut, F>(f: F, config: RetryConfig) -> Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    do_with_retries_while(f, config, is_transient_error).await
+}
+
+/// Work out which sync partitions this node should claim when it joins the cluster, assuming it
+/// is added to the current list of workers.
+async fn initial_sync_partitions_to_claim(
+    kv_client: &mut KvClient,
+    node_name: &str,
+    partition_allowlist: Option<&[u16]>,
+    max_partitions_per_node: Option<usize>,
+) -> Result<Vec<usize>> {
+    let worker_records = get_all_worker_records(kv_client).await?;
+
+    let mut worker_names: Vec<_> = worker_names(&worker_records)
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+
+    if !worker_names.iter().any(|name| name == node_name) {
+        worker_names.push(node_name.to_owned());
+        // etcd returns range results sorted by key, so keep the same ordering
+        worker_names.sort();
+    }
+
+    let current_worker_index = worker_names
+        .iter()
+        .position(|name| name == node_name)
+        .expect("node_name was just added to the list");
+
+    Ok(sync_records_to_claim_or_not(
+        current_worker_index,
+        TOTAL_NUMBER_OF_SYNC_PARTITIONS,
+        worker_names.len(),
+    )
+    .restrict_to(partition_allowlist)
+    .limit_to(max_partitions_per_node)
+    .do_claim)
+}
+
+/// Record node membership and claim the initial sync partition locks in a single etcd
+/// transaction, so that a node can't appear in `/nodes/` without having attempted to claim its
+/// locks.
+///
+/// If the node is already recorded under a different lease, another node must be using the same
+/// name, so [Error::DuplicateNodeName] is returned rather than taking over its record.
+#[tracing::instrument]
+pub async fn record_node_membership_and_claim_sync_locks(
+    kv_client: &mut KvClient,
+    lease: i64,
+    node_name: String,
+    partitions: &[usize],
+) -> Result<TxnResponse> {
+    let response = kv_client
+        .txn(membership_and_sync_locks_txn(&node_name, lease, partitions))
+        .await?
+        .into_inner();
+
+    if response.succeeded {
+        return Ok(response);
+    }
+
+    match existing_membership_lease(&response) {
+        // already recorded under this lease, e.g. by an earlier attempt whose response was lost,
+        // in which case that transaction claimed the sync locks too
+        Some(existing_lease) if existing_lease == lease => Ok(response),
+        Some(existing_lease) => Err(Error::DuplicateNodeName {
+            node_name,
+            lease: existing_lease,
+        }),
+        None => Err(Error::MembershipRecordChanged(node_name)),
+    }
+}
+
+/// Records node membership of the cluster of workers, without claiming any sync locks (e.g. when
+/// rebalancing).
+///
+/// Recording membership again under the same lease does nothing, but if the node is already
+/// recorded under a different lease, another node must be using the same name, so
+/// [Error::DuplicateNodeName] is returned rather than taking over its record.
 #[tracing::instrument]
 pub async fn record_node_membership(
     etcd_clients: &mut EtcdClients,
     lease: i64,
     node_name: String,
-) -> Result<PutResponse> {
-    let hostname = node_name;
+) -> Result<TxnResponse> {
+    record_node_membership_and_claim_sync_locks(&mut etcd_clients.kv, lease, node_name, &[]).await
+}
 
-    let kv_request = tonic::Request::new(crate::etcd::PutRequest {
-        key: format!("{}{}", REPLICA_PREFIX, hostname).into(),
-        lease,
-        value: "replica".into(),
-        ..Default::default()
+/// Build a transaction that records node membership (only if this node isn't already recorded)
+/// and claims each of the given sync partitions that are not already locked by another worker.
+/// Otherwise it reads the existing record, so that its lease can be checked.
+///
+/// All of the keys are attached to `lease`, so they will all expire together.
+fn membership_and_sync_locks_txn(
+    node_name: &str,
+    lease: i64,
+    partitions: &[usize],
+) -> etcd::TxnRequest {
+    let membership_key: Vec<u8> = node_key(node_name).into();
+
+    let membership_put = etcd::RequestOp {
+        request: Some(etcd::request_op::Request::RequestPut(etcd::PutRequest {
+            key: membership_key.clone(),
+            lease,
+            value: "replica".into(),
+            ..Default::default()
+        })),
+    };
+
+    let lock_claims = partitions.iter().map(|partition| etcd::RequestOp {
+        request: Some(etcd::request_op::Request::RequestTxn(sync_lock_claim_txn(
+            lease,
+            node_name,
+            &partition.to_string(),
+        ))),
     });
 
-    Ok(etcd_clients.kv.put(kv_request).await?.into_inner())
+    etcd::TxnRequest {
+        compare: vec![etcd::Compare {
+            result: etcd::compare::CompareResult::Equal.into(),
+            key: membership_key.clone(),
+            // range_end has to be blank to just check one item
+            range_end: Vec::new(),
+            target: etcd::compare::CompareTarget::Version.into(),
+            target_union: Some(etcd::compare::TargetUnion::Version(0)),
+        }],
+        success: std::iter::once(membership_put).chain(lock_claims).collect(),
+        failure: vec![etcd::RequestOp {
+            request: Some(etcd::request_op::Request::RequestRange(
+                etcd::RangeRequest {
+                    key: membership_key,
+                    ..Default::default()
+                },
+            )),
+        }],
+    }
+}
+
+/// The lease of the existing record read by a failed [membership_and_sync_locks_txn], if there
+/// still is one
+fn existing_membership_lease(response: &TxnResponse) -> Option<i64> {
+    response
+        .responses
+        .iter()
+        .filter_map(|response| match &response.response {
+            Some(etcd::etcdserverpb::response_op::Response::ResponseRange(range)) => {
+                range.kvs.first()
+            }
+            _ => None,
+        })
+        .map(|kv| kv.lease)
+        .next()
 }
 
 /// Get a count of registered cluster workers/nodes
@@ -100,6 +463,62 @@ pub async fn get_all_worker_records(kv_client: &mut KvClient) -> Result<RangeRes
     Ok(kv_client.range(range_request).await?.into_inner())
 }
 
+/// A node that is currently a member of the cluster, with the remaining TTL of its lease
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterMember {
+    pub node_name: String,
+    pub lease_id: i64,
+    /// Remaining lease TTL in seconds. etcd returns -1 if the lease has already expired.
+    pub ttl_remaining: i64,
+}
+
+/// List all of the nodes registered in the cluster, along with how long their leases have left.
+///
+/// Useful for operational visibility. This makes one `LeaseTimeToLive` request per distinct lease.
+#[tracing::instrument]
+pub async fn list_cluster_members(etcd_clients: &mut EtcdClients) -> Result<Vec<ClusterMember>> {
+    let worker_records = get_all_worker_records(&mut etcd_clients.kv).await?;
+
+    let mut lease_ttls = HashMap::new();
+    for lease_id in worker_records.kvs.iter().map(|element| element.lease) {
+        if let std::collections::hash_map::Entry::Vacant(entry) = lease_ttls.entry(lease_id) {
+            let response = etcd_clients
+                .lease
+                .lease_time_to_live(etcd::LeaseTimeToLiveRequest {
+                    id: lease_id,
+                    keys: false,
+                })
+                .await?
+                .into_inner();
+            entry.insert(response);
+        }
+    }
+
+    Ok(cluster_members_from_responses(&worker_records, &lease_ttls))
+}
+
+/// Combine the `/nodes/` range response with the `LeaseTimeToLive` responses for each lease
+fn cluster_members_from_responses(
+    worker_records: &RangeResponse,
+    lease_ttls: &HashMap<i64, etcd::LeaseTimeToLiveResponse>,
+) -> Vec<ClusterMember> {
+    worker_records
+        .kvs
+        .iter()
+        .filter_map(|element| {
+            let node_name = parse_node_key(key_str(&element.key)?)?;
+
+            Some(ClusterMember {
+                node_name: node_name.to_owned(),
+                lease_id: element.lease,
+                ttl_remaining: lease_ttls
+                    .get(&element.lease)
+                    .map_or(-1, |response| response.ttl),
+            })
+        })
+        .collect()
+}
+
 /// Get all lock partition records from etcd
 #[tracing::instrument]
 pub async fn get_all_sync_lock_records(kv_client: &mut KvClient) -> Result<RangeResponse> {
@@ -122,30 +541,8 @@ pub async fn create_a_sync_lock_record(
     worker_id: String,
     lock_key: &str,
 ) -> Result<()> {
-    let lock_key: Vec<u8> = format!("{}{}", SYNC_LOCK_PREFIX, lock_key).into();
-
     kv_client
-        .txn(etcd::TxnRequest {
-            compare: vec![etcd::Compare {
-                result: etcd::compare::CompareResult::Equal.into(),
-                key: lock_key.clone(),
-                // range_end has to be blank to just check one item
-                range_end: Vec::new(),
-                target: etcd::compare::CompareTarget::Version.into(),
-                target_union: Some(etcd::compare::TargetUnion::Version(0)),
-            }],
-            success: vec![etcd::RequestOp {
-                request: Some(etcd::request_op::Request::RequestPut(etcd::PutRequest {
-                    key: lock_key,
-                    value: worker_id.into(),
-                    lease: current_lease,
-                    prev_kv: false,
-                    ignore_value: false,
-                    ignore_lease: false,
-                })),
-            }],
-            failure: vec![],
-        })
+        .txn(sync_lock_claim_txn(current_lease, &worker_id, lock_key))
         .await?;
 
     // NOTE: Should this return an error or some kind of status if the workers were not all created
@@ -154,47 +551,231 @@ pub async fn create_a_sync_lock_record(
     Ok(())
 }
 
-/// Remove a KV record in etcd if it is owned by this worker
+/// Build a transaction that creates a sync lock record, only if it doesn't already exist
+fn sync_lock_claim_txn(current_lease: i64, worker_id: &str, lock_key: &str) -> etcd::TxnRequest {
+    lock_claim_txn(current_lease, worker_id, sync_lock_key(lock_key).into())
+}
+
+/// Build a transaction that puts `worker_id` in `lock_key`, only if the key doesn't already exist
+fn lock_claim_txn(current_lease: i64, worker_id: &str, lock_key: Vec<u8>) -> etcd::TxnRequest {
+    etcd::TxnRequest {
+        compare: vec![etcd::Compare {
+            result: etcd::compare::CompareResult::Equal.into(),
+            key: lock_key.clone(),
+            // range_end has to be blank to just check one item
+            range_end: Vec::new(),
+            target: etcd::compare::CompareTarget::Version.into(),
+            target_union: Some(etcd::compare::TargetUnion::Version(0)),
+        }],
+        success: vec![etcd::RequestOp {
+            request: Some(etcd::request_op::Request::RequestPut(etcd::PutRequest {
+                key: lock_key,
+                value: worker_id.into(),
+                lease: current_lease,
+                prev_kv: false,
+                ignore_value: false,
+                ignore_lease: false,
+            })),
+        }],
+        failure: vec![],
+    }
+}
+
+/// Remove a KV record in etcd if it is owned by this worker. Returns whether it was removed.
 #[tracing::instrument(level = "trace")]
 pub async fn remove_sync_lock_if_owned(
     kv_client: &mut KvClient,
     worker_id: String,
     lock_key: &str,
-) -> Result<()> {
-    let lock_key: Vec<u8> = format!("{}{}", SYNC_LOCK_PREFIX, lock_key).into();
+) -> Result<bool> {
+    let response = kv_client
+        .txn(remove_sync_lock_if_owned_txn(&worker_id, lock_key))
+        .await?
+        .into_inner();
+
+    Ok(response.succeeded)
+}
+
+fn remove_sync_lock_if_owned_txn(worker_id: &str, lock_key: &str) -> etcd::TxnRequest {
+    lock_release_txn(worker_id, sync_lock_key(lock_key).into())
+}
+
+/// Build a transaction that deletes `lock_key`, only if its value is `worker_id`
+fn lock_release_txn(worker_id: &str, lock_key: Vec<u8>) -> etcd::TxnRequest {
+    etcd::TxnRequest {
+        compare: vec![etcd::Compare {
+            result: etcd::compare::CompareResult::Equal.into(),
+            key: lock_key.clone(),
+            // range_end has to be blank to just check one item
+            range_end: Vec::new(),
+            target: etcd::compare::CompareTarget::Value.into(),
+            target_union: Some(etcd::compare::TargetUnion::Value(worker_id.into())),
+        }],
+        success: vec![etcd::RequestOp {
+            request: Some(etcd::request_op::Request::RequestDeleteRange(
+                etcd::DeleteRangeRequest {
+                    key: lock_key,
+                    range_end: Vec::new(),
+                    prev_kv: false,
+                },
+            )),
+        }],
+        failure: vec![],
+    }
+}
+
+/// Try to take the lock for syncing a user, so that two nodes can't sync the same user at the same
+/// time (e.g. while partitions are being rebalanced). The lock is attached to `lease`, so it is
+/// released if this node dies.
+///
+/// Returns `false` if another node holds the lock. Succeeds if this node already holds it.
+#[tracing::instrument(level = "trace")]
+pub async fn try_lock_user(
+    kv_client: &mut KvClient,
+    lease: i64,
+    node_name: &str,
+    user_id: &str,
+) -> Result<bool> {
+    let response = kv_client
+        .txn(user_lock_claim_txn(lease, node_name, user_id))
+        .await?
+        .into_inner();
+
+    Ok(user_lock_acquired(&response, node_name))
+}
 
+/// Release a user's lock, if it is held by this node. See [try_lock_user].
+#[tracing::instrument(level = "trace")]
+pub async fn unlock_user(kv_client: &mut KvClient, node_name: &str, user_id: &str) -> Result<()> {
     kv_client
-        .txn(etcd::TxnRequest {
-            compare: vec![etcd::Compare {
-                result: etcd::compare::CompareResult::Equal.into(),
-                key: lock_key.clone(),
-                // range_end has to be blank to just check one item
-                range_end: Vec::new(),
-                target: etcd::compare::CompareTarget::Value.into(),
-                target_union: Some(etcd::compare::TargetUnion::Value(worker_id.into())),
-            }],
-            success: vec![etcd::RequestOp {
-                request: Some(etcd::request_op::Request::RequestDeleteRange(
-                    etcd::DeleteRangeRequest {
-                        key: lock_key,
-                        range_end: Vec::new(),
-                        prev_kv: false,
-                    },
-                )),
-            }],
-            failure: vec![],
-        })
+        .txn(lock_release_txn(node_name, user_lock_key(user_id).into()))
         .await?;
 
     Ok(())
 }
 
+/// Claim a user's lock, reading the current holder if it is already locked
+fn user_lock_claim_txn(lease: i64, node_name: &str, user_id: &str) -> etcd::TxnRequest {
+    let lock_key: Vec<u8> = user_lock_key(user_id).into();
+    let claim = lock_claim_txn(lease, node_name, lock_key.clone());
+
+    etcd::TxnRequest {
+        failure: vec![etcd::RequestOp {
+            request: Some(etcd::request_op::Request::RequestRange(
+                etcd::RangeRequest {
+                    key: lock_key,
+                    ..Default::default()
+                },
+            )),
+        }],
+        ..claim
+    }
+}
+
+/// Whether the user lock is held by this node after a [user_lock_claim_txn]
+fn user_lock_acquired(response: &TxnResponse, node_name: &str) -> bool {
+    if response.succeeded {
+        return true;
+    }
+
+    response.responses.iter().any(|response| {
+        matches!(
+            &response.response,
+            Some(etcd::etcdserverpb::response_op::Response::ResponseRange(range))
+                if range.kvs.iter().any(|kv| kv.value == node_name.as_bytes())
+        )
+    })
+}
+
+/// Release every sync lock held by this node, e.g. before draining it for maintenance. The node is
+/// first marked as draining, so that the other nodes no longer assign it partitions. The locks are
+/// then released one at a time, waiting `interval` between each, so that other nodes pick up the
+/// partitions gradually.
+///
+/// Unlike revoking the lease, the node stays a member of the cluster. Returns the released
+/// partitions.
+#[tracing::instrument]
+pub async fn release_all_owned_locks(
+    kv_client: &mut KvClient,
+    node_name: &str,
+    interval: std::time::Duration,
+) -> Result<Vec<u16>> {
+    kv_client.txn(mark_node_draining_txn(node_name)).await?;
+
+    let owned = partitions_locked_by(&get_all_sync_lock_records(kv_client).await?, node_name);
+
+    for (i, txn) in release_txns(&owned, node_name).into_iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(interval).await;
+        }
+        kv_client.txn(txn).await?;
+        debug!(node_name, partition = owned[i], "released sync lock");
+    }
+
+    Ok(owned)
+}
+
+/// Replace a node's membership record with [DRAINING_NODE_VALUE], keeping its lease. Does nothing
+/// if the node isn't recorded as a member.
+fn mark_node_draining_txn(node_name: &str) -> etcd::TxnRequest {
+    let membership_key: Vec<u8> = node_key(node_name).into();
+
+    etcd::TxnRequest {
+        compare: vec![etcd::Compare {
+            result: etcd::compare::CompareResult::Greater.into(),
+            key: membership_key.clone(),
+            range_end: Vec::new(),
+            target: etcd::compare::CompareTarget::Version.into(),
+            target_union: Some(etcd::compare::TargetUnion::Version(0)),
+        }],
+        success: vec![etcd::RequestOp {
+            request: Some(etcd::request_op::Request::RequestPut(etcd::PutRequest {
+                key: membership_key,
+                value: DRAINING_NODE_VALUE.into(),
+                ignore_lease: true,
+                ..Default::default()
+            })),
+        }],
+        failure: vec![],
+    }
+}
+
+/// A transaction for releasing each of `partitions`, if still owned by this node
+fn release_txns(partitions: &[u16], node_name: &str) -> Vec<etcd::TxnRequest> {
+    partitions
+        .iter()
+        .map(|partition| remove_sync_lock_if_owned_txn(node_name, &partition.to_string()))
+        .collect()
+}
+
+/// The partitions currently locked by this node, without changing any locks (e.g. while the node
+/// is releasing its locks for maintenance)
+#[tracing::instrument]
+pub async fn current_partition_assignment(
+    kv_client: &mut KvClient,
+    node_name: &str,
+) -> Result<PartitionAssignment> {
+    let lock_records = get_all_sync_lock_records(kv_client).await?;
+    let worker_records = get_all_worker_records(kv_client).await?;
+
+    Ok(PartitionAssignment {
+        owned: partitions_locked_by(&lock_records, node_name),
+        released: vec![],
+        total: TOTAL_NUMBER_OF_SYNC_PARTITIONS as u16,
+        workers: worker_names(&worker_records).len(),
+    })
+}
+
 /// Remove redundant sync lock records and create the correct new ones
 ///
 /// TODO: remove locks that are not required if the number of workers has changed
 /// How should this work?!? Maybe run a transaction before to remove all sync records except the
 /// ones that are required
 ///
+/// If there is a `partition_allowlist`, only partitions in it are claimed. At most
+/// `max_partitions_per_node` are claimed, if set.
+///
+/// Returns the partitions whose locks this node released.
 #[tracing::instrument]
 pub async fn update_n_sync_lock_records(
     kv_client: &mut KvClient,
@@ -203,12 +784,16 @@ pub async fn update_n_sync_lock_records(
     number_of_sync_partitions: usize,
     workers_count: usize,
     current_worker_index: usize,
-) -> Result<()> {
+    partition_allowlist: Option<&[u16]>,
+    max_partitions_per_node: Option<usize>,
+) -> Result<Vec<u16>> {
     let sync_records_to_claim_or_not = sync_records_to_claim_or_not(
         current_worker_index,
         number_of_sync_partitions,
         workers_count,
-    );
+    )
+    .restrict_to(partition_allowlist)
+    .limit_to(max_partitions_per_node);
 
     let n_sync_records_to_claim = sync_records_to_claim_or_not.do_claim.len();
 
@@ -219,8 +804,13 @@ pub async fn update_n_sync_lock_records(
         let worker_id = worker_id.clone();
         join_set.spawn(
             async move {
-                remove_sync_lock_if_owned(&mut kv_client, worker_id.to_owned(), &i.to_string())
-                    .await
+                let released =
+                    remove_sync_lock_if_owned(&mut kv_client, worker_id.to_owned(), &i.to_string())
+                        .await?;
+                Ok::<_, Error>(
+                    released
+                        .then(|| u16::try_from(i).expect("partition numbers should fit in a u16")),
+                )
             }
             .in_current_span(),
         );
@@ -237,22 +827,25 @@ pub async fn update_n_sync_lock_records(
                     worker_id.to_owned(),
                     &i.to_string(),
                 )
-                .await
+                .await?;
+                Ok::<_, Error>(None)
             }
             .in_current_span(),
         );
     }
 
+    let mut released = vec![];
     while let Some(res) = join_set.join_next().await {
-        res.unwrap().unwrap();
+        released.extend(res.expect("sync lock tasks shouldn't panic")?);
     }
+    released.sort_unstable();
 
     debug!(
         workers_count,
         worker_id, current_worker_index, n_sync_records_to_claim
     );
 
-    Ok(())
+    Ok(released)
 }
 
 #[derive(Debug)]
@@ -260,6 +853,47 @@ struct SyncRecordsToClaimOrNot {
     do_claim: Vec<usize>,
     no_claim: Vec<usize>,
 }
+impl SyncRecordsToClaimOrNot {
+    /// Only claim partitions that are in the allowlist (if there is one). Any others are moved to
+    /// `no_claim`, so that they are released if currently owned.
+    fn restrict_to(self, partition_allowlist: Option<&[u16]>) -> Self {
+        let Some(partition_allowlist) = partition_allowlist else {
+            return self;
+        };
+
+        let (do_claim, not_allowed): (Vec<_>, Vec<_>) =
+            self.do_claim.into_iter().partition(|partition| {
+                u16::try_from(*partition).is_ok_and(|p| partition_allowlist.contains(&p))
+            });
+
+        let mut no_claim = self.no_claim;
+        no_claim.extend(not_allowed);
+
+        Self { do_claim, no_claim }
+    }
+
+    /// Claim at most `max_partitions`. Any others are moved to `no_claim`, so they are left
+    /// unassigned until the cluster has enough nodes.
+    fn limit_to(self, max_partitions: Option<usize>) -> Self {
+        let Some(max_partitions) = max_partitions.filter(|max| self.do_claim.len() > *max) else {
+            return self;
+        };
+
+        let mut do_claim = self.do_claim;
+        let unassigned = do_claim.split_off(max_partitions);
+        warn!(
+            max_partitions,
+            ?unassigned,
+            "node has reached max_partitions_per_node, so some partitions are unassigned. The \
+            cluster is under-provisioned."
+        );
+
+        let mut no_claim = self.no_claim;
+        no_claim.extend(unassigned);
+
+        Self { do_claim, no_claim }
+    }
+}
 fn sync_records_to_claim_or_not(
     current_worker_index: usize,
     number_of_sync_partitions: usize,
@@ -274,98 +908,1254 @@ fn sync_records_to_claim_or_not(
     }
 }
 
-/// Establish the correct locks
+/// The sync partitions whose locks have `node_name` as their value
+fn partitions_locked_by(lock_records: &RangeResponse, node_name: &str) -> Vec<u16> {
+    lock_records
+        .kvs
+        .iter()
+        .filter(|element| element.value == node_name.as_bytes())
+        .filter_map(|element| parse_sync_lock_key(key_str(&element.key)?)?.parse().ok())
+        .collect()
+}
+
+/// Check that this node still holds the locks for `partitions`, returning the ones it does.
+///
+/// Renewing the lease keeps this node's locks alive, but doesn't detect another node having
+/// claimed one of them (e.g. after this node's lease expired during a network partition). Any
+/// partitions that are no longer owned are logged and dropped, so that they aren't processed twice.
 #[tracing::instrument]
-pub async fn establish_correct_sync_partition_locks(
+pub async fn verify_sync_lock_ownership(
     kv_client: &mut KvClient,
     node_name: &str,
-    current_lease: i64,
-) -> Vec<u16> {
-    let list_of_all_worker_records = get_all_worker_records(kv_client).await;
-    if let Ok(list) = list_of_all_worker_records {
-        let mapped_kv: Vec<_> = list
-            .kvs
-            .iter()
-            .map(|element| {
-                std::str::from_utf8(&element.key)
-                    .expect("Should be valid utf8")
-                    .strip_prefix(REPLICA_PREFIX)
-                    .expect("should be formatted with /nodes/ at start")
-            })
-            .collect();
+    partitions: &[u16],
+) -> Result<Vec<u16>> {
+    let lock_records = get_all_sync_lock_records(kv_client).await?;
 
-        let current_worker_index = mapped_kv
-            .iter()
-            .position(|x| *x == node_name)
-            .expect("should exist");
-        let workers_count = list.count;
-
-        // This should be equal to the total number of sync partitions in DynamoDB.
-        // Perhaps there should be a way to calculate this automatically?! For now it
-        // is fine as a compile time constant.
-        let total_number_of_sync_partitions = 100;
-
-        update_n_sync_lock_records(
-            kv_client,
-            current_lease,
-            node_name.to_string(),
-            total_number_of_sync_partitions,
-            workers_count.try_into().unwrap(),
-            current_worker_index,
-        )
-        .await
-        .unwrap();
+    Ok(still_owned_partitions(&lock_records, node_name, partitions))
+}
 
-        let current_lock_records = get_all_sync_lock_records(kv_client)
-            .await
-            .expect("should be valid");
-        let sync_partitions: Vec<_> = current_lock_records
-            .kvs
-            .iter()
-            .filter_map(|element| {
-                if std::str::from_utf8(&element.value).expect("Should be valid utf8") == node_name {
-                    Some(
-                        std::str::from_utf8(&element.key)
-                            .expect("Should be valid utf8")
-                            .strip_prefix(SYNC_LOCK_PREFIX)
-                            .expect("should be formatted with correct prefix")
-                            .parse()
-                            .expect("should be valid number"),
-                    )
-                } else {
-                    None
-                }
-            })
-            .collect();
+fn still_owned_partitions(
+    lock_records: &RangeResponse,
+    node_name: &str,
+    partitions: &[u16],
+) -> Vec<u16> {
+    let locked_by_this_node = partitions_locked_by(lock_records, node_name);
 
-        debug!(
-            workers_count,
-            node_name, current_lease, current_worker_index, "kvs strings: {:#?}", mapped_kv
+    let (still_owned, lost): (Vec<_>, Vec<_>) = partitions
+        .iter()
+        .copied()
+        .partition(|partition| locked_by_this_node.contains(partition));
+
+    if !lost.is_empty() {
+        warn!(
+            node_name,
+            ?lost,
+            "sync partition locks are no longer owned by this node, not processing them"
         );
+    }
+
+    still_owned
+}
+
+/// Every sync partition (or just those in `partition_allowlist`, and at most
+/// `max_partitions_per_node`), for a node that isn't sharing the partitions with any others
+pub fn all_sync_partitions(
+    partition_allowlist: Option<&[u16]>,
+    max_partitions_per_node: Option<usize>,
+) -> Vec<u16> {
+    sync_records_to_claim_or_not(0, TOTAL_NUMBER_OF_SYNC_PARTITIONS, 1)
+        .restrict_to(partition_allowlist)
+        .limit_to(max_partitions_per_node)
+        .do_claim
+        .into_iter()
+        .map(|partition| u16::try_from(partition).expect("partition numbers should fit in a u16"))
+        .collect()
+}
 
-        sync_partitions
-    } else {
-        vec![]
+/// Work out which sync partitions the worker at `node_index` (in the key-sorted list of
+/// workers) owns, given the number of workers. This does no I/O, so can be used for planning what
+/// would happen if the cluster membership changed. With no workers, nothing is owned.
+pub fn compute_owned_partitions(
+    node_index: usize,
+    workers_count: usize,
+    total_partitions: u16,
+) -> Vec<u16> {
+    if workers_count == 0 {
+        return Vec::new();
     }
+
+    sync_records_to_claim_or_not(node_index, total_partitions.into(), workers_count)
+        .do_claim
+        .into_iter()
+        // each partition is below total_partitions, so fits in a u16
+        .map(|partition| partition as u16)
+        .collect()
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::cluster_management::sync_records_to_claim_or_not;
+/// The sync partitions owned by a node after establishing its locks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartitionAssignment {
+    /// Partitions locked by this node
+    pub owned: Vec<u16>,
+    /// Partitions this node had locked before, but released when establishing its locks (e.g.
+    /// because another node joined the cluster)
+    pub released: Vec<u16>,
+    /// Total number of sync partitions, across all nodes
+    pub total: u16,
+    /// Number of nodes in the cluster
+    pub workers: usize,
+}
+impl PartitionAssignment {
+    /// No partitions owned, e.g. when the locks couldn't be established this round
+    pub fn empty() -> Self {
+        Self {
+            total: TOTAL_NUMBER_OF_SYNC_PARTITIONS as u16,
+            ..Default::default()
+        }
+    }
 
-    #[test]
-    fn sync_lock_records() {
-        assert_eq!(
-            vec![0, 2, 4],
-            sync_records_to_claim_or_not(0, 5, 2).do_claim
-        );
-        assert_eq!(
-            vec![1, 4, 7, 10],
-            sync_records_to_claim_or_not(1, 12, 3).do_claim
-        );
-        assert_eq!(
-            vec![0, 4, 8, 12, 16],
-            sync_records_to_claim_or_not(0, 20, 4).do_claim
-        );
+    /// A node that isn't sharing the partitions with any others owns all of them
+    pub fn single_node(
+        partition_allowlist: Option<&[u16]>,
+        max_partitions_per_node: Option<usize>,
+    ) -> Self {
+        Self {
+            owned: all_sync_partitions(partition_allowlist, max_partitions_per_node),
+            released: vec![],
+            total: TOTAL_NUMBER_OF_SYNC_PARTITIONS as u16,
+            workers: 1,
+        }
+    }
+
+    /// What changed since a previous assignment
+    pub fn changes_since(&self, previous: &Self) -> PartitionAssignmentChanges {
+        PartitionAssignmentChanges {
+            added: self
+                .owned
+                .iter()
+                .copied()
+                .filter(|partition| !previous.owned.contains(partition))
+                .collect(),
+            removed: previous
+                .owned
+                .iter()
+                .copied()
+                .filter(|partition| !self.owned.contains(partition))
+                .collect(),
+            previous_workers: previous.workers,
+            workers: self.workers,
+        }
+    }
+}
+
+/// The difference between two [PartitionAssignment]s
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionAssignmentChanges {
+    /// Partitions that are owned now but weren't before
+    pub added: Vec<u16>,
+    /// Partitions that were owned before but aren't now
+    pub removed: Vec<u16>,
+    pub previous_workers: usize,
+    pub workers: usize,
+}
+impl PartitionAssignmentChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.previous_workers == self.workers
+    }
+}
+
+/// Work out a node's assignment from the lock records after establishing its locks, which released
+/// the `released` partitions
+fn partition_assignment(
+    released: Vec<u16>,
+    lock_records: &RangeResponse,
+    node_name: &str,
+    partition_allowlist: Option<&[u16]>,
+    workers: usize,
+) -> PartitionAssignment {
+    let owned: Vec<_> = partitions_locked_by(lock_records, node_name)
+        .into_iter()
+        .filter(|partition| {
+            partition_allowlist.is_none_or(|allowlist| allowlist.contains(partition))
+        })
+        .collect();
+
+    PartitionAssignment {
+        owned,
+        released,
+        total: TOTAL_NUMBER_OF_SYNC_PARTITIONS as u16,
+        workers,
+    }
+}
+
+/// Establish the correct locks
+///
+/// If there is a `partition_allowlist` (e.g. for a canary node), only partitions in it are
+/// claimed, regardless of cluster size. Partitions assigned to this node but not in the allowlist
+/// are left unclaimed. No more than `max_partitions_per_node` are claimed, if set.
+///
+/// Updates the [metrics::OWNED_PARTITIONS] gauge with the number of partitions owned.
+///
+/// Fails if the locks can't be read or updated, e.g. with [Error::LeaseNotFound] if this node's
+/// lease has expired.
+#[tracing::instrument(err)]
+pub async fn establish_correct_sync_partition_locks(
+    kv_client: &mut KvClient,
+    node_name: &str,
+    current_lease: i64,
+    partition_allowlist: Option<&[u16]>,
+    max_partitions_per_node: Option<usize>,
+) -> Result<PartitionAssignment> {
+    let assignment = claim_sync_partitions(
+        kv_client,
+        node_name,
+        current_lease,
+        partition_allowlist,
+        max_partitions_per_node,
+    )
+    .await?;
+    record_owned_partitions(node_name, &assignment);
+
+    Ok(assignment)
+}
+
+fn record_owned_partitions(node_name: &str, assignment: &PartitionAssignment) {
+    metrics::OWNED_PARTITIONS.set(node_name, assignment.owned.len() as i64);
+}
+
+async fn claim_sync_partitions(
+    kv_client: &mut KvClient,
+    node_name: &str,
+    current_lease: i64,
+    partition_allowlist: Option<&[u16]>,
+    max_partitions_per_node: Option<usize>,
+) -> Result<PartitionAssignment> {
+    let list = get_all_worker_records(kv_client).await?;
+    // not `list.count`, as nodes with unreadable keys and draining nodes aren't assigned partitions
+    let mapped_kv = worker_names(&list);
+    let workers_count = mapped_kv.len();
+
+    let Some(current_worker_index) = worker_index(&list, node_name) else {
+        debug!(
+            node_name,
+            workers_count,
+            "this node isn't in the worker list yet, claiming no partitions until the next round"
+        );
+        return Ok(PartitionAssignment::empty());
+    };
+
+    let released = update_n_sync_lock_records(
+        kv_client,
+        current_lease,
+        node_name.to_string(),
+        TOTAL_NUMBER_OF_SYNC_PARTITIONS,
+        workers_count,
+        current_worker_index,
+        partition_allowlist,
+        max_partitions_per_node,
+    )
+    .await?;
+
+    let current_lock_records = get_all_sync_lock_records(kv_client).await?;
+    let assignment = partition_assignment(
+        released,
+        &current_lock_records,
+        node_name,
+        partition_allowlist,
+        workers_count,
+    );
+
+    debug!(
+        workers_count,
+        node_name, current_lease, current_worker_index, "kvs strings: {:#?}", mapped_kv
+    );
+
+    // Partitions that are still locked by another worker, e.g. one that has left the cluster
+    // but whose lease hasn't expired yet
+    let not_yet_claimed: Vec<_> = compute_owned_partitions(
+        current_worker_index,
+        workers_count,
+        TOTAL_NUMBER_OF_SYNC_PARTITIONS as u16,
+    )
+    .into_iter()
+    .filter(|partition| partition_allowlist.is_none_or(|allowlist| allowlist.contains(partition)))
+    .take(max_partitions_per_node.unwrap_or(usize::MAX))
+    .filter(|partition| !assignment.owned.contains(partition))
+    .collect();
+    if !not_yet_claimed.is_empty() {
+        debug!(
+            node_name,
+            ?not_yet_claimed,
+            "some partitions are not yet claimed"
+        );
+    }
+
+    Ok(assignment)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use crate::cluster_management::{
+        all_sync_partitions, cluster_members_from_responses, compute_owned_partitions,
+        current_partition_assignment, establish_correct_sync_partition_locks,
+        existing_membership_lease, initialise_lease_and_node_membership, is_retryable_status,
+        list_cluster_members, membership_and_sync_locks_txn, node_key, parse_node_key,
+        parse_sync_lock_key, partition_assignment, partitions_locked_by,
+        record_membership_under_lease, record_node_membership, record_owned_partitions,
+        release_all_owned_locks, release_txns, still_owned_partitions, sync_lock_key,
+        sync_records_to_claim_or_not, user_lock_acquired, user_lock_claim_txn,
+        with_transient_error_retries, worker_index, worker_names, ClusterMember, Error,
+        PartitionAssignment, PartitionAssignmentChanges, TOTAL_NUMBER_OF_SYNC_PARTITIONS,
+    };
+    use crate::fake_etcd::FakeEtcd;
+    use crate::{clock, etcd, RetryConfig};
+
+    fn retry_config_without_waiting() -> RetryConfig {
+        RetryConfig {
+            maximum_n_tries: Some(3),
+            clock: Arc::new(clock::MockClock::new(std::time::SystemTime::UNIX_EPOCH)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn membership_recorded_again_while_previous_lease_alive() {
+        let etcd = FakeEtcd::start().await;
+        let etcd_clients = etcd.clients().await;
+
+        let first = initialise_lease_and_node_membership(
+            etcd_clients.clone(),
+            "node-a".to_owned(),
+            None,
+            None,
+            None,
+            3,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // e.g. the first lease couldn't be kept alive, but it hasn't expired yet
+        let second = initialise_lease_and_node_membership(
+            etcd_clients,
+            "node-a".to_owned(),
+            Some(first.id),
+            None,
+            None,
+            3,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert!(!etcd.lease_exists(first.id));
+        assert_eq!(second.id, etcd.get(&node_key("node-a")).unwrap().lease);
+    }
+
+    #[tokio::test]
+    async fn membership_retried_after_unavailable() {
+        let attempts = AtomicU32::new(0);
+        let registrations = AtomicU32::new(0);
+
+        let result = with_transient_error_retries(
+            || async {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Err(Error::RecordingMembershipError(tonic::Status::unavailable(
+                        "etcd unavailable",
+                    )));
+                }
+                registrations.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            },
+            retry_config_without_waiting(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+        assert_eq!(1, registrations.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn membership_not_retried_after_logical_error() {
+        let attempts = AtomicU32::new(0);
+
+        let result: Result<(), _> = with_transient_error_retries(
+            || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(Error::DuplicateNodeName {
+                    node_name: "node-a".to_owned(),
+                    lease: 999,
+                })
+            },
+            retry_config_without_waiting(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::DuplicateNodeName { .. })));
+        assert_eq!(1, attempts.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn lease_reused_across_membership_retries() {
+        let lease_ids = Mutex::new(Vec::new());
+
+        let result = record_membership_under_lease(
+            42,
+            |lease_id| {
+                let mut lease_ids = lease_ids.lock().unwrap();
+                lease_ids.push(lease_id);
+                let result = if lease_ids.len() == 1 {
+                    Err(Error::RecordingMembershipError(tonic::Status::unavailable(
+                        "etcd unavailable",
+                    )))
+                } else {
+                    Ok(())
+                };
+                async move { result }
+            },
+            retry_config_without_waiting(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(vec![42, 42], *lease_ids.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn membership_record_that_disappeared_retried() {
+        let attempts = AtomicU32::new(0);
+
+        let result = with_transient_error_retries(
+            || async {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Err(Error::MembershipRecordChanged("node-a".to_owned()));
+                }
+                Ok(())
+            },
+            retry_config_without_waiting(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn membership_recorded_when_txn_response_lost() {
+        let etcd = FakeEtcd::start().await;
+        let etcd_clients = etcd.clients().await;
+        etcd.lose_next_txn_response();
+
+        let lease = initialise_lease_and_node_membership(
+            etcd_clients,
+            "node-a".to_owned(),
+            None,
+            None,
+            None,
+            3,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // the retry found the record committed by the first attempt, under the same lease
+        assert_eq!(2, etcd.requests().iter().filter(|r| **r == "txn").count());
+        assert_eq!(lease.id, etcd.get(&node_key("node-a")).unwrap().lease);
+        assert!(!etcd.entries_with_prefix("/sync_locks/").is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_lease_mapped_and_not_retried() {
+        let attempts = AtomicU32::new(0);
+
+        let result: Result<(), _> = with_transient_error_retries(
+            || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                // what a put with an expired lease returns
+                Err(tonic::Status::not_found("etcdserver: requested lease not found").into())
+            },
+            retry_config_without_waiting(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::LeaseNotFound(_))));
+        assert_eq!(1, attempts.load(Ordering::SeqCst));
+
+        assert!(matches!(
+            Error::from(tonic::Status::not_found("etcdserver: key not found")),
+            Error::RecordingMembershipError(_)
+        ));
+    }
+
+    #[test]
+    fn duplicate_node_name_detected() {
+        let txn = membership_and_sync_locks_txn("node-a", 1234, &[]);
+        // otherwise read the existing record, to check its lease
+        match &txn.failure[..] {
+            [etcd::RequestOp {
+                request: Some(etcd::request_op::Request::RequestRange(range)),
+            }] => assert_eq!(b"/nodes/node-a".to_vec(), range.key),
+            other => panic!("expected a range, got {other:?}"),
+        }
+
+        let recorded_under = |lease: i64| etcd::etcdserverpb::TxnResponse {
+            succeeded: false,
+            responses: vec![etcd::etcdserverpb::ResponseOp {
+                response: Some(etcd::etcdserverpb::response_op::Response::ResponseRange(
+                    etcd::etcdserverpb::RangeResponse {
+                        kvs: vec![etcd::mvccpb::KeyValue {
+                            key: b"/nodes/node-a".to_vec(),
+                            value: b"replica".to_vec(),
+                            lease,
+                            ..Default::default()
+                        }],
+                        count: 1,
+                        ..Default::default()
+                    },
+                )),
+            }],
+            ..Default::default()
+        };
+        let recorded = etcd::etcdserverpb::TxnResponse {
+            succeeded: true,
+            ..Default::default()
+        };
+
+        assert_eq!(None, existing_membership_lease(&recorded));
+        assert_eq!(Some(999), existing_membership_lease(&recorded_under(999)));
+    }
+
+    #[test]
+    fn user_lock_rejected_while_held() {
+        let txn = user_lock_claim_txn(1234, "node-a", "user-1");
+        // only claimed if nobody holds the lock
+        assert_eq!(
+            vec![etcd::Compare {
+                result: etcd::compare::CompareResult::Equal.into(),
+                key: b"/user_locks/user-1".to_vec(),
+                range_end: Vec::new(),
+                target: etcd::compare::CompareTarget::Version.into(),
+                target_union: Some(etcd::compare::TargetUnion::Version(0)),
+            }],
+            txn.compare
+        );
+        match &txn.success[0].request {
+            Some(etcd::request_op::Request::RequestPut(put)) => {
+                assert_eq!(1234, put.lease);
+                assert_eq!(b"node-a".to_vec(), put.value);
+            }
+            other => panic!("expected a put, got {other:?}"),
+        }
+
+        let held_by = |node_name: &str| etcd::etcdserverpb::TxnResponse {
+            succeeded: false,
+            responses: vec![etcd::etcdserverpb::ResponseOp {
+                response: Some(etcd::etcdserverpb::response_op::Response::ResponseRange(
+                    etcd::etcdserverpb::RangeResponse {
+                        kvs: vec![etcd::mvccpb::KeyValue {
+                            key: b"/user_locks/user-1".to_vec(),
+                            value: node_name.into(),
+                            ..Default::default()
+                        }],
+                        count: 1,
+                        ..Default::default()
+                    },
+                )),
+            }],
+            ..Default::default()
+        };
+        let claimed = etcd::etcdserverpb::TxnResponse {
+            succeeded: true,
+            ..Default::default()
+        };
+
+        assert!(user_lock_acquired(&claimed, "node-a"));
+        // a second attempt while node-a holds the lock
+        assert!(!user_lock_acquired(&held_by("node-a"), "node-b"));
+        assert!(user_lock_acquired(&held_by("node-a"), "node-a"));
+    }
+
+    #[test]
+    fn non_utf8_keys_skipped() {
+        let records = etcd::etcdserverpb::RangeResponse {
+            kvs: vec![
+                etcd::mvccpb::KeyValue {
+                    key: node_key("node-a").into(),
+                    ..Default::default()
+                },
+                etcd::mvccpb::KeyValue {
+                    key: b"/nodes/\xff\xfe".to_vec(),
+                    ..Default::default()
+                },
+                etcd::mvccpb::KeyValue {
+                    key: node_key("node-b").into(),
+                    ..Default::default()
+                },
+                etcd::mvccpb::KeyValue {
+                    key: b"/sync_locks/\xc3\x28".to_vec(),
+                    value: "node-a".into(),
+                    ..Default::default()
+                },
+                lock_record(4, "node-a"),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(vec!["node-a", "node-b"], worker_names(&records));
+        assert_eq!(vec![4], partitions_locked_by(&records, "node-a"));
+    }
+
+    #[test]
+    fn retryable_statuses() {
+        for code in [
+            tonic::Code::Unavailable,
+            tonic::Code::DeadlineExceeded,
+            tonic::Code::ResourceExhausted,
+            tonic::Code::Aborted,
+        ] {
+            let status = tonic::Status::new(code, "etcd is busy");
+            assert!(is_retryable_status(&status), "{code:?}");
+            assert!(is_transient_error(&Error::from(status)), "{code:?}");
+        }
+
+        for code in [
+            tonic::Code::InvalidArgument,
+            tonic::Code::NotFound,
+            tonic::Code::FailedPrecondition,
+            tonic::Code::PermissionDenied,
+            tonic::Code::Unauthenticated,
+        ] {
+            let status = tonic::Status::new(code, "bad request");
+            assert!(!is_retryable_status(&status), "{code:?}");
+            assert!(!is_transient_error(&Error::from(status)), "{code:?}");
+        }
+
+        assert!(!is_transient_error(&Error::LeaseNotFound(
+            tonic::Status::unavailable("requested lease not found")
+        )));
+    }
+
+    #[test]
+    fn missing_worker_claims_no_partitions() {
+        let workers = |names: &[&str]| etcd::etcdserverpb::RangeResponse {
+            kvs: names
+                .iter()
+                .map(|name| etcd::mvccpb::KeyValue {
+                    key: node_key(name).into(),
+                    ..Default::default()
+                })
+                .collect(),
+            count: names.len() as i64,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Some(1),
+            worker_index(&workers(&["node-a", "node-b"]), "node-b")
+        );
+        assert_eq!(None, worker_index(&workers(&[]), "node-b"));
+        assert_eq!(None, worker_index(&workers(&["node-a"]), "node-b"));
+
+        let empty = PartitionAssignment::empty();
+        assert!(empty.owned.is_empty());
+        assert_eq!(TOTAL_NUMBER_OF_SYNC_PARTITIONS as u16, empty.total);
+    }
+
+    #[test]
+    fn owned_partitions_gauge_updated() {
+        let assignment = PartitionAssignment {
+            owned: vec![3, 7, 11],
+            ..PartitionAssignment::empty()
+        };
+        record_owned_partitions("gauge-test-node", &assignment);
+        assert_eq!(
+            Some(3),
+            crate::metrics::OWNED_PARTITIONS.get("gauge-test-node")
+        );
+
+        record_owned_partitions("gauge-test-node", &PartitionAssignment::empty());
+        assert_eq!(
+            Some(0),
+            crate::metrics::OWNED_PARTITIONS.get("gauge-test-node")
+        );
+    }
+
+    #[test]
+    fn sync_lock_records() {
+        assert_eq!(
+            vec![0, 2, 4],
+            sync_records_to_claim_or_not(0, 5, 2).do_claim
+        );
+        assert_eq!(
+            vec![1, 4, 7, 10],
+            sync_records_to_claim_or_not(1, 12, 3).do_claim
+        );
+        assert_eq!(
+            vec![0, 4, 8, 12, 16],
+            sync_records_to_claim_or_not(0, 20, 4).do_claim
+        );
+    }
+
+    fn lock_record(partition: u16, worker: &str) -> etcd::mvccpb::KeyValue {
+        etcd::mvccpb::KeyValue {
+            key: sync_lock_key(&partition.to_string()).into(),
+            value: worker.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn releasing_locks_keeps_membership() {
+        let records = etcd::etcdserverpb::RangeResponse {
+            kvs: vec![
+                lock_record(0, "node-a"),
+                lock_record(1, "node-b"),
+                lock_record(2, "node-a"),
+                etcd::mvccpb::KeyValue {
+                    key: node_key("node-a").into(),
+                    value: "replica".into(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let owned = partitions_locked_by(&records, "node-a");
+        assert_eq!(vec![0, 2], owned);
+
+        let txns = release_txns(&owned, "node-a");
+        let deleted_keys: Vec<_> = txns
+            .iter()
+            .flat_map(|txn| &txn.success)
+            .map(|op| match &op.request {
+                Some(etcd::request_op::Request::RequestDeleteRange(delete)) => {
+                    assert!(delete.range_end.is_empty());
+                    String::from_utf8(delete.key.clone()).unwrap()
+                }
+                other => panic!("expected a delete, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(vec!["/sync_locks/0", "/sync_locks/2"], deleted_keys);
+
+        // only deleted if still owned by this node
+        for txn in &txns {
+            assert_eq!(
+                Some(etcd::compare::TargetUnion::Value("node-a".into())),
+                txn.compare[0].target_union
+            );
+        }
+    }
+
+    #[test]
+    fn partition_assignment_changes_when_a_node_joins() {
+        let lock_records = etcd::etcdserverpb::RangeResponse {
+            kvs: vec![
+                lock_record(0, "node-a"),
+                lock_record(1, "node-a"),
+                lock_record(2, "node-a"),
+            ],
+            ..Default::default()
+        };
+        let alone = partition_assignment(vec![], &lock_records, "node-a", None, 1);
+        assert_eq!(vec![0, 1, 2], alone.owned);
+        assert!(alone.released.is_empty());
+        assert_eq!(100, alone.total);
+
+        // node-b joins and takes partition 1, and node-a picks up partition 3
+        let lock_records = etcd::etcdserverpb::RangeResponse {
+            kvs: vec![
+                lock_record(0, "node-a"),
+                lock_record(1, "node-b"),
+                lock_record(2, "node-a"),
+                lock_record(3, "node-a"),
+            ],
+            ..Default::default()
+        };
+        let shared = partition_assignment(vec![1], &lock_records, "node-a", None, 2);
+        assert_eq!(vec![0, 2, 3], shared.owned);
+        assert_eq!(vec![1], shared.released);
+
+        let changes = shared.changes_since(&alone);
+        assert_eq!(
+            PartitionAssignmentChanges {
+                added: vec![3],
+                removed: vec![1],
+                previous_workers: 1,
+                workers: 2,
+            },
+            changes
+        );
+        assert!(!changes.is_empty());
+        assert!(shared.changes_since(&shared).is_empty());
+    }
+
+    #[tokio::test]
+    async fn drained_partitions_taken_over_by_peers() {
+        let etcd = FakeEtcd::start().await;
+        let mut etcd_clients = etcd.clients().await;
+        let leases: Vec<_> = ["node-a", "node-b"]
+            .into_iter()
+            .map(|node_name| {
+                let lease = etcd.grant_lease(30);
+                etcd.put(&node_key(node_name), "replica", lease);
+                (node_name, lease)
+            })
+            .collect();
+        let kv = etcd_clients.kv.clone();
+        let establish = |node_name: &'static str, lease: i64| {
+            let mut kv = kv.clone();
+            async move {
+                establish_correct_sync_partition_locks(&mut kv, node_name, lease, None, None)
+                    .await
+                    .unwrap()
+            }
+        };
+        for (node_name, lease) in leases.iter().copied() {
+            establish(node_name, lease).await;
+        }
+        let before = establish("node-a", leases[0].1).await;
+        assert_eq!(50, before.owned.len());
+
+        let released =
+            release_all_owned_locks(&mut etcd_clients.kv, "node-b", std::time::Duration::ZERO)
+                .await
+                .unwrap();
+        assert_eq!(50, released.len());
+
+        let after = establish("node-a", leases[0].1).await;
+        assert_eq!(100, after.owned.len());
+        assert_eq!(1, after.workers);
+        // node-b is still a member until it is shut down
+        assert_eq!(leases[1].1, etcd.get(&node_key("node-b")).unwrap().lease);
+    }
+
+    #[tokio::test]
+    async fn partitions_released_when_a_node_joins() {
+        let etcd = FakeEtcd::start().await;
+        let mut etcd_clients = etcd.clients().await;
+        let lease_a = etcd.grant_lease(30);
+        etcd.put(&node_key("node-a"), "node-a", lease_a);
+
+        let alone = establish_correct_sync_partition_locks(
+            &mut etcd_clients.kv,
+            "node-a",
+            lease_a,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(100, alone.owned.len());
+        assert!(alone.released.is_empty());
+        assert_eq!(1, alone.workers);
+
+        let lease_b = etcd.grant_lease(30);
+        etcd.put(&node_key("node-b"), "node-b", lease_b);
+        let ranges_before = etcd.requests().iter().filter(|r| **r == "range").count();
+
+        let shared = establish_correct_sync_partition_locks(
+            &mut etcd_clients.kv,
+            "node-a",
+            lease_a,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let odd_partitions: Vec<u16> = (0..100).filter(|partition| partition % 2 == 1).collect();
+        assert_eq!(odd_partitions, shared.released);
+        assert_eq!(50, shared.owned.len());
+        assert_eq!(2, shared.workers);
+        // the workers, and the locks once they have been updated
+        let ranges = etcd.requests().iter().filter(|r| **r == "range").count() - ranges_before;
+        assert_eq!(2, ranges);
+    }
+
+    #[tokio::test]
+    async fn failed_lock_claim_is_returned() {
+        let etcd = FakeEtcd::start().await;
+        let mut etcd_clients = etcd.clients().await;
+        let lease = etcd.grant_lease(30);
+        etcd.put(&node_key("node-a"), "node-a", lease);
+        etcd.fail_next_txn(tonic::Status::not_found(
+            "etcdserver: requested lease not found",
+        ));
+
+        let result = establish_correct_sync_partition_locks(
+            &mut etcd_clients.kv,
+            "node-a",
+            lease,
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::LeaseNotFound(_))), "{result:?}");
+
+        // the other locks were still claimed, so the next round carries on
+        let assignment = establish_correct_sync_partition_locks(
+            &mut etcd_clients.kv,
+            "node-a",
+            lease,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(100, assignment.owned.len());
+    }
+
+    #[test]
+    fn lock_taken_by_another_node_is_dropped() {
+        let mut lock_records = etcd::etcdserverpb::RangeResponse {
+            kvs: vec![
+                lock_record(0, "node-a"),
+                lock_record(2, "node-a"),
+                lock_record(4, "node-a"),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![0, 2, 4],
+            still_owned_partitions(&lock_records, "node-a", &[0, 2, 4])
+        );
+
+        // another node claims partition 2 under this node
+        lock_records.kvs[1] = lock_record(2, "node-b");
+        assert_eq!(
+            vec![0, 4],
+            still_owned_partitions(&lock_records, "node-a", &[0, 2, 4])
+        );
+
+        // and a lock that has expired altogether
+        lock_records.kvs.pop();
+        assert_eq!(
+            vec![0],
+            still_owned_partitions(&lock_records, "node-a", &[0, 2, 4])
+        );
+    }
+
+    #[test]
+    fn owned_partitions_uneven_split() {
+        assert_eq!(vec![0, 3, 6, 9], compute_owned_partitions(0, 3, 10));
+        assert_eq!(vec![1, 4, 7], compute_owned_partitions(1, 3, 10));
+        assert_eq!(vec![2, 5, 8], compute_owned_partitions(2, 3, 10));
+
+        // every partition is owned by exactly one worker
+        let mut all_partitions: Vec<_> = (0..7)
+            .flat_map(|node_index| compute_owned_partitions(node_index, 7, 100))
+            .collect();
+        all_partitions.sort();
+        assert_eq!((0..100).collect::<Vec<u16>>(), all_partitions);
+    }
+
+    #[test]
+    fn owned_partitions_single_node() {
+        assert_eq!(
+            (0..100).collect::<Vec<u16>>(),
+            compute_owned_partitions(0, 1, 100)
+        );
+        // more workers than partitions
+        assert_eq!(Vec::<u16>::new(), compute_owned_partitions(4, 5, 3));
+    }
+
+    #[test]
+    fn no_partitions_owned_without_workers() {
+        assert_eq!(Vec::<u16>::new(), compute_owned_partitions(0, 0, 100));
+    }
+
+    #[tokio::test]
+    async fn node_membership_recorded_unless_name_in_use() {
+        let etcd = FakeEtcd::start().await;
+        let mut etcd_clients = etcd.clients().await;
+        let lease = etcd.grant_lease(30);
+
+        record_node_membership(&mut etcd_clients, lease, "node-a".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(lease, etcd.get(&node_key("node-a")).unwrap().lease);
+        // recording it again under the same lease does nothing
+        record_node_membership(&mut etcd_clients, lease, "node-a".to_owned())
+            .await
+            .unwrap();
+
+        let other_node_lease = etcd.grant_lease(30);
+        let result =
+            record_node_membership(&mut etcd_clients, other_node_lease, "node-a".to_owned()).await;
+        assert!(matches!(
+            result,
+            Err(Error::DuplicateNodeName { node_name, lease: existing_lease })
+                if node_name == "node-a" && existing_lease == lease
+        ));
+        assert_eq!(lease, etcd.get(&node_key("node-a")).unwrap().lease);
+        assert!(etcd.entries_with_prefix("/sync_locks/").is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicate_node_name_rejected_by_etcd() {
+        let etcd = FakeEtcd::start().await;
+        let other_node_lease = etcd.grant_lease(30);
+        etcd.put(&node_key("node-a"), "replica", other_node_lease);
+
+        let result = initialise_lease_and_node_membership(
+            etcd.clients().await,
+            "node-a".to_owned(),
+            None,
+            None,
+            None,
+            3,
+            None,
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(Error::DuplicateNodeName { node_name, lease })
+                if node_name == "node-a" && lease == other_node_lease
+        ));
+        // the other node's record is left alone
+        assert_eq!(
+            other_node_lease,
+            etcd.get(&node_key("node-a")).unwrap().lease
+        );
+        assert!(etcd.entries_with_prefix("/sync_locks/").is_empty());
+    }
+
+    #[test]
+    fn membership_and_sync_locks_txn_contents() {
+        let txn = membership_and_sync_locks_txn("node-a", 1234, &[0, 2]);
+
+        assert_eq!(
+            vec![etcd::Compare {
+                result: etcd::compare::CompareResult::Equal.into(),
+                key: b"/nodes/node-a".to_vec(),
+                range_end: Vec::new(),
+                target: etcd::compare::CompareTarget::Version.into(),
+                target_union: Some(etcd::compare::TargetUnion::Version(0)),
+            }],
+            txn.compare
+        );
+
+        assert_eq!(3, txn.success.len());
+        match &txn.success[0].request {
+            Some(etcd::request_op::Request::RequestPut(put)) => {
+                assert_eq!(b"/nodes/node-a".to_vec(), put.key);
+                assert_eq!(1234, put.lease);
+            }
+            other => panic!("expected membership put, got {other:?}"),
+        }
+        match &txn.success[2].request {
+            Some(etcd::request_op::Request::RequestTxn(lock_txn)) => {
+                assert_eq!(b"/sync_locks/2".to_vec(), lock_txn.compare[0].key);
+            }
+            other => panic!("expected sync lock txn, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn node_key_round_trip() {
+        for node_name in ["node-a", "", "/nodes/", "with space", "ünïcödé", "a/b"] {
+            assert_eq!(Some(node_name), parse_node_key(&node_key(node_name)));
+        }
+
+        assert_eq!("/nodes/node-a", node_key("node-a"));
+        assert_eq!(None, parse_node_key("/sync_locks/1"));
+        assert_eq!(None, parse_node_key("nodes/node-a"));
+    }
+
+    #[test]
+    fn sync_lock_key_round_trip() {
+        for lock_key in ["0", "99", "/sync_locks/", "with space"] {
+            assert_eq!(
+                Some(lock_key),
+                parse_sync_lock_key(&sync_lock_key(lock_key))
+            );
+        }
+
+        assert_eq!("/sync_locks/7", sync_lock_key("7"));
+        assert_eq!(None, parse_sync_lock_key("/nodes/node-a"));
+    }
+
+    #[tokio::test]
+    async fn node_key_that_isnt_utf8_not_counted_as_a_worker() {
+        let etcd = FakeEtcd::start().await;
+        let mut etcd_clients = etcd.clients().await;
+        let lease = etcd.grant_lease(30);
+        etcd.put(&node_key("node-a"), "node-a", lease);
+        etcd_clients
+            .kv
+            .put(etcd::PutRequest {
+                key: [node_key("").as_bytes(), b"\xff"].concat(),
+                value: b"unknown".to_vec(),
+                lease,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let claimed = establish_correct_sync_partition_locks(
+            &mut etcd_clients.kv,
+            "node-a",
+            lease,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, claimed.workers);
+        assert_eq!(100, claimed.owned.len());
+
+        let current = current_partition_assignment(&mut etcd_clients.kv, "node-a")
+            .await
+            .unwrap();
+        assert_eq!(claimed.workers, current.workers);
+        assert_eq!(claimed.owned, current.owned);
+    }
+
+    #[test]
+    fn cluster_members_with_lease_ttls() {
+        let worker_records = etcd::etcdserverpb::RangeResponse {
+            kvs: vec![
+                etcd::mvccpb::KeyValue {
+                    key: b"/nodes/node-a".to_vec(),
+                    lease: 1,
+                    ..Default::default()
+                },
+                etcd::mvccpb::KeyValue {
+                    key: b"/nodes/node-b".to_vec(),
+                    lease: 2,
+                    ..Default::default()
+                },
+                etcd::mvccpb::KeyValue {
+                    key: b"/nodes/node-c".to_vec(),
+                    lease: 3,
+                    ..Default::default()
+                },
+            ],
+            count: 3,
+            ..Default::default()
+        };
+        let lease_ttls = HashMap::from([
+            (
+                1,
+                etcd::LeaseTimeToLiveResponse {
+                    id: 1,
+                    ttl: 25,
+                    granted_ttl: 30,
+                    ..Default::default()
+                },
+            ),
+            (
+                2,
+                etcd::LeaseTimeToLiveResponse {
+                    id: 2,
+                    ttl: -1,
+                    granted_ttl: 30,
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        assert_eq!(
+            vec![
+                ClusterMember {
+                    node_name: "node-a".to_owned(),
+                    lease_id: 1,
+                    ttl_remaining: 25
+                },
+                ClusterMember {
+                    node_name: "node-b".to_owned(),
+                    lease_id: 2,
+                    ttl_remaining: -1
+                },
+                // no response for this lease, so treated as expired
+                ClusterMember {
+                    node_name: "node-c".to_owned(),
+                    lease_id: 3,
+                    ttl_remaining: -1
+                },
+            ],
+            cluster_members_from_responses(&worker_records, &lease_ttls)
+        );
+    }
+
+    #[tokio::test]
+    async fn cluster_members_listed_with_lease_ttls() {
+        let etcd = FakeEtcd::start().await;
+        let lease_a = etcd.grant_lease(30);
+        let lease_b = etcd.grant_lease(10);
+        etcd.put(&node_key("node-a"), "replica", lease_a);
+        etcd.put(&node_key("node-b"), "replica", lease_b);
+        etcd.put(&node_key("node-c"), "replica", lease_b);
+        // not a node record
+        etcd.put(&sync_lock_key("1"), "node-a", lease_a);
+
+        let members = list_cluster_members(&mut etcd.clients().await)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                ClusterMember {
+                    node_name: "node-a".to_owned(),
+                    lease_id: lease_a,
+                    ttl_remaining: 30
+                },
+                ClusterMember {
+                    node_name: "node-b".to_owned(),
+                    lease_id: lease_b,
+                    ttl_remaining: 10
+                },
+                ClusterMember {
+                    node_name: "node-c".to_owned(),
+                    lease_id: lease_b,
+                    ttl_remaining: 10
+                },
+            ],
+            members
+        );
+        // one request per distinct lease
+        assert_eq!(
+            2,
+            etcd.requests()
+                .iter()
+                .filter(|request| **request == "lease_time_to_live")
+                .count()
+        );
+    }
+
+    #[test]
+    fn sync_lock_records_with_allowlist() {
+        // partition 5 is assigned to worker 1 by the hash
+        assert!(sync_records_to_claim_or_not(1, 10, 2).do_claim.contains(&5));
+
+        let restricted = sync_records_to_claim_or_not(1, 10, 2).restrict_to(Some(&[0, 1, 2][..]));
+        assert_eq!(vec![1], restricted.do_claim);
+        assert!(!restricted.do_claim.contains(&5));
+        // released if currently owned
+        assert!(restricted.no_claim.contains(&5));
+
+        let unrestricted = sync_records_to_claim_or_not(1, 10, 2).restrict_to(None);
+        assert_eq!(vec![1, 3, 5, 7, 9], unrestricted.do_claim);
+    }
+
+    #[test]
+    fn claims_capped_at_max_partitions_per_node() {
+        // assigned 30 partitions
+        assert_eq!(30, sync_records_to_claim_or_not(0, 30, 1).do_claim.len());
+
+        let capped = sync_records_to_claim_or_not(0, 30, 1).limit_to(Some(10));
+        assert_eq!((0..10).collect::<Vec<_>>(), capped.do_claim);
+        // released if currently owned
+        assert_eq!((10..30).collect::<Vec<_>>(), capped.no_claim);
+
+        let under_cap = sync_records_to_claim_or_not(0, 5, 1).limit_to(Some(10));
+        assert_eq!(5, under_cap.do_claim.len());
+        assert!(under_cap.no_claim.is_empty());
+
+        assert_eq!(10, all_sync_partitions(None, Some(10)).len());
     }
 }
@@ -3,24 +3,33 @@
 use self::etcdserverpb::LeaseKeepAliveResponse;
 // reexports
 pub use self::etcdserverpb::{
-    compare, kv_client, lease_client, request_op, Compare, DeleteRangeRequest, LeaseGrantRequest,
-    LeaseGrantResponse, LeaseKeepAliveRequest, PutRequest, RangeRequest, RequestOp, TxnRequest,
+    auth_client, compare, kv_client, lease_client, request_op, AuthenticateRequest, Compare,
+    DeleteRangeRequest, LeaseGrantRequest, LeaseGrantResponse, LeaseKeepAliveRequest,
+    LeaseRevokeRequest, LeaseTimeToLiveRequest, LeaseTimeToLiveResponse, PutRequest, RangeRequest,
+    RequestOp, TxnRequest,
 };
 
 use std::env::VarError;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::Sender;
 use tokio::time::Instant;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::transport::Endpoint;
+use tokio_stream::{Stream, StreamExt};
+use tonic::codegen::InterceptedService;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, Endpoint};
 use tonic::Streaming;
 use tracing::{event, span, Instrument, Level};
 
 use opentelemetry_tracing_utils::GrpcInterceptor;
 use opentelemetry_tracing_utils::InterceptedGrpcService;
 
+use crate::secret::SecretString;
+
 #[allow(clippy::all)]
 pub mod mvccpb {
     tonic::include_proto!("mvccpb"); // The string specified here must match the proto package name
@@ -59,52 +68,252 @@ pub enum Error {
     RefreshLease,
     #[error("error refreshing lease")]
     LeaseExpired,
+    #[error("etcd auth token can't be used as request metadata")]
+    InvalidAuthToken,
+}
+
+/// Credentials for an etcd cluster with authentication (RBAC) enabled
+#[derive(Debug, Clone)]
+pub struct EtcdCredentials {
+    pub username: String,
+    pub password: SecretString,
+}
+
+/// Adds the etcd auth token to requests once authenticated, as well as the tracing context added
+/// by [GrpcInterceptor]
+#[derive(Debug, Clone, Default)]
+pub struct EtcdInterceptor {
+    /// Shared between all the clients for a connection, so re-authenticating updates them all
+    auth_token: Arc<RwLock<Option<AsciiMetadataValue>>>,
+}
+impl EtcdInterceptor {
+    fn set_auth_token(&self, auth_token: AsciiMetadataValue) {
+        *self
+            .auth_token
+            .write()
+            .expect("lock should not be poisoned") = Some(auth_token);
+    }
+}
+impl Interceptor for EtcdInterceptor {
+    fn call(
+        &mut self,
+        request: tonic::Request<()>,
+    ) -> std::result::Result<tonic::Request<()>, tonic::Status> {
+        let mut request = GrpcInterceptor.call(request)?;
+
+        if let Some(auth_token) = self
+            .auth_token
+            .read()
+            .expect("lock should not be poisoned")
+            .clone()
+        {
+            request.metadata_mut().insert("token", auth_token);
+        }
+
+        Ok(request)
+    }
+}
+
+pub type EtcdGrpcService = InterceptedService<Channel, EtcdInterceptor>;
+pub type KvClient = kv_client::KvClient<EtcdGrpcService>;
+pub type LeaseClient = lease_client::LeaseClient<EtcdGrpcService>;
+type AuthClient = auth_client::AuthClient<InterceptedGrpcService>;
+
+#[derive(Debug, Clone)]
+struct EtcdAuthenticator {
+    credentials: EtcdCredentials,
+    client: AuthClient,
+    interceptor: EtcdInterceptor,
+}
+
+/// HTTP/2 and TCP keepalive for the etcd connection, so that a dead connection (e.g. after etcd
+/// restarts) is noticed and replaced, rather than requests hanging on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EtcdKeepAlive {
+    /// How often to send keepalive pings
+    pub interval: Duration,
+    /// How long to wait for a ping to be acknowledged before closing the connection
+    pub timeout: Duration,
+}
+impl Default for EtcdKeepAlive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+fn endpoint(etcd_endpoint: String, keep_alive: EtcdKeepAlive) -> Result<Endpoint> {
+    Ok(Endpoint::from_shared(etcd_endpoint)?
+        .http2_keep_alive_interval(keep_alive.interval)
+        .keep_alive_timeout(keep_alive.timeout)
+        .keep_alive_while_idle(true)
+        .tcp_keepalive(Some(keep_alive.interval)))
 }
 
-pub type KvClient = kv_client::KvClient<InterceptedGrpcService>;
-pub type LeaseClient = lease_client::LeaseClient<InterceptedGrpcService>;
+/// How often [EtcdClients::keep_authenticated] gets a new auth token. etcd's auth tokens last 5
+/// minutes by default (`--auth-token-ttl`), so a few refreshes can fail before one expires.
+pub const AUTH_TOKEN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Clone)]
 pub struct EtcdClients {
     pub kv: KvClient,
     pub lease: LeaseClient,
+    auth: Option<EtcdAuthenticator>,
 }
 impl EtcdClients {
-    pub async fn connect(etcd_endpoint: String) -> Result<Self> {
-        let channel = Endpoint::from_shared(etcd_endpoint)?.connect().await?;
-        Ok(Self {
-            kv: kv_client::KvClient::with_interceptor(channel.clone(), GrpcInterceptor),
-            lease: lease_client::LeaseClient::with_interceptor(channel, GrpcInterceptor),
-        })
+    /// Connect to etcd, authenticating first if `credentials` are given.
+    ///
+    /// Fails if etcd can't be reached, so that connecting can be retried. Once connected, the
+    /// connection is remade whenever it drops (e.g. if etcd restarts).
+    pub async fn connect(
+        etcd_endpoint: String,
+        credentials: Option<EtcdCredentials>,
+        keep_alive: EtcdKeepAlive,
+    ) -> Result<Self> {
+        let channel = endpoint(etcd_endpoint, keep_alive)?.connect().await?;
+        let interceptor = EtcdInterceptor::default();
+
+        let etcd_clients = Self {
+            kv: kv_client::KvClient::with_interceptor(channel.clone(), interceptor.clone()),
+            lease: lease_client::LeaseClient::with_interceptor(
+                channel.clone(),
+                interceptor.clone(),
+            ),
+            auth: credentials.map(|credentials| EtcdAuthenticator {
+                credentials,
+                client: auth_client::AuthClient::with_interceptor(channel, GrpcInterceptor),
+                interceptor,
+            }),
+        };
+        etcd_clients.authenticate().await?;
+
+        Ok(etcd_clients)
+    }
+
+    /// Get a new auth token, which is then used by all the clients. Auth tokens expire, so this
+    /// should be called again if requests start failing. Does nothing without credentials.
+    #[tracing::instrument(skip(self))]
+    pub async fn authenticate(&self) -> Result<()> {
+        let Some(auth) = &self.auth else {
+            return Ok(());
+        };
+
+        let response = auth
+            .client
+            .clone()
+            .authenticate(AuthenticateRequest {
+                name: auth.credentials.username.clone(),
+                password: auth.credentials.password.expose_secret().to_owned(),
+            })
+            .await?
+            .into_inner();
+
+        let auth_token =
+            AsciiMetadataValue::try_from(response.token).map_err(|_| Error::InvalidAuthToken)?;
+        auth.interceptor.set_auth_token(auth_token);
+
+        event!(Level::INFO, "authenticated with etcd");
+
+        Ok(())
+    }
+
+    /// Get a new auth token every `interval`, so that requests don't start failing when the
+    /// current one expires. Never returns with credentials, and returns straight away without.
+    pub async fn keep_authenticated(&self, interval: Duration) {
+        if self.auth.is_none() {
+            return;
+        }
+
+        let mut refresh = tokio::time::interval(interval);
+        // the first tick is immediate, but the clients authenticated when they connected
+        refresh.tick().await;
+        loop {
+            refresh.tick().await;
+            if let Err(error) = self.authenticate().await {
+                event!(
+                    Level::WARN,
+                    %error,
+                    "failed to refresh the etcd auth token, will try again"
+                );
+            }
+        }
+    }
+}
+
+/// Changes in the state of a lease, e.g. for health checks or metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseEvent {
+    Granted {
+        lease_id: i64,
+        ttl_in_seconds: i64,
+    },
+    Renewed {
+        lease_id: i64,
+        ttl_in_seconds: i64,
+    },
+    /// The lease expired, or couldn't be kept alive
+    Lost {
+        lease_id: i64,
+    },
+}
+
+/// Publish a lease event, if anyone is listening. This doesn't wait for a slow receiver, as keeping
+/// the lease alive is more important than the event being seen.
+fn publish_lease_event(lease_events: Option<&Sender<LeaseEvent>>, lease_event: LeaseEvent) {
+    if let Some(lease_events) = lease_events {
+        if let Err(error) = lease_events.try_send(lease_event) {
+            event!(Level::DEBUG, %error, "lease event not published");
+        }
     }
 }
 
 #[tracing::instrument]
-pub async fn create_lease(mut grpc_client: LeaseClient) -> Result<LeaseGrantResponse> {
+pub async fn create_lease(
+    mut grpc_client: LeaseClient,
+    lease_events: Option<Sender<LeaseEvent>>,
+) -> Result<LeaseGrantResponse> {
     let request = tonic::Request::new(LeaseGrantRequest { id: 0, ttl: 30 });
     let response = grpc_client.lease_grant(request).await?;
 
     event!(Level::INFO, "Response={:?}", response);
 
-    Ok(response.into_inner())
+    let response = response.into_inner();
+    publish_lease_event(
+        lease_events.as_ref(),
+        LeaseEvent::Granted {
+            lease_id: response.id,
+            ttl_in_seconds: response.ttl,
+        },
+    );
+
+    Ok(response)
 }
 
 #[derive(Debug, Clone)]
 struct RefreshLeaseOnceResponse {
     ttl_in_seconds: i64,
 }
-#[tracing::instrument(level = "debug")]
-async fn refresh_lease_once(
+#[tracing::instrument(level = "debug", skip(response_receiver))]
+async fn refresh_lease_once<S: KeepAliveResponses>(
     request_sender: &Sender<LeaseKeepAliveRequest>,
-    response_receiver: &mut tonic::Streaming<LeaseKeepAliveResponse>,
+    response_receiver: &mut S,
     lease_id: i64,
 ) -> Result<RefreshLeaseOnceResponse> {
     send_lease_keep_alive_request(request_sender, lease_id).await?;
 
     event!(Level::INFO, lease_id, "trying to keep the lease alive");
 
+    receive_lease_keep_alive_response(response_receiver).await
+}
+
+/// Wait for the response to a keep alive request, confirming the lease refresh
+async fn receive_lease_keep_alive_response<S: KeepAliveResponses>(
+    response_receiver: &mut S,
+) -> Result<RefreshLeaseOnceResponse> {
     let lease_ttl;
-    // wait for response on channel (confirming the lease refresh)
-    if let Some(response) = response_receiver.message().await? {
+    if let Some(response) = response_receiver.next().await.transpose()? {
         lease_ttl = response.ttl;
         if lease_ttl > 0 {
             event!(Level::INFO, lease_ttl, "refreshed lease");
@@ -130,30 +339,77 @@ async fn send_lease_keep_alive_request(
         .map_err(|_| Error::ChannelClosed)
 }
 
+/// Publish the event for the result of a single keep alive request
+fn publish_keep_alive_result(
+    lease_events: Option<&Sender<LeaseEvent>>,
+    lease_id: i64,
+    result: &Result<RefreshLeaseOnceResponse>,
+) {
+    let lease_event = match result {
+        Ok(response) => LeaseEvent::Renewed {
+            lease_id,
+            ttl_in_seconds: response.ttl_in_seconds,
+        },
+        Err(_) => LeaseEvent::Lost { lease_id },
+    };
+    publish_lease_event(lease_events, lease_event);
+}
+
+/// Default size of the buffer for keep alive requests waiting to be streamed to etcd. A request is
+/// only sent once the response to the previous one has been received, so there is never more than
+/// one waiting, and a small buffer is plenty.
+pub const DEFAULT_LEASE_KEEP_ALIVE_BUFFER_SIZE: usize = 8;
+
+/// The stream of responses to keep alive requests, a [Streaming] from etcd except in tests
+trait KeepAliveResponses:
+    Stream<Item = std::result::Result<LeaseKeepAliveResponse, tonic::Status>> + Unpin
+{
+}
+impl<S> KeepAliveResponses for S where
+    S: Stream<Item = std::result::Result<LeaseKeepAliveResponse, tonic::Status>> + Unpin
+{
+}
+
 #[derive(Debug)]
-struct LeaseLivenessKeeper {
+struct LeaseLivenessKeeper<S = Streaming<LeaseKeepAliveResponse>> {
     request_sender: Sender<LeaseKeepAliveRequest>,
-    response_receiver: Streaming<LeaseKeepAliveResponse>,
+    response_receiver: S,
     lease_id: i64,
+    lease_events: Option<Sender<LeaseEvent>>,
+    /// Whether the request sent when initialising the keep alive stream is still waiting for its
+    /// response, which is then the result of the first [Self::keep_alive]
+    initial_request_pending: bool,
 }
-impl LeaseLivenessKeeper {
+impl<S: KeepAliveResponses> LeaseLivenessKeeper<S> {
     /// send a keep alive request to etcd
     async fn keep_alive(&mut self) -> Result<RefreshLeaseOnceResponse> {
-        refresh_lease_once(
-            &self.request_sender,
-            &mut self.response_receiver,
-            self.lease_id,
-        )
-        .await
-    }
+        let result = if std::mem::take(&mut self.initial_request_pending) {
+            receive_lease_keep_alive_response(&mut self.response_receiver).await
+        } else {
+            refresh_lease_once(
+                &self.request_sender,
+                &mut self.response_receiver,
+                self.lease_id,
+            )
+            .await
+        };
+        publish_keep_alive_result(self.lease_events.as_ref(), self.lease_id, &result);
 
+        result
+    }
+}
+impl LeaseLivenessKeeper {
+    /// Start streaming keep alive requests to etcd. The first request is sent straight away, so
+    /// that etcd responds to the stream being opened.
     #[tracing::instrument]
     async fn initialise_lease_keep_alive(
         mut lease_client: LeaseClient,
         lease_id: i64,
+        lease_events: Option<Sender<LeaseEvent>>,
+        buffer_size: usize,
     ) -> Result<LeaseLivenessKeeper> {
         event!(Level::DEBUG, "creating channel and ReceiverStream");
-        let (req_sender, req_receiver) = channel::<LeaseKeepAliveRequest>(1024);
+        let (req_sender, req_receiver) = channel::<LeaseKeepAliveRequest>(buffer_size);
         send_lease_keep_alive_request(&req_sender, lease_id).await?;
 
         let req_receiver = ReceiverStream::new(req_receiver);
@@ -174,20 +430,39 @@ impl LeaseLivenessKeeper {
             lease_id,
             request_sender: req_sender,
             response_receiver,
+            lease_events,
+            initial_request_pending: true,
         })
     }
 }
 
 /// loop, refreshing lease before it expires
 /// Shouldn't ever return unless there is an error.
+///
+/// Each refresh publishes a [LeaseEvent::Renewed] to `lease_events`, or [LeaseEvent::Lost] if it
+/// fails.
+///
+/// `buffer_size` is the size of the buffer for requests waiting to be streamed to etcd, see
+/// [DEFAULT_LEASE_KEEP_ALIVE_BUFFER_SIZE].
 pub async fn lease_keep_alive(
     lease_client: LeaseClient,
     lease_id: i64,
+    lease_events: Option<Sender<LeaseEvent>>,
+    buffer_size: usize,
 ) -> Result<std::convert::Infallible> {
     println!("______________________Keep the lease alive!!!_________________");
 
-    let mut lease_liveness_keeper =
-        LeaseLivenessKeeper::initialise_lease_keep_alive(lease_client.clone(), lease_id).await?;
+    let mut lease_liveness_keeper = LeaseLivenessKeeper::initialise_lease_keep_alive(
+        lease_client.clone(),
+        lease_id,
+        lease_events.clone(),
+        buffer_size,
+    )
+    .await
+    .map_err(|e| {
+        publish_lease_event(lease_events.as_ref(), LeaseEvent::Lost { lease_id });
+        e
+    })?;
 
     let ttl_desired_preemption = 10;
     let span = span!(Level::TRACE, "test spannnnn");
@@ -251,3 +526,260 @@ pub fn calculate_prefix_range_end(prefix: &str) -> String {
 
     calculated_prefix
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_etcd::FakeEtcd;
+
+    fn credentials() -> EtcdCredentials {
+        EtcdCredentials {
+            username: "node".to_owned(),
+            password: SecretString::new("password"),
+        }
+    }
+
+    async fn range_nodes(etcd_clients: &EtcdClients) -> std::result::Result<(), tonic::Status> {
+        etcd_clients
+            .kv
+            .clone()
+            .range(RangeRequest {
+                key: b"/nodes/".to_vec(),
+                ..Default::default()
+            })
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn requests_authenticated_with_credentials() {
+        let fake = FakeEtcd::start_with_credentials(&credentials()).await;
+
+        let without_credentials = fake.clients().await;
+        assert_eq!(
+            tonic::Code::Unauthenticated,
+            range_nodes(&without_credentials).await.unwrap_err().code()
+        );
+
+        let etcd_clients = EtcdClients::connect(
+            fake.endpoint(),
+            Some(credentials()),
+            EtcdKeepAlive::default(),
+        )
+        .await
+        .unwrap();
+        range_nodes(&etcd_clients).await.unwrap();
+        assert_eq!(1, fake.authentications());
+
+        // rejected once the token expires, until the clients authenticate again
+        fake.expire_auth_token();
+        assert_eq!(
+            tonic::Code::Unauthenticated,
+            range_nodes(&etcd_clients).await.unwrap_err().code()
+        );
+        etcd_clients.authenticate().await.unwrap();
+        range_nodes(&etcd_clients).await.unwrap();
+        assert_eq!(2, fake.authentications());
+    }
+
+    #[tokio::test]
+    async fn wrong_password_fails_to_connect() {
+        let fake = FakeEtcd::start_with_credentials(&credentials()).await;
+
+        let result = EtcdClients::connect(
+            fake.endpoint(),
+            Some(EtcdCredentials {
+                password: SecretString::new("wrong"),
+                ..credentials()
+            }),
+            EtcdKeepAlive::default(),
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(Error::ResponseStatusError(status)) if status.code() == tonic::Code::InvalidArgument
+        ));
+    }
+
+    #[tokio::test]
+    async fn auth_token_refreshed_periodically() {
+        let fake = FakeEtcd::start_with_credentials(&credentials()).await;
+        let etcd_clients = EtcdClients::connect(
+            fake.endpoint(),
+            Some(credentials()),
+            EtcdKeepAlive::default(),
+        )
+        .await
+        .unwrap();
+
+        let refresh = tokio::spawn({
+            let etcd_clients = etcd_clients.clone();
+            async move {
+                etcd_clients
+                    .keep_authenticated(Duration::from_millis(20))
+                    .await
+            }
+        });
+        // an expired token is replaced without anything else re-authenticating
+        fake.expire_auth_token();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        refresh.abort();
+
+        assert!(fake.authentications() >= 3);
+        range_nodes(&etcd_clients).await.unwrap();
+    }
+
+    #[test]
+    fn auth_token_attached_after_authentication() {
+        let mut interceptor = EtcdInterceptor::default();
+
+        let request = interceptor.call(tonic::Request::new(())).unwrap();
+        assert!(request.metadata().get("token").is_none());
+
+        // clients share the token with the interceptor that is updated by authenticating
+        interceptor
+            .clone()
+            .set_auth_token(AsciiMetadataValue::from_static("abc.123"));
+
+        let request = interceptor.call(tonic::Request::new(())).unwrap();
+        assert_eq!("abc.123", request.metadata()["token"]);
+    }
+
+    #[tokio::test]
+    async fn unreachable_etcd_fails_to_connect() {
+        // nothing is listening on port 1
+        assert!(matches!(
+            EtcdClients::connect(
+                "http://127.0.0.1:1".to_owned(),
+                None,
+                EtcdKeepAlive::default()
+            )
+            .await,
+            Err(Error::Transport(_))
+        ));
+
+        assert!(matches!(
+            EtcdClients::connect("not a url".to_owned(), None, EtcdKeepAlive::default()).await,
+            Err(Error::Transport(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn idle_connection_kept_alive() {
+        let fake = FakeEtcd::start().await;
+        let etcd_clients = EtcdClients::connect(
+            fake.endpoint(),
+            None,
+            EtcdKeepAlive {
+                interval: Duration::from_millis(50),
+                timeout: Duration::from_millis(500),
+            },
+        )
+        .await
+        .unwrap();
+        range_nodes(&etcd_clients).await.unwrap();
+
+        // several pings are sent and acknowledged while idle, so the connection isn't closed
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        range_nodes(&etcd_clients).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn renewed_event_published_per_keep_alive() {
+        let (sender, mut receiver) = channel(8);
+
+        for ttl_in_seconds in [30, 29] {
+            publish_keep_alive_result(
+                Some(&sender),
+                7,
+                &Ok(RefreshLeaseOnceResponse { ttl_in_seconds }),
+            );
+        }
+        publish_keep_alive_result(Some(&sender), 7, &Err(Error::LeaseExpired));
+        drop(sender);
+
+        let mut lease_events = vec![];
+        while let Some(lease_event) = receiver.recv().await {
+            lease_events.push(lease_event);
+        }
+
+        assert_eq!(
+            vec![
+                LeaseEvent::Renewed {
+                    lease_id: 7,
+                    ttl_in_seconds: 30
+                },
+                LeaseEvent::Renewed {
+                    lease_id: 7,
+                    ttl_in_seconds: 29
+                },
+                LeaseEvent::Lost { lease_id: 7 },
+            ],
+            lease_events
+        );
+    }
+
+    #[tokio::test]
+    async fn each_keep_alive_gets_its_own_response() {
+        let (request_sender, mut request_receiver) =
+            channel::<LeaseKeepAliveRequest>(DEFAULT_LEASE_KEEP_ALIVE_BUFFER_SIZE);
+        let (response_sender, response_receiver) = channel::<
+            std::result::Result<LeaseKeepAliveResponse, tonic::Status>,
+        >(DEFAULT_LEASE_KEEP_ALIVE_BUFFER_SIZE);
+
+        // etcd answers each request with a ttl that counts down, so the responses can be told apart
+        let fake_etcd = tokio::spawn(async move {
+            let mut ttl = 30;
+            let mut n_requests = 0;
+            while let Some(request) = request_receiver.recv().await {
+                assert_eq!(7, request.id);
+                n_requests += 1;
+                let response = LeaseKeepAliveResponse {
+                    id: request.id,
+                    ttl,
+                    ..Default::default()
+                };
+                ttl -= 1;
+                if response_sender.send(Ok(response)).await.is_err() {
+                    break;
+                }
+            }
+            n_requests
+        });
+
+        // as sent by initialise_lease_keep_alive
+        send_lease_keep_alive_request(&request_sender, 7)
+            .await
+            .unwrap();
+        let mut keeper = LeaseLivenessKeeper {
+            request_sender,
+            response_receiver: ReceiverStream::new(response_receiver),
+            lease_id: 7,
+            lease_events: None,
+            initial_request_pending: true,
+        };
+
+        let mut ttls = vec![];
+        for _ in 0..3 {
+            ttls.push(keeper.keep_alive().await.unwrap().ttl_in_seconds);
+        }
+        drop(keeper);
+
+        assert_eq!(vec![30, 29, 28], ttls);
+        // the initial request is the first round's request, rather than an extra one
+        assert_eq!(3, fake_etcd.await.unwrap());
+    }
+
+    #[test]
+    fn full_or_missing_receiver_does_not_block() {
+        let (sender, mut receiver) = channel(1);
+
+        publish_lease_event(None, LeaseEvent::Lost { lease_id: 7 });
+        publish_lease_event(Some(&sender), LeaseEvent::Lost { lease_id: 1 });
+        publish_lease_event(Some(&sender), LeaseEvent::Lost { lease_id: 2 });
+
+        assert_eq!(Ok(LeaseEvent::Lost { lease_id: 1 }), receiver.try_recv());
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,678 @@
+//! An in-memory etcd server for tests, so that the etcd clients can be tested against real gRPC
+//! requests. It supports the KV, Lease and Auth requests made by this crate. Leases don't expire
+//! by themselves, use [FakeEtcd::expire_lease] instead.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::etcd::etcdserverpb::{
+    auth_server, compare, kv_server, lease_server, request_op, response_op, *,
+};
+use crate::etcd::mvccpb::KeyValue;
+use crate::etcd::{EtcdClients, EtcdCredentials, EtcdKeepAlive};
+
+fn lease_not_found() -> Status {
+    // see ErrGRPCLeaseNotFound in etcd's api/v3rpc/rpctypes
+    Status::not_found("etcdserver: requested lease not found")
+}
+
+#[derive(Debug, Default)]
+struct State {
+    revision: i64,
+    kvs: BTreeMap<Vec<u8>, KeyValue>,
+    /// Lease ids and their TTLs
+    leases: HashMap<i64, i64>,
+    next_lease_id: i64,
+    /// Errors to return for the next transactions, without applying them
+    txn_failures: VecDeque<Status>,
+    /// The number of upcoming transactions to apply, but then fail as if the response was lost
+    lost_txn_responses: usize,
+    /// The name of each request received, in order
+    requests: Vec<&'static str>,
+    credentials: Option<(String, String)>,
+    auth_token: Option<String>,
+    authentications: usize,
+}
+impl State {
+    fn header(&self) -> Option<ResponseHeader> {
+        Some(ResponseHeader {
+            revision: self.revision,
+            ..Default::default()
+        })
+    }
+
+    fn check_auth_token<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        if self.credentials.is_none() {
+            return Ok(());
+        }
+        let token = request
+            .metadata()
+            .get("token")
+            .and_then(|token| token.to_str().ok());
+        match (&self.auth_token, token) {
+            (Some(valid), Some(token)) if valid == token => Ok(()),
+            _ => Err(Status::unauthenticated("etcdserver: invalid auth token")),
+        }
+    }
+
+    fn matching_keys(&self, key: &[u8], range_end: &[u8]) -> Vec<Vec<u8>> {
+        self.kvs
+            .keys()
+            .filter(|candidate| match range_end {
+                [] => candidate.as_slice() == key,
+                [0] => candidate.as_slice() >= key,
+                range_end => candidate.as_slice() >= key && candidate.as_slice() < range_end,
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn range(&self, request: &RangeRequest) -> RangeResponse {
+        let mut kvs: Vec<_> = self
+            .matching_keys(&request.key, &request.range_end)
+            .iter()
+            .map(|key| self.kvs[key].clone())
+            .collect();
+        let count = kvs.len() as i64;
+
+        if request.count_only {
+            kvs.clear();
+        }
+        if request.keys_only {
+            kvs.iter_mut().for_each(|kv| kv.value.clear());
+        }
+        let more = request.limit > 0 && kvs.len() as i64 > request.limit;
+        if more {
+            kvs.truncate(request.limit as usize);
+        }
+
+        RangeResponse {
+            header: self.header(),
+            kvs,
+            more,
+            count,
+        }
+    }
+
+    fn put(&mut self, request: PutRequest) -> Result<PutResponse, Status> {
+        let prev_kv = self.kvs.get(&request.key).cloned();
+        let lease = match (&prev_kv, request.ignore_lease) {
+            (Some(prev_kv), true) => prev_kv.lease,
+            // see ErrGRPCKeyNotFound in etcd's api/v3rpc/rpctypes
+            (None, true) => return Err(Status::invalid_argument("etcdserver: key not found")),
+            (_, false) => request.lease,
+        };
+        if lease != 0 && !self.leases.contains_key(&lease) {
+            return Err(lease_not_found());
+        }
+        self.revision += 1;
+        let revision = self.revision;
+
+        let kv = KeyValue {
+            key: request.key.clone(),
+            create_revision: prev_kv.as_ref().map_or(revision, |kv| kv.create_revision),
+            mod_revision: revision,
+            version: prev_kv.as_ref().map_or(0, |kv| kv.version) + 1,
+            value: request.value,
+            lease,
+        };
+        self.kvs.insert(request.key, kv);
+
+        Ok(PutResponse {
+            header: self.header(),
+            prev_kv: prev_kv.filter(|_| request.prev_kv),
+        })
+    }
+
+    fn delete_range(&mut self, request: DeleteRangeRequest) -> DeleteRangeResponse {
+        let keys = self.matching_keys(&request.key, &request.range_end);
+        if !keys.is_empty() {
+            self.revision += 1;
+        }
+        let prev_kvs: Vec<_> = keys.iter().filter_map(|key| self.kvs.remove(key)).collect();
+
+        DeleteRangeResponse {
+            header: self.header(),
+            deleted: prev_kvs.len() as i64,
+            prev_kvs: if request.prev_kv { prev_kvs } else { vec![] },
+        }
+    }
+
+    fn compare(&self, compare: &Compare) -> bool {
+        let kv = self.kvs.get(&compare.key);
+        let ordering = match &compare.target_union {
+            Some(compare::TargetUnion::Version(version)) => {
+                kv.map_or(0, |kv| kv.version).cmp(version)
+            }
+            Some(compare::TargetUnion::CreateRevision(revision)) => {
+                kv.map_or(0, |kv| kv.create_revision).cmp(revision)
+            }
+            Some(compare::TargetUnion::ModRevision(revision)) => {
+                kv.map_or(0, |kv| kv.mod_revision).cmp(revision)
+            }
+            Some(compare::TargetUnion::Lease(lease)) => kv.map_or(0, |kv| kv.lease).cmp(lease),
+            // a missing key never matches a value comparison
+            Some(compare::TargetUnion::Value(value)) => match kv {
+                Some(kv) => kv.value.cmp(value),
+                None => return false,
+            },
+            None => return false,
+        };
+
+        match compare::CompareResult::from_i32(compare.result) {
+            Some(compare::CompareResult::Equal) => ordering.is_eq(),
+            Some(compare::CompareResult::Greater) => ordering.is_gt(),
+            Some(compare::CompareResult::Less) => ordering.is_lt(),
+            Some(compare::CompareResult::NotEqual) => ordering.is_ne(),
+            None => false,
+        }
+    }
+
+    fn txn(&mut self, request: TxnRequest) -> Result<TxnResponse, Status> {
+        let succeeded = request.compare.iter().all(|compare| self.compare(compare));
+        let operations = if succeeded {
+            request.success
+        } else {
+            request.failure
+        };
+
+        let mut responses = vec![];
+        for operation in operations {
+            let response = match operation.request {
+                Some(request_op::Request::RequestRange(request)) => {
+                    response_op::Response::ResponseRange(self.range(&request))
+                }
+                Some(request_op::Request::RequestPut(request)) => {
+                    response_op::Response::ResponsePut(self.put(request)?)
+                }
+                Some(request_op::Request::RequestDeleteRange(request)) => {
+                    response_op::Response::ResponseDeleteRange(self.delete_range(request))
+                }
+                Some(request_op::Request::RequestTxn(request)) => {
+                    response_op::Response::ResponseTxn(self.txn(request)?)
+                }
+                None => return Err(Status::invalid_argument("empty request op")),
+            };
+            responses.push(ResponseOp {
+                response: Some(response),
+            });
+        }
+
+        Ok(TxnResponse {
+            header: self.header(),
+            succeeded,
+            responses,
+        })
+    }
+
+    fn revoke_lease(&mut self, lease: i64) -> Result<(), Status> {
+        self.leases.remove(&lease).ok_or_else(lease_not_found)?;
+        self.kvs.retain(|_, kv| kv.lease != lease);
+        self.revision += 1;
+        Ok(())
+    }
+}
+
+/// A fake etcd server, listening on a free local port. The server runs until the test's runtime
+/// shuts down.
+#[derive(Debug, Clone)]
+pub struct FakeEtcd {
+    state: Arc<Mutex<State>>,
+    endpoint: String,
+}
+impl FakeEtcd {
+    pub async fn start() -> Self {
+        Self::start_with_state(State::default()).await
+    }
+
+    /// A server with authentication enabled, for a single user
+    pub async fn start_with_credentials(credentials: &EtcdCredentials) -> Self {
+        Self::start_with_state(State {
+            credentials: Some((
+                credentials.username.clone(),
+                credentials.password.expose_secret().to_owned(),
+            )),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn start_with_state(state: State) -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = tonic::transport::server::TcpIncoming::from_listener(
+            tokio::net::TcpListener::from_std(listener).unwrap(),
+            true,
+            None,
+        )
+        .unwrap();
+
+        let fake = Self {
+            state: Arc::new(Mutex::new(state)),
+            endpoint,
+        };
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(kv_server::KvServer::new(fake.clone()))
+                .add_service(lease_server::LeaseServer::new(fake.clone()))
+                .add_service(auth_server::AuthServer::new(fake.clone()))
+                .serve_with_incoming(incoming),
+        );
+
+        fake
+    }
+
+    /// The URL to connect to
+    pub fn endpoint(&self) -> String {
+        self.endpoint.clone()
+    }
+
+    /// Connect to the server, without credentials
+    pub async fn clients(&self) -> EtcdClients {
+        EtcdClients::connect(self.endpoint(), None, EtcdKeepAlive::default())
+            .await
+            .unwrap()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("lock should not be poisoned")
+    }
+
+    /// Handle a request, after recording it and checking its auth token
+    fn handle<T, R>(
+        &self,
+        name: &'static str,
+        request: Request<T>,
+        f: impl FnOnce(&mut State, T) -> Result<R, Status>,
+    ) -> Result<Response<R>, Status> {
+        let mut state = self.state();
+        state.requests.push(name);
+        state.check_auth_token(&request)?;
+        f(&mut state, request.into_inner()).map(Response::new)
+    }
+
+    /// Grant a lease directly, e.g. for another node
+    pub fn grant_lease(&self, ttl: i64) -> i64 {
+        let mut state = self.state();
+        state.next_lease_id += 1;
+        let lease = state.next_lease_id;
+        state.leases.insert(lease, ttl);
+        lease
+    }
+
+    /// Expire a lease, deleting its keys
+    pub fn expire_lease(&self, lease: i64) {
+        self.state().revoke_lease(lease).unwrap();
+    }
+
+    pub fn lease_exists(&self, lease: i64) -> bool {
+        self.state().leases.contains_key(&lease)
+    }
+
+    /// Put a key directly, e.g. another node's record
+    pub fn put(&self, key: &str, value: &str, lease: i64) {
+        self.state()
+            .put(PutRequest {
+                key: key.into(),
+                value: value.into(),
+                lease,
+                ..Default::default()
+            })
+            .unwrap();
+    }
+
+    pub fn get(&self, key: &str) -> Option<KeyValue> {
+        self.state().kvs.get(key.as_bytes()).cloned()
+    }
+
+    /// Every key starting with `prefix`, with its value
+    pub fn entries_with_prefix(&self, prefix: &str) -> Vec<(String, String)> {
+        self.state()
+            .kvs
+            .values()
+            .filter(|kv| kv.key.starts_with(prefix.as_bytes()))
+            .map(|kv| {
+                (
+                    String::from_utf8_lossy(&kv.key).into_owned(),
+                    String::from_utf8_lossy(&kv.value).into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    /// Fail the next transaction with `status`, without applying it
+    pub fn fail_next_txn(&self, status: Status) {
+        self.state().txn_failures.push_back(status);
+    }
+
+    /// Apply the next transaction, but then fail it as if the response was lost on the way back
+    pub fn lose_next_txn_response(&self) {
+        self.state().lost_txn_responses += 1;
+    }
+
+    /// The name of each request received so far, e.g. `"range"`
+    pub fn requests(&self) -> Vec<&'static str> {
+        self.state().requests.clone()
+    }
+
+    /// The number of successful `Authenticate` requests
+    pub fn authentications(&self) -> usize {
+        self.state().authentications
+    }
+
+    /// Make the current auth token invalid, as if it had expired
+    pub fn expire_auth_token(&self) {
+        self.state().auth_token = None;
+    }
+}
+
+#[tonic::async_trait]
+impl kv_server::Kv for FakeEtcd {
+    async fn range(
+        &self,
+        request: Request<RangeRequest>,
+    ) -> Result<Response<RangeResponse>, Status> {
+        self.handle("range", request, |state, request| Ok(state.range(&request)))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        self.handle("put", request, State::put)
+    }
+
+    async fn delete_range(
+        &self,
+        request: Request<DeleteRangeRequest>,
+    ) -> Result<Response<DeleteRangeResponse>, Status> {
+        self.handle("delete_range", request, |state, request| {
+            Ok(state.delete_range(request))
+        })
+    }
+
+    async fn txn(&self, request: Request<TxnRequest>) -> Result<Response<TxnResponse>, Status> {
+        self.handle("txn", request, |state, request| {
+            if let Some(status) = state.txn_failures.pop_front() {
+                return Err(status);
+            }
+
+            // a failed transaction changes nothing
+            let mut applied = State {
+                revision: state.revision,
+                kvs: state.kvs.clone(),
+                leases: state.leases.clone(),
+                ..Default::default()
+            };
+            let response = applied.txn(request)?;
+            state.revision = applied.revision;
+            state.kvs = applied.kvs;
+
+            if state.lost_txn_responses > 0 {
+                state.lost_txn_responses -= 1;
+                return Err(Status::unavailable("response lost"));
+            }
+            Ok(response)
+        })
+    }
+
+    async fn compact(
+        &self,
+        _request: Request<CompactionRequest>,
+    ) -> Result<Response<CompactionResponse>, Status> {
+        Err(Status::unimplemented("compact"))
+    }
+}
+
+#[tonic::async_trait]
+impl lease_server::Lease for FakeEtcd {
+    async fn lease_grant(
+        &self,
+        request: Request<LeaseGrantRequest>,
+    ) -> Result<Response<LeaseGrantResponse>, Status> {
+        self.handle("lease_grant", request, |state, request| {
+            let id = if request.id == 0 {
+                state.next_lease_id += 1;
+                state.next_lease_id
+            } else {
+                request.id
+            };
+            state.leases.insert(id, request.ttl);
+
+            Ok(LeaseGrantResponse {
+                header: state.header(),
+                id,
+                ttl: request.ttl,
+                error: String::new(),
+            })
+        })
+    }
+
+    async fn lease_revoke(
+        &self,
+        request: Request<LeaseRevokeRequest>,
+    ) -> Result<Response<LeaseRevokeResponse>, Status> {
+        self.handle("lease_revoke", request, |state, request| {
+            state.revoke_lease(request.id)?;
+            Ok(LeaseRevokeResponse {
+                header: state.header(),
+            })
+        })
+    }
+
+    type LeaseKeepAliveStream = ReceiverStream<Result<LeaseKeepAliveResponse, Status>>;
+
+    async fn lease_keep_alive(
+        &self,
+        request: Request<Streaming<LeaseKeepAliveRequest>>,
+    ) -> Result<Response<Self::LeaseKeepAliveStream>, Status> {
+        let mut requests = self
+            .handle("lease_keep_alive", request, |_, requests| Ok(requests))?
+            .into_inner();
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+
+        let fake = self.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(request)) = requests.message().await {
+                let response = {
+                    let state = fake.state();
+                    LeaseKeepAliveResponse {
+                        header: state.header(),
+                        id: request.id,
+                        // etcd responds with a TTL of 0 for a lease that doesn't exist
+                        ttl: state.leases.get(&request.id).copied().unwrap_or_default(),
+                    }
+                };
+                if sender.send(Ok(response)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn lease_time_to_live(
+        &self,
+        request: Request<LeaseTimeToLiveRequest>,
+    ) -> Result<Response<LeaseTimeToLiveResponse>, Status> {
+        self.handle("lease_time_to_live", request, |state, request| {
+            let ttl = state.leases.get(&request.id).copied();
+            let keys = if request.keys {
+                state
+                    .kvs
+                    .values()
+                    .filter(|kv| kv.lease == request.id)
+                    .map(|kv| kv.key.clone())
+                    .collect()
+            } else {
+                vec![]
+            };
+
+            Ok(LeaseTimeToLiveResponse {
+                header: state.header(),
+                id: request.id,
+                // etcd returns -1 for an expired lease
+                ttl: ttl.unwrap_or(-1),
+                granted_ttl: ttl.unwrap_or_default(),
+                keys,
+            })
+        })
+    }
+
+    async fn lease_leases(
+        &self,
+        request: Request<LeaseLeasesRequest>,
+    ) -> Result<Response<LeaseLeasesResponse>, Status> {
+        self.handle("lease_leases", request, |state, _| {
+            Ok(LeaseLeasesResponse {
+                header: state.header(),
+                leases: state
+                    .leases
+                    .keys()
+                    .map(|id| LeaseStatus { id: *id })
+                    .collect(),
+            })
+        })
+    }
+}
+
+/// Only `Authenticate` is supported
+#[tonic::async_trait]
+impl auth_server::Auth for FakeEtcd {
+    async fn authenticate(
+        &self,
+        request: Request<AuthenticateRequest>,
+    ) -> Result<Response<AuthenticateResponse>, Status> {
+        let mut state = self.state();
+        state.requests.push("authenticate");
+        let request = request.into_inner();
+
+        if state.credentials != Some((request.name, request.password)) {
+            return Err(Status::invalid_argument(
+                "etcdserver: authentication failed, invalid user ID or password",
+            ));
+        }
+        state.authentications += 1;
+        let token = format!("token-{}", state.authentications);
+        state.auth_token = Some(token.clone());
+
+        Ok(Response::new(AuthenticateResponse {
+            header: state.header(),
+            token,
+        }))
+    }
+
+    async fn auth_enable(
+        &self,
+        _request: Request<AuthEnableRequest>,
+    ) -> Result<Response<AuthEnableResponse>, Status> {
+        Err(Status::unimplemented("auth_enable"))
+    }
+
+    async fn auth_disable(
+        &self,
+        _request: Request<AuthDisableRequest>,
+    ) -> Result<Response<AuthDisableResponse>, Status> {
+        Err(Status::unimplemented("auth_disable"))
+    }
+
+    async fn auth_status(
+        &self,
+        _request: Request<AuthStatusRequest>,
+    ) -> Result<Response<AuthStatusResponse>, Status> {
+        Err(Status::unimplemented("auth_status"))
+    }
+
+    async fn user_add(
+        &self,
+        _request: Request<AuthUserAddRequest>,
+    ) -> Result<Response<AuthUserAddResponse>, Status> {
+        Err(Status::unimplemented("user_add"))
+    }
+
+    async fn user_get(
+        &self,
+        _request: Request<AuthUserGetRequest>,
+    ) -> Result<Response<AuthUserGetResponse>, Status> {
+        Err(Status::unimplemented("user_get"))
+    }
+
+    async fn user_list(
+        &self,
+        _request: Request<AuthUserListRequest>,
+    ) -> Result<Response<AuthUserListResponse>, Status> {
+        Err(Status::unimplemented("user_list"))
+    }
+
+    async fn user_delete(
+        &self,
+        _request: Request<AuthUserDeleteRequest>,
+    ) -> Result<Response<AuthUserDeleteResponse>, Status> {
+        Err(Status::unimplemented("user_delete"))
+    }
+
+    async fn user_change_password(
+        &self,
+        _request: Request<AuthUserChangePasswordRequest>,
+    ) -> Result<Response<AuthUserChangePasswordResponse>, Status> {
+        Err(Status::unimplemented("user_change_password"))
+    }
+
+    async fn user_grant_role(
+        &self,
+        _request: Request<AuthUserGrantRoleRequest>,
+    ) -> Result<Response<AuthUserGrantRoleResponse>, Status> {
+        Err(Status::unimplemented("user_grant_role"))
+    }
+
+    async fn user_revoke_role(
+        &self,
+        _request: Request<AuthUserRevokeRoleRequest>,
+    ) -> Result<Response<AuthUserRevokeRoleResponse>, Status> {
+        Err(Status::unimplemented("user_revoke_role"))
+    }
+
+    async fn role_add(
+        &self,
+        _request: Request<AuthRoleAddRequest>,
+    ) -> Result<Response<AuthRoleAddResponse>, Status> {
+        Err(Status::unimplemented("role_add"))
+    }
+
+    async fn role_get(
+        &self,
+        _request: Request<AuthRoleGetRequest>,
+    ) -> Result<Response<AuthRoleGetResponse>, Status> {
+        Err(Status::unimplemented("role_get"))
+    }
+
+    async fn role_list(
+        &self,
+        _request: Request<AuthRoleListRequest>,
+    ) -> Result<Response<AuthRoleListResponse>, Status> {
+        Err(Status::unimplemented("role_list"))
+    }
+
+    async fn role_delete(
+        &self,
+        _request: Request<AuthRoleDeleteRequest>,
+    ) -> Result<Response<AuthRoleDeleteResponse>, Status> {
+        Err(Status::unimplemented("role_delete"))
+    }
+
+    async fn role_grant_permission(
+        &self,
+        _request: Request<AuthRoleGrantPermissionRequest>,
+    ) -> Result<Response<AuthRoleGrantPermissionResponse>, Status> {
+        Err(Status::unimplemented("role_grant_permission"))
+    }
+
+    async fn role_revoke_permission(
+        &self,
+        _request: Request<AuthRoleRevokePermissionRequest>,
+    ) -> Result<Response<AuthRoleRevokePermissionResponse>, Status> {
+        Err(Status::unimplemented("role_revoke_permission"))
+    }
+}
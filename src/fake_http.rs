@@ -0,0 +1,143 @@
+//! A local HTTP server with canned JSON responses, standing in for the Notion and Google APIs in
+//! tests. It records every request it receives, so that tests can check what was sent.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A request received by a [FakeHttpServer]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedRequest {
+    /// e.g. "POST /v1/databases/database/query"
+    pub route: String,
+    pub query: Option<String>,
+    /// The JSON body, or null if the body was empty
+    pub body: serde_json::Value,
+}
+
+#[derive(Clone)]
+pub struct FakeHttpServer {
+    base_url: String,
+    requests: Arc<Mutex<Vec<ReceivedRequest>>>,
+    response_delay: Arc<Mutex<Duration>>,
+    max_in_flight: Arc<AtomicUsize>,
+}
+impl FakeHttpServer {
+    /// Serve each route (e.g. "GET /v1/users/me") with its status and JSON body. Any other route
+    /// is a 404.
+    ///
+    /// A route can end with a query parameter (e.g. "GET /v1/search?q=meeting"), to serve only the
+    /// requests that have that parameter. It takes precedence over the same route without one.
+    pub fn start(routes: impl IntoIterator<Item = (&'static str, u16, serde_json::Value)>) -> Self {
+        let routes: Arc<HashMap<String, (u16, serde_json::Value)>> = Arc::new(
+            routes
+                .into_iter()
+                .map(|(route, status, body)| (route.to_owned(), (status, body)))
+                .collect(),
+        );
+        let requests = Arc::new(Mutex::new(vec![]));
+        let response_delay = Arc::new(Mutex::new(Duration::ZERO));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let received = requests.clone();
+        let delay = response_delay.clone();
+        let max_in_flight_counter = max_in_flight.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let routes = routes.clone();
+            let received = received.clone();
+            let delay = delay.clone();
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight_counter.clone();
+            async move {
+                Ok::<_, Infallible>(hyper::service::service_fn(
+                    move |request: hyper::Request<hyper::Body>| {
+                        let routes = routes.clone();
+                        let received = received.clone();
+                        let delay = *delay.lock().unwrap();
+                        let in_flight = in_flight.clone();
+                        let max_in_flight = max_in_flight.clone();
+                        async move {
+                            let now_in_flight = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                            max_in_flight.fetch_max(now_in_flight, Ordering::SeqCst);
+                            tokio::time::sleep(delay).await;
+                            in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                            let route = format!("{} {}", request.method(), request.uri().path());
+                            let query = request.uri().query().map(str::to_owned);
+                            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                            received.lock().unwrap().push(ReceivedRequest {
+                                route: route.clone(),
+                                query: query.clone(),
+                                body: serde_json::from_slice(&body)
+                                    .unwrap_or(serde_json::Value::Null),
+                            });
+
+                            let (status, body) = query_pairs(query.as_deref())
+                                .into_iter()
+                                .find_map(|(name, value)| {
+                                    routes.get(&format!("{route}?{name}={value}"))
+                                })
+                                .or_else(|| routes.get(&route))
+                                .cloned()
+                                .unwrap_or((404, serde_json::json!({ "error": "not found" })));
+                            Ok::<_, Infallible>(
+                                hyper::Response::builder()
+                                    .status(status)
+                                    .header("content-type", "application/json")
+                                    .body(hyper::Body::from(body.to_string()))
+                                    .unwrap(),
+                            )
+                        }
+                    },
+                ))
+            }
+        });
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(make_service),
+        );
+
+        Self {
+            base_url: format!("http://{address}/"),
+            requests,
+            response_delay,
+            max_in_flight,
+        }
+    }
+
+    /// Wait this long before responding to each request, e.g. so that concurrent requests overlap
+    pub fn delay_responses(&self, delay: Duration) {
+        *self.response_delay.lock().unwrap() = delay;
+    }
+
+    /// The most requests that have been waiting for a response at the same time
+    pub fn max_concurrent_requests(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// e.g. "http://127.0.0.1:1234/"
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The requests received so far, in order
+    pub fn requests(&self) -> Vec<ReceivedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// The decoded name and value of each parameter in a request's query
+fn query_pairs(query: Option<&str>) -> Vec<(String, String)> {
+    let url = reqwest::Url::parse(&format!("http://localhost/?{}", query.unwrap_or_default()))
+        .expect("a request's query should make a valid url");
+
+    url.query_pairs()
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect()
+}
@@ -1,62 +1,161 @@
-use std::{collections::HashMap, future::Future, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 use aws::get_users;
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use opentelemetry_tracing_utils::{
+    sampling::{set_sampling_override, SamplingOverride},
+    trace_output_fmt::Truncated,
+};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tokio_util::sync::CancellationToken;
 use tracing::{
-    debug, debug_span, error, event, info_span, instrument, span, trace, Instrument, Level,
+    debug, debug_span, error, event, info, info_span, instrument, span, trace, warn, Instrument,
+    Level,
 };
 
 use crate::{
     aws::get_sync_records_for_partitions,
+    circuit_breaker::{CircuitBreaker, CircuitBreakerError},
+    clock::{Clock, SystemClock},
     cluster_management::{
-        establish_correct_sync_partition_locks, initialise_lease_and_node_membership,
+        current_partition_assignment, establish_correct_sync_partition_locks,
+        initialise_lease_and_node_membership, release_all_owned_locks, try_lock_user, unlock_user,
+        verify_sync_lock_ownership, PartitionAssignment,
     },
     etcd::EtcdClients,
+    secret::SecretString,
+    sync_actions::SyncSink,
+    sync_outcome::{record_sync_job_outcome, SyncJobOutcome, SyncJobResult},
 };
 
+pub mod admin;
 pub mod aws;
+pub mod circuit_breaker;
+pub mod clock;
 pub mod cluster_management;
 pub mod etcd;
+#[cfg(test)]
+mod fake_etcd;
+#[cfg(test)]
+mod fake_http;
+pub mod http_client;
+pub mod metrics;
 pub mod notion_api;
+pub mod rate_limit;
+pub mod retry;
+pub mod secret;
 pub mod settings;
 mod source_gcal;
 mod source_notion;
+pub mod sync_actions;
+pub mod sync_outcome;
+
+/// Why the node is shutting down, sent to every task through the shutdown channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// SIGTERM was received
+    Sigterm,
+    /// SIGINT was received
+    Sigint,
+    /// The work task finished by itself
+    WorkCompleted,
+    /// The work task returned an error or panicked
+    WorkFailed,
+}
+
+/// The receiving end of the shutdown channel. Holds `None` until shutdown is requested.
+pub type ShutdownReceiver = tokio::sync::watch::Receiver<Option<ShutdownReason>>;
+
+/// Wait for a termination signal or for the work task to finish, whichever comes first, and
+/// return why the node should shut down
+pub async fn wait_for_shutdown_reason(
+    sigterm: impl Future<Output = Option<()>>,
+    sigint: impl Future<Output = Option<()>>,
+    work: impl Future<Output = Result<Result<(), RunError>, tokio::task::JoinError>>,
+) -> ShutdownReason {
+    tokio::select! {
+        _ = sigterm => {
+            event!(Level::INFO, "sigterm received");
+            ShutdownReason::Sigterm
+        }
+        _ = sigint => {
+            event!(Level::INFO, "sigint received");
+            ShutdownReason::Sigint
+        }
+        // also quit if the work task has completed
+        result = work => {
+            match result {
+                Ok(Ok(())) => {
+                    event!(Level::INFO, "work finished");
+                    ShutdownReason::WorkCompleted
+                }
+                Ok(Err(error)) => {
+                    event!(Level::ERROR, ?error, "Work task failed: {}", error);
+                    ShutdownReason::WorkFailed
+                }
+                Err(error) => {
+                    event!(Level::ERROR, ?error, "Work task panicked");
+                    ShutdownReason::WorkFailed
+                }
+            }
+        }
+    }
+}
+
+/// Errors that stop [run] from completing
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error("Failed to set up logging")]
+    Logging(#[source] anyhow::Error),
+    #[error("Failed to load settings")]
+    Settings(#[from] figment::Error),
+    #[error("Invalid settings")]
+    InvalidSettings(#[from] settings::ValidationError),
+    #[error("Shutdown channel closed unexpectedly")]
+    ShutdownChannel(#[from] tokio::sync::watch::error::RecvError),
+    #[error("No etcd endpoint set")]
+    NoEtcdEndpoint,
+    #[error("Error while talking to etcd")]
+    EtcdConnect(#[source] anyhow::Error),
+    #[error("Sync task failed")]
+    Sync(#[from] tokio::task::JoinError),
+}
 
-pub async fn run(mut shutdown_rx: tokio::sync::watch::Receiver<()>) -> anyhow::Result<()> {
+pub async fn run(mut shutdown_rx: ShutdownReceiver) -> Result<(), RunError> {
     let init_stuff_that_can_be_shutdown_immediately = async move {
-        opentelemetry_tracing_utils::set_up_logging()?;
+        opentelemetry_tracing_utils::set_up_logging().map_err(RunError::Logging)?;
 
         // Env vars! -----------------------------------
         event!(Level::INFO, "Looking for settings.");
-        let settings_map = do_with_retries_sync(
-            settings::get_settings,
-            RetryConfig {
-                maximum_backoff: Duration::from_secs(300),
-                ..Default::default()
-            },
-        )
-        .await
-        .unwrap();
+        let settings_map = load_settings(settings::get_settings, settings_retry_config()).await?;
 
         event!(Level::INFO, "Settings successfully obtained.");
-        event!(Level::INFO, "{:#?}", settings_map);
-
-        dbg!(std::env::var("NO_OTLP")
-            .unwrap_or_else(|_| "0".to_owned())
-            .as_str());
+        log_startup_banner(
+            &settings_map,
+            opentelemetry_tracing_utils::LoggingSetupBuilder::new().otlp_output_enabled,
+        );
 
-        anyhow::Ok::<_>(settings_map)
+        Ok::<_, RunError>(settings_map)
     };
 
     let settings_map = tokio::select! {
         result = init_stuff_that_can_be_shutdown_immediately => {
-            Some(result.unwrap())
+            Some(result?)
         },
         s = shutdown_rx.changed() => {
-            s.expect("receiver should work");
-            event!(Level::INFO, "rx shutdown channel changed");
+            s?;
+            let reason = *shutdown_rx.borrow();
+            event!(Level::INFO, ?reason, "shutdown during startup");
             None
         }
     };
@@ -64,12 +163,24 @@ pub async fn run(mut shutdown_rx: tokio::sync::watch::Receiver<()>) -> anyhow::R
     if let Some(settings_map) = settings_map {
         let span = span!(Level::TRACE, "talk to etcd");
 
-        let node_name = settings_map.node_name;
+        let settings_map = Arc::new(settings_map);
+        let node_name = settings_map.node_name.clone();
 
         let result_of_work = async {
             // This is correct! If we yield here, the span will be exited,
             // and re-entered when we resume.
-            if settings_map.etcd_url.is_some() {
+            if !settings_map.clustered {
+                event!(
+                    Level::INFO,
+                    "Not clustered, so syncing all partitions without etcd"
+                );
+
+                Ok(spawn_single_node_sync_pipeline(
+                    node_name.clone(),
+                    settings_map.clone(),
+                    shutdown_rx.clone(),
+                ))
+            } else if let Some(etcd_url) = &settings_map.etcd_url {
                 event!(Level::INFO, "About to try talking to etcd!");
 
                 event!(Level::INFO, "Clustered setting: {}", settings_map.clustered);
@@ -77,8 +188,9 @@ pub async fn run(mut shutdown_rx: tokio::sync::watch::Receiver<()>) -> anyhow::R
                 let shutdown_receiver = shutdown_rx.clone();
 
                 let result = do_some_stuff_with_etcd_and_init(
-                    &settings_map.etcd_url.expect("should be valid string"),
+                    etcd_url,
                     node_name.as_str(),
+                    settings_map.clone(),
                     shutdown_receiver,
                 )
                 .await;
@@ -91,10 +203,10 @@ pub async fn run(mut shutdown_rx: tokio::sync::watch::Receiver<()>) -> anyhow::R
                         event!(Level::ERROR, "Error while talking to etcd. {:#?}", error)
                     }
                 }
-                result.ok()
+                result.map_err(RunError::EtcdConnect)
             } else {
                 event!(Level::WARN, "No etcd endpoint set.");
-                None
+                Err(RunError::NoEtcdEndpoint)
             }
         }
         // instrument the async block with the span...
@@ -102,24 +214,9 @@ pub async fn run(mut shutdown_rx: tokio::sync::watch::Receiver<()>) -> anyhow::R
         // ...and await it.
         .await;
 
-        let mut rx2 = shutdown_rx.clone();
-        tokio::spawn(async move {
-            tokio::select! {
-                _ = async move {
-                    loop {
-                        event!(Level::INFO, "a loop");
-                        tokio::time::sleep(Duration::from_secs(10)).await;
-                    }
-                }
-                    .instrument(span!(Level::TRACE, "loop span")) => {},
-                _ = rx2.changed() => {
-                    event!(Level::INFO, "rx shutdown channel changed");
-                }
-            }
-        });
+        spawn_debug_loop(settings_map.debug_loop, shutdown_rx.clone());
 
-        let result_of_work_join_handle =
-            result_of_work.expect("Should have a join handle (check that etcd endpoint is set)");
+        let result_of_work_join_handle = result_of_work?;
 
         result_of_work_join_handle.await?;
     }
@@ -127,60 +224,450 @@ pub async fn run(mut shutdown_rx: tokio::sync::watch::Receiver<()>) -> anyhow::R
     Ok(())
 }
 
+/// Log the effective configuration as a single structured event, so that it is easy to check how a
+/// node is configured from its first log line. Secrets are left out.
+fn log_startup_banner(settings: &settings::Settings, otlp_enabled: bool) {
+    let role = if settings.clustered {
+        "clustered"
+    } else {
+        "single_node"
+    };
+    let sync_partitions = match &settings.partition_allowlist {
+        Some(partition_allowlist) => format!("{partition_allowlist:?}"),
+        None => "all".to_owned(),
+    };
+
+    event!(
+        Level::INFO,
+        node_name = settings.node_name.as_str(),
+        clustered = settings.clustered,
+        role,
+        otlp_enabled,
+        sync_interval = ?SYNC_INTERVAL,
+        sync_partitions = sync_partitions.as_str(),
+        max_partitions_per_node = settings.max_partitions_per_node,
+        max_concurrent_sync_jobs = settings.max_concurrent_sync_jobs,
+        admin_api_address = settings.admin_api_address.map(|address| address.to_string()),
+        sync_statuses = ?settings.sync_statuses,
+        etcd_url = settings.etcd_url.as_deref(),
+        etcd_username = settings.etcd_username.as_deref(),
+        aws_role_arn = settings.aws_role_arn.as_deref(),
+        conflict_strategy = ?settings.conflict_strategy,
+        missing_notion_data = ?settings.missing_notion_data,
+        notion_requests_per_second = settings.notion_requests_per_second,
+        google_oauth_client_id = settings.google_oauth_client_id.as_str(),
+        "starting with effective configuration"
+    );
+}
+
+/// Spawn a task that logs "a loop" every 10 seconds until shutdown, if `enabled`. Nothing relies
+/// on this task, it is just for demos.
+fn spawn_debug_loop(
+    enabled: bool,
+    mut shutdown_rx: ShutdownReceiver,
+) -> Option<tokio::task::JoinHandle<()>> {
+    if !enabled {
+        return None;
+    }
+
+    let loop_span = span!(Level::TRACE, "loop span");
+    set_sampling_override(&loop_span, SamplingOverride::Never);
+
+    Some(tokio::spawn(async move {
+        tokio::select! {
+            _ = async move {
+                loop {
+                    event!(Level::INFO, "a loop");
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+            }
+                .instrument(loop_span) => {},
+            _ = shutdown_rx.changed() => {
+                let reason = *shutdown_rx.borrow();
+                event!(Level::INFO, ?reason, "shutdown received, ending debug loop");
+            }
+        }
+    }))
+}
+
+/// Errors building the tokio runtime, see [build_runtime]
+#[derive(Error, Debug)]
+pub enum BuildRuntimeError {
+    #[error("Invalid runtime settings")]
+    InvalidSettings(#[from] settings::ValidationError),
+    #[error("Failed to build the tokio runtime")]
+    Build(#[from] std::io::Error),
+}
+
+/// Build the multi-threaded tokio runtime, using the defaults for anything not set
+pub fn build_runtime(
+    runtime_settings: &settings::RuntimeSettings,
+) -> Result<tokio::runtime::Runtime, BuildRuntimeError> {
+    // the builder panics on 0 threads
+    runtime_settings.validate()?;
+
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+
+    if let Some(worker_threads) = runtime_settings.worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    if let Some(max_blocking_threads) = runtime_settings.max_blocking_threads {
+        builder.max_blocking_threads(max_blocking_threads);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// How many times to try loading settings before giving up. With the backoff this is around a
+/// quarter of an hour, long enough for e.g. a config map to be fixed without restarting.
+const SETTINGS_MAX_ATTEMPTS: u32 = 20;
+
+fn settings_retry_config() -> RetryConfig {
+    RetryConfig {
+        maximum_backoff: Duration::from_secs(300),
+        maximum_n_tries: Some(SETTINGS_MAX_ATTEMPTS),
+        ..Default::default()
+    }
+}
+
+/// Load and validate settings, retrying if they can't be loaded. Invalid settings aren't retried.
+async fn load_settings<F>(
+    get_settings: F,
+    config: RetryConfig,
+) -> Result<settings::Settings, RunError>
+where
+    F: Fn() -> Result<settings::Settings, figment::Error>,
+{
+    let settings_map = do_with_retries_sync(get_settings, config).await?;
+
+    settings_map.validate()?;
+
+    Ok(settings_map)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GoogleResponse {
     pub items: Vec<serde_json::Value>,
     pub kind: String,
     #[serde(rename = "nextPageToken")]
     pub next_page_token: Option<String>,
+    /// For fetching only the events that change after this response. Only on the last page.
+    #[serde(rename = "nextSyncToken")]
+    pub next_sync_token: Option<String>,
     pub summary: String,
     #[serde(rename = "timeZone")]
     pub time_zone: String,
     pub updated: String,
 }
+impl GoogleResponse {
+    /// Deserialize the raw `items` into typed events. Fields that aren't modelled by
+    /// [GoogleCalendarEvent] are ignored, but are still available in `items`.
+    pub fn events(&self) -> Result<Vec<GoogleCalendarEvent>, serde_json::Error> {
+        self.items
+            .iter()
+            .map(GoogleCalendarEvent::deserialize)
+            .collect()
+    }
+}
+
+/// A single Google Calendar event. Only the fields used for syncing are modelled, which are the
+/// ones requested by [GOOGLE_EVENTS_FIELDS].
+///
+/// See <https://developers.google.com/calendar/api/v3/reference/events#resource>
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GoogleCalendarEvent {
+    pub id: String,
+    /// Cancelled events don't always have a summary
+    pub summary: Option<String>,
+    /// Cancelled events don't include start and end times
+    pub start: Option<EventDateTime>,
+    pub end: Option<EventDateTime>,
+    pub updated: Option<String>,
+    pub status: Option<String>,
+    /// Properties set by this app, e.g. the linked Notion page
+    #[serde(
+        rename = "extendedProperties",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub extended_properties: Option<ExtendedProperties>,
+}
+impl GoogleCalendarEvent {
+    /// The id of the Notion page that the event is synced with, if it is linked to one
+    pub fn notion_page_id(&self) -> Option<&str> {
+        self.extended_properties
+            .as_ref()?
+            .private
+            .get(NOTION_PAGE_ID_PROPERTY)
+            .map(String::as_str)
+    }
+}
+
+/// The key in an event's private [ExtendedProperties] holding the id of its Notion page
+pub const NOTION_PAGE_ID_PROPERTY: &str = "notionPageId";
+
+/// Key-value pairs attached to a [GoogleCalendarEvent]. Private properties are only visible to
+/// this app, see <https://developers.google.com/calendar/api/guides/extended-properties>
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExtendedProperties {
+    #[serde(default)]
+    pub private: HashMap<String, String>,
+}
+
+/// The start or end time of a [GoogleCalendarEvent]
+///
+/// All-day events only have a `date`, while timed events have a `dateTime` RFC3339 timestamp.
+/// The timestamp always includes an offset, so the `timeZone` field (and the calendar's
+/// [GoogleResponse::time_zone]) isn't needed to work out the instant of a timed event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventDateTime {
+    /// An all-day event, with no time component
+    Date(NaiveDate),
+    /// A timed event
+    DateTime(DateTime<FixedOffset>),
+}
+impl EventDateTime {
+    pub fn is_all_day(&self) -> bool {
+        matches!(self, Self::Date(_))
+    }
+
+    /// The calendar date of this time. For timed events this is the date in the event's own
+    /// offset, which is what the user sees in Google Calendar.
+    pub fn date_naive(&self) -> NaiveDate {
+        match self {
+            Self::Date(date) => *date,
+            Self::DateTime(date_time) => date_time.date_naive(),
+        }
+    }
+
+    /// Check whether two times refer to the same point in a calendar. An all-day time can only
+    /// match another all-day time, as changing an event between all-day and timed is a real
+    /// change that needs syncing. Timed events are compared as instants, so the same time in
+    /// different offsets is equal.
+    pub fn same_as(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Date(a), Self::Date(b)) => a == b,
+            (Self::DateTime(a), Self::DateTime(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// The raw JSON shape of an [EventDateTime]
+#[derive(Serialize, Deserialize, Debug)]
+struct RawEventDateTime {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date: Option<String>,
+    #[serde(rename = "dateTime", skip_serializing_if = "Option::is_none")]
+    date_time: Option<String>,
+    #[serde(rename = "timeZone", skip_serializing_if = "Option::is_none")]
+    time_zone: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for EventDateTime {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let raw = RawEventDateTime::deserialize(deserializer)?;
+
+        match (raw.date_time, raw.date) {
+            (Some(date_time), _) => DateTime::parse_from_rfc3339(&date_time)
+                .map(Self::DateTime)
+                .map_err(D::Error::custom),
+            (None, Some(date)) => NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                .map(Self::Date)
+                .map_err(D::Error::custom),
+            (None, None) => Err(D::Error::custom(
+                "event time should have either a date or a dateTime",
+            )),
+        }
+    }
+}
+
+impl Serialize for EventDateTime {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let raw = match self {
+            Self::Date(date) => RawEventDateTime {
+                date: Some(date.format("%Y-%m-%d").to_string()),
+                date_time: None,
+                time_zone: None,
+            },
+            Self::DateTime(date_time) => RawEventDateTime {
+                date: None,
+                date_time: Some(date_time.to_rfc3339()),
+                time_zone: None,
+            },
+        };
+
+        raw.serialize(serializer)
+    }
+}
+
+/// Access tokens are refreshed this long before they expire, so that they don't expire part way
+/// through a request
+pub const ACCESS_TOKEN_EXPIRY_SKEW: Duration = Duration::from_secs(10);
 
 #[derive(Debug)]
 pub struct GoogleToken {
-    pub refresh_token: String,
+    pub refresh_token: SecretString,
     pub access_token: Option<GoogleAccessToken>,
+    clock: Arc<dyn Clock>,
+    /// Used to refresh the access token. Share one client between tokens (see
+    /// [http_client::build_client]), as each client has its own connection pool.
+    client: reqwest::Client,
+    /// Where the access token is refreshed, [GOOGLE_OAUTH_TOKEN_URL] unless changed for tests
+    token_url: String,
 }
 
+pub const GOOGLE_OAUTH_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+
 #[derive(Debug)]
 pub struct GoogleAccessToken {
-    pub access_token: String,
+    pub access_token: SecretString,
     pub expiry_time: std::time::SystemTime,
 }
 
+/// Error refreshing a Google access token, see [GoogleToken::refresh_token]
+#[derive(Error, Debug)]
+pub enum GoogleTokenError {
+    /// The user has revoked access, or the refresh token has expired, so it will never work again
+    #[error("Google refresh token has been revoked or has expired")]
+    InvalidGrant,
+    /// Google rejected the refresh for another reason, e.g. `invalid_client`
+    #[error("Google rejected the token refresh: {0}")]
+    Rejected(String),
+    /// The token doesn't allow access to calendar events, e.g. because the OAuth app's scopes
+    /// were changed after the user connected their calendar
+    #[error("Google access token is missing the calendar scope, it only has {0:?}")]
+    InsufficientScope(String),
+    #[error("Error requesting a Google access token")]
+    Request(#[from] reqwest::Error),
+}
+
+impl GoogleTokenError {
+    /// Whether refreshing the token again might work, see [retry::is_retryable]
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Request(error) => retry::is_retryable(error),
+            Self::InvalidGrant | Self::Rejected(_) | Self::InsufficientScope(_) => false,
+        }
+    }
+}
+
+/// The body of an error response from Google's OAuth token endpoint
+#[derive(Deserialize, Debug)]
+struct GoogleOAuthErrorResponse {
+    /// e.g. "invalid_grant"
+    error: String,
+}
+
+/// Work out why Google rejected a token refresh from the error response body, if it says
+fn google_token_error(body: &[u8]) -> Option<GoogleTokenError> {
+    let response: GoogleOAuthErrorResponse = serde_json::from_slice(body).ok()?;
+
+    Some(match response.error.as_str() {
+        "invalid_grant" => GoogleTokenError::InvalidGrant,
+        _ => GoogleTokenError::Rejected(response.error),
+    })
+}
+
+/// Scopes that allow reading and writing calendar events. An access token needs one of them.
+const GOOGLE_CALENDAR_SCOPES: [&str; 2] = [
+    "https://www.googleapis.com/auth/calendar",
+    "https://www.googleapis.com/auth/calendar.events",
+];
+
+/// Check that a token's scopes (space separated, as in a token response) include calendar access,
+/// so that a narrower token fails clearly here rather than with a 403 part way through a sync
+fn check_calendar_scope(scope: &str) -> Result<(), GoogleTokenError> {
+    if scope
+        .split_whitespace()
+        .any(|scope| GOOGLE_CALENDAR_SCOPES.contains(&scope))
+    {
+        Ok(())
+    } else {
+        Err(GoogleTokenError::InsufficientScope(scope.to_owned()))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct GoogleRefreshTokenRequestResponse {
-    access_token: String, // e.g. "1/fFAasGRNJTz70BzhT3Zg"
+    access_token: SecretString, // e.g. "1/fFAasGRNJTz70BzhT3Zg"
     /// in seconds
     expires_in: u64, // e.g. 3920
-    scope: String,        // e.g. "https://www.googleapis.com/auth/drive.metadata.readonly"
-    token_type: String,   // always "Bearer"
+    scope: String,              // e.g. "https://www.googleapis.com/auth/drive.metadata.readonly"
+    token_type: String,         // always "Bearer"
 }
 
 impl GoogleToken {
-    pub fn new(refresh_token: &str) -> Self {
+    pub fn new(refresh_token: &str, client: reqwest::Client) -> Self {
+        Self::with_clock(refresh_token, client, Arc::new(SystemClock))
+    }
+
+    /// Use a different clock for working out when the access token expires, e.g. a
+    /// [clock::MockClock] in tests
+    pub fn with_clock(refresh_token: &str, client: reqwest::Client, clock: Arc<dyn Clock>) -> Self {
         Self {
-            refresh_token: refresh_token.to_owned(),
+            refresh_token: refresh_token.into(),
             access_token: None,
+            clock,
+            client,
+            token_url: GOOGLE_OAUTH_TOKEN_URL.to_owned(),
         }
     }
 
-    /// Refresh the access token
+    /// Refresh the access token somewhere other than Google, e.g. a fake token endpoint in tests
+    pub fn with_token_url(mut self, token_url: impl Into<String>) -> Self {
+        self.token_url = token_url.into();
+        self
+    }
+
+    /// Whether there is no access token, or it expires within [ACCESS_TOKEN_EXPIRY_SKEW]
+    pub fn needs_refresh(&self) -> bool {
+        self.access_token.as_ref().is_none_or(|access_token| {
+            self.clock.now() + ACCESS_TOKEN_EXPIRY_SKEW >= access_token.expiry_time
+        })
+    }
+
+    /// Refresh the access token, keeping the new one
     ///
     /// # Errors
     ///
-    /// This function can return an error for several reasons: the request to google fails, the
-    /// refresh token is invalid, or the response from google does not match the serde struct.
-    ///
-    /// TODO: return something different for some of these errors
+    /// See [GoogleToken::refresh_access_token]
     pub async fn refresh_token(
         &mut self,
         google_oauth_client_id: &str,
         google_oauth_client_secret: &str,
-    ) -> Result<&Self, reqwest::Error> {
+    ) -> Result<&Self, GoogleTokenError> {
+        let access_token = self
+            .refresh_access_token(google_oauth_client_id, google_oauth_client_secret)
+            .await?;
+        self.access_token = Some(access_token);
+
+        Ok(self)
+    }
+
+    /// Get a new access token using the refresh token
+    ///
+    /// # Errors
+    ///
+    /// Returns [GoogleTokenError::InvalidGrant] if the refresh token has been revoked,
+    /// [GoogleTokenError::InsufficientScope] if the new access token can't be used for calendar
+    /// events, and
+    /// [GoogleTokenError::Request] if the request to google fails or the response from google
+    /// does not match the serde struct.
+    pub async fn refresh_access_token(
+        &self,
+        google_oauth_client_id: &str,
+        google_oauth_client_secret: &str,
+    ) -> Result<GoogleAccessToken, GoogleTokenError> {
         // POST /token HTTP/1.1
         // Host: oauth2.googleapis.com
         // Content-Type: application/x-www-form-urlencoded
@@ -189,31 +676,44 @@ impl GoogleToken {
         // client_secret=your_client_secret&
         // refresh_token=refresh_token&
         // grant_type=refresh_token
-        let client = reqwest::Client::builder().build()?;
         let params = [
             ("client_id", google_oauth_client_id),
             ("client_secret", google_oauth_client_secret),
-            ("refresh_token", &self.refresh_token),
+            ("refresh_token", self.refresh_token.expose_secret()),
             ("grant_type", "refresh_token"),
         ];
-        let response_json = client
-            .post("https://oauth2.googleapis.com/token")
+        let response = self
+            .client
+            .post(&self.token_url)
             .form(&params)
             .send()
-            .await
-            .map(|response| response.json::<GoogleRefreshTokenRequestResponse>());
+            .await?;
+
+        let status_error = response.error_for_status_ref().err();
+        if let Some(status_error) = status_error {
+            // Google explains why a refresh was rejected in the body of a 400 or 401 response
+            if matches!(
+                status_error.status(),
+                Some(reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::UNAUTHORIZED)
+            ) {
+                let body = response.bytes().await?;
+                if let Some(error) = google_token_error(&body) {
+                    return Err(error);
+                }
+            }
+            return Err(status_error.into());
+        }
 
-        let response_json = response_json?.await?;
+        let response_json = response.json::<GoogleRefreshTokenRequestResponse>().await?;
+        check_calendar_scope(&response_json.scope)?;
 
         let expires_in = std::time::Duration::from_secs(response_json.expires_in); // TODO: expiry time
-        let expiry_time = std::time::SystemTime::now() + expires_in;
+        let expiry_time = self.clock.now() + expires_in;
 
-        self.access_token = Some(GoogleAccessToken {
+        Ok(GoogleAccessToken {
             access_token: response_json.access_token,
             expiry_time,
-        });
-
-        Ok(self)
+        })
     }
 
     pub async fn get(
@@ -221,14 +721,7 @@ impl GoogleToken {
         google_oauth_client_id: &str,
         google_oauth_client_secret: &str,
     ) -> String {
-        let mut expired = false;
-        if let Some(ref access_token) = self.access_token {
-            if access_token.expiry_time <= std::time::SystemTime::now() {
-                expired = true
-            }
-        } else {
-            expired = true
-        };
+        let expired = self.needs_refresh();
 
         let _refresh_response = if expired {
             println!("Refreshing Google Calendar user access token");
@@ -244,6 +737,7 @@ impl GoogleToken {
             .as_ref()
             .expect("Access token should exist")
             .access_token
+            .expose_secret()
             .to_owned()
     }
 }
@@ -260,24 +754,246 @@ pub fn filter_data_by_hardcoded_user_id(users: &[aws::UserRecord]) -> Option<&aw
     filtered_user
 }
 
+/// Number of events to request from Google Calendar at once
+pub const DEFAULT_GOOGLE_EVENTS_MAX_RESULTS: u32 = 4;
+
+/// A partial response projection (see
+/// <https://developers.google.com/calendar/api/guides/performance#partial-response>) with only
+/// the fields in [GoogleResponse] and [GoogleCalendarEvent], to keep responses small
+pub const GOOGLE_EVENTS_FIELDS: &str = "kind,summary,updated,timeZone,nextPageToken,\
+     nextSyncToken,items(id,summary,start,end,updated,status,extendedProperties)";
+
+/// Get events from one of the user's Google Calendars. `fields` is a partial response projection,
+/// e.g. [GOOGLE_EVENTS_FIELDS]. The full event objects are returned if it is `None`.
 pub async fn get_some_data_from_google_calendar(
+    google_client: &reqwest::Client,
+    google_api: &GoogleCalendarApi,
     bearer_auth_token: &str,
+    calendar_id: &str,
+    max_results: u32,
+    fields: Option<&str>,
+    sync_token: Option<&str>,
 ) -> Result<GoogleResponse, reqwest::Error> {
-    // client for google requests
-    let google_client = reqwest::Client::builder().build()?;
-
     // Do a request using the google token
-    // TODO: make this fetch the correct calendar, rather than the primary one
-    let res = google_client
-        .get("https://www.googleapis.com/calendar/v3/calendars/primary/events?maxResults=4")
+    let res = google_calendar_events_request(
+        google_client,
+        google_api,
+        bearer_auth_token,
+        calendar_id,
+        max_results,
+        fields,
+        sync_token,
+    )
+    .send()
+    .await?
+    .error_for_status()?
+    .json::<GoogleResponse>()
+    .await?;
+    dbg!(
+        "from the google response:\n{:#?}",
+        res.items.first().map(|item| &item["summary"])
+    );
+
+    Ok(res)
+}
+
+fn google_calendar_events_request(
+    google_client: &reqwest::Client,
+    google_api: &GoogleCalendarApi,
+    bearer_auth_token: &str,
+    calendar_id: &str,
+    max_results: u32,
+    fields: Option<&str>,
+    sync_token: Option<&str>,
+) -> reqwest::RequestBuilder {
+    let request = google_client
+        .get(google_api.calendar_url(calendar_id, &["events"]))
+        .query(&[("maxResults", max_results)])
+        .bearer_auth(bearer_auth_token);
+
+    let request = match fields {
+        Some(fields) => request.query(&[("fields", fields)]),
+        None => request,
+    };
+
+    // Only the events changed since the previous sync, otherwise a full fetch
+    match sync_token {
+        Some(sync_token) => request.query(&[("syncToken", sync_token)]),
+        None => request,
+    }
+}
+
+const GOOGLE_CALENDAR_API_BASE_URL: &str = "https://www.googleapis.com/calendar/v3/";
+
+/// Where Google Calendar API requests are sent
+#[derive(Debug, Clone)]
+pub struct GoogleCalendarApi {
+    base_url: reqwest::Url,
+}
+impl Default for GoogleCalendarApi {
+    fn default() -> Self {
+        Self::with_base_url(
+            reqwest::Url::parse(GOOGLE_CALENDAR_API_BASE_URL)
+                .expect("hard-coded base url should be valid"),
+        )
+    }
+}
+impl GoogleCalendarApi {
+    /// Send requests somewhere other than Google, e.g. to a fake API in tests. `base_url` is the
+    /// equivalent of "https://www.googleapis.com/calendar/v3/".
+    pub fn with_base_url(base_url: reqwest::Url) -> Self {
+        Self { base_url }
+    }
+
+    /// The URL of `path` within the API, e.g. `["channels", "stop"]`
+    fn url(&self, path: &[&str]) -> reqwest::Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("base url should have a path")
+            .pop_if_empty()
+            .extend(path);
+
+        url
+    }
+
+    /// The URL of `path` within a calendar, e.g. `["events"]` for its events
+    fn calendar_url(&self, calendar_id: &str, path: &[&str]) -> reqwest::Url {
+        // calendar ids can contain characters that need escaping, e.g. `#` in holiday calendars
+        self.url(&[&["calendars", calendar_id], path].concat())
+    }
+}
+
+/// A Google Calendar push notification channel, see
+/// <https://developers.google.com/calendar/api/guides/push>
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchChannel {
+    /// The id given when the channel was created
+    pub id: String,
+    /// Identifies the watched calendar's events. Needed to stop the channel.
+    pub resource_id: String,
+    /// When Google will stop sending notifications, in milliseconds since the Unix epoch
+    pub expiration: Option<String>,
+}
+
+/// Body for a watch request, see <https://developers.google.com/calendar/api/v3/reference/events/watch>
+#[derive(Serialize, Debug)]
+struct WatchRequest<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    channel_type: &'static str,
+    address: &'a str,
+}
+
+/// Body for a stop request, see <https://developers.google.com/calendar/api/v3/reference/channels/stop>
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StopWatchRequest<'a> {
+    id: &'a str,
+    resource_id: &'a str,
+}
+
+/// Ask Google to send a notification to `webhook_url` (which must be HTTPS) whenever an event in
+/// the calendar changes, so that it can be synced straight away rather than on the next poll.
+/// `channel_id` must be unique, e.g. a UUID.
+pub async fn watch_calendar(
+    google_client: &reqwest::Client,
+    google_api: &GoogleCalendarApi,
+    bearer_auth_token: &str,
+    calendar_id: &str,
+    webhook_url: &str,
+    channel_id: &str,
+) -> Result<WatchChannel, reqwest::Error> {
+    google_watch_request(
+        google_client,
+        google_api,
+        bearer_auth_token,
+        calendar_id,
+        webhook_url,
+        channel_id,
+    )
+    .send()
+    .await?
+    .error_for_status()?
+    .json()
+    .await
+}
+
+/// Stop the notifications from a channel created by [watch_calendar]
+pub async fn stop_watch(
+    google_client: &reqwest::Client,
+    google_api: &GoogleCalendarApi,
+    bearer_auth_token: &str,
+    channel_id: &str,
+    resource_id: &str,
+) -> Result<(), reqwest::Error> {
+    google_stop_watch_request(
+        google_client,
+        google_api,
+        bearer_auth_token,
+        channel_id,
+        resource_id,
+    )
+    .send()
+    .await?
+    .error_for_status()?;
+
+    Ok(())
+}
+
+/// Change some of an event's fields, leaving the rest as they are, see
+/// <https://developers.google.com/calendar/api/v3/reference/events/patch>
+pub async fn patch_google_event(
+    google_client: &reqwest::Client,
+    google_api: &GoogleCalendarApi,
+    bearer_auth_token: &str,
+    calendar_id: &str,
+    event_id: &str,
+    fields: &serde_json::Value,
+) -> Result<(), reqwest::Error> {
+    google_client
+        .patch(google_api.calendar_url(calendar_id, &["events", event_id]))
         .bearer_auth(bearer_auth_token)
+        .json(fields)
         .send()
         .await?
-        .json::<GoogleResponse>()
-        .await?;
-    dbg!("from the google response:\n{:#?}", &res.items[0]["summary"]);
+        .error_for_status()?;
 
-    Ok(res)
+    Ok(())
+}
+
+fn google_watch_request(
+    google_client: &reqwest::Client,
+    google_api: &GoogleCalendarApi,
+    bearer_auth_token: &str,
+    calendar_id: &str,
+    webhook_url: &str,
+    channel_id: &str,
+) -> reqwest::RequestBuilder {
+    google_client
+        .post(google_api.calendar_url(calendar_id, &["events", "watch"]))
+        .bearer_auth(bearer_auth_token)
+        .json(&WatchRequest {
+            id: channel_id,
+            channel_type: "web_hook",
+            address: webhook_url,
+        })
+}
+
+fn google_stop_watch_request(
+    google_client: &reqwest::Client,
+    google_api: &GoogleCalendarApi,
+    bearer_auth_token: &str,
+    channel_id: &str,
+    resource_id: &str,
+) -> reqwest::RequestBuilder {
+    google_client
+        .post(google_api.url(&["channels", "stop"]))
+        .bearer_auth(bearer_auth_token)
+        .json(&StopWatchRequest {
+            id: channel_id,
+            resource_id,
+        })
 }
 
 pub async fn do_with_retries_infinite<A, Fut, E, F: Fn() -> Fut>(f: F) -> A
@@ -314,6 +1030,10 @@ struct RetryConfig {
     maximum_backoff: Duration,
     maximum_n_tries: Option<u32>,
     initial_duration: Duration,
+    /// Used to wait between tries
+    clock: Arc<dyn Clock>,
+    /// Shared with other requests, which may have used up the retries already
+    budget: Option<Arc<retry::RetryBudget>>,
 }
 impl Default for RetryConfig {
     fn default() -> Self {
@@ -321,15 +1041,55 @@ impl Default for RetryConfig {
             maximum_backoff: Duration::from_secs(30),
             maximum_n_tries: None,
             initial_duration: Duration::from_millis(5),
+            clock: Arc::new(SystemClock),
+            budget: None,
+        }
+    }
+}
+impl RetryConfig {
+    /// Whether another retry is allowed by the shared budget, using it up if so
+    fn spend_budget(&self) -> bool {
+        let allowed = self.budget.as_ref().is_none_or(|budget| budget.try_spend());
+        if !allowed {
+            debug!("retry budget used up, not retrying");
         }
+        allowed
+    }
+}
+
+/// Retries for a request to Notion or Google, within a single sync job. All the requests in the
+/// job share `budget`.
+fn external_request_retry_config(budget: &Arc<retry::RetryBudget>) -> RetryConfig {
+    RetryConfig {
+        maximum_backoff: Duration::from_secs(5),
+        maximum_n_tries: Some(3),
+        initial_duration: Duration::from_millis(200),
+        budget: Some(budget.clone()),
+        ..Default::default()
     }
 }
 
-#[instrument(err(Debug), skip(f), level = "trace")]
 async fn do_with_retries<A, Fut, E, F: Fn() -> Fut>(f: F, config: RetryConfig) -> Result<A, E>
 where
     E: std::error::Error,
     Fut: Future<Output = Result<A, E>>,
+{
+    do_with_retries_while(f, config, |_| true).await
+}
+
+/// Like [do_with_retries], but gives up straight away on errors that `is_retryable` says aren't
+/// worth retrying (see [retry::is_retryable])
+#[instrument(err(Debug), skip(f, is_retryable), level = "trace")]
+async fn do_with_retries_while<A, Fut, E, F, R>(
+    f: F,
+    config: RetryConfig,
+    is_retryable: R,
+) -> Result<A, E>
+where
+    E: std::error::Error,
+    Fut: Future<Output = Result<A, E>>,
+    F: Fn() -> Fut,
+    R: Fn(&E) -> bool,
 {
     let mut n_tries = 0;
     let mut retry_wait_duration = config.initial_duration;
@@ -343,13 +1103,27 @@ where
 
                 trace!(n_tries, "{}", error);
 
+                if !is_retryable(&error) {
+                    break Err(error);
+                }
+
                 if let Some(max) = config.maximum_n_tries {
                     if n_tries == max {
                         break Err(error);
                     };
                 }
 
-                tokio::time::sleep(retry_wait_duration).await;
+                if !config.spend_budget() {
+                    break Err(error);
+                }
+
+                debug!(
+                    attempt = n_tries,
+                    %error,
+                    wait_ms = retry_wait_duration.as_millis() as u64,
+                    "retry scheduled"
+                );
+                config.clock.sleep(retry_wait_duration).await;
                 if retry_wait_duration < config.maximum_backoff {
                     retry_wait_duration *= 2;
                 };
@@ -386,7 +1160,17 @@ where
                     };
                 }
 
-                tokio::time::sleep(retry_wait_duration).await;
+                if !config.spend_budget() {
+                    break Err(error);
+                }
+
+                debug!(
+                    attempt = n_tries,
+                    %error,
+                    wait_ms = retry_wait_duration.as_millis() as u64,
+                    "retry scheduled"
+                );
+                config.clock.sleep(retry_wait_duration).await;
                 if retry_wait_duration < config.maximum_backoff {
                     retry_wait_duration *= 2;
                 };
@@ -398,6 +1182,46 @@ where
     }
 }
 
+/// Exponential backoff for a loop that retries forever: the wait starts at `initial_duration` and
+/// doubles after each consecutive failure, but (unlike [do_with_retries]) never goes over
+/// `maximum_backoff`. Unlike a single retried call, the loop can succeed and later fail again, so
+/// the backoff can be reset.
+#[derive(Debug)]
+struct Backoff {
+    config: RetryConfig,
+    next_wait: Duration,
+}
+
+impl Backoff {
+    fn new(config: RetryConfig) -> Self {
+        Self {
+            next_wait: config.initial_duration,
+            config,
+        }
+    }
+
+    /// How long to wait after a failure. Each consecutive failure waits longer, up to the
+    /// maximum backoff.
+    fn next_wait(&mut self) -> Duration {
+        let wait = self.next_wait;
+        if self.next_wait < self.config.maximum_backoff {
+            self.next_wait = (self.next_wait * 2).min(self.config.maximum_backoff);
+        }
+        wait
+    }
+
+    /// Go back to the initial wait, after a success
+    fn reset(&mut self) {
+        self.next_wait = self.config.initial_duration;
+    }
+
+    async fn wait(&mut self) {
+        let wait = self.next_wait();
+        debug!(wait_ms = wait.as_millis() as u64, "backing off");
+        self.config.clock.sleep(wait).await;
+    }
+}
+
 #[derive(Debug)]
 pub struct InitAndEtcdTaskReturn {
     pub etcd_clients: EtcdClients,
@@ -409,68 +1233,189 @@ pub struct InitAndEtcdTaskReturn {
 pub async fn do_some_stuff_with_etcd_and_init(
     etcd_endpoint: &str,
     node_name: &str,
-    mut shutdown_receiver: tokio::sync::watch::Receiver<()>,
+    settings: Arc<settings::Settings>,
+    mut shutdown_receiver: ShutdownReceiver,
 ) -> anyhow::Result<tokio::task::JoinHandle<()>> {
     event!(Level::INFO, "Initialising etcd grpc clients");
     let etcd_clients = tokio::select! {
-        x = do_with_retries_infinite(|| EtcdClients::connect(etcd_endpoint.to_owned())) => {Some(x)},
-        _ = shutdown_receiver.changed() => {None}
+        x = connect_to_etcd(
+            etcd_endpoint,
+            settings.etcd_connect_max_attempts,
+            settings.etcd_credentials(),
+            settings.etcd_keep_alive(),
+        ) => {Some(x)},
+        _ = shutdown_receiver.changed() => {
+            let reason = *shutdown_receiver.borrow();
+            event!(Level::INFO, ?reason, "shutdown received while connecting to etcd");
+            None
+        }
     };
 
-    let etcd_clients = etcd_clients.ok_or(anyhow!("Shutdown, so no etcd clients available"))?;
+    let etcd_clients = etcd_clients.ok_or(anyhow!("Shutdown, so no etcd clients available"))??;
 
     let result_of_tokio_task = tokio::spawn(manage_cluster_node_membership_and_start_work(
         etcd_clients,
         node_name.to_owned(),
+        settings,
         shutdown_receiver,
     ));
 
     Ok(result_of_tokio_task)
 }
 
-/// Manage cluster membership recording
-///
-/// Uses [initialise_lease_and_node_membership] and various lease functions.
-///
+/// Connect to etcd, retrying up to `max_attempts` times, or forever if that is `None`
+async fn connect_to_etcd(
+    etcd_endpoint: &str,
+    max_attempts: Option<u32>,
+    credentials: Option<etcd::EtcdCredentials>,
+    keep_alive: etcd::EtcdKeepAlive,
+) -> etcd::Result<EtcdClients> {
+    let connect =
+        || EtcdClients::connect(etcd_endpoint.to_owned(), credentials.clone(), keep_alive);
+
+    match max_attempts {
+        None => Ok(do_with_retries_infinite(connect).await),
+        Some(max_attempts) => {
+            do_with_retries(
+                connect,
+                RetryConfig {
+                    maximum_backoff: Duration::from_secs(300),
+                    maximum_n_tries: Some(max_attempts),
+                    initial_duration: Duration::from_secs(1),
+                    ..Default::default()
+                },
+            )
+            .await
+        }
+    }
+}
+
+/// Manage cluster membership recording
+///
+/// Uses [initialise_lease_and_node_membership] and various lease functions.
+///
 /// Doesn't return a result, so that it can run nicely in a separate tokio task. Will just retry
 /// the whole thing if any part fails.
 async fn manage_cluster_node_membership_and_start_work(
     etcd_clients: EtcdClients,
     node_name: String,
-    mut shutdown_receiver: tokio::sync::watch::Receiver<()>,
+    settings: Arc<settings::Settings>,
+    mut shutdown_receiver: ShutdownReceiver,
 ) {
     let token = CancellationToken::new();
     let cloned_token = token.clone();
 
     tokio::spawn(async move {
         shutdown_receiver.changed().await.unwrap();
+        let reason = *shutdown_receiver.borrow();
         event!(
             Level::DEBUG,
+            ?reason,
             "shutdown received, triggering cancellation token"
         );
         cloned_token.cancel();
     });
 
+    let draining = Arc::new(AtomicBool::new(false));
+    tokio::spawn(drain_on_signal(
+        etcd_clients.clone(),
+        node_name.clone(),
+        draining.clone(),
+    ));
+
+    // replace the auth token before it expires, rather than waiting for requests to fail
+    let refreshing_clients = etcd_clients.clone();
+    let refresh_cancelled = token.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = refreshing_clients.keep_authenticated(etcd::AUTH_TOKEN_REFRESH_INTERVAL) => {}
+            _ = refresh_cancelled.cancelled() => {}
+        }
+    });
+
     // initialising the dynamo db client is expensive, so should only be done once
-    let dynamo_db_client = aws::load_client().await;
+    let dynamo_db_client = match load_dynamo_db_client(&settings).await {
+        Ok(dynamo_db_client) => dynamo_db_client,
+        Err(error) => {
+            error!(%error, "failed to load the DynamoDB client, so not joining the cluster");
+            return;
+        }
+    };
+    let kms_client = load_kms_client(&settings).await;
+
+    spawn_admin_api(
+        &settings,
+        Arc::new(admin::NodeAdmin::clustered(
+            dynamo_db_client.clone(),
+            node_name.clone(),
+            settings.clone(),
+            etcd_clients.clone(),
+            draining.clone(),
+        )),
+        token.clone(),
+    );
+
+    let mut backoff = Backoff::new(settings.membership_backoff());
+
+    // shared by every pipeline this node runs, so that overlapping pipelines are bounded together
+    let sync_job_limit = Arc::new(tokio::sync::Semaphore::new(
+        settings.max_concurrent_sync_jobs,
+    ));
+
+    // the lease from the last time membership was recorded, which may still be alive
+    let mut previous_lease = None;
+
+    // before joining, as joining triggers a rebalance, and the pipeline starts syncing straight
+    // away
+    if wait_for_startup_splay(&settings, &token).await {
+        return;
+    }
 
     loop {
+        if draining.load(Ordering::SeqCst) {
+            // recording membership again would replace the draining marker and reclaim partitions
+            info!(%node_name, "node is draining, so not rejoining the cluster");
+            token.cancelled().await;
+            break;
+        }
+
         let mut lease = Default::default();
-        let result = initialise_lease_and_node_membership(etcd_clients.clone(), node_name.clone())
-            .await
-            .map(|x| lease = x);
+        let result = initialise_lease_and_node_membership(
+            etcd_clients.clone(),
+            node_name.clone(),
+            previous_lease,
+            settings.partition_allowlist.as_deref(),
+            settings.max_partitions_per_node,
+            settings.membership_max_attempts,
+            None,
+        )
+        .await
+        .map(|x| lease = x);
 
         match result {
             Ok(_) => {
+                backoff.reset();
+                previous_lease = Some(lease.id);
+
                 let lease_keep_alive_join_handle = tokio::spawn(crate::etcd::lease_keep_alive(
                     etcd_clients.clone().lease,
                     lease.id,
+                    None,
+                    settings.lease_keep_alive_buffer_size,
                 ));
+                let lease_keep_alive_abort_handle = lease_keep_alive_join_handle.abort_handle();
                 let run_work_join_handle = tokio::spawn(start_sync_pipeline(
-                    etcd_clients.clone(),
+                    PartitionOwnership::Clustered {
+                        etcd_clients: etcd_clients.clone(),
+                        current_lease: lease.id,
+                        draining: draining.clone(),
+                    },
                     node_name.clone(),
-                    lease.id,
                     dynamo_db_client.clone(),
+                    kms_client.clone(),
+                    settings.clone(),
+                    sync_job_limit.clone(),
+                    token.clone(),
                 ));
 
                 tokio::select! {
@@ -487,13 +1432,15 @@ async fn manage_cluster_node_membership_and_start_work(
                         match handle.expect("join result should be valid") {
                             Ok(inner) => {
                                 dbg!{inner};
+                                break
                             },
+                            // e.g. the lease expired while claiming sync locks, so record
+                            // membership again (under a new lease if need be) and restart the work
                             Err(error) => {
-                                error!{"Error in running work"};
-                                dbg!{error};
+                                error!(?error, "Error in running work, will try again");
+                                lease_keep_alive_abort_handle.abort();
                             },
                         };
-                        break
                     },
                     _ = token.cancelled() => {
                         event!(Level::INFO, "received shutdown message, ending event loop");
@@ -501,6 +1448,10 @@ async fn manage_cluster_node_membership_and_start_work(
                     }
                 };
             }
+            Err(cluster_management::Error::LeaseNotFound(_)) => {
+                warn!("lease expired before cluster membership was recorded, granting a new one");
+                continue;
+            }
             Err(e) => {
                 event!(
                     Level::ERROR,
@@ -511,116 +1462,464 @@ async fn manage_cluster_node_membership_and_start_work(
 
         dbg!("Reached end of event loop");
 
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        backoff.wait().await;
+
+        // the failure may have been caused by the etcd auth token expiring
+        if let Err(error) = etcd_clients.authenticate().await {
+            error!(%error, "failed to re-authenticate with etcd");
+        }
+    }
+}
+
+/// Run the sync pipeline for a node that isn't part of a cluster, so doesn't need etcd. Nothing
+/// stops another node processing the same sync records, so only one of these should be running.
+fn spawn_single_node_sync_pipeline(
+    node_name: String,
+    settings: Arc<settings::Settings>,
+    mut shutdown_receiver: ShutdownReceiver,
+) -> tokio::task::JoinHandle<()> {
+    let token = CancellationToken::new();
+    let cloned_token = token.clone();
+
+    tokio::spawn(async move {
+        let _ = shutdown_receiver.changed().await;
+        let reason = *shutdown_receiver.borrow();
+        event!(
+            Level::DEBUG,
+            ?reason,
+            "shutdown received, triggering cancellation token"
+        );
+        cloned_token.cancel();
+    });
+
+    tokio::spawn(async move {
+        let dynamo_db_client = match load_dynamo_db_client(&settings).await {
+            Ok(dynamo_db_client) => dynamo_db_client,
+            Err(error) => {
+                error!(%error, "failed to load the DynamoDB client, so not syncing");
+                return;
+            }
+        };
+        let kms_client = load_kms_client(&settings).await;
+        let sync_job_limit = Arc::new(tokio::sync::Semaphore::new(
+            settings.max_concurrent_sync_jobs,
+        ));
+
+        spawn_admin_api(
+            &settings,
+            Arc::new(admin::NodeAdmin::single_node(
+                dynamo_db_client.clone(),
+                node_name.clone(),
+                settings.clone(),
+            )),
+            token.clone(),
+        );
+
+        if wait_for_startup_splay(&settings, &token).await {
+            return;
+        }
+
+        if let Err(error) = start_sync_pipeline(
+            PartitionOwnership::SingleNode,
+            node_name,
+            dynamo_db_client,
+            kms_client,
+            settings,
+            sync_job_limit,
+            token,
+        )
+        .await
+        {
+            error!(%error, "Error in running work");
+        }
+    })
+}
+
+/// How long to wait between rounds of sync jobs
+const SYNC_INTERVAL: Duration = Duration::from_secs(20);
+
+/// How often a node checks that it still owns its sync partition locks while processing sync jobs
+const SYNC_LOCK_VERIFICATION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How the sync pipeline works out which sync partitions this node should process
+#[derive(Debug)]
+pub enum PartitionOwnership {
+    /// Partitions are shared between the nodes in the cluster, using locks in etcd
+    Clustered {
+        etcd_clients: EtcdClients,
+        current_lease: i64,
+        /// Set once this node has started releasing its locks for maintenance, so that it stops
+        /// claiming partitions while remaining a (draining) member of the cluster
+        draining: Arc<AtomicBool>,
+    },
+    /// This is the only node, so it processes every partition (in the allowlist, if there is one)
+    SingleNode,
+}
+impl PartitionOwnership {
+    /// The partitions to process in the next round of sync jobs
+    async fn establish(
+        &mut self,
+        node_name: &str,
+        partition_allowlist: Option<&[u16]>,
+        max_partitions_per_node: Option<usize>,
+    ) -> Result<PartitionAssignment> {
+        Ok(match self {
+            Self::Clustered {
+                etcd_clients,
+                draining,
+                ..
+            } if draining.load(Ordering::SeqCst) => {
+                current_partition_assignment(&mut etcd_clients.kv, node_name)
+                    .await
+                    .unwrap_or_else(|error| {
+                        error!(?error, "failed to get the partitions owned while draining");
+                        PartitionAssignment::default()
+                    })
+            }
+            Self::Clustered {
+                etcd_clients,
+                current_lease,
+                ..
+            } => {
+                establish_correct_sync_partition_locks(
+                    &mut etcd_clients.kv,
+                    node_name,
+                    *current_lease,
+                    partition_allowlist,
+                    max_partitions_per_node,
+                )
+                .await?
+            }
+            Self::SingleNode => {
+                PartitionAssignment::single_node(partition_allowlist, max_partitions_per_node)
+            }
+        })
+    }
+
+    /// Which of `partitions` are still owned by this node
+    async fn verify(&mut self, node_name: &str, partitions: &[u16]) -> Result<Vec<u16>> {
+        match self {
+            Self::Clustered { etcd_clients, .. } => {
+                Ok(verify_sync_lock_ownership(&mut etcd_clients.kv, node_name, partitions).await?)
+            }
+            Self::SingleNode => Ok(partitions.to_vec()),
+        }
+    }
+
+    /// Lock a user while syncing them, so that no other node syncs them at the same time. Returns
+    /// `false` if another node holds the lock.
+    async fn lock_user(&mut self, node_name: &str, user_id: &str) -> Result<bool> {
+        match self {
+            Self::Clustered {
+                etcd_clients,
+                current_lease,
+                ..
+            } => Ok(try_lock_user(&mut etcd_clients.kv, *current_lease, node_name, user_id).await?),
+            Self::SingleNode => Ok(true),
+        }
+    }
+
+    /// Release a lock taken with [Self::lock_user]
+    async fn unlock_user(&mut self, node_name: &str, user_id: &str) -> Result<()> {
+        match self {
+            Self::Clustered { etcd_clients, .. } => {
+                Ok(unlock_user(&mut etcd_clients.kv, node_name, user_id).await?)
+            }
+            Self::SingleNode => Ok(()),
+        }
+    }
+}
+
+/// Load the DynamoDB client, assuming `aws_role_arn` if it is set
+async fn load_dynamo_db_client(
+    settings: &settings::Settings,
+) -> Result<aws_sdk_dynamodb::Client, aws::LoadClientError> {
+    match &settings.aws_role_arn {
+        Some(role_arn) => {
+            aws::load_client_assuming_role(
+                role_arn,
+                &aws::role_session_name(&settings.node_name),
+                aws::ConnectionPoolSettings::default(),
+            )
+            .await
+        }
+        None => Ok(aws::load_client().await),
+    }
+}
+
+/// The KMS client for decrypting users' credentials, if they are encrypted at rest. Like the
+/// DynamoDB client, it should only be loaded once.
+async fn load_kms_client(settings: &settings::Settings) -> Option<aws_sdk_kms::Client> {
+    if settings.encrypted_credentials {
+        Some(aws::load_kms_client().await)
+    } else {
+        None
+    }
+}
+
+/// Force the next sync of one user (or every user, if `user_id` is `None`) to fetch everything
+/// rather than just the changes since the last sync
+pub async fn force_full_resync(user_id: Option<String>) -> Result<()> {
+    opentelemetry_tracing_utils::set_up_logging()?;
+    let dynamo_db_client = load_dynamo_db_client(&settings::get_settings()?).await?;
+
+    let n_sync_records = match &user_id {
+        Some(user_id) => aws::force_full_resync(&dynamo_db_client, user_id).await?,
+        None => aws::force_full_resync_all_users(&dynamo_db_client).await?,
+    };
+    info!(
+        ?user_id,
+        n_sync_records, "cleared the sync state of the sync records"
+    );
+    opentelemetry_tracing_utils::shutdown_tracer_provider();
+
+    Ok(())
+}
+
+/// How long to wait between releasing each sync lock when draining a node for maintenance, so that
+/// the other nodes take over its partitions gradually
+const DRAIN_LOCK_RELEASE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Release all of this node's sync locks when it receives SIGUSR1, so that it can be taken down
+/// for maintenance without interrupting the sync of its partitions. The node remains a member of
+/// the cluster, but is marked as draining, so the other nodes take over its partitions.
+async fn drain_on_signal(etcd_clients: EtcdClients, node_name: String, draining: Arc<AtomicBool>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signal_stream = match signal(SignalKind::user_defined1()) {
+        Ok(stream) => stream,
+        Err(error) => {
+            warn!(?error, "unable to listen for the drain signal");
+            return;
+        }
+    };
+
+    while signal_stream.recv().await.is_some() {
+        info!(%node_name, "drain signal received, releasing sync locks");
+
+        match drain_node(&etcd_clients, &node_name, &draining).await {
+            Ok(released) => info!(%node_name, ?released, "released all sync locks"),
+            Err(error) => error!(?error, "failed to release sync locks"),
+        }
     }
 }
 
+/// Stop claiming partitions and release all of this node's sync locks. Returns the released
+/// partitions.
+pub(crate) async fn drain_node(
+    etcd_clients: &EtcdClients,
+    node_name: &str,
+    draining: &AtomicBool,
+) -> cluster_management::Result<Vec<u16>> {
+    draining.store(true, Ordering::SeqCst);
+    release_all_owned_locks(
+        &mut etcd_clients.kv.clone(),
+        node_name,
+        DRAIN_LOCK_RELEASE_INTERVAL,
+    )
+    .await
+}
+
+/// Serve the admin API (see [admin]) if `admin_api_address` is set, until `cancellation_token` is
+/// cancelled
+fn spawn_admin_api(
+    settings: &settings::Settings,
+    operations: Arc<dyn admin::AdminOperations>,
+    cancellation_token: CancellationToken,
+) {
+    let (Some(address), Some(admin_token)) =
+        (settings.admin_api_address, settings.admin_token.clone())
+    else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let listener = match std::net::TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!(%error, %address, "failed to bind the admin API");
+                return;
+            }
+        };
+        info!(%address, "serving the admin API");
+
+        let shutdown = async move { cancellation_token.cancelled().await };
+        if let Err(error) = admin::serve(listener, admin_token, operations, shutdown).await {
+            error!(%error, "admin API failed");
+        }
+    });
+}
+
+/// Put the partitions in the order they should be synced: the `priority_partitions` first, in the
+/// order given, then the rest in partition order
+fn prioritise_partitions(mut partitions: Vec<u16>, priority_partitions: &[u16]) -> Vec<u16> {
+    partitions.sort_by_key(|partition| {
+        let priority = priority_partitions
+            .iter()
+            .position(|priority_partition| priority_partition == partition)
+            .unwrap_or(priority_partitions.len());
+        (priority, *partition)
+    });
+
+    partitions
+}
+
+/// Wait a random time before this node's first sync, see [startup_splay]. Only done once per
+/// process, not each time the pipeline restarts. Returns whether it was cancelled.
+async fn wait_for_startup_splay(
+    settings: &settings::Settings,
+    cancellation_token: &CancellationToken,
+) -> bool {
+    let splay = startup_splay(settings.startup_splay_max());
+    debug!(?splay, "waiting before the first sync");
+    sleep_unless_cancelled(splay, cancellation_token).await
+}
+
+/// A random wait of up to `max`, to spread out the first syncs of nodes that start together
+fn startup_splay(max: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    // RandomState is randomly seeded, which is plenty for spreading out load
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// Run sync jobs in a loop. Only returns `Ok` once `cancellation_token` is cancelled.
+///
+/// Every sync job, whichever partition it is from, needs a permit from `sync_job_limit`, which is
+/// shared by the whole node (see [settings::Settings::max_concurrent_sync_jobs]).
 pub async fn start_sync_pipeline(
-    mut etcd_clients: EtcdClients,
+    mut partition_ownership: PartitionOwnership,
     node_name: String,
-    current_lease: i64,
     dynamo_db_client: aws_sdk_dynamodb::Client,
-) -> Result<std::convert::Infallible> {
+    kms_client: Option<aws_sdk_kms::Client>,
+    settings: Arc<settings::Settings>,
+    sync_job_limit: Arc<tokio::sync::Semaphore>,
+    cancellation_token: CancellationToken,
+) -> Result<()> {
     let start_span = info_span!("set up pipeline");
 
-    let (_reqwest_client, mut user_creds) = start_span.in_scope(|| {
+    let (reqwest_client, user_creds) = start_span.in_scope(|| {
         // Client is cheap to clone and uses a pool, so it is better to just use one for everything!
-        let reqwest_client = reqwest::Client::new();
+        let reqwest_client = http_client::build_client(
+            settings
+                .http_proxy
+                .as_ref()
+                .map(SecretString::expose_secret),
+        )?;
+
+        let user_creds: Arc<UserCredsCache> = Default::default();
 
-        let user_creds: HashMap<String, aws::UserRecord> = HashMap::new();
+        anyhow::Ok((reqwest_client, user_creds))
+    })?;
 
-        (reqwest_client, user_creds)
+    let sync_job_context = Arc::new(SyncJobContext {
+        dynamo_db_client: dynamo_db_client.clone(),
+        kms_client,
+        settings: settings.clone(),
+        reqwest_client,
+        notion_client: notion_api::NotionClientUnauthenticated::with_http_proxy(
+            Arc::new(notion_api::notion_rate_limiter(
+                settings.notion_requests_per_second,
+            )),
+            settings
+                .http_proxy
+                .as_ref()
+                .map(SecretString::expose_secret),
+        )?,
+        notion_circuit_breaker: CircuitBreaker::new("notion", 5, Duration::from_secs(30)),
+        google_circuit_breaker: CircuitBreaker::new("google", 5, Duration::from_secs(30)),
+        google_calendar_api: GoogleCalendarApi::default(),
+        google_token_url: GOOGLE_OAUTH_TOKEN_URL.to_owned(),
+        sync_sink: None,
+        clock: Arc::new(SystemClock),
     });
 
     // NOTE: THIS IS JUST HERE FOR TESTING
     let users = get_users(&dynamo_db_client).await?;
     dbg!(users);
 
+    // Compared with each new assignment, to log which partitions were gained or lost
+    let mut previous_assignment: Option<PartitionAssignment> = None;
+
+    // Links each iteration's span to the previous one, so that a worker's history can be followed
+    let mut pipeline_spans = opentelemetry_tracing_utils::SpanChain::new();
+
     loop {
         let pipeline_span = info_span!("sync pipeline");
         pipeline_span.follows_from(&start_span);
+        pipeline_spans.link(&pipeline_span);
 
         let sync_job = async {
-            let sync_partition_lock_records = establish_correct_sync_partition_locks(
-                &mut etcd_clients.kv,
-                node_name.as_str(),
-                current_lease,
-            )
-            .await;
+            let assignment = partition_ownership
+                .establish(
+                    node_name.as_str(),
+                    settings.partition_allowlist.as_deref(),
+                    settings.max_partitions_per_node,
+                )
+                .await?;
+            if let Some(previous_assignment) = &previous_assignment {
+                let changes = assignment.changes_since(previous_assignment);
+                if !changes.is_empty() {
+                    info!(
+                        added = ?changes.added,
+                        removed = ?changes.removed,
+                        previous_workers = changes.previous_workers,
+                        workers = changes.workers,
+                        "sync partition assignment changed"
+                    );
+                }
+            }
+            let sync_partition_lock_records =
+                prioritise_partitions(assignment.owned.clone(), &settings.priority_partitions);
+            previous_assignment = Some(assignment);
 
             let db_sync_records = get_sync_records_for_partitions(
                 dynamo_db_client.clone(),
-                sync_partition_lock_records,
+                sync_partition_lock_records.clone(),
+                &settings.sync_statuses,
+                settings.dynamodb_query_page_size,
             )
             .await?;
 
-            // NOTE: This should run in a task
-            // see:
-            // https://medium.com/@polyglot_factotum/rust-concurrency-a-streaming-workflow-served-with-a-side-of-back-pressure-955bdf0266b5
-            //
-            // TODO: communicate between source and processor over channels
-            // could use this: https://docs.rs/async-channel/latest/async_channel/
-            for i in db_sync_records {
-                let single_sync_job_span = info_span!("single sync job");
-                async {
-                    dbg!(&i);
-
-                    let user_id = i.user_id.clone();
-
-                    let current_user_creds = user_creds.get(&user_id);
-                    let current_user_creds = match current_user_creds {
-                        None => {
-                            let user =
-                                aws::get_single_user(&dynamo_db_client, user_id.clone()).await;
-                            user_creds.insert(user_id.clone(), user.unwrap());
-                            user_creds.get(&user_id).unwrap()
-                        }
-                        Some(u) => u,
-                    };
-
-                    dbg!(current_user_creds);
-
-                    println!("SHOULD GET NOTION DATA FOR THIS USER");
-                    let notion_data = current_user_creds.notion_data.as_ref().unwrap();
-                    let notion_client = notion_api::NotionClientUnauthenticated::new();
-                    let x = notion_client
-                        .get_pages_from_notion_database(
-                            &notion_data.notion_access_token,
-                            "asdfasdf",
-                        )
-                        .await;
-                    dbg!(x.unwrap());
-
-                    println!(
-                        "THEN GET GOOGLE CALENDAR RECENTLY EDITED STUFF (USING SYNC ENDPOINT?)"
-                    );
-
-                    println!("THEN COMPARE -> THIS IS THE KEY LOGIC");
-
-                    println!("MAKE ANY REQUIRED CHANGES");
+            run_sync_jobs(
+                &sync_job_context,
+                &mut partition_ownership,
+                &node_name,
+                sync_partition_lock_records,
+                db_sync_records,
+                &user_creds,
+                &sync_job_limit,
+            )
+            .await?;
 
-                    debug!("end of single sync pipeline");
-                }
-                .instrument(single_sync_job_span)
-                .await;
-            }
+            let artificial_sleep_span = debug_span!("artificial sleep time");
+            set_sampling_override(&artificial_sleep_span, SamplingOverride::Never);
 
-            tokio::time::sleep(Duration::from_secs(20))
-                .instrument(debug_span!("artificial sleep time"))
+            let cancelled = sleep_unless_cancelled(SYNC_INTERVAL, &cancellation_token)
+                .instrument(artificial_sleep_span)
                 .await;
 
-            anyhow::Ok(())
+            anyhow::Ok(cancelled)
         };
 
-        async {
+        let cancelled = async {
             let result = sync_job.await;
             result.map_err(|e| {
-                error!(error = %e, "error!!!!! (this is the one at the end)");
+                opentelemetry_tracing_utils::record_error_chain(&tracing::Span::current(), &e);
                 e
             })
         }
         .instrument(pipeline_span)
         .await?;
+
+        if cancelled {
+            debug!("shutdown received between sync jobs, stopping sync pipeline");
+            break Ok(());
+        }
         // .instrument(pipeline_span)
         // .await
         // .map_err(|e| {
@@ -630,13 +1929,2553 @@ pub async fn start_sync_pipeline(
     }
 }
 
-#[cfg(test)]
-mod tests {
-    // use super::*;
+/// Clients and settings shared by every sync job in a pipeline
+struct SyncJobContext {
+    dynamo_db_client: aws_sdk_dynamodb::Client,
+    kms_client: Option<aws_sdk_kms::Client>,
+    settings: Arc<settings::Settings>,
+    reqwest_client: reqwest::Client,
+    // Shared, as Notion's rate limit applies to all requests from this integration
+    notion_client: notion_api::NotionClientUnauthenticated,
+    // Shared by all sync jobs, so that an outage of one of the services makes jobs fail fast
+    notion_circuit_breaker: CircuitBreaker,
+    google_circuit_breaker: CircuitBreaker,
+    google_calendar_api: GoogleCalendarApi,
+    /// See [GoogleToken::with_token_url]
+    google_token_url: String,
+    /// Where sync actions are applied instead of Notion and Google Calendar, e.g. to record them
+    /// in tests
+    sync_sink: Option<Arc<dyn SyncSink>>,
+    clock: Arc<dyn Clock>,
+}
 
-    #[test]
-    fn fake_test() {
-        let result = 2 + 2;
-        assert_eq!(result, 4);
+/// Users' credentials, cached so that each user is only loaded once per pipeline. Shared by the
+/// pipeline's sync jobs, which run concurrently.
+type UserCredsCache = std::sync::Mutex<HashMap<String, Arc<aws::UserRecord>>>;
+
+/// Run a sync job for each of `db_sync_records` that is still in `owned_partitions` and due to be
+/// synced. The jobs run concurrently, no more than `sync_job_limit` at a time (shared with any
+/// other pipelines on this node). Returns the outcome of each job that was run, in the order they
+/// finished.
+async fn run_sync_jobs(
+    context: &Arc<SyncJobContext>,
+    partition_ownership: &mut PartitionOwnership,
+    node_name: &str,
+    mut owned_partitions: Vec<u16>,
+    db_sync_records: Vec<aws::SyncRecord>,
+    user_creds: &Arc<UserCredsCache>,
+    sync_job_limit: &Arc<tokio::sync::Semaphore>,
+) -> Result<Vec<SyncJobOutcome>> {
+    let mut ownership_verified_at = std::time::Instant::now();
+    let mut outcomes = vec![];
+    let mut sync_jobs = tokio::task::JoinSet::new();
+    let mut running_jobs = RunningSyncJobs::new();
+    let mut verification_error = None;
+
+    for i in db_sync_records {
+        // Make sure another node hasn't taken over any of the partitions, so that records
+        // aren't processed twice
+        if ownership_verified_at.elapsed() >= SYNC_LOCK_VERIFICATION_INTERVAL {
+            match partition_ownership
+                .verify(node_name, &owned_partitions)
+                .await
+            {
+                Ok(still_owned) => owned_partitions = still_owned,
+                Err(error) => {
+                    // the jobs already running are left to finish
+                    verification_error = Some(error);
+                    break;
+                }
+            }
+            ownership_verified_at = std::time::Instant::now();
+        }
+        if i.partition()
+            .is_some_and(|partition| !owned_partitions.contains(&partition))
+        {
+            continue;
+        }
+        if let Err(error) = i.next_sync_time() {
+            warn!(
+                user_id = i.user_id.as_str(),
+                data = i.data.as_str(),
+                %error,
+                "sync record has a malformed next sync time, skipping"
+            );
+            continue;
+        }
+        if !context.settings.user_selected(&i.user_id) {
+            trace!(
+                user_id = i.user_id.as_str(),
+                "user not selected by the user allowlist or denylist, skipping"
+            );
+            continue;
+        }
+
+        // A user's lock is held by the node rather than a job, so two of the user's sync records
+        // (e.g. for two Notion databases) mustn't run at once, or the first to finish would unlock
+        // the user while the other is still running. It is synced in the next round instead.
+        if running_jobs
+            .values()
+            .any(|(user_id, _)| *user_id == i.user_id)
+        {
+            debug!(
+                user_id = i.user_id.as_str(),
+                "another of the user's sync records is being synced, skipping"
+            );
+            continue;
+        }
+
+        // The user may also be in a partition that another node is still processing,
+        // e.g. during a rebalance
+        match partition_ownership.lock_user(node_name, &i.user_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!(
+                    user_id = i.user_id.as_str(),
+                    "user is being synced by another node, skipping"
+                );
+                continue;
+            }
+            Err(error) => {
+                warn!(user_id = i.user_id.as_str(), %error, "failed to lock user, skipping");
+                continue;
+            }
+        }
+
+        // waiting for a permit before starting the job holds back the rest of the records
+        let permit = sync_job_limit
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("sync job limit semaphore is never closed");
+        let context = context.clone();
+        let user_creds = user_creds.clone();
+        let user_id = i.user_id.clone();
+        let sync_job = sync_jobs.spawn(
+            async move {
+                let outcome = run_sync_job(&context, &user_creds, &i).await;
+                drop(permit);
+                outcome
+            }
+            .instrument(info_span!("single sync job")),
+        );
+        running_jobs.insert(sync_job.id(), (user_id, std::time::Instant::now()));
+
+        // unlock the users of the jobs that have already finished
+        while let Some(finished) = sync_jobs.try_join_next_with_id() {
+            outcomes.push(
+                finish_sync_job(partition_ownership, node_name, &mut running_jobs, finished).await,
+            );
+        }
+    }
+
+    while let Some(finished) = sync_jobs.join_next_with_id().await {
+        outcomes.push(
+            finish_sync_job(partition_ownership, node_name, &mut running_jobs, finished).await,
+        );
+    }
+
+    match verification_error {
+        Some(error) => Err(error),
+        None => Ok(outcomes),
+    }
+}
+
+/// The user and start time of each sync job that hasn't finished yet
+type RunningSyncJobs = HashMap<tokio::task::Id, (String, std::time::Instant)>;
+
+/// Record the outcome of a finished sync job, and unlock its user. A job that panicked has failed.
+async fn finish_sync_job(
+    partition_ownership: &mut PartitionOwnership,
+    node_name: &str,
+    running_jobs: &mut RunningSyncJobs,
+    finished: std::result::Result<(tokio::task::Id, SyncJobOutcome), tokio::task::JoinError>,
+) -> SyncJobOutcome {
+    let outcome = match finished {
+        Ok((id, outcome)) => {
+            running_jobs.remove(&id);
+            outcome
+        }
+        Err(error) => {
+            let (user_id, started) = running_jobs
+                .remove(&error.id())
+                .expect("every sync job should be recorded as running");
+            error!(user_id, %error, "sync job didn't finish");
+            SyncJobOutcome {
+                user_id,
+                notion_pages_seen: 0,
+                calendar_events_seen: 0,
+                actions_applied: 0,
+                outcome: SyncJobResult::Error,
+                duration: started.elapsed(),
+            }
+        }
+    };
+    record_sync_job_outcome(&outcome);
+
+    if let Err(error) = partition_ownership
+        .unlock_user(node_name, &outcome.user_id)
+        .await
+    {
+        warn!(user_id = outcome.user_id.as_str(), %error, "failed to unlock user");
+    }
+
+    outcome
+}
+
+/// Sync a single user's Notion database and Google Calendars
+async fn run_sync_job(
+    context: &SyncJobContext,
+    user_creds: &UserCredsCache,
+    i: &aws::SyncRecord,
+) -> SyncJobOutcome {
+    let settings = &context.settings;
+    let dynamo_db_client = &context.dynamo_db_client;
+    let reqwest_client = &context.reqwest_client;
+    let notion_client = &context.notion_client;
+
+    trace!(sync_record = ?i, "starting sync job");
+
+    let job_start = std::time::Instant::now();
+    let mut notion_pages_seen = 0;
+    let mut calendar_events_seen = 0;
+    let mut job_result = SyncJobResult::Success;
+
+    let user_id = i.user_id.clone();
+    let skipped = |user_id: String| SyncJobOutcome {
+        user_id,
+        notion_pages_seen: 0,
+        calendar_events_seen: 0,
+        actions_applied: 0,
+        outcome: SyncJobResult::Skipped,
+        duration: job_start.elapsed(),
+    };
+
+    // shared by every request in this job, so that a struggling job fails fast
+    let retry_budget = Arc::new(retry::RetryBudget::new(
+        settings.sync_job_max_retries,
+        settings.sync_job_max_retry_seconds.map(Duration::from_secs),
+        context.clock.clone(),
+    ));
+
+    let now = context.clock.now();
+    if recently_synced(i, Duration::from_secs(settings.sync_debounce_seconds), now) {
+        debug!(
+            user_id,
+            last_sync = i.last_sync.as_deref(),
+            "user synced recently, skipping"
+        );
+        return skipped(user_id);
+    }
+    if !failure_backoff_elapsed(i, now) {
+        debug!(
+            user_id,
+            next_retry_after = i.next_retry_after.as_deref(),
+            "user's sync is failing, skipping until the backoff has passed"
+        );
+        return skipped(user_id);
+    }
+
+    let cached_user_creds = user_creds.lock().unwrap().get(&user_id).cloned();
+    let current_user_creds = match cached_user_creds {
+        Some(current_user_creds) => current_user_creds,
+        None => {
+            let user = aws::get_single_user(
+                dynamo_db_client,
+                user_id.clone(),
+                settings.consistent_user_reads,
+                context.kms_client.as_ref(),
+            )
+            .await;
+            let user = match user {
+                Ok(user) => Arc::new(user),
+                Err(aws::DatabaseRequestError::UserNotFound { .. }) => {
+                    // e.g. they deleted their account, but their sync records are still there
+                    warn!(user_id, "user not found, skipping");
+                    return skipped(user_id);
+                }
+                Err(error) => {
+                    // e.g. the user's credentials can't be decrypted, which only affects them
+                    error!(user_id, %error, "error loading user's credentials");
+                    record_failure_backoff(context, i, SyncJobResult::Error).await;
+                    return SyncJobOutcome {
+                        outcome: SyncJobResult::Error,
+                        ..skipped(user_id)
+                    };
+                }
+            };
+            user_creds
+                .lock()
+                .unwrap()
+                .insert(user_id.clone(), user.clone());
+            user
+        }
+    };
+
+    debug!("getting Notion pages");
+    let notion_data = match notion_sync_plan(&current_user_creds, settings.missing_notion_data) {
+        NotionSyncPlan::Sync(notion_data) => Some(notion_data),
+        NotionSyncPlan::SkipNotion => {
+            warn!(
+                user_id,
+                "user has no Notion credentials, only syncing Google"
+            );
+            None
+        }
+        NotionSyncPlan::SkipUser => {
+            warn!(user_id, "user has no Notion credentials, skipping");
+            return skipped(user_id);
+        }
+    };
+
+    let notion_data = match notion_data {
+        Some(notion_data) if settings.verify_notion_token => {
+            match notion_client
+                .verify_token(notion_data.notion_access_token.expose_secret())
+                .await
+            {
+                Ok(true) => Some(notion_data),
+                Ok(false) => {
+                    warn!(
+                        user_id,
+                        "user's Notion token has been revoked, only syncing Google"
+                    );
+                    None
+                }
+                Err(error) => {
+                    warn!(user_id, %error, "couldn't verify Notion token, syncing anyway");
+                    Some(notion_data)
+                }
+            }
+        }
+        notion_data => notion_data,
+    };
+
+    let mut notion_pages = vec![];
+    if let Some(notion_data) = &notion_data {
+        let x = context
+            .notion_circuit_breaker
+            .call(
+                || {
+                    do_with_retries_while(
+                        || {
+                            notion_client.get_pages_from_notion_database(
+                                notion_data.notion_access_token.expose_secret(),
+                                &i.notion_database,
+                            )
+                        },
+                        external_request_retry_config(&retry_budget),
+                        notion_api::NotionError::is_retryable,
+                    )
+                },
+                notion_api::NotionError::is_retryable,
+            )
+            .await;
+        match x {
+            Ok(x) => {
+                notion_pages_seen = x.results.len();
+                trace!(response = %Truncated::new(&x), "Notion pages");
+                notion_pages = x.results;
+            }
+            // Notion is down for everyone, so don't back this user off for it
+            Err(CircuitBreakerError::Open(service)) => {
+                warn!(service, "circuit breaker open, not getting Notion pages");
+                if job_result == SyncJobResult::Success {
+                    job_result = SyncJobResult::Skipped;
+                }
+            }
+            Err(error) => {
+                error!(%error, "error getting Notion pages");
+                job_result = SyncJobResult::Error;
+            }
+        };
+    }
+
+    debug!("getting Google Calendar events");
+    // each event with the calendar that it is in
+    let mut google_events = vec![];
+    // the token for the next sync of each calendar
+    let mut google_sync_tokens = HashMap::new();
+    let mut google_access_token = None;
+    if let Some(google_refresh_token) = &current_user_creds.google_refresh_token {
+        // one access token for every calendar in the job
+        let google_token = GoogleToken::with_clock(
+            google_refresh_token.expose_secret(),
+            reqwest_client.clone(),
+            context.clock.clone(),
+        )
+        .with_token_url(&context.google_token_url);
+        let access_token = do_with_retries_while(
+            || {
+                google_token.refresh_access_token(
+                    &settings.google_oauth_client_id,
+                    &settings.google_oauth_client_secret,
+                )
+            },
+            external_request_retry_config(&retry_budget),
+            GoogleTokenError::is_retryable,
+        )
+        .await;
+
+        match access_token {
+            Ok(access_token) => {
+                for google_calendar_id in &i.google_calendars {
+                    let mut google_sync_token = i.google_sync_token(google_calendar_id);
+                    let google_response = loop {
+                        let google_response = context
+                            .google_circuit_breaker
+                            .call(
+                                || {
+                                    do_with_retries_while(
+                                        || {
+                                            get_some_data_from_google_calendar(
+                                                reqwest_client,
+                                                &context.google_calendar_api,
+                                                access_token.access_token.expose_secret(),
+                                                google_calendar_id,
+                                                DEFAULT_GOOGLE_EVENTS_MAX_RESULTS,
+                                                Some(GOOGLE_EVENTS_FIELDS),
+                                                google_sync_token,
+                                            )
+                                        },
+                                        external_request_retry_config(&retry_budget),
+                                        retry::is_retryable,
+                                    )
+                                },
+                                retry::is_retryable,
+                            )
+                            .await;
+                        match google_response {
+                            // Google no longer accepts the sync token, so start again from a
+                            // full fetch, which returns a new one
+                            Err(CircuitBreakerError::Inner(error))
+                                if google_sync_token.is_some()
+                                    && error.status() == Some(reqwest::StatusCode::GONE) =>
+                            {
+                                warn!(
+                                    google_calendar_id,
+                                    "Google sync token expired, fetching every event"
+                                );
+                                google_sync_token = None;
+                            }
+                            google_response => break google_response,
+                        }
+                    };
+                    match google_response {
+                        Ok(google_response) => {
+                            calendar_events_seen += google_response.items.len();
+                            if let Some(next_sync_token) = &google_response.next_sync_token {
+                                google_sync_tokens
+                                    .insert(google_calendar_id.clone(), next_sync_token.clone());
+                            }
+                            trace!(
+                                google_calendar_id,
+                                response = %Truncated::new(&google_response),
+                                "Google Calendar events"
+                            );
+                            match google_response.events() {
+                                Ok(events) => google_events.extend(
+                                    events
+                                        .into_iter()
+                                        .map(|event| (google_calendar_id.clone(), event)),
+                                ),
+                                Err(error) => {
+                                    error!(
+                                        google_calendar_id,
+                                        %error,
+                                        "error reading Google Calendar events"
+                                    );
+                                    job_result = SyncJobResult::Error;
+                                }
+                            }
+                        }
+                        // Google is down for everyone, so don't back this user off for it
+                        Err(CircuitBreakerError::Open(service)) => {
+                            warn!(
+                                google_calendar_id,
+                                service, "circuit breaker open, not getting Google Calendar events"
+                            );
+                            if job_result == SyncJobResult::Success {
+                                job_result = SyncJobResult::Skipped;
+                            }
+                        }
+                        Err(error) => {
+                            error!(
+                                google_calendar_id,
+                                %error,
+                                "error getting Google Calendar events"
+                            );
+                            job_result = SyncJobResult::Error;
+                        }
+                    };
+                }
+                google_access_token = Some(access_token);
+            }
+            Err(GoogleTokenError::InvalidGrant) => {
+                warn!(
+                    user_id,
+                    "user has revoked Google access, clearing their refresh token"
+                );
+                if let Err(error) =
+                    aws::clear_google_refresh_token(dynamo_db_client, &user_id).await
+                {
+                    error!(%error, "error clearing Google refresh token");
+                }
+                // so that the user is loaded again without the refresh token
+                user_creds.lock().unwrap().remove(&user_id);
+                if job_result == SyncJobResult::Success {
+                    job_result = SyncJobResult::Skipped;
+                }
+            }
+            Err(error) => {
+                error!(%error, "error refreshing Google access token");
+                job_result = SyncJobResult::Error;
+            }
+        }
+    } else if job_result == SyncJobResult::Success {
+        job_result = SyncJobResult::Skipped;
+    }
+
+    debug!("working out sync actions");
+    let linked_items = sync_actions::link_items(&notion_pages, &google_events);
+    // everything has changed since a sync that has never happened
+    let last_sync = i
+        .last_sync_time()
+        .unwrap_or_else(|| DateTime::<chrono::Utc>::from(std::time::SystemTime::UNIX_EPOCH).into());
+    let actions =
+        sync_actions::compute_sync_actions(&linked_items, last_sync, settings.conflict_strategy);
+
+    debug!(actions = actions.len(), "applying sync actions");
+    let applied_actions = match (&context.sync_sink, &notion_data, &google_access_token) {
+        (Some(sync_sink), _, _) => {
+            sync_actions::apply_sync_actions(sync_sink.as_ref(), actions).await
+        }
+        (None, Some(notion_data), Some(google_access_token)) => {
+            let sync_sink = sync_actions::ApiSyncSink {
+                notion_client,
+                notion_token: notion_data.notion_access_token.expose_secret(),
+                notion_title_id: &i.notion_db_props.notion_title_id,
+                google_client: reqwest_client,
+                google_api: &context.google_calendar_api,
+                google_token: google_access_token.access_token.expose_secret(),
+                pages: &notion_pages,
+                events: &google_events,
+            };
+            sync_actions::apply_sync_actions(&sync_sink, actions).await
+        }
+        // nothing is linked unless both sides were fetched, so there are no actions
+        _ => sync_actions::AppliedActions::default(),
+    };
+    if applied_actions.failed > 0 {
+        job_result = SyncJobResult::Error;
+    } else if applied_actions.skipped > 0 && job_result == SyncJobResult::Success {
+        // not recorded as synced, so that the skipped changes are picked up next time
+        job_result = SyncJobResult::Skipped;
+    }
+
+    // only once the changes they cover have been synced
+    let google_sync_tokens_changed =
+        google_sync_tokens != i.google_sync_tokens || i.google_sync_token.is_some();
+    if job_result == SyncJobResult::Success
+        && google_access_token.is_some()
+        && google_sync_tokens_changed
+    {
+        if let Err(error) =
+            aws::record_google_sync_tokens(dynamo_db_client, i, &google_sync_tokens).await
+        {
+            error!(%error, "error recording Google sync tokens");
+        }
+    }
+
+    record_failure_backoff(context, i, job_result).await;
+
+    debug!("end of single sync pipeline");
+
+    SyncJobOutcome {
+        user_id,
+        notion_pages_seen,
+        calendar_events_seen,
+        actions_applied: applied_actions.applied,
+        outcome: job_result,
+        duration: job_start.elapsed(),
+    }
+}
+
+/// Back off a user whose sync failed, or clear the backoff once it succeeds. Persisted, so that a
+/// user whose sync keeps failing is backed off even across restarts.
+///
+/// A successful sync also records its time as the record's `lastSync`, which the next sync
+/// compares against and which [recently_synced] debounces on.
+async fn record_failure_backoff(
+    context: &SyncJobContext,
+    i: &aws::SyncRecord,
+    job_result: SyncJobResult,
+) {
+    let dynamo_db_client = &context.dynamo_db_client;
+    let backoff_update = match job_result {
+        SyncJobResult::Error => {
+            let next_retry_after = chrono::DateTime::<chrono::Utc>::from(
+                context.clock.now() + failure_backoff(i.consecutive_failures.saturating_add(1)),
+            );
+            Some(aws::record_sync_failure(dynamo_db_client, i, next_retry_after).await)
+        }
+        SyncJobResult::Success => {
+            let last_sync = chrono::DateTime::<chrono::Utc>::from(context.clock.now());
+            if let Err(error) = aws::record_successful_sync(dynamo_db_client, i, last_sync).await {
+                error!(%error, "error recording successful sync");
+            }
+            if i.consecutive_failures > 0 {
+                Some(aws::clear_sync_failures(dynamo_db_client, i).await)
+            } else {
+                None
+            }
+        }
+        SyncJobResult::Skipped => None,
+    };
+    if let Some(Err(error)) = backoff_update {
+        error!(%error, "error updating sync failure backoff");
+    }
+}
+
+/// Longest wait before retrying a user whose sync keeps failing
+const MAXIMUM_FAILURE_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long to wait before retrying a user's sync after it has failed `consecutive_failures`
+/// times in a row. Starts at a minute and doubles each time.
+fn failure_backoff(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+
+    (Duration::from_secs(60) * 2_u32.pow(exponent)).min(MAXIMUM_FAILURE_BACKOFF)
+}
+
+/// Whether a sync record is due to be retried after failing (or has no failures to back off from)
+fn failure_backoff_elapsed(sync_record: &aws::SyncRecord, now: std::time::SystemTime) -> bool {
+    sync_record
+        .next_retry_after_time()
+        .is_none_or(|next_retry_after| now >= next_retry_after.into())
+}
+
+/// Whether a sync record's last sync was within `debounce` of `now`
+fn recently_synced(
+    sync_record: &aws::SyncRecord,
+    debounce: Duration,
+    now: std::time::SystemTime,
+) -> bool {
+    sync_record
+        .last_sync_time()
+        .and_then(|last_sync| now.duration_since(last_sync.into()).ok())
+        .is_some_and(|since_last_sync| since_last_sync < debounce)
+}
+
+#[derive(Debug)]
+enum NotionSyncPlan<'a> {
+    Sync(&'a aws::UserRecordNotionData),
+    /// The user has no Notion credentials, but the Google side should still be synced
+    SkipNotion,
+    /// The user has no Notion credentials, so nothing should be synced
+    SkipUser,
+}
+
+/// Decide how to handle the Notion side of a user's sync, given whether they have connected
+/// Notion
+fn notion_sync_plan(
+    user: &aws::UserRecord,
+    missing_notion_data: settings::MissingNotionData,
+) -> NotionSyncPlan<'_> {
+    match (&user.notion_data, missing_notion_data) {
+        (Some(notion_data), _) => NotionSyncPlan::Sync(notion_data),
+        (None, settings::MissingNotionData::SkipUser) => NotionSyncPlan::SkipUser,
+        (None, settings::MissingNotionData::SyncGoogleOnly) => NotionSyncPlan::SkipNotion,
+    }
+}
+
+/// Sleep for `duration`, waking early if `cancellation_token` is cancelled. Returns whether it was
+/// cancelled.
+async fn sleep_unless_cancelled(
+    duration: Duration,
+    cancellation_token: &CancellationToken,
+) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => false,
+        _ = cancellation_token.cancelled() => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_smithy_client::test_connection::TestConnection;
+
+    use super::*;
+    use crate::fake_http::FakeHttpServer;
+
+    #[test]
+    fn fake_test() {
+        let result = 2 + 2;
+        assert_eq!(result, 4);
+    }
+
+    #[test]
+    fn runtime_worker_threads_applied() {
+        let runtime = build_runtime(&settings::RuntimeSettings {
+            worker_threads: Some(3),
+            max_blocking_threads: Some(8),
+        })
+        .unwrap();
+
+        assert_eq!(3, runtime.metrics().num_workers());
+        assert_eq!(3, runtime.handle().metrics().num_workers());
+    }
+
+    #[test]
+    fn zero_runtime_threads_rejected() {
+        for runtime_settings in [
+            settings::RuntimeSettings {
+                worker_threads: Some(0),
+                ..Default::default()
+            },
+            settings::RuntimeSettings {
+                max_blocking_threads: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                build_runtime(&runtime_settings),
+                Err(BuildRuntimeError::InvalidSettings(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn google_token_errors_from_response_body() {
+        let error = |body: &str| google_token_error(body.as_bytes());
+
+        assert!(matches!(
+            error(
+                r#"{"error": "invalid_grant", "error_description": "Token has been expired or revoked."}"#
+            ),
+            Some(GoogleTokenError::InvalidGrant)
+        ));
+        assert!(matches!(
+            error(r#"{"error": "invalid_client"}"#),
+            Some(GoogleTokenError::Rejected(reason)) if reason == "invalid_client"
+        ));
+        assert!(error("not json").is_none());
+        assert!(!GoogleTokenError::InvalidGrant.is_retryable());
+    }
+
+    #[test]
+    fn google_token_without_calendar_scope_rejected() {
+        let response: GoogleRefreshTokenRequestResponse = serde_json::from_str(
+            r#"{
+                "access_token": "1/fFAasGRNJTz70BzhT3Zg",
+                "expires_in": 3920,
+                "scope": "https://www.googleapis.com/auth/drive.metadata.readonly openid",
+                "token_type": "Bearer"
+            }"#,
+        )
+        .unwrap();
+
+        let error = check_calendar_scope(&response.scope).unwrap_err();
+        assert!(matches!(
+            &error,
+            GoogleTokenError::InsufficientScope(scope) if scope == &response.scope
+        ));
+        assert!(!error.is_retryable());
+
+        assert!(
+            check_calendar_scope("openid https://www.googleapis.com/auth/calendar.events").is_ok()
+        );
+        assert!(check_calendar_scope("https://www.googleapis.com/auth/calendar").is_ok());
+        assert!(check_calendar_scope("https://www.googleapis.com/auth/calendar.readonly").is_err());
+    }
+
+    #[test]
+    fn google_token_refreshed_at_skew_boundary() {
+        let clock = Arc::new(clock::MockClock::new(std::time::SystemTime::UNIX_EPOCH));
+        let mut google_token =
+            GoogleToken::with_clock("refresh_token", reqwest::Client::new(), clock.clone());
+        assert!(google_token.needs_refresh());
+
+        google_token.access_token = Some(GoogleAccessToken {
+            access_token: "access_token".into(),
+            expiry_time: clock.now() + Duration::from_secs(3600),
+        });
+        assert!(!google_token.needs_refresh());
+
+        clock
+            .advance(Duration::from_secs(3600) - ACCESS_TOKEN_EXPIRY_SKEW - Duration::from_secs(1));
+        assert!(!google_token.needs_refresh());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(google_token.needs_refresh());
+    }
+
+    #[tokio::test]
+    async fn retry_backoff_uses_clock() {
+        let start = std::time::SystemTime::UNIX_EPOCH;
+        let clock = Arc::new(clock::MockClock::new(start));
+
+        let result = do_with_retries(
+            || async { Err::<(), _>(figment::Error::from("always fails".to_owned())) },
+            RetryConfig {
+                maximum_n_tries: Some(4),
+                initial_duration: Duration::from_millis(5),
+                clock: clock.clone(),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        assert!(result.is_err());
+        // waits of 5ms, 10ms and 20ms between the four tries
+        assert_eq!(
+            Duration::from_millis(35),
+            clock.now().duration_since(start).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_events_record_attempt_and_wait() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let fields = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(CaptureFields(fields.clone())),
+        );
+        let n_tries = std::sync::atomic::AtomicU32::new(0);
+
+        let result = do_with_retries(
+            || async {
+                if n_tries.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 3 {
+                    Err(figment::Error::from("fails".to_owned()))
+                } else {
+                    Ok(())
+                }
+            },
+            RetryConfig {
+                initial_duration: Duration::from_millis(5),
+                clock: Arc::new(clock::MockClock::new(std::time::SystemTime::UNIX_EPOCH)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let fields = fields.lock().unwrap();
+        let retry_fields = |name: &str| -> Vec<String> {
+            fields
+                .iter()
+                .filter(|field| field.starts_with(&format!("{name}=")))
+                .cloned()
+                .collect()
+        };
+        assert_eq!(
+            vec!["attempt=1", "attempt=2", "attempt=3"],
+            retry_fields("attempt")
+        );
+        assert_eq!(
+            vec!["wait_ms=5", "wait_ms=10", "wait_ms=20"],
+            retry_fields("wait_ms")
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_budget_shared_between_calls() {
+        let clock: Arc<dyn Clock> =
+            Arc::new(clock::MockClock::new(std::time::SystemTime::UNIX_EPOCH));
+        let budget = Arc::new(retry::RetryBudget::new(4, None, clock.clone()));
+        let n_tries = std::sync::atomic::AtomicU32::new(0);
+        let always_fails = || async {
+            n_tries.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err::<(), _>(figment::Error::from("fails".to_owned()))
+        };
+        let config = || RetryConfig {
+            maximum_n_tries: Some(3),
+            clock: clock.clone(),
+            budget: Some(budget.clone()),
+            ..Default::default()
+        };
+
+        // each call would retry twice by itself, but only four retries are shared between them
+        for _ in 0..3 {
+            assert!(do_with_retries(always_fails, config()).await.is_err());
+        }
+
+        // 3 first tries, and 4 retries
+        assert_eq!(7, n_tries.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn retries_stop_on_non_retryable_error() {
+        let n_tries = std::sync::atomic::AtomicU32::new(0);
+
+        let result = do_with_retries_while(
+            || async {
+                n_tries.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Err::<(), _>(figment::Error::from("fatal".to_owned()))
+            },
+            RetryConfig {
+                maximum_n_tries: Some(5),
+                clock: Arc::new(clock::MockClock::new(std::time::SystemTime::UNIX_EPOCH)),
+                ..Default::default()
+            },
+            |_| false,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(1, n_tries.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    /// Captures the message of every event
+    struct CaptureMessages(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CaptureMessages {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct MessageVisitor<'a>(&'a mut Vec<String>);
+            impl tracing::field::Visit for MessageVisitor<'_> {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    if field.name() == "message" {
+                        self.0.push(format!("{value:?}"));
+                    }
+                }
+            }
+
+            event.record(&mut MessageVisitor(&mut self.0.lock().unwrap()));
+        }
+    }
+
+    #[tokio::test]
+    async fn debug_loop_off_by_default() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(CaptureMessages(messages.clone())),
+        );
+
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(None);
+        let settings: settings::Settings = serde_json::from_value(serde_json::json!({
+            "google_oauth_client_id": "id",
+            "google_oauth_client_secret": "secret",
+            "node_name": "node-a",
+        }))
+        .unwrap();
+        assert!(!settings.debug_loop);
+
+        assert!(spawn_debug_loop(settings.debug_loop, shutdown_rx).is_none());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(!messages.lock().unwrap().iter().any(|m| m == "a loop"));
+    }
+
+    /// Resolve the shutdown reason, send it, and return what a shutdown site receives
+    async fn propagated_reason(
+        sigterm: impl Future<Output = Option<()>>,
+        work: impl Future<Output = Result<Result<(), RunError>, tokio::task::JoinError>>,
+    ) -> Option<ShutdownReason> {
+        let (shutdown_tx, mut shutdown_rx) = tokio::sync::watch::channel(None);
+        let shutdown_site = tokio::spawn(async move {
+            shutdown_rx.changed().await.unwrap();
+            let reason = *shutdown_rx.borrow();
+            reason
+        });
+
+        let reason = wait_for_shutdown_reason(sigterm, std::future::pending(), work).await;
+        shutdown_tx.send(Some(reason)).unwrap();
+
+        shutdown_site.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn shutdown_reason_propagated() {
+        assert_eq!(
+            propagated_reason(async { Some(()) }, std::future::pending()).await,
+            Some(ShutdownReason::Sigterm)
+        );
+        assert_eq!(
+            propagated_reason(std::future::pending(), async { Ok(Ok(())) }).await,
+            Some(ShutdownReason::WorkCompleted)
+        );
+        assert_eq!(
+            propagated_reason(std::future::pending(), async {
+                Ok(Err(RunError::NoEtcdEndpoint))
+            })
+            .await,
+            Some(ShutdownReason::WorkFailed)
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_jobs_limited_across_partitions() {
+        let notion = fake_notion();
+        notion.delay_responses(Duration::from_millis(100));
+        let context = Arc::new(sync_job_context(
+            settings::Settings {
+                missing_notion_data: settings::MissingNotionData::SkipUser,
+                ..settings::Settings::new("id", "secret", "node/a")
+            },
+            &notion,
+        ));
+        let user_ids = ["a1", "a2", "a3", "b1", "b2", "b3"];
+        let user_creds = cached_user_creds(
+            user_ids
+                .iter()
+                .map(|user_id| user_record(user_id, Some("secret_token"))),
+        );
+        let sync_records = |user_ids: &[&str]| -> Vec<_> {
+            user_ids
+                .iter()
+                .map(|user_id| {
+                    let mut sync_record = aws::test_support::sync_record(user_id);
+                    sync_record.notion_database = NOTION_DATABASE_ID.to_owned();
+                    sync_record
+                })
+                .collect()
+        };
+        let sync_job_limit = Arc::new(tokio::sync::Semaphore::new(2));
+
+        // two partitions' pipelines running side by side, sharing the job limit
+        let (first, second) = tokio::join!(
+            run_sync_jobs(
+                &context,
+                &mut PartitionOwnership::SingleNode,
+                "node/a",
+                vec![1],
+                sync_records(&user_ids[..3]),
+                &user_creds,
+                &sync_job_limit,
+            ),
+            run_sync_jobs(
+                &context,
+                &mut PartitionOwnership::SingleNode,
+                "node/a",
+                vec![2],
+                sync_records(&user_ids[3..]),
+                &user_creds,
+                &sync_job_limit,
+            ),
+        );
+
+        assert_eq!(3, first.unwrap().len());
+        assert_eq!(3, second.unwrap().len());
+        assert_eq!(6, notion.requests().len());
+        assert_eq!(2, notion.max_concurrent_requests());
+    }
+
+    #[test]
+    fn priority_partitions_processed_first() {
+        assert_eq!(
+            vec![42, 7, 1, 3, 50],
+            prioritise_partitions(vec![50, 7, 3, 42, 1], &[42, 7, 99])
+        );
+        assert_eq!(vec![1, 3, 7], prioritise_partitions(vec![7, 3, 1], &[]));
+    }
+
+    #[test]
+    fn backoff_grows_and_resets() {
+        let mut backoff = Backoff::new(RetryConfig {
+            initial_duration: Duration::from_secs(1),
+            maximum_backoff: Duration::from_secs(5),
+            ..Default::default()
+        });
+
+        let waits: Vec<_> = (0..5).map(|_| backoff.next_wait().as_secs()).collect();
+        assert_eq!(waits, [1, 2, 4, 5, 5]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_wait(), Duration::from_secs(1));
+        assert_eq!(backoff.next_wait(), Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn cancellation_interrupts_sleep() {
+        let cancellation_token = CancellationToken::new();
+
+        let cloned_token = cancellation_token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            cloned_token.cancel();
+        });
+
+        let start = std::time::Instant::now();
+        let cancelled = tokio::time::timeout(
+            Duration::from_secs(5),
+            sleep_unless_cancelled(Duration::from_secs(20), &cancellation_token),
+        )
+        .await
+        .expect("should return as soon as cancelled");
+
+        assert!(cancelled);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn sleep_completes_without_cancellation() {
+        assert!(!sleep_unless_cancelled(Duration::from_millis(1), &CancellationToken::new()).await);
+    }
+
+    #[test]
+    fn user_without_notion_data() {
+        let user: aws::UserRecord = serde_json::from_value(serde_json::json!({
+            "userId": "user-1",
+            "type": "userDetails",
+            "data": "ACTIVE",
+            "googleRefreshToken": "refresh_token",
+        }))
+        .unwrap();
+        assert!(user.notion_data.is_none());
+
+        assert!(matches!(
+            notion_sync_plan(&user, settings::MissingNotionData::SkipUser),
+            NotionSyncPlan::SkipUser
+        ));
+        assert!(matches!(
+            notion_sync_plan(&user, settings::MissingNotionData::SyncGoogleOnly),
+            NotionSyncPlan::SkipNotion
+        ));
+
+        let user: aws::UserRecord = serde_json::from_value(serde_json::json!({
+            "userId": "user-2",
+            "type": "userDetails",
+            "data": "ACTIVE",
+            "notionBotId": "notionB#bot_id",
+            "notionAccessToken": "secret_token",
+        }))
+        .unwrap();
+        assert!(matches!(
+            notion_sync_plan(&user, settings::MissingNotionData::SkipUser),
+            NotionSyncPlan::Sync(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn bad_etcd_endpoint_gives_up() {
+        let result = tokio::time::timeout(
+            Duration::from_secs(30),
+            // nothing is listening on port 1
+            connect_to_etcd("http://127.0.0.1:1", Some(3), None, Default::default()),
+        )
+        .await
+        .expect("should give up after 3 attempts");
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn settings_failure_returns_err() {
+        let result = load_settings(
+            || Err(figment::Error::from("missing node_name".to_owned())),
+            RetryConfig {
+                maximum_n_tries: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        assert!(matches!(result, Err(RunError::Settings(_))));
+    }
+
+    #[tokio::test]
+    async fn settings_loading_gives_up() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        let result = load_settings(
+            || {
+                attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Err(figment::Error::from("missing node_name".to_owned()))
+            },
+            RetryConfig {
+                clock: Arc::new(clock::MockClock::new(std::time::SystemTime::UNIX_EPOCH)),
+                ..settings_retry_config()
+            },
+        )
+        .await;
+
+        assert!(matches!(result, Err(RunError::Settings(_))));
+        assert_eq!(
+            SETTINGS_MAX_ATTEMPTS,
+            attempts.load(std::sync::atomic::Ordering::SeqCst)
+        );
+    }
+
+    /// A user's credentials as loaded from DynamoDB, with Notion credentials if given an access
+    /// token
+    fn user_record(user_id: &str, notion_access_token: Option<&str>) -> aws::UserRecord {
+        let mut user = serde_json::json!({
+            "userId": user_id,
+            "type": "userDetails",
+            "data": "user@example.com",
+        });
+        if let Some(notion_access_token) = notion_access_token {
+            user["notionBotId"] = "notionB#bot".into();
+            user["notionAccessToken"] = notion_access_token.into();
+        }
+        serde_json::from_value(user).unwrap()
+    }
+
+    /// A credentials cache already holding `users`, so that the sync jobs don't load them from
+    /// DynamoDB
+    fn cached_user_creds(users: impl IntoIterator<Item = aws::UserRecord>) -> Arc<UserCredsCache> {
+        Arc::new(std::sync::Mutex::new(
+            users
+                .into_iter()
+                .map(|user| (user.user_id.clone(), Arc::new(user)))
+                .collect(),
+        ))
+    }
+
+    /// Context for sync jobs whose Notion requests go to `notion`. Any DynamoDB request fails.
+    fn sync_job_context(settings: settings::Settings, notion: &FakeHttpServer) -> SyncJobContext {
+        SyncJobContext {
+            dynamo_db_client: aws::test_support::client_with_connection(TestConnection::<
+                &'static str,
+            >::new(vec![])),
+            kms_client: None,
+            settings: Arc::new(settings),
+            reqwest_client: reqwest::Client::new(),
+            notion_client: notion_api::NotionClientUnauthenticated::new()
+                .with_base_url(notion.base_url().to_owned() + "v1/"),
+            notion_circuit_breaker: CircuitBreaker::new("notion", 5, Duration::from_secs(30)),
+            google_circuit_breaker: CircuitBreaker::new("google", 5, Duration::from_secs(30)),
+            google_calendar_api: GoogleCalendarApi::default(),
+            google_token_url: GOOGLE_OAUTH_TOKEN_URL.to_owned(),
+            sync_sink: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    const NOTION_DATABASE_ID: &str = "d9824bdc84454327be8b5b47500af6ce";
+
+    /// A fake Notion API with a single page in [NOTION_DATABASE_ID]
+    fn fake_notion() -> FakeHttpServer {
+        FakeHttpServer::start([(
+            "POST /v1/databases/d9824bdc84454327be8b5b47500af6ce/query",
+            200,
+            serde_json::json!({
+                "object": "list",
+                "results": [{
+                    "object": "page",
+                    "id": "b55c9c91-384d-452b-81db-d1ef79372b75",
+                    "created_time": "2022-10-24T22:54:00.000Z",
+                    "last_edited_time": "2023-03-08T18:25:00.000Z",
+                    "created_by": { "object": "user", "id": "user" },
+                    "last_edited_by": { "object": "user", "id": "user" },
+                    "icon": null,
+                    "parent": { "type": "database_id", "database_id": NOTION_DATABASE_ID },
+                    "archived": false,
+                    "properties": {},
+                    "url": "https://www.notion.so/page",
+                }],
+                "next_cursor": null,
+                "has_more": false,
+                "type": "page_or_database",
+                "page": {},
+            }),
+        )])
+    }
+
+    #[tokio::test]
+    async fn pipeline_continues_past_user_without_notion_data() {
+        let notion = fake_notion();
+        let context = Arc::new(sync_job_context(
+            settings::Settings {
+                missing_notion_data: settings::MissingNotionData::SkipUser,
+                ..settings::Settings::new("id", "secret", "node/a")
+            },
+            &notion,
+        ));
+        let user_creds = cached_user_creds([
+            user_record("no-notion", None),
+            user_record("notion", Some("secret_token")),
+        ]);
+        let mut with_notion = aws::test_support::sync_record("notion");
+        with_notion.notion_database = NOTION_DATABASE_ID.to_owned();
+
+        let outcomes = run_sync_jobs(
+            &context,
+            &mut PartitionOwnership::SingleNode,
+            "node/a",
+            vec![3],
+            vec![aws::test_support::sync_record("no-notion"), with_notion],
+            &user_creds,
+            &Arc::new(tokio::sync::Semaphore::new(1)),
+        )
+        .await
+        .unwrap();
+
+        let mut outcomes: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| {
+                (
+                    outcome.user_id.as_str(),
+                    outcome.outcome,
+                    outcome.notion_pages_seen,
+                )
+            })
+            .collect();
+        outcomes.sort_unstable_by_key(|(user_id, _, _)| *user_id);
+        // neither user has connected Google Calendar, so the user with Notion credentials is
+        // skipped after their pages are fetched
+        assert_eq!(
+            vec![
+                ("no-notion", SyncJobResult::Skipped, 0),
+                ("notion", SyncJobResult::Skipped, 1),
+            ],
+            outcomes
+        );
+        let requests = notion.requests();
+        let requests: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                (
+                    request.route.as_str(),
+                    request.query.as_deref(),
+                    &request.body,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![(
+                "POST /v1/databases/d9824bdc84454327be8b5b47500af6ce/query",
+                None,
+                &serde_json::Value::Null
+            )],
+            requests
+        );
+    }
+
+    #[tokio::test]
+    async fn user_with_two_sync_records_synced_once_per_round() {
+        let notion = fake_notion();
+        let context = Arc::new(sync_job_context(
+            settings::Settings::new("id", "secret", "node/a"),
+            &notion,
+        ));
+        let user_creds = cached_user_creds([user_record("user", Some("secret_token"))]);
+        let sync_records = ["sync#0", "sync#1"].map(|sort_key| {
+            let mut sync_record = aws::test_support::sync_record("user");
+            sync_record.sort_key = sort_key.to_owned();
+            sync_record.notion_database = NOTION_DATABASE_ID.to_owned();
+            sync_record
+        });
+
+        let outcomes = run_sync_jobs(
+            &context,
+            &mut PartitionOwnership::SingleNode,
+            "node/a",
+            vec![3],
+            sync_records.into(),
+            &user_creds,
+            &Arc::new(tokio::sync::Semaphore::new(2)),
+        )
+        .await
+        .unwrap();
+
+        // the second record is left until the next round, as the first was still running
+        assert_eq!(1, outcomes.len());
+        assert_eq!(1, notion.requests().len());
+    }
+
+    /// Panics instead of applying any sync action
+    struct PanickingSyncSink;
+
+    impl SyncSink for PanickingSyncSink {
+        fn apply(&self, _action: sync_actions::SyncAction) -> sync_actions::SinkFuture<'_> {
+            panic!("simulated panic in a sync job")
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_sync_job_fails_only_itself() {
+        let etcd = crate::fake_etcd::FakeEtcd::start().await;
+        let mut partition_ownership = PartitionOwnership::Clustered {
+            etcd_clients: etcd.clients().await,
+            current_lease: etcd.grant_lease(60),
+            draining: Arc::new(AtomicBool::new(false)),
+        };
+        let notion = fake_notion();
+        let google = fake_google();
+        let context = Arc::new(SyncJobContext {
+            google_calendar_api: GoogleCalendarApi::with_base_url(
+                reqwest::Url::parse(&(google.base_url().to_owned() + "calendar/v3/")).unwrap(),
+            ),
+            google_token_url: google.base_url().to_owned() + "token",
+            sync_sink: Some(Arc::new(PanickingSyncSink)),
+            ..sync_job_context(
+                settings::Settings {
+                    missing_notion_data: settings::MissingNotionData::SkipUser,
+                    ..settings::Settings::new("id", "secret", "node/a")
+                },
+                &notion,
+            )
+        });
+        let mut panics = user_record("panics", Some("secret_token"));
+        panics.google_refresh_token = Some("refresh_token".into());
+        let user_creds = cached_user_creds([panics, user_record("no-notion", None)]);
+        let mut panicking_sync_record = aws::test_support::sync_record("panics");
+        panicking_sync_record.notion_database = NOTION_DATABASE_ID.to_owned();
+
+        let outcomes = run_sync_jobs(
+            &context,
+            &mut partition_ownership,
+            "node/a",
+            vec![3],
+            vec![
+                panicking_sync_record,
+                aws::test_support::sync_record("no-notion"),
+            ],
+            &user_creds,
+            &Arc::new(tokio::sync::Semaphore::new(2)),
+        )
+        .await
+        .unwrap();
+
+        let mut outcomes: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| (outcome.user_id.as_str(), outcome.outcome))
+            .collect();
+        outcomes.sort_unstable_by_key(|(user_id, _)| *user_id);
+        assert_eq!(
+            vec![
+                ("no-notion", SyncJobResult::Skipped),
+                ("panics", SyncJobResult::Error),
+            ],
+            outcomes
+        );
+        // both users were unlocked
+        assert!(etcd
+            .entries_with_prefix(cluster_management::USER_LOCK_PREFIX)
+            .is_empty());
+    }
+
+    /// A fake Google API with a token endpoint and two events in the calendar "calendar", one
+    /// linked to the page in [fake_notion] and edited after it
+    fn fake_google() -> FakeHttpServer {
+        FakeHttpServer::start([
+            (
+                "POST /token",
+                200,
+                serde_json::json!({
+                    "access_token": "access_token",
+                    "expires_in": 3599,
+                    "scope": "https://www.googleapis.com/auth/calendar",
+                    "token_type": "Bearer",
+                }),
+            ),
+            (
+                "GET /calendar/v3/calendars/calendar/events",
+                200,
+                serde_json::json!({
+                    "kind": "calendar#events",
+                    "summary": "calendar",
+                    "timeZone": "Europe/London",
+                    "updated": "2023-03-09T10:00:00.000Z",
+                    "items": [
+                        {
+                            "id": "linked",
+                            "summary": "Write the sync",
+                            "updated": "2023-03-09T10:00:00.000Z",
+                            "status": "confirmed",
+                            "extendedProperties": {
+                                "private": { "notionPageId": "b55c9c91384d452b81dbd1ef79372b75" }
+                            },
+                        },
+                        {
+                            "id": "unlinked",
+                            "summary": "Lunch",
+                            "updated": "2023-03-09T10:00:00.000Z",
+                            "status": "confirmed",
+                        },
+                    ],
+                }),
+            ),
+        ])
+    }
+
+    /// Records the actions applied by a sync job, rather than making any changes
+    #[derive(Default)]
+    struct RecordingSyncSink {
+        actions: std::sync::Mutex<Vec<sync_actions::SyncAction>>,
+    }
+
+    impl SyncSink for RecordingSyncSink {
+        fn apply(&self, action: sync_actions::SyncAction) -> sync_actions::SinkFuture<'_> {
+            self.actions.lock().unwrap().push(action);
+            Box::pin(async { Ok(sync_actions::SinkOutcome::Applied) })
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_actions_computed_from_fetched_pages_and_events() {
+        let notion = fake_notion();
+        let google = fake_google();
+        let sync_sink = Arc::new(RecordingSyncSink::default());
+        let context = SyncJobContext {
+            google_calendar_api: GoogleCalendarApi::with_base_url(
+                reqwest::Url::parse(&(google.base_url().to_owned() + "calendar/v3/")).unwrap(),
+            ),
+            google_token_url: google.base_url().to_owned() + "token",
+            sync_sink: Some(sync_sink.clone()),
+            ..sync_job_context(settings::Settings::new("id", "secret", "node/a"), &notion)
+        };
+        let mut user = user_record("user", Some("secret_token"));
+        user.google_refresh_token = Some("refresh_token".into());
+        let mut sync_record = aws::test_support::sync_record("user");
+        sync_record.notion_database = NOTION_DATABASE_ID.to_owned();
+
+        let outcome = run_sync_job(&context, &cached_user_creds([user]), &sync_record).await;
+
+        assert_eq!(SyncJobResult::Success, outcome.outcome);
+        assert_eq!(
+            (1, 2, 1),
+            (
+                outcome.notion_pages_seen,
+                outcome.calendar_events_seen,
+                outcome.actions_applied
+            )
+        );
+        // never synced, so both sides have changed, and the event was edited most recently
+        assert_eq!(
+            vec![sync_actions::SyncAction::UpdateNotionPage {
+                notion_page_id: "b55c9c91-384d-452b-81db-d1ef79372b75".to_owned(),
+                google_event_id: "linked".to_owned(),
+                google_calendar_id: "calendar".to_owned(),
+            }],
+            *sync_sink.actions.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_to_apply_when_nothing_changed_since_last_sync() {
+        let notion = fake_notion();
+        let google = fake_google();
+        let sync_sink = Arc::new(RecordingSyncSink::default());
+        let dynamo_db = TestConnection::new(vec![
+            aws::test_support::json_response(serde_json::json!({})),
+            aws::test_support::json_response(serde_json::json!({})),
+        ]);
+        // after the page and the event were last edited
+        let clock = Arc::new(clock::MockClock::new(std::time::SystemTime::from(
+            DateTime::parse_from_rfc3339("2023-05-04T10:12:00Z").unwrap(),
+        )));
+        let context = SyncJobContext {
+            dynamo_db_client: aws::test_support::client_with_connection(dynamo_db.clone()),
+            google_calendar_api: GoogleCalendarApi::with_base_url(
+                reqwest::Url::parse(&(google.base_url().to_owned() + "calendar/v3/")).unwrap(),
+            ),
+            google_token_url: google.base_url().to_owned() + "token",
+            sync_sink: Some(sync_sink.clone()),
+            clock: clock.clone(),
+            ..sync_job_context(settings::Settings::new("id", "secret", "node/a"), &notion)
+        };
+        let mut user = user_record("user", Some("secret_token"));
+        user.google_refresh_token = Some("refresh_token".into());
+        let user_creds = cached_user_creds([user]);
+        let mut sync_record = aws::test_support::sync_record("user");
+        sync_record.notion_database = NOTION_DATABASE_ID.to_owned();
+
+        let outcome = run_sync_job(&context, &user_creds, &sync_record).await;
+        assert_eq!(1, outcome.actions_applied);
+        sync_record.last_sync = Some(recorded_last_sync(&dynamo_db));
+
+        clock.advance(Duration::from_secs(
+            context.settings.sync_debounce_seconds + 1,
+        ));
+        let outcome = run_sync_job(&context, &user_creds, &sync_record).await;
+
+        assert_eq!(SyncJobResult::Success, outcome.outcome);
+        assert_eq!(
+            (1, 2, 0),
+            (
+                outcome.notion_pages_seen,
+                outcome.calendar_events_seen,
+                outcome.actions_applied
+            )
+        );
+        assert_eq!(1, sync_sink.actions.lock().unwrap().len());
+    }
+
+    /// The route and `syncToken` parameter of each Google Calendar events request
+    fn google_sync_tokens_sent(google: &FakeHttpServer) -> Vec<(String, Option<String>)> {
+        google
+            .requests()
+            .into_iter()
+            .filter(|request| request.route.ends_with("/events"))
+            .map(|request| {
+                let url = reqwest::Url::parse(&format!(
+                    "http://localhost/?{}",
+                    request.query.unwrap_or_default()
+                ))
+                .unwrap();
+                let sync_token = url
+                    .query_pairs()
+                    .find(|(name, _)| name == "syncToken")
+                    .map(|(_, sync_token)| sync_token.into_owned());
+                (request.route, sync_token)
+            })
+            .collect()
+    }
+
+    /// The Google sync tokens stored by a DynamoDB request, if any, in DynamoDB's JSON format
+    fn recorded_google_sync_tokens(
+        dynamo_db: &TestConnection<String>,
+    ) -> Option<serde_json::Value> {
+        dynamo_db.requests().iter().find_map(|request| {
+            let body: serde_json::Value =
+                serde_json::from_slice(request.actual.body().bytes().unwrap()).unwrap();
+            body["UpdateExpression"]
+                .as_str()?
+                .starts_with("SET googleSyncTokens")
+                .then(|| body["ExpressionAttributeValues"][":googleSyncTokens"].clone())
+        })
+    }
+
+    /// Context for sync jobs that only sync Google Calendar, with Google requests going to
+    /// `google` and DynamoDB requests to `dynamo_db`
+    fn google_only_sync_job_context(
+        google: &FakeHttpServer,
+        dynamo_db: &TestConnection<String>,
+        notion: &FakeHttpServer,
+    ) -> SyncJobContext {
+        SyncJobContext {
+            dynamo_db_client: aws::test_support::client_with_connection(dynamo_db.clone()),
+            google_calendar_api: GoogleCalendarApi::with_base_url(
+                reqwest::Url::parse(&(google.base_url().to_owned() + "calendar/v3/")).unwrap(),
+            ),
+            google_token_url: google.base_url().to_owned() + "token",
+            ..sync_job_context(
+                settings::Settings {
+                    missing_notion_data: settings::MissingNotionData::SyncGoogleOnly,
+                    ..settings::Settings::new("id", "secret", "node/a")
+                },
+                notion,
+            )
+        }
+    }
+
+    /// A Google Calendar events response without any events, and with `next_sync_token`
+    fn no_google_events(next_sync_token: &str) -> serde_json::Value {
+        serde_json::json!({
+            "kind": "calendar#events",
+            "summary": "calendar",
+            "timeZone": "Europe/London",
+            "updated": "2023-03-09T10:00:00.000Z",
+            "items": [],
+            "nextSyncToken": next_sync_token,
+        })
+    }
+
+    fn google_token_route() -> (&'static str, u16, serde_json::Value) {
+        (
+            "POST /token",
+            200,
+            serde_json::json!({
+                "access_token": "access_token",
+                "expires_in": 3599,
+                "scope": "https://www.googleapis.com/auth/calendar",
+                "token_type": "Bearer",
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn each_calendar_fetched_with_its_own_sync_token() {
+        let notion = fake_notion();
+        let google = FakeHttpServer::start([
+            google_token_route(),
+            (
+                "GET /calendar/v3/calendars/calendar/events",
+                200,
+                no_google_events("next_calendar_sync_token"),
+            ),
+            (
+                "GET /calendar/v3/calendars/work/events",
+                200,
+                no_google_events("next_work_sync_token"),
+            ),
+        ]);
+        let dynamo_db = TestConnection::new(vec![
+            aws::test_support::json_response(serde_json::json!({})),
+            aws::test_support::json_response(serde_json::json!({})),
+        ]);
+        let context = google_only_sync_job_context(&google, &dynamo_db, &notion);
+        let mut user = user_record("user", None);
+        user.google_refresh_token = Some("refresh_token".into());
+        let mut sync_record = aws::test_support::sync_record("user");
+        sync_record.google_calendars = vec!["calendar".to_owned(), "work".to_owned()];
+        sync_record.google_sync_tokens = HashMap::from([
+            ("calendar".to_owned(), "calendar_sync_token".to_owned()),
+            ("work".to_owned(), "work_sync_token".to_owned()),
+        ]);
+
+        let outcome = run_sync_job(&context, &cached_user_creds([user]), &sync_record).await;
+
+        assert_eq!(SyncJobResult::Success, outcome.outcome);
+        assert_eq!(
+            vec![
+                (
+                    "GET /calendar/v3/calendars/calendar/events".to_owned(),
+                    Some("calendar_sync_token".to_owned())
+                ),
+                (
+                    "GET /calendar/v3/calendars/work/events".to_owned(),
+                    Some("work_sync_token".to_owned())
+                ),
+            ],
+            google_sync_tokens_sent(&google)
+        );
+        assert_eq!(
+            Some(serde_json::json!({ "M": {
+                "calendar": { "S": "next_calendar_sync_token" },
+                "work": { "S": "next_work_sync_token" },
+            } })),
+            recorded_google_sync_tokens(&dynamo_db)
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_google_sync_token_replaced_by_a_full_fetch() {
+        let notion = fake_notion();
+        let google = FakeHttpServer::start([
+            google_token_route(),
+            (
+                "GET /calendar/v3/calendars/calendar/events?syncToken=expired_sync_token",
+                410,
+                serde_json::json!({ "error": { "code": 410, "message": "Sync token is no longer valid" } }),
+            ),
+            (
+                "GET /calendar/v3/calendars/calendar/events",
+                200,
+                no_google_events("new_sync_token"),
+            ),
+        ]);
+        let dynamo_db = TestConnection::new(vec![
+            aws::test_support::json_response(serde_json::json!({})),
+            aws::test_support::json_response(serde_json::json!({})),
+        ]);
+        let context = google_only_sync_job_context(&google, &dynamo_db, &notion);
+        let mut user = user_record("user", None);
+        user.google_refresh_token = Some("refresh_token".into());
+        let mut sync_record = aws::test_support::sync_record("user");
+        sync_record.google_sync_tokens =
+            HashMap::from([("calendar".to_owned(), "expired_sync_token".to_owned())]);
+
+        let outcome = run_sync_job(&context, &cached_user_creds([user]), &sync_record).await;
+
+        assert_eq!(SyncJobResult::Success, outcome.outcome);
+        assert_eq!(
+            vec![
+                (
+                    "GET /calendar/v3/calendars/calendar/events".to_owned(),
+                    Some("expired_sync_token".to_owned())
+                ),
+                (
+                    "GET /calendar/v3/calendars/calendar/events".to_owned(),
+                    None
+                ),
+            ],
+            google_sync_tokens_sent(&google)
+        );
+        assert_eq!(
+            Some(serde_json::json!({ "M": { "calendar": { "S": "new_sync_token" } } })),
+            recorded_google_sync_tokens(&dynamo_db)
+        );
+    }
+
+    #[tokio::test]
+    async fn user_that_cant_be_loaded_fails_only_their_job() {
+        let notion = fake_notion();
+        let dynamo_db = TestConnection::new(vec![
+            // the user's details, in a shape that can't be loaded
+            aws::test_support::json_response(serde_json::json!({
+                "Item": { "userId": { "S": "user" }, "SK": { "S": "userDetails" } }
+            })),
+            // backing off the user's sync
+            aws::test_support::json_response(serde_json::json!({})),
+        ]);
+        let context = SyncJobContext {
+            dynamo_db_client: aws::test_support::client_with_connection(dynamo_db.clone()),
+            ..sync_job_context(settings::Settings::new("id", "secret", "node/a"), &notion)
+        };
+
+        let outcome = run_sync_job(
+            &context,
+            &UserCredsCache::default(),
+            &aws::test_support::sync_record("user"),
+        )
+        .await;
+
+        assert_eq!(SyncJobResult::Error, outcome.outcome);
+        assert_eq!(2, dynamo_db.requests().len());
+        assert!(notion.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn open_circuit_breaker_skips_without_backing_off() {
+        let notion = fake_notion();
+        let dynamo_db = TestConnection::<&'static str>::new(vec![]);
+        let context = SyncJobContext {
+            dynamo_db_client: aws::test_support::client_with_connection(dynamo_db.clone()),
+            notion_circuit_breaker: CircuitBreaker::new("notion", 1, Duration::from_secs(3600)),
+            ..sync_job_context(settings::Settings::new("id", "secret", "node/a"), &notion)
+        };
+        let _ = context
+            .notion_circuit_breaker
+            .call(|| async { Err::<(), _>("Notion unavailable") }, |_| true)
+            .await;
+        let user_creds = cached_user_creds([user_record("user", Some("secret_token"))]);
+        let mut sync_record = aws::test_support::sync_record("user");
+        sync_record.notion_database = NOTION_DATABASE_ID.to_owned();
+
+        let outcome = run_sync_job(&context, &user_creds, &sync_record).await;
+
+        assert_eq!(SyncJobResult::Skipped, outcome.outcome);
+        assert!(notion.requests().is_empty());
+        // no failure recorded for the user
+        assert!(dynamo_db.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_user_skipped() {
+        let notion = fake_notion();
+        let dynamo_db = TestConnection::new(vec![aws::test_support::json_response(
+            serde_json::json!({}),
+        )]);
+        let context = SyncJobContext {
+            dynamo_db_client: aws::test_support::client_with_connection(dynamo_db.clone()),
+            ..sync_job_context(settings::Settings::new("id", "secret", "node/a"), &notion)
+        };
+
+        let outcome = run_sync_job(
+            &context,
+            &UserCredsCache::default(),
+            &aws::test_support::sync_record("user"),
+        )
+        .await;
+
+        assert_eq!(SyncJobResult::Skipped, outcome.outcome);
+        assert_eq!(1, dynamo_db.requests().len());
+        assert!(notion.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn recently_synced_user_skipped_by_pipeline() {
+        let notion = fake_notion();
+        let clock = Arc::new(clock::MockClock::new(std::time::SystemTime::from(
+            DateTime::parse_from_rfc3339("2023-05-04T10:12:00Z").unwrap(),
+        )));
+        let context = SyncJobContext {
+            clock: clock.clone(),
+            ..sync_job_context(
+                settings::Settings {
+                    sync_debounce_seconds: 60,
+                    ..settings::Settings::new("id", "secret", "node/a")
+                },
+                &notion,
+            )
+        };
+        let user_creds = cached_user_creds([user_record("user", Some("secret_token"))]);
+        let mut sync_record = aws::test_support::sync_record("user");
+        sync_record.notion_database = NOTION_DATABASE_ID.to_owned();
+        sync_record.last_sync = Some("2023-05-04T10:11:50Z".to_owned());
+
+        let outcome = run_sync_job(&context, &user_creds, &sync_record).await;
+        assert_eq!(SyncJobResult::Skipped, outcome.outcome);
+        assert!(notion.requests().is_empty());
+
+        clock.advance(Duration::from_secs(60));
+        let outcome = run_sync_job(&context, &user_creds, &sync_record).await;
+        assert_eq!(1, outcome.notion_pages_seen);
+    }
+
+    /// The `lastSync` set by the latest DynamoDB request, which records a successful sync
+    fn recorded_last_sync(dynamo_db: &TestConnection<String>) -> String {
+        let requests = dynamo_db.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests.last().unwrap().actual.body().bytes().unwrap())
+                .unwrap();
+        assert_eq!("SET lastSync = :lastSync", body["UpdateExpression"]);
+        body["ExpressionAttributeValues"][":lastSync"]["S"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn successful_sync_recorded_for_the_debounce() {
+        let notion = fake_notion();
+        let google = fake_google();
+        let dynamo_db = TestConnection::new(vec![aws::test_support::json_response(
+            serde_json::json!({}),
+        )]);
+        let clock = Arc::new(clock::MockClock::new(std::time::SystemTime::from(
+            DateTime::parse_from_rfc3339("2023-05-04T10:12:00Z").unwrap(),
+        )));
+        let context = SyncJobContext {
+            dynamo_db_client: aws::test_support::client_with_connection(dynamo_db.clone()),
+            google_calendar_api: GoogleCalendarApi::with_base_url(
+                reqwest::Url::parse(&(google.base_url().to_owned() + "calendar/v3/")).unwrap(),
+            ),
+            google_token_url: google.base_url().to_owned() + "token",
+            sync_sink: Some(Arc::new(RecordingSyncSink::default())),
+            clock: clock.clone(),
+            ..sync_job_context(
+                settings::Settings {
+                    sync_debounce_seconds: 60,
+                    ..settings::Settings::new("id", "secret", "node/a")
+                },
+                &notion,
+            )
+        };
+        let mut user = user_record("user", Some("secret_token"));
+        user.google_refresh_token = Some("refresh_token".into());
+        let user_creds = cached_user_creds([user]);
+        let mut sync_record = aws::test_support::sync_record("user");
+        sync_record.notion_database = NOTION_DATABASE_ID.to_owned();
+
+        let outcome = run_sync_job(&context, &user_creds, &sync_record).await;
+        assert_eq!(SyncJobResult::Success, outcome.outcome);
+        assert_eq!(1, dynamo_db.requests().len());
+        sync_record.last_sync = Some(recorded_last_sync(&dynamo_db));
+        assert_eq!(
+            Some("2023-05-04T10:12:00.000Z"),
+            sync_record.last_sync.as_deref()
+        );
+
+        // within the debounce of the recorded sync
+        clock.advance(Duration::from_secs(30));
+        let outcome = run_sync_job(&context, &user_creds, &sync_record).await;
+        assert_eq!(SyncJobResult::Skipped, outcome.outcome);
+        assert_eq!(1, notion.requests().len());
+    }
+
+    #[tokio::test]
+    async fn invalid_settings_returns_err() {
+        let result = load_settings(
+            || {
+                Ok(settings::Settings {
+                    clustered: false,
+                    ..settings::Settings::new("id", "secret", "node/a")
+                })
+            },
+            RetryConfig::default(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RunError::InvalidSettings(_))));
+    }
+
+    const GOOGLE_EVENTS_RESPONSE: &str = r#"{
+        "kind": "calendar#events",
+        "etag": "\"p33cbhjuvp2eg80g\"",
+        "summary": "someone@example.com",
+        "updated": "2023-05-04T10:12:33.101Z",
+        "timeZone": "Europe/London",
+        "accessRole": "owner",
+        "nextPageToken": "CigKGjZ0",
+        "items": [
+            {
+                "kind": "calendar#event",
+                "id": "4bd1ffr2rb0k1kmkkj3ml6vmbo",
+                "status": "confirmed",
+                "htmlLink": "https://www.google.com/calendar/event?eid=NGJk",
+                "created": "2023-05-01T09:00:00.000Z",
+                "updated": "2023-05-01T09:00:00.000Z",
+                "summary": "Bank holiday",
+                "start": { "date": "2023-05-08" },
+                "end": { "date": "2023-05-09" },
+                "transparency": "transparent"
+            },
+            {
+                "kind": "calendar#event",
+                "id": "0r4pq8m6a3b1l5mbmt0c2e7k5s",
+                "status": "confirmed",
+                "updated": "2023-05-02T14:21:08.512Z",
+                "summary": "Dentist",
+                "start": {
+                    "dateTime": "2023-05-10T09:30:00+01:00",
+                    "timeZone": "Europe/London"
+                },
+                "end": {
+                    "dateTime": "2023-05-10T10:00:00+01:00",
+                    "timeZone": "Europe/London"
+                },
+                "reminders": { "useDefault": true }
+            },
+            {
+                "kind": "calendar#event",
+                "id": "7ghbgl0ph3c4g1hbvq1sts6c1k",
+                "status": "cancelled"
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn google_response_events() {
+        let response: GoogleResponse = serde_json::from_str(GOOGLE_EVENTS_RESPONSE).unwrap();
+        let events = response.events().unwrap();
+
+        assert_eq!(3, events.len());
+        // raw items are kept
+        assert_eq!("transparent", response.items[0]["transparency"]);
+
+        let all_day = &events[0];
+        assert_eq!(Some("Bank holiday"), all_day.summary.as_deref());
+        assert_eq!(
+            Some(EventDateTime::Date(
+                NaiveDate::from_ymd_opt(2023, 5, 8).unwrap()
+            )),
+            all_day.start
+        );
+
+        let timed = &events[1];
+        assert_eq!("0r4pq8m6a3b1l5mbmt0c2e7k5s", timed.id);
+        assert_eq!(
+            Some(EventDateTime::DateTime(
+                DateTime::parse_from_rfc3339("2023-05-10T09:30:00+01:00").unwrap()
+            )),
+            timed.start
+        );
+
+        let cancelled = &events[2];
+        assert_eq!(Some("cancelled"), cancelled.status.as_deref());
+        assert_eq!(None, cancelled.start);
+    }
+
+    #[test]
+    fn event_date_time_all_day() {
+        let date: EventDateTime = serde_json::from_str(r#"{ "date": "2023-05-08" }"#).unwrap();
+
+        assert!(date.is_all_day());
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2023, 5, 8).unwrap(),
+            date.date_naive()
+        );
+        assert_eq!(
+            serde_json::json!({ "date": "2023-05-08" }),
+            serde_json::to_value(date).unwrap()
+        );
+    }
+
+    #[test]
+    fn event_date_time_timed() {
+        let date_time: EventDateTime =
+            serde_json::from_str(r#"{ "dateTime": "2023-05-10T23:30:00-04:00" }"#).unwrap();
+
+        assert!(!date_time.is_all_day());
+        // the date is the one in the event's own offset, not UTC
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2023, 5, 10).unwrap(),
+            date_time.date_naive()
+        );
+
+        let same_instant: EventDateTime =
+            serde_json::from_str(r#"{ "dateTime": "2023-05-11T03:30:00Z" }"#).unwrap();
+        assert!(date_time.same_as(&same_instant));
+
+        let all_day: EventDateTime = serde_json::from_str(r#"{ "date": "2023-05-10" }"#).unwrap();
+        assert!(!date_time.same_as(&all_day));
+    }
+
+    #[test]
+    fn event_date_time_invalid() {
+        assert!(serde_json::from_str::<EventDateTime>(r#"{ "timeZone": "UTC" }"#).is_err());
+        assert!(serde_json::from_str::<EventDateTime>(r#"{ "date": "08/05/2023" }"#).is_err());
+    }
+
+    #[test]
+    fn event_date_time_ignores_time_zone_names() {
+        // The calendar time zone and the event time zone both differ from the offset in the
+        // timestamp. The offset in the timestamp is what defines the instant.
+        let response: GoogleResponse = serde_json::from_value(serde_json::json!({
+            "kind": "calendar#events",
+            "summary": "someone@example.com",
+            "updated": "2023-05-04T10:12:33.101Z",
+            "timeZone": "Asia/Tokyo",
+            "items": [
+                {
+                    "id": "timed",
+                    "start": {
+                        "dateTime": "2023-05-10T09:30:00+01:00",
+                        "timeZone": "America/New_York"
+                    },
+                    "end": { "dateTime": "2023-05-10T10:00:00+01:00" }
+                },
+                {
+                    "id": "all-day",
+                    "start": { "date": "2023-05-10", "timeZone": "Asia/Tokyo" },
+                    "end": { "date": "2023-05-11" }
+                }
+            ]
+        }))
+        .unwrap();
+        assert_eq!("Asia/Tokyo", response.time_zone);
+
+        let events = response.events().unwrap();
+
+        match events[0].start.unwrap() {
+            EventDateTime::DateTime(date_time) => {
+                assert_eq!(3600, date_time.offset().local_minus_utc())
+            }
+            other => panic!("expected a timed event, got {other:?}"),
+        }
+        assert_eq!(
+            EventDateTime::Date(NaiveDate::from_ymd_opt(2023, 5, 10).unwrap()),
+            events[1].start.unwrap()
+        );
+    }
+
+    #[test]
+    fn google_events_request_query() {
+        let request = google_calendar_events_request(
+            &reqwest::Client::new(),
+            &GoogleCalendarApi::default(),
+            "access_token",
+            "primary",
+            10,
+            Some(GOOGLE_EVENTS_FIELDS),
+            None,
+        )
+        .build()
+        .unwrap();
+
+        assert_eq!(
+            "/calendar/v3/calendars/primary/events",
+            request.url().path()
+        );
+        let query: HashMap<_, _> = request.url().query_pairs().into_owned().collect();
+        assert_eq!("10", query["maxResults"]);
+        assert_eq!(GOOGLE_EVENTS_FIELDS, query["fields"]);
+
+        let request = google_calendar_events_request(
+            &reqwest::Client::new(),
+            &GoogleCalendarApi::default(),
+            "access_token",
+            "en.uk#holiday@group.v.calendar.google.com",
+            4,
+            None,
+            None,
+        )
+        .build()
+        .unwrap();
+        assert_eq!(
+            "/calendar/v3/calendars/en.uk%23holiday@group.v.calendar.google.com/events",
+            request.url().path()
+        );
+        assert_eq!(Some("maxResults=4"), request.url().query());
+    }
+
+    #[test]
+    fn google_watch_requests() {
+        let request = google_watch_request(
+            &reqwest::Client::new(),
+            &GoogleCalendarApi::default(),
+            "access_token",
+            "en.uk#holiday@group.v.calendar.google.com",
+            "https://example.com/notifications",
+            "01234567-89ab-cdef-0123456789ab",
+        )
+        .build()
+        .unwrap();
+
+        assert_eq!(reqwest::Method::POST, request.method());
+        assert_eq!(
+            "/calendar/v3/calendars/en.uk%23holiday@group.v.calendar.google.com/events/watch",
+            request.url().path()
+        );
+        assert_eq!(
+            "Bearer access_token",
+            request.headers()[reqwest::header::AUTHORIZATION]
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "id": "01234567-89ab-cdef-0123456789ab",
+                "type": "web_hook",
+                "address": "https://example.com/notifications"
+            }),
+            body
+        );
+
+        let request = google_stop_watch_request(
+            &reqwest::Client::new(),
+            &GoogleCalendarApi::default(),
+            "access_token",
+            "01234567-89ab-cdef-0123456789ab",
+            "o3hgv1538sdjfh",
+        )
+        .build()
+        .unwrap();
+
+        assert_eq!(reqwest::Method::POST, request.method());
+        assert_eq!("/calendar/v3/channels/stop", request.url().path());
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "id": "01234567-89ab-cdef-0123456789ab",
+                "resourceId": "o3hgv1538sdjfh"
+            }),
+            body
+        );
+    }
+
+    #[test]
+    fn deserialize_watch_channel() {
+        let channel: WatchChannel = serde_json::from_str(
+            r#"{
+                "kind": "api#channel",
+                "id": "01234567-89ab-cdef-0123456789ab",
+                "resourceId": "o3hgv1538sdjfh",
+                "resourceUri": "https://www.googleapis.com/calendar/v3/calendars/primary/events",
+                "expiration": "1426325213000"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            WatchChannel {
+                id: "01234567-89ab-cdef-0123456789ab".to_owned(),
+                resource_id: "o3hgv1538sdjfh".to_owned(),
+                expiration: Some("1426325213000".to_owned()),
+            },
+            channel
+        );
+    }
+
+    #[tokio::test]
+    async fn watch_and_stop_watching_calendar() {
+        let google = FakeHttpServer::start([
+            (
+                "POST /calendar/v3/calendars/primary/events/watch",
+                200,
+                serde_json::json!({
+                    "kind": "api#channel",
+                    "id": "01234567-89ab-cdef-0123456789ab",
+                    "resourceId": "o3hgv1538sdjfh",
+                    "resourceUri": "https://www.googleapis.com/calendar/v3/calendars/primary/events",
+                    "expiration": "1426325213000"
+                }),
+            ),
+            (
+                "POST /calendar/v3/channels/stop",
+                200,
+                serde_json::json!({}),
+            ),
+        ]);
+        let google_api = GoogleCalendarApi::with_base_url(
+            reqwest::Url::parse(&(google.base_url().to_owned() + "calendar/v3/")).unwrap(),
+        );
+
+        let channel = watch_calendar(
+            &reqwest::Client::new(),
+            &google_api,
+            "access_token",
+            "primary",
+            "https://example.com/notifications",
+            "01234567-89ab-cdef-0123456789ab",
+        )
+        .await
+        .unwrap();
+        assert_eq!("o3hgv1538sdjfh", channel.resource_id);
+
+        stop_watch(
+            &reqwest::Client::new(),
+            &google_api,
+            "access_token",
+            &channel.id,
+            &channel.resource_id,
+        )
+        .await
+        .unwrap();
+
+        let requests = google.requests();
+        let requests: Vec<_> = requests
+            .iter()
+            .map(|request| (request.route.as_str(), &request.body))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "POST /calendar/v3/calendars/primary/events/watch",
+                    &serde_json::json!({
+                        "id": "01234567-89ab-cdef-0123456789ab",
+                        "type": "web_hook",
+                        "address": "https://example.com/notifications"
+                    })
+                ),
+                (
+                    "POST /calendar/v3/channels/stop",
+                    &serde_json::json!({
+                        "id": "01234567-89ab-cdef-0123456789ab",
+                        "resourceId": "o3hgv1538sdjfh"
+                    })
+                ),
+            ],
+            requests
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_watch_request_is_an_error() {
+        let google = FakeHttpServer::start([(
+            "POST /calendar/v3/calendars/primary/events/watch",
+            403,
+            serde_json::json!({ "error": { "code": 403, "message": "forbidden" } }),
+        )]);
+        let google_api = GoogleCalendarApi::with_base_url(
+            reqwest::Url::parse(&(google.base_url().to_owned() + "calendar/v3/")).unwrap(),
+        );
+
+        let error = watch_calendar(
+            &reqwest::Client::new(),
+            &google_api,
+            "access_token",
+            "primary",
+            "https://example.com/notifications",
+            "01234567-89ab-cdef-0123456789ab",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(Some(reqwest::StatusCode::FORBIDDEN), error.status());
+    }
+
+    #[test]
+    fn google_events_request_is_incremental_with_a_sync_token() {
+        let sync_record = |sync_token: Option<&str>| {
+            let mut sync_record = aws::test_support::sync_record("user");
+            sync_record.google_sync_token = sync_token.map(str::to_owned);
+            sync_record
+        };
+        let sync_token = |sync_record: &aws::SyncRecord| -> Option<String> {
+            let request = google_calendar_events_request(
+                &reqwest::Client::new(),
+                &GoogleCalendarApi::default(),
+                "access_token",
+                &sync_record.google_calendars[0],
+                4,
+                None,
+                sync_record.google_sync_token.as_deref(),
+            )
+            .build()
+            .unwrap();
+            let query: HashMap<_, _> = request.url().query_pairs().into_owned().collect();
+            query.get("syncToken").cloned()
+        };
+
+        assert_eq!(
+            Some("CPDAlvWDx70CEPDAlvWDx70CGAU=".to_owned()),
+            sync_token(&sync_record(Some("CPDAlvWDx70CEPDAlvWDx70CGAU=")))
+        );
+        // after a forced full resync
+        assert_eq!(None, sync_token(&sync_record(None)));
+    }
+
+    #[test]
+    fn trimmed_google_response() {
+        let response: GoogleResponse = serde_json::from_value(serde_json::json!({
+            "kind": "calendar#events",
+            "summary": "someone@example.com",
+            "updated": "2023-05-04T10:12:33.101Z",
+            "timeZone": "Europe/London",
+            "items": [
+                {
+                    "id": "4bd1ffr2rb0k1kmkkj3ml6vmbo",
+                    "status": "confirmed",
+                    "summary": "Write the sync",
+                    "updated": "2023-05-04T10:12:33.101Z",
+                    "start": { "dateTime": "2023-05-10T09:30:00+01:00" },
+                    "end": { "dateTime": "2023-05-10T10:00:00+01:00" }
+                },
+                { "id": "cancelled", "status": "cancelled" }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(None, response.next_page_token);
+
+        let events = response.events().unwrap();
+        assert_eq!(Some("Write the sync".to_owned()), events[0].summary);
+        assert_eq!(Some("cancelled".to_owned()), events[1].status);
+        assert_eq!(None, events[1].start);
+    }
+
+    #[tokio::test]
+    async fn single_node_owns_all_partitions() {
+        let mut partition_ownership = PartitionOwnership::SingleNode;
+
+        let partitions = partition_ownership
+            .establish("node-a", None, None)
+            .await
+            .unwrap()
+            .owned;
+        assert_eq!((0..100).collect::<Vec<u16>>(), partitions);
+        assert_eq!(
+            partitions,
+            partition_ownership
+                .verify("node-a", &partitions)
+                .await
+                .unwrap()
+        );
+
+        assert_eq!(
+            vec![3, 7],
+            partition_ownership
+                .establish("node-a", Some(&[3, 7, 500]), None)
+                .await
+                .unwrap()
+                .owned
+        );
+    }
+
+    /// Captures every field of every event, as `name=value`
+    struct CaptureFields(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CaptureFields {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct FieldVisitor<'a>(&'a mut Vec<String>);
+            impl tracing::field::Visit for FieldVisitor<'_> {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    self.0.push(format!("{}={value:?}", field.name()));
+                }
+            }
+
+            event.record(&mut FieldVisitor(&mut self.0.lock().unwrap()));
+        }
+    }
+
+    #[test]
+    fn startup_banner_redacts_secrets() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let fields = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(CaptureFields(fields.clone())),
+        );
+
+        let settings: settings::Settings = serde_json::from_value(serde_json::json!({
+            "google_oauth_client_id": "client-id",
+            "google_oauth_client_secret": "client-secret-value",
+            "node_name": "node-a",
+            "etcd_username": "hello-rust",
+            "etcd_password": "etcd-password-value",
+            "partition_allowlist": [1, 2],
+        }))
+        .unwrap();
+        log_startup_banner(&settings, false);
+        // loading the settings doesn't log them either. Only read by this test.
+        std::env::set_var("APP_GOOGLE_OAUTH_CLIENT_ID", "client-id");
+        std::env::set_var("APP_GOOGLE_OAUTH_CLIENT_SECRET", "client-secret-value");
+        std::env::set_var("APP_NODE_NAME", "node-a");
+        std::env::set_var("APP_ETCD_PASSWORD", "etcd-password-value");
+        settings::get_settings().unwrap();
+
+        let fields = fields.lock().unwrap();
+        for expected in [
+            r#"node_name="node-a""#,
+            "clustered=true",
+            r#"role="clustered""#,
+            "otlp_enabled=false",
+            "sync_interval=20s",
+            r#"sync_partitions="[1, 2]""#,
+            r#"etcd_username="hello-rust""#,
+        ] {
+            assert!(
+                fields.iter().any(|field| field == expected),
+                "{expected} not in {fields:?}"
+            );
+        }
+        assert!(!fields
+            .iter()
+            .any(|field| field.contains("client-secret-value")
+                || field.contains("etcd-password-value")));
+    }
+
+    #[test]
+    fn startup_splay_within_max() {
+        let max = Duration::from_secs(30);
+        for _ in 0..1000 {
+            assert!(startup_splay(max) <= max);
+        }
+
+        assert_eq!(startup_splay(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn recently_synced_users_debounced() {
+        let sync_record = |last_sync: Option<&str>| {
+            let mut sync_record = aws::test_support::sync_record("user");
+            sync_record.last_sync = last_sync.map(str::to_owned);
+            sync_record
+        };
+        let now = std::time::SystemTime::from(
+            DateTime::parse_from_rfc3339("2023-05-04T10:12:00Z").unwrap(),
+        );
+        let debounce = Duration::from_secs(60);
+
+        assert!(recently_synced(
+            &sync_record(Some("2023-05-04T10:11:50Z")),
+            debounce,
+            now
+        ));
+        assert!(!recently_synced(
+            &sync_record(Some("2023-05-04T10:10:00Z")),
+            debounce,
+            now
+        ));
+        assert!(!recently_synced(&sync_record(None), debounce, now));
+        assert!(!recently_synced(
+            &sync_record(Some("not a timestamp")),
+            debounce,
+            now
+        ));
+    }
+
+    #[test]
+    fn failing_users_backed_off() {
+        let sync_record = |next_retry_after: &str| {
+            let mut sync_record = aws::test_support::sync_record("user");
+            sync_record.next_retry_after = Some(next_retry_after.to_owned());
+            sync_record.consecutive_failures = 2;
+            sync_record
+        };
+        let now = std::time::SystemTime::from(
+            DateTime::parse_from_rfc3339("2023-05-04T10:12:00Z").unwrap(),
+        );
+
+        assert!(!failure_backoff_elapsed(
+            &sync_record("2023-05-04T10:14:00Z"),
+            now
+        ));
+        assert!(failure_backoff_elapsed(
+            &sync_record("2023-05-04T10:10:00Z"),
+            now
+        ));
+    }
+
+    #[test]
+    fn failure_backoff_doubles_up_to_maximum() {
+        assert_eq!(Duration::from_secs(60), failure_backoff(1));
+        assert_eq!(Duration::from_secs(120), failure_backoff(2));
+        assert_eq!(Duration::from_secs(480), failure_backoff(4));
+        assert_eq!(MAXIMUM_FAILURE_BACKOFF, failure_backoff(12));
+        assert_eq!(MAXIMUM_FAILURE_BACKOFF, failure_backoff(u32::MAX));
     }
 }
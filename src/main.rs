@@ -1,33 +1,40 @@
 use anyhow::Result;
+use hello_rust_backend::{settings, wait_for_shutdown_reason};
 use tokio::signal::unix::{signal, SignalKind};
 use tracing::{event, span, Instrument, Level};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let (tx, rx) = tokio::sync::watch::channel(());
+fn main() -> Result<()> {
+    let runtime_settings = settings::get_runtime_settings()?;
+    let runtime = hello_rust_backend::build_runtime(&runtime_settings)?;
+
+    // Admin command: `hello-rust-backend force-full-resync [user_id]`
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("force-full-resync") {
+        return runtime.block_on(hello_rust_backend::force_full_resync(args.next()));
+    }
+
+    runtime.block_on(async_main())
+}
+
+async fn async_main() -> Result<()> {
+    let (tx, rx) = tokio::sync::watch::channel(None);
 
     let app_run_join_handle = tokio::spawn(hello_rust_backend::run(rx.clone()));
 
     let mut sigterm_stream = signal(SignalKind::terminate())?;
     let mut sigint_stream = signal(SignalKind::interrupt())?;
-    tokio::select! {
-        _ = sigterm_stream.recv() => {event!(Level::INFO, "sigterm received");}
-        _ = sigint_stream.recv() => {event!(Level::INFO, "sigint received");}
-        // also quit if the work task has completed
-        result = app_run_join_handle => {
-            match result {
-                Ok(_) => {event!(Level::INFO, "work finished");},
-                Err(error) => {
-                    event!(Level::ERROR, ?error, "Work task panicked");
-                }
-            }
-        }
-    }
+    let reason = wait_for_shutdown_reason(
+        sigterm_stream.recv(),
+        sigint_stream.recv(),
+        app_run_join_handle,
+    )
+    .await;
 
-    let span = span!(Level::TRACE, "Shutting down tasks");
+    let span = span!(Level::TRACE, "Shutting down tasks", ?reason);
     async {
         // send shutdown signal to application and wait
-        tx.send(())?;
+        event!(Level::INFO, ?reason, "shutting down");
+        tx.send(Some(reason))?;
 
         // // Wait for the tasks to finish.
         // //
@@ -51,7 +58,7 @@ async fn main() -> Result<()> {
     .await?;
 
     // Shutdown trace pipeline
-    opentelemetry::global::shutdown_tracer_provider();
+    opentelemetry_tracing_utils::shutdown_tracer_provider();
 
     println!("Shutdown complete!");
 
@@ -0,0 +1,95 @@
+//! Gauges describing the state of this node, e.g. for dashboards of the cluster's partition
+//! balance. Exported in the Prometheus text format by the admin API's `/metrics` route.
+
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+/// Number of sync partitions owned by each node, set after each rebalance
+pub static OWNED_PARTITIONS: Gauge = Gauge::new(
+    "owned_partitions",
+    "Number of sync partitions owned by the node",
+);
+
+/// Every gauge, in the order they are exported
+static GAUGES: [&Gauge; 1] = [&OWNED_PARTITIONS];
+
+/// A gauge with a value for each node, labelled by `node_name`
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<String, i64>>,
+}
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set(&self, node_name: &str, value: i64) {
+        self.values
+            .lock()
+            .expect("gauge lock shouldn't be poisoned")
+            .insert(node_name.to_owned(), value);
+    }
+
+    /// The value for `node_name`, if it has been set
+    pub fn get(&self, node_name: &str) -> Option<i64> {
+        self.values
+            .lock()
+            .expect("gauge lock shouldn't be poisoned")
+            .get(node_name)
+            .copied()
+    }
+
+    fn render(&self, output: &mut String) {
+        let _ = writeln!(output, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(output, "# TYPE {} gauge", self.name);
+        for (node_name, value) in self
+            .values
+            .lock()
+            .expect("gauge lock shouldn't be poisoned")
+            .iter()
+        {
+            let node_name = node_name.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(output, "{}{{node_name=\"{node_name}\"}} {value}", self.name);
+        }
+    }
+}
+
+/// Every gauge in the Prometheus text format
+pub fn render() -> String {
+    let mut output = String::new();
+    for gauge in GAUGES {
+        gauge.render(&mut output);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gauge_rendered_per_node() {
+        let gauge = Gauge::new("test_gauge", "A gauge for testing");
+        assert_eq!(None, gauge.get("node-a"));
+
+        gauge.set("node-b", 4);
+        gauge.set("node-a", 2);
+        gauge.set("node-b", 3);
+        assert_eq!(Some(2), gauge.get("node-a"));
+
+        let mut output = String::new();
+        gauge.render(&mut output);
+        assert_eq!(
+            "# HELP test_gauge A gauge for testing\n\
+             # TYPE test_gauge gauge\n\
+             test_gauge{node_name=\"node-a\"} 2\n\
+             test_gauge{node_name=\"node-b\"} 3\n",
+            output
+        );
+    }
+}
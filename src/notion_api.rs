@@ -1,5 +1,56 @@
+use std::{collections::HashMap, sync::Arc};
+
 use reqwest::{header::InvalidHeaderValue, ClientBuilder};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+
+use crate::rate_limit::RateLimiter;
+
+const NOTION_API_BASE_URL: &str = "https://api.notion.com/v1/";
+/// Notion allows an average of 3 requests per second per integration
+pub const DEFAULT_NOTION_REQUESTS_PER_SECOND: f64 = 3.0;
+
+#[derive(Error, Debug)]
+pub enum NotionError {
+    #[error("error in request to the Notion API")]
+    Request(#[from] reqwest::Error),
+    #[error("property {0} does not exist in the Notion database")]
+    MissingDatabaseProperty(String),
+    #[error("invalid Notion database id {0:?}")]
+    InvalidDatabaseId(String),
+}
+impl NotionError {
+    /// Whether the error is likely to be transient, see [crate::retry::is_retryable]
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Request(error) => crate::retry::is_retryable(error),
+            Self::MissingDatabaseProperty(_) | Self::InvalidDatabaseId(_) => false,
+        }
+    }
+}
+
+/// Check that a database id looks like a Notion id (32 hex characters, optionally dashed as a
+/// UUID), so that an unset id doesn't turn into a request to `/databases//query`
+pub fn validate_database_id(database_id: &str) -> Result<(), NotionError> {
+    let hex_digits = database_id
+        .chars()
+        .filter(|c| *c != '-')
+        .collect::<Vec<_>>();
+    let dashed = database_id.len() == 36
+        && database_id
+            .char_indices()
+            .all(|(i, c)| (c == '-') == matches!(i, 8 | 13 | 18 | 23));
+
+    if hex_digits.len() == 32
+        && hex_digits.iter().all(char::is_ascii_hexdigit)
+        && (database_id.len() == 32 || dashed)
+    {
+        Ok(())
+    } else {
+        Err(NotionError::InvalidDatabaseId(database_id.to_owned()))
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NotionPagesResponse {
@@ -26,6 +77,152 @@ pub struct NotionPageObject {
     properties: serde_json::Value,
     url: String,
 }
+impl NotionPageObject {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// When the page was last edited. Notion rounds this down to the minute.
+    pub fn last_edited_time(&self) -> &str {
+        &self.last_edited_time
+    }
+
+    /// The page's title as plain text, from the title property with id `title_property_id`
+    /// (see [crate::aws::NotionDBPropertyOptions]). `None` if the page has no such property.
+    pub fn title(&self, title_property_id: &str) -> Option<String> {
+        let property = self
+            .properties
+            .as_object()?
+            .values()
+            .find(|property| property["id"] == title_property_id)?;
+
+        Some(
+            property["title"]
+                .as_array()?
+                .iter()
+                .filter_map(|rich_text| rich_text["plain_text"].as_str())
+                .collect(),
+        )
+    }
+}
+
+/// Properties for [NotionClientUnauthenticated::update_page_if_unchanged] that set the title
+/// property with id `title_property_id` to `title`
+pub fn title_properties(title_property_id: &str, title: &str) -> serde_json::Value {
+    serde_json::json!({
+        title_property_id: { "title": [{ "text": { "content": title } }] }
+    })
+}
+
+/// The result of [NotionClientUnauthenticated::update_page_if_unchanged]
+#[derive(Debug)]
+pub enum PageUpdate {
+    Updated(NotionPageObject),
+    /// The page was edited by someone else after it was read, so it wasn't updated
+    Skipped {
+        /// When the page was last edited, according to Notion
+        last_edited_time: String,
+    },
+}
+
+/// Body for a page update, see <https://developers.notion.com/reference/patch-page>
+#[derive(Serialize, Debug)]
+struct UpdatePageRequest<'a> {
+    properties: &'a serde_json::Value,
+}
+
+/// A Notion database definition, see <https://developers.notion.com/reference/database>
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NotionDatabase {
+    pub object: String,
+    pub id: String,
+    pub title: serde_json::Value,
+    pub last_edited_time: String,
+    pub archived: bool,
+    /// Database properties, keyed by property name
+    pub properties: HashMap<String, NotionDatabaseProperty>,
+}
+impl NotionDatabase {
+    /// Find a property by its id (which, unlike the name, doesn't change if the user renames it)
+    pub fn property(&self, property_id: &str) -> Option<&NotionDatabaseProperty> {
+        self.properties
+            .values()
+            .find(|property| property.id == property_id)
+    }
+
+    /// Check that a property still exists, e.g. one of the ids in a sync record's
+    /// [crate::aws::NotionDBPropertyOptions]
+    pub fn require_property(
+        &self,
+        property_id: &str,
+    ) -> Result<&NotionDatabaseProperty, NotionError> {
+        self.property(property_id)
+            .ok_or_else(|| NotionError::MissingDatabaseProperty(property_id.to_owned()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NotionDatabaseProperty {
+    pub id: String,
+    pub name: String,
+    /// e.g. "title", "checkbox", "date"
+    #[serde(rename = "type")]
+    pub property_type: String,
+}
+
+/// The id and title of a database, e.g. to let a user pick which database to sync
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NotionDatabaseSummary {
+    pub id: String,
+    /// The title as plain text, without any formatting
+    pub title: String,
+}
+
+/// Body for a search request, see <https://developers.notion.com/reference/post-search>
+#[derive(Serialize, Debug)]
+struct NotionSearchRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query: Option<&'a str>,
+    filter: NotionSearchFilter,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_cursor: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+struct NotionSearchFilter {
+    property: &'static str,
+    value: &'static str,
+}
+
+#[derive(Deserialize, Debug)]
+struct NotionDatabaseSearchResponse {
+    has_more: bool,
+    next_cursor: Option<String>,
+    results: Vec<NotionDatabaseSearchResult>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NotionDatabaseSearchResult {
+    id: String,
+    title: Vec<NotionRichText>,
+}
+impl From<NotionDatabaseSearchResult> for NotionDatabaseSummary {
+    fn from(result: NotionDatabaseSearchResult) -> Self {
+        Self {
+            id: result.id,
+            title: result
+                .title
+                .into_iter()
+                .map(|rich_text| rich_text.plain_text)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct NotionRichText {
+    plain_text: String,
+}
 
 pub trait NotionReqwest {
     fn add_notion_headers(self) -> Result<ClientBuilder, InvalidHeaderValue>;
@@ -43,24 +240,236 @@ impl NotionReqwest for reqwest::ClientBuilder {
     }
 }
 
-pub struct NotionClientUnauthenticated(reqwest::Client);
+pub struct NotionClientUnauthenticated {
+    client: reqwest::Client,
+    base_url: String,
+    /// Every request waits for this, so share it between all clients for the same integration
+    rate_limiter: Arc<RateLimiter>,
+}
 impl NotionClientUnauthenticated {
     pub fn new() -> Self {
-        Self(make_notion_client())
+        Self::with_rate_limiter(Arc::new(notion_rate_limiter(
+            DEFAULT_NOTION_REQUESTS_PER_SECOND,
+        )))
+    }
+
+    pub fn with_rate_limiter(rate_limiter: Arc<RateLimiter>) -> Self {
+        Self::with_http_proxy(rate_limiter, None).expect("client without a proxy should build")
+    }
+
+    /// Send requests through `http_proxy`, see [crate::http_client::client_builder]
+    pub fn with_http_proxy(
+        rate_limiter: Arc<RateLimiter>,
+        http_proxy: Option<&str>,
+    ) -> Result<Self, reqwest::Error> {
+        Ok(Self {
+            client: make_notion_client(http_proxy)?,
+            base_url: NOTION_API_BASE_URL.to_owned(),
+            rate_limiter,
+        })
+    }
+
+    /// Send requests to a fake Notion API
+    #[cfg(test)]
+    pub(crate) fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
     }
 
     pub async fn get_pages_from_notion_database(
         &self,
         authorisation_token: &str,
         database_id: &str,
-    ) -> Result<NotionPagesResponse, reqwest::Error> {
-        self.0
-            .post("https://api.notion.com/v1/databases/".to_owned() + database_id + "/query")
+    ) -> Result<NotionPagesResponse, NotionError> {
+        validate_database_id(database_id)?;
+        self.rate_limiter.acquire().await;
+
+        Ok(self
+            .client
+            .post(self.base_url.clone() + "databases/" + database_id + "/query")
             .add_notion_authorisation_token(authorisation_token)
             .send()
             .await?
+            .error_for_status()?
             .json()
-            .await
+            .await?)
+    }
+
+    /// Retrieve a single page. Unlike a database query, this returns the page object directly.
+    pub async fn get_page(
+        &self,
+        authorisation_token: &str,
+        page_id: &str,
+    ) -> Result<NotionPageObject, NotionError> {
+        self.rate_limiter.acquire().await;
+
+        Ok(self
+            .get_page_request(authorisation_token, page_id)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Update some of a page's properties, unless it has been edited since it was read.
+    /// `read_last_edited_time` is the page's `last_edited_time` when it was read.
+    ///
+    /// Notion doesn't support conditional updates, so the page is read again just before the
+    /// update to check. This narrows the window for overwriting a concurrent edit, but doesn't
+    /// close it, and an edit in the same minute as the original read isn't noticed, as Notion
+    /// rounds `last_edited_time` down to the minute.
+    pub async fn update_page_if_unchanged(
+        &self,
+        authorisation_token: &str,
+        page_id: &str,
+        read_last_edited_time: &str,
+        properties: &serde_json::Value,
+    ) -> Result<PageUpdate, NotionError> {
+        let current = self.get_page(authorisation_token, page_id).await?;
+        if !same_edit_time(read_last_edited_time, current.last_edited_time()) {
+            info!(
+                page_id,
+                read_last_edited_time,
+                last_edited_time = current.last_edited_time(),
+                "Notion page edited since it was read, skipping the update"
+            );
+            return Ok(PageUpdate::Skipped {
+                last_edited_time: current.last_edited_time,
+            });
+        }
+
+        self.rate_limiter.acquire().await;
+
+        Ok(PageUpdate::Updated(
+            self.update_page_request(authorisation_token, page_id, properties)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?,
+        ))
+    }
+
+    /// Retrieve a database definition, including its properties
+    pub async fn get_database(
+        &self,
+        authorisation_token: &str,
+        database_id: &str,
+    ) -> Result<NotionDatabase, NotionError> {
+        validate_database_id(database_id)?;
+        self.rate_limiter.acquire().await;
+
+        Ok(self
+            .get_database_request(authorisation_token, database_id)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Find the databases shared with the integration, optionally only those with a title matching
+    /// `query`. Fetches every page of results.
+    pub async fn search_databases(
+        &self,
+        authorisation_token: &str,
+        query: Option<&str>,
+    ) -> Result<Vec<NotionDatabaseSummary>, NotionError> {
+        let mut databases = vec![];
+        let mut start_cursor = None;
+
+        loop {
+            self.rate_limiter.acquire().await;
+
+            let response: NotionDatabaseSearchResponse = self
+                .search_databases_request(authorisation_token, query, start_cursor.as_deref())
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            databases.extend(
+                response
+                    .results
+                    .into_iter()
+                    .map(NotionDatabaseSummary::from),
+            );
+
+            match response.next_cursor {
+                Some(next_cursor) if response.has_more => start_cursor = Some(next_cursor),
+                _ => break Ok(databases),
+            }
+        }
+    }
+
+    /// Check whether a token still authenticates with Notion, e.g. before syncing a user, with a
+    /// request for the integration's bot user. Returns `Ok(false)` if Notion rejects the token
+    /// (it has been revoked), and an error if the check itself fails.
+    pub async fn verify_token(&self, authorisation_token: &str) -> Result<bool, NotionError> {
+        self.rate_limiter.acquire().await;
+
+        let response = self.bot_user_request(authorisation_token).send().await?;
+
+        token_validity(response)
+    }
+
+    fn bot_user_request(&self, authorisation_token: &str) -> reqwest::RequestBuilder {
+        self.client
+            .get(self.base_url.clone() + "users/me")
+            .add_notion_authorisation_token(authorisation_token)
+    }
+
+    fn search_databases_request(
+        &self,
+        authorisation_token: &str,
+        query: Option<&str>,
+        start_cursor: Option<&str>,
+    ) -> reqwest::RequestBuilder {
+        self.client
+            .post(self.base_url.clone() + "search")
+            .add_notion_authorisation_token(authorisation_token)
+            .json(&NotionSearchRequest {
+                query,
+                filter: NotionSearchFilter {
+                    property: "object",
+                    value: "database",
+                },
+                start_cursor,
+            })
+    }
+
+    fn get_database_request(
+        &self,
+        authorisation_token: &str,
+        database_id: &str,
+    ) -> reqwest::RequestBuilder {
+        self.client
+            .get(self.base_url.clone() + "databases/" + database_id)
+            .add_notion_authorisation_token(authorisation_token)
+    }
+
+    fn update_page_request(
+        &self,
+        authorisation_token: &str,
+        page_id: &str,
+        properties: &serde_json::Value,
+    ) -> reqwest::RequestBuilder {
+        self.client
+            .patch(self.base_url.clone() + "pages/" + page_id)
+            .add_notion_authorisation_token(authorisation_token)
+            .json(&UpdatePageRequest { properties })
+    }
+
+    fn get_page_request(
+        &self,
+        authorisation_token: &str,
+        page_id: &str,
+    ) -> reqwest::RequestBuilder {
+        self.client
+            .get(self.base_url.clone() + "pages/" + page_id)
+            .add_notion_authorisation_token(authorisation_token)
     }
 }
 
@@ -83,11 +492,435 @@ impl NotionRequestBuilder for reqwest::RequestBuilder {
     }
 }
 
-fn make_notion_client() -> reqwest::Client {
+/// Whether the token used for a request was valid, from the response. Notion responds with 401
+/// Unauthorized to a revoked token.
+fn token_validity(response: reqwest::Response) -> Result<bool, NotionError> {
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Ok(false);
+    }
+
+    response.error_for_status()?;
+
+    Ok(true)
+}
+
+/// Whether two `last_edited_time`s are the same, comparing them as times if they can be parsed
+/// (so that e.g. `.000Z` and `+00:00` are equal)
+fn same_edit_time(a: &str, b: &str) -> bool {
+    match (
+        chrono::DateTime::parse_from_rfc3339(a),
+        chrono::DateTime::parse_from_rfc3339(b),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// A rate limiter for Notion requests. A short burst is allowed, as Notion's limit is an average.
+pub fn notion_rate_limiter(requests_per_second: f64) -> RateLimiter {
+    RateLimiter::new(requests_per_second, 3)
+}
+
+fn make_notion_client(http_proxy: Option<&str>) -> Result<reqwest::Client, reqwest::Error> {
     // client for notion requests
-    reqwest::Client::builder()
+    crate::http_client::client_builder(http_proxy)?
         .add_notion_headers()
         .expect("this should work")
         .build()
-        .expect("this should work")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Mutex};
+
+    use super::*;
+
+    #[test]
+    fn get_page_request_path() {
+        let request = NotionClientUnauthenticated::new()
+            .get_page_request("secret_token", "b55c9c91-384d-452b-81db-d1ef79372b75")
+            .build()
+            .unwrap();
+
+        assert_eq!(reqwest::Method::GET, request.method());
+        assert_eq!(
+            "/v1/pages/b55c9c91-384d-452b-81db-d1ef79372b75",
+            request.url().path()
+        );
+        assert_eq!(
+            "Bearer secret_token",
+            request.headers()[reqwest::header::AUTHORIZATION]
+        );
+        assert_eq!("2022-06-28", request.headers()["Notion-Version"]);
+    }
+
+    #[test]
+    fn token_checked_with_bot_user() {
+        let request = NotionClientUnauthenticated::new()
+            .bot_user_request("secret_token")
+            .build()
+            .unwrap();
+
+        assert_eq!(reqwest::Method::GET, request.method());
+        assert_eq!("/v1/users/me", request.url().path());
+
+        let response = |status: u16| {
+            reqwest::Response::from(
+                http::Response::builder()
+                    .status(status)
+                    .body(r#"{"object": "user", "type": "bot"}"#)
+                    .unwrap(),
+            )
+        };
+        assert!(token_validity(response(200)).unwrap());
+        // revoked
+        assert!(!token_validity(response(401)).unwrap());
+
+        let error = token_validity(response(503)).unwrap_err();
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn deserialize_page() {
+        let page: NotionPageObject = serde_json::from_str(
+            r#"{
+                "object": "page",
+                "id": "b55c9c91-384d-452b-81db-d1ef79372b75",
+                "created_time": "2022-10-24T22:54:00.000Z",
+                "last_edited_time": "2023-03-08T18:25:00.000Z",
+                "created_by": { "object": "user", "id": "cdd5b4a4-c33e-4b91-a5ab-0a2f5ff8a1d4" },
+                "last_edited_by": { "object": "user", "id": "cdd5b4a4-c33e-4b91-a5ab-0a2f5ff8a1d4" },
+                "cover": null,
+                "icon": { "type": "emoji", "emoji": "🐞" },
+                "parent": { "type": "database_id", "database_id": "d9824bdc-8445-4327-be8b-5b47500af6ce" },
+                "archived": false,
+                "properties": {
+                    "Name": {
+                        "id": "title",
+                        "type": "title",
+                        "title": [{ "type": "text", "plain_text": "Write the sync" }]
+                    },
+                    "Done": { "id": "O%7CaE", "type": "checkbox", "checkbox": false }
+                },
+                "url": "https://www.notion.so/Write-the-sync-b55c9c91384d452b81dbd1ef79372b75",
+                "public_url": null
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!("b55c9c91-384d-452b-81db-d1ef79372b75", page.id);
+        assert!(!page.archived);
+        assert_eq!(Some(false), page.properties["Done"]["checkbox"].as_bool());
+        assert_eq!(Some("Write the sync".to_owned()), page.title("title"));
+        // not a title property
+        assert_eq!(None, page.title("O%7CaE"));
+    }
+
+    const PAGE_ID: &str = "b55c9c91-384d-452b-81db-d1ef79372b75";
+
+    /// Serve a fake Notion API on a free port, with a page last edited at `last_edited_time`.
+    /// Returns its base URL and the method and path of each request it receives.
+    fn fake_notion(last_edited_time: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let received = requests.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let received = received.clone();
+            async move {
+                Ok::<_, Infallible>(hyper::service::service_fn(
+                    move |request: hyper::Request<hyper::Body>| {
+                        received.lock().unwrap().push(format!(
+                            "{} {}",
+                            request.method(),
+                            request.uri().path()
+                        ));
+                        let page = serde_json::json!({
+                            "object": "page",
+                            "id": PAGE_ID,
+                            "created_time": "2022-10-24T22:54:00.000Z",
+                            "last_edited_time": last_edited_time,
+                            "created_by": { "object": "user", "id": "user" },
+                            "last_edited_by": { "object": "user", "id": "user" },
+                            "icon": null,
+                            "parent": { "type": "database_id", "database_id": "database" },
+                            "archived": false,
+                            "properties": {},
+                            "url": "https://www.notion.so/page",
+                        });
+                        async move {
+                            Ok::<_, Infallible>(hyper::Response::new(hyper::Body::from(
+                                page.to_string(),
+                            )))
+                        }
+                    },
+                ))
+            }
+        });
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(make_service),
+        );
+
+        (format!("http://{address}/v1/"), requests)
+    }
+
+    #[tokio::test]
+    async fn concurrently_edited_page_not_updated() {
+        let (base_url, requests) = fake_notion("2023-03-08T18:27:00.000Z");
+        let client = NotionClientUnauthenticated::new().with_base_url(base_url);
+        let properties = serde_json::json!({ "Done": { "checkbox": true } });
+
+        // edited by someone else since it was read at 18:25
+        let update = client
+            .update_page_if_unchanged(
+                "secret_token",
+                PAGE_ID,
+                "2023-03-08T18:25:00.000Z",
+                &properties,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            update,
+            PageUpdate::Skipped { last_edited_time } if last_edited_time == "2023-03-08T18:27:00.000Z"
+        ));
+        assert_eq!(
+            vec![format!("GET /v1/pages/{PAGE_ID}")],
+            *requests.lock().unwrap()
+        );
+
+        let update = client
+            .update_page_if_unchanged(
+                "secret_token",
+                PAGE_ID,
+                "2023-03-08T18:27:00+00:00",
+                &properties,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(update, PageUpdate::Updated(page) if page.id() == PAGE_ID));
+        assert_eq!(
+            vec![
+                format!("GET /v1/pages/{PAGE_ID}"),
+                format!("GET /v1/pages/{PAGE_ID}"),
+                format!("PATCH /v1/pages/{PAGE_ID}"),
+            ],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn update_page_request_body() {
+        let request = NotionClientUnauthenticated::new()
+            .update_page_request(
+                "secret_token",
+                PAGE_ID,
+                &serde_json::json!({ "Done": { "checkbox": true } }),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(reqwest::Method::PATCH, request.method());
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({ "properties": { "Done": { "checkbox": true } } }),
+            body
+        );
+    }
+
+    #[test]
+    fn get_database_request_path() {
+        let request = NotionClientUnauthenticated::new()
+            .get_database_request("secret_token", "d9824bdc-8445-4327-be8b-5b47500af6ce")
+            .build()
+            .unwrap();
+
+        assert_eq!(reqwest::Method::GET, request.method());
+        assert_eq!(
+            "/v1/databases/d9824bdc-8445-4327-be8b-5b47500af6ce",
+            request.url().path()
+        );
+    }
+
+    #[test]
+    fn deserialize_database() {
+        let database: NotionDatabase = serde_json::from_str(
+            r#"{
+                "object": "database",
+                "id": "d9824bdc-8445-4327-be8b-5b47500af6ce",
+                "created_time": "2022-10-24T22:54:00.000Z",
+                "last_edited_time": "2023-03-08T18:25:00.000Z",
+                "title": [{ "type": "text", "plain_text": "Tasks" }],
+                "description": [],
+                "icon": null,
+                "cover": null,
+                "archived": false,
+                "is_inline": false,
+                "properties": {
+                    "Name": { "id": "title", "name": "Name", "type": "title", "title": {} },
+                    "Done": { "id": "O%7CaE", "name": "Done", "type": "checkbox", "checkbox": {} },
+                    "Due": { "id": "%3DmYb", "name": "Due", "type": "date", "date": {} },
+                    "Status": {
+                        "id": "biOx",
+                        "name": "Status",
+                        "type": "select",
+                        "select": {
+                            "options": [{ "id": "1", "name": "Not started", "color": "red" }]
+                        }
+                    },
+                    "Estimate": {
+                        "id": "qgx%5C",
+                        "name": "Estimate",
+                        "type": "number",
+                        "number": { "format": "number" }
+                    }
+                },
+                "url": "https://www.notion.so/d9824bdc84454327be8b5b47500af6ce"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(5, database.properties.len());
+        assert_eq!("select", database.properties["Status"].property_type);
+
+        let done = database.require_property("O%7CaE").unwrap();
+        assert_eq!("Done", done.name);
+        assert_eq!("checkbox", done.property_type);
+
+        assert!(matches!(
+            database.require_property("deleted"),
+            Err(NotionError::MissingDatabaseProperty(id)) if id == "deleted"
+        ));
+    }
+
+    #[test]
+    fn database_id_validation() {
+        for valid in [
+            "d9824bdc-8445-4327-be8b-5b47500af6ce",
+            "d9824bdc84454327be8b5b47500af6ce",
+            "D9824BDC84454327BE8B5B47500AF6CE",
+        ] {
+            assert!(
+                validate_database_id(valid).is_ok(),
+                "{valid} should be valid"
+            );
+        }
+
+        for invalid in [
+            "",
+            "asdfasdf",
+            "d9824bdc-8445-4327-be8b-5b47500af6c",
+            "d9824bdc-8445-4327-be8b-5b47500af6cz",
+            "d9824bdc84454327be8b5b47500af6ce00",
+            "d9824bdc-84454327-be8b-5b47500af6ce",
+            "d9824bdc8445-4327-be8b-5b47500af6ce-",
+        ] {
+            assert!(
+                matches!(
+                    validate_database_id(invalid),
+                    Err(NotionError::InvalidDatabaseId(id)) if id == invalid
+                ),
+                "{invalid} should be invalid"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_database_id_is_not_requested() {
+        let error = NotionClientUnauthenticated::new()
+            .get_pages_from_notion_database("secret_token", "")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, NotionError::InvalidDatabaseId(id) if id.is_empty()));
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn search_databases_request_body() {
+        let request = NotionClientUnauthenticated::new()
+            .search_databases_request("secret_token", Some("Tasks"), Some("cursor"))
+            .build()
+            .unwrap();
+
+        assert_eq!(reqwest::Method::POST, request.method());
+        assert_eq!("/v1/search", request.url().path());
+
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "query": "Tasks",
+                "filter": { "property": "object", "value": "database" },
+                "start_cursor": "cursor"
+            }),
+            body
+        );
+
+        let request = NotionClientUnauthenticated::new()
+            .search_databases_request("secret_token", None, None)
+            .build()
+            .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({ "filter": { "property": "object", "value": "database" } }),
+            body
+        );
+    }
+
+    #[test]
+    fn deserialize_database_search_results() {
+        let response: NotionDatabaseSearchResponse = serde_json::from_str(
+            r#"{
+                "object": "list",
+                "results": [
+                    {
+                        "object": "database",
+                        "id": "d9824bdc-8445-4327-be8b-5b47500af6ce",
+                        "title": [
+                            { "type": "text", "plain_text": "Work " },
+                            { "type": "text", "plain_text": "Tasks" }
+                        ],
+                        "properties": {}
+                    },
+                    {
+                        "object": "database",
+                        "id": "a7f3cbb1-54f4-4f4b-9b4b-1fd2a9b7d3c1",
+                        "title": [],
+                        "properties": {}
+                    }
+                ],
+                "next_cursor": "a7f3cbb1-54f4-4f4b-9b4b-1fd2a9b7d3c1",
+                "has_more": true,
+                "type": "page_or_database",
+                "page_or_database": {}
+            }"#,
+        )
+        .unwrap();
+        assert!(response.has_more);
+
+        let databases: Vec<_> = response
+            .results
+            .into_iter()
+            .map(NotionDatabaseSummary::from)
+            .collect();
+        assert_eq!(
+            vec![
+                NotionDatabaseSummary {
+                    id: "d9824bdc-8445-4327-be8b-5b47500af6ce".to_owned(),
+                    title: "Work Tasks".to_owned(),
+                },
+                NotionDatabaseSummary {
+                    id: "a7f3cbb1-54f4-4f4b-9b4b-1fd2a9b7d3c1".to_owned(),
+                    title: String::new(),
+                },
+            ],
+            databases
+        );
+    }
 }
@@ -0,0 +1,108 @@
+//! A token bucket rate limiter, for APIs with a request rate limit (e.g. Notion allows an average
+//! of 3 requests per second per integration).
+
+use std::sync::Mutex;
+
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    /// Can go negative, in which case callers are waiting for tokens that have been reserved
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests_per_second: f64,
+    /// Maximum number of requests that can be made at once after being idle
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+
+        Self {
+            requests_per_second,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until a request can be made. Each call reserves a token straight away, so waiting
+    /// callers are let through in the order they called this.
+    pub async fn acquire(&self) {
+        let wait = self.reserve(Instant::now());
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take a token, returning how long to wait before it is available
+    fn reserve(&self, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().expect("mutex should not be poisoned");
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.requests_per_second).min(self.burst);
+        bucket.last_refill = now;
+
+        bucket.tokens -= 1.0;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.requests_per_second)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn waits_once_burst_is_used() {
+        let rate_limiter = RateLimiter::new(2.0, 2);
+        let now = Instant::now();
+
+        assert_eq!(Duration::ZERO, rate_limiter.reserve(now));
+        assert_eq!(Duration::ZERO, rate_limiter.reserve(now));
+        assert_eq!(Duration::from_millis(500), rate_limiter.reserve(now));
+        assert_eq!(Duration::from_millis(1000), rate_limiter.reserve(now));
+
+        // tokens are refilled over time, but never beyond the burst size
+        let later = now + Duration::from_secs(60);
+        assert_eq!(Duration::ZERO, rate_limiter.reserve(later));
+        assert_eq!(Duration::ZERO, rate_limiter.reserve(later));
+        assert_eq!(Duration::from_millis(500), rate_limiter.reserve(later));
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_are_paced() {
+        let rate_limiter = Arc::new(RateLimiter::new(20.0, 1));
+        let start = Instant::now();
+
+        let mut join_set = tokio::task::JoinSet::new();
+        for _ in 0..5 {
+            let rate_limiter = rate_limiter.clone();
+            join_set.spawn(async move { rate_limiter.acquire().await });
+        }
+        while let Some(result) = join_set.join_next().await {
+            result.unwrap();
+        }
+
+        // the first call is immediate, then one every 50ms
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+    }
+}
@@ -0,0 +1,143 @@
+//! Deciding whether a failed request is worth retrying.
+//!
+//! Connection failures, timeouts and server errors are usually transient, while other client
+//! errors (bad credentials, missing resources) and responses that can't be decoded will fail the
+//! same way every time.
+//!
+//! A [RetryBudget] limits the retries across several requests, e.g. all those in one sync job.
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use reqwest::StatusCode;
+
+use crate::clock::Clock;
+
+/// Whether a reqwest error is likely to be transient
+pub fn is_retryable(error: &reqwest::Error) -> bool {
+    match error.status() {
+        Some(status) => is_retryable_status(status),
+        None => error.is_connect() || error.is_timeout(),
+    }
+}
+
+/// Whether a response status is likely to be transient. Rate limiting (429) is the one client
+/// error that is worth retrying, after backing off.
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Retries shared between several requests, so that a job where every request is struggling fails
+/// fast instead of retrying each request in turn. Once either the retries or the time run out, no
+/// more retries are allowed.
+#[derive(Debug)]
+pub struct RetryBudget {
+    remaining_retries: AtomicU32,
+    /// No retries are allowed after this time
+    deadline: Option<SystemTime>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RetryBudget {
+    /// A budget of `max_retries` retries, all within `max_duration` of now if that is set
+    pub fn new(max_retries: u32, max_duration: Option<Duration>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            remaining_retries: AtomicU32::new(max_retries),
+            deadline: max_duration.map(|max_duration| clock.now() + max_duration),
+            clock,
+        }
+    }
+
+    /// Use up one retry, returning `false` if the budget has run out
+    pub fn try_spend(&self) -> bool {
+        if self
+            .deadline
+            .is_some_and(|deadline| self.clock.now() >= deadline)
+        {
+            return false;
+        }
+
+        self.remaining_retries
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| {
+                remaining.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_error(status: u16) -> reqwest::Error {
+        reqwest::Response::from(http::Response::builder().status(status).body("").unwrap())
+            .error_for_status()
+            .unwrap_err()
+    }
+
+    #[test]
+    fn status_classification() {
+        for status in [500, 502, 503, 504, 429] {
+            assert!(
+                is_retryable_status(StatusCode::from_u16(status).unwrap()),
+                "{status} should be retryable"
+            );
+            assert!(is_retryable(&status_error(status)));
+        }
+
+        for status in [400, 401, 403, 404, 409] {
+            assert!(
+                !is_retryable_status(StatusCode::from_u16(status).unwrap()),
+                "{status} should not be retryable"
+            );
+            assert!(!is_retryable(&status_error(status)));
+        }
+    }
+
+    #[test]
+    fn retry_budget_runs_out_of_time() {
+        let clock = Arc::new(crate::clock::MockClock::new(SystemTime::UNIX_EPOCH));
+        let budget = RetryBudget::new(10, Some(Duration::from_secs(30)), clock.clone());
+
+        assert!(budget.try_spend());
+        clock.advance(Duration::from_secs(30));
+        assert!(!budget.try_spend());
+    }
+
+    #[tokio::test]
+    async fn decode_error_not_retryable() {
+        let error = reqwest::Response::from(
+            http::Response::builder()
+                .status(200)
+                .body("not json")
+                .unwrap(),
+        )
+        .json::<serde_json::Value>()
+        .await
+        .unwrap_err();
+
+        assert!(error.is_decode());
+        assert!(!is_retryable(&error));
+    }
+
+    #[test]
+    fn builder_error_not_retryable() {
+        let error = reqwest::Client::new().get("not a url").build().unwrap_err();
+
+        assert!(!is_retryable(&error));
+    }
+
+    #[tokio::test]
+    async fn connect_error_retryable() {
+        // nothing should be listening on port 1
+        let error = reqwest::get("http://127.0.0.1:1").await.unwrap_err();
+
+        assert!(error.is_connect());
+        assert!(is_retryable(&error));
+    }
+}
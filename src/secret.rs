@@ -0,0 +1,67 @@
+//! A string that isn't printed by `Debug` or `Display`, for tokens that shouldn't end up in logs or
+//! traces.
+
+use serde::{Deserialize, Serialize};
+use typeshare::typeshare;
+
+#[typeshare(serialized_as = "String")]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Self(secret.into())
+    }
+
+    /// The actual secret value. Only use this where the secret is needed, e.g. in a request.
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self(secret.to_owned())
+    }
+}
+
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
+
+impl std::fmt::Display for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_and_display_are_redacted() {
+        let secret = SecretString::new("secret_token");
+
+        assert_eq!("SecretString([REDACTED])", format!("{secret:?}"));
+        assert_eq!("[REDACTED]", secret.to_string());
+        assert_eq!("secret_token", secret.expose_secret());
+    }
+
+    #[test]
+    fn serialized_as_plain_string() {
+        let secret: SecretString = serde_json::from_str(r#""secret_token""#).unwrap();
+
+        assert_eq!("secret_token", secret.expose_secret());
+        assert_eq!(r#""secret_token""#, serde_json::to_string(&secret).unwrap());
+    }
+}
@@ -0,0 +1,739 @@
+//! Comparison of Notion pages with Google Calendar events, to work out what needs to change, and
+//! the [SyncSink] that the changes are applied to.
+
+use std::{collections::HashMap, future::Future, pin::Pin};
+
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset};
+use tracing::{info, warn};
+
+use crate::{
+    notion_api::{self, NotionClientUnauthenticated, NotionPageObject, PageUpdate},
+    settings::ConflictStrategy,
+    GoogleCalendarApi, GoogleCalendarEvent,
+};
+
+/// A Notion page and the Google Calendar event that it is synced with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkedItem {
+    pub notion_page_id: String,
+    /// The page's `last_edited_time`
+    pub notion_last_edited: DateTime<FixedOffset>,
+    pub google_event_id: String,
+    /// The calendar that the event is in, as a sync record can have several
+    pub google_calendar_id: String,
+    /// The event's `updated` time
+    pub google_updated: DateTime<FixedOffset>,
+}
+
+/// Pair up Notion pages with the Google Calendar events linked to them (see
+/// [GoogleCalendarEvent::notion_page_id]). Each event is given with the calendar that it is in.
+/// Cancelled events, and pages or events without a valid edit time, are left out.
+pub fn link_items(
+    pages: &[NotionPageObject],
+    events: &[(String, GoogleCalendarEvent)],
+) -> Vec<LinkedItem> {
+    let pages: HashMap<_, _> = pages
+        .iter()
+        .map(|page| (undashed_id(page.id()), page))
+        .collect();
+
+    events
+        .iter()
+        .filter(|(_, event)| event.status.as_deref() != Some("cancelled"))
+        .filter_map(|(google_calendar_id, event)| {
+            let page = pages.get(&undashed_id(event.notion_page_id()?))?;
+
+            Some(LinkedItem {
+                notion_page_id: page.id().to_owned(),
+                notion_last_edited: DateTime::parse_from_rfc3339(page.last_edited_time()).ok()?,
+                google_event_id: event.id.clone(),
+                google_calendar_id: google_calendar_id.clone(),
+                google_updated: DateTime::parse_from_rfc3339(event.updated.as_deref()?).ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Notion ids are UUIDs, which may or may not be dashed
+fn undashed_id(id: &str) -> String {
+    id.replace('-', "")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncAction {
+    /// Copy the Notion page's state to the Google Calendar event
+    UpdateGoogleEvent {
+        notion_page_id: String,
+        google_event_id: String,
+        google_calendar_id: String,
+    },
+    /// Copy the Google Calendar event's state to the Notion page
+    UpdateNotionPage {
+        notion_page_id: String,
+        google_event_id: String,
+        google_calendar_id: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Winner {
+    Notion,
+    Google,
+}
+
+/// Work out which changes need making, given the time of the last sync.
+///
+/// If only one side has changed since the last sync, it is copied to the other side. If both sides
+/// have changed, `conflict_strategy` decides what happens.
+pub fn compute_sync_actions(
+    items: &[LinkedItem],
+    last_sync: DateTime<FixedOffset>,
+    conflict_strategy: ConflictStrategy,
+) -> Vec<SyncAction> {
+    items
+        .iter()
+        .filter_map(|item| {
+            let notion_changed = item.notion_last_edited > last_sync;
+            let google_changed = item.google_updated > last_sync;
+
+            let winner = match (notion_changed, google_changed) {
+                (false, false) => None,
+                (true, false) => Some(Winner::Notion),
+                (false, true) => Some(Winner::Google),
+                (true, true) => resolve_conflict(item, conflict_strategy),
+            }?;
+
+            let notion_page_id = item.notion_page_id.clone();
+            let google_event_id = item.google_event_id.clone();
+            let google_calendar_id = item.google_calendar_id.clone();
+
+            Some(match winner {
+                Winner::Notion => SyncAction::UpdateGoogleEvent {
+                    notion_page_id,
+                    google_event_id,
+                    google_calendar_id,
+                },
+                Winner::Google => SyncAction::UpdateNotionPage {
+                    notion_page_id,
+                    google_event_id,
+                    google_calendar_id,
+                },
+            })
+        })
+        .collect()
+}
+
+/// What became of an action given to a [SyncSink]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkOutcome {
+    Applied,
+    /// The action wasn't applied, e.g. because the Notion page was edited after it was fetched,
+    /// so it should be synced next time
+    Skipped,
+}
+
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<SinkOutcome>> + Send + 'a>>;
+
+/// Where sync actions are applied, so that the pipeline's output can be checked without making any
+/// requests
+pub trait SyncSink: Send + Sync {
+    fn apply(&self, action: SyncAction) -> SinkFuture<'_>;
+}
+
+/// The sink used by the pipeline, which copies a Notion page's title to its Google Calendar
+/// event's summary, or the other way. Made for each sync job, as it holds the user's tokens and the
+/// pages and events that the actions were computed from.
+pub struct ApiSyncSink<'a> {
+    pub notion_client: &'a NotionClientUnauthenticated,
+    pub notion_token: &'a str,
+    /// The id of the database's title property, see [crate::aws::NotionDBPropertyOptions]
+    pub notion_title_id: &'a str,
+    pub google_client: &'a reqwest::Client,
+    pub google_api: &'a GoogleCalendarApi,
+    pub google_token: &'a str,
+    pub pages: &'a [NotionPageObject],
+    /// Each event with the calendar that it is in
+    pub events: &'a [(String, GoogleCalendarEvent)],
+}
+
+impl ApiSyncSink<'_> {
+    fn page(&self, page_id: &str) -> anyhow::Result<&NotionPageObject> {
+        self.pages
+            .iter()
+            .find(|page| page.id() == page_id)
+            .ok_or_else(|| anyhow!("Notion page {page_id} wasn't fetched"))
+    }
+
+    fn event(&self, calendar_id: &str, event_id: &str) -> anyhow::Result<&GoogleCalendarEvent> {
+        self.events
+            .iter()
+            .find(|(event_calendar_id, event)| {
+                event_calendar_id == calendar_id && event.id == event_id
+            })
+            .map(|(_, event)| event)
+            .ok_or_else(|| anyhow!("Google Calendar event {event_id} wasn't fetched"))
+    }
+
+    async fn update_google_event(
+        &self,
+        notion_page_id: &str,
+        google_event_id: &str,
+        google_calendar_id: &str,
+    ) -> anyhow::Result<SinkOutcome> {
+        let title = self
+            .page(notion_page_id)?
+            .title(self.notion_title_id)
+            .unwrap_or_default();
+
+        crate::patch_google_event(
+            self.google_client,
+            self.google_api,
+            self.google_token,
+            google_calendar_id,
+            google_event_id,
+            &serde_json::json!({ "summary": title }),
+        )
+        .await?;
+
+        Ok(SinkOutcome::Applied)
+    }
+
+    async fn update_notion_page(
+        &self,
+        notion_page_id: &str,
+        google_event_id: &str,
+        google_calendar_id: &str,
+    ) -> anyhow::Result<SinkOutcome> {
+        let page = self.page(notion_page_id)?;
+        let summary = self
+            .event(google_calendar_id, google_event_id)?
+            .summary
+            .as_deref()
+            .unwrap_or_default();
+
+        // a page edited since it was fetched is skipped (and logged), and synced next time
+        let update = self
+            .notion_client
+            .update_page_if_unchanged(
+                self.notion_token,
+                page.id(),
+                page.last_edited_time(),
+                &notion_api::title_properties(self.notion_title_id, summary),
+            )
+            .await?;
+
+        Ok(match update {
+            PageUpdate::Updated(_) => SinkOutcome::Applied,
+            PageUpdate::Skipped { .. } => SinkOutcome::Skipped,
+        })
+    }
+}
+
+impl SyncSink for ApiSyncSink<'_> {
+    fn apply(&self, action: SyncAction) -> SinkFuture<'_> {
+        Box::pin(async move {
+            match &action {
+                SyncAction::UpdateGoogleEvent {
+                    notion_page_id,
+                    google_event_id,
+                    google_calendar_id,
+                } => {
+                    self.update_google_event(notion_page_id, google_event_id, google_calendar_id)
+                        .await
+                }
+                SyncAction::UpdateNotionPage {
+                    notion_page_id,
+                    google_event_id,
+                    google_calendar_id,
+                } => {
+                    self.update_notion_page(notion_page_id, google_event_id, google_calendar_id)
+                        .await
+                }
+            }
+        })
+    }
+}
+
+/// How many of a sync job's actions were applied
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AppliedActions {
+    pub applied: usize,
+    /// Actions the sink chose not to apply, see [SinkOutcome::Skipped]
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Apply each action to `sink` in order. A failed action is logged, and doesn't stop the rest from
+/// being applied.
+pub async fn apply_sync_actions(sink: &dyn SyncSink, actions: Vec<SyncAction>) -> AppliedActions {
+    let mut result = AppliedActions::default();
+    for action in actions {
+        match sink.apply(action.clone()).await {
+            Ok(SinkOutcome::Applied) => result.applied += 1,
+            Ok(SinkOutcome::Skipped) => {
+                info!(?action, "sync action skipped");
+                result.skipped += 1;
+            }
+            Err(error) => {
+                warn!(?action, ?error, "failed to apply sync action");
+                result.failed += 1;
+            }
+        }
+    }
+    result
+}
+
+fn resolve_conflict(item: &LinkedItem, conflict_strategy: ConflictStrategy) -> Option<Winner> {
+    match conflict_strategy {
+        ConflictStrategy::NotionWins => Some(Winner::Notion),
+        ConflictStrategy::GoogleWins => Some(Winner::Google),
+        // Notion wins a tie, as it is the source of truth for tasks
+        ConflictStrategy::MostRecentWins if item.google_updated > item.notion_last_edited => {
+            Some(Winner::Google)
+        }
+        ConflictStrategy::MostRecentWins => Some(Winner::Notion),
+        ConflictStrategy::Skip => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::fake_http::FakeHttpServer;
+
+    fn time(rfc3339: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap()
+    }
+
+    fn last_sync() -> DateTime<FixedOffset> {
+        time("2023-05-01T12:00:00Z")
+    }
+
+    /// Both sides changed since the last sync, with Google changed most recently
+    fn both_changed() -> LinkedItem {
+        LinkedItem {
+            notion_page_id: "page".to_owned(),
+            notion_last_edited: time("2023-05-02T09:00:00Z"),
+            google_event_id: "event".to_owned(),
+            google_calendar_id: "calendar".to_owned(),
+            google_updated: time("2023-05-02T10:00:00+00:00"),
+        }
+    }
+
+    fn update_google() -> SyncAction {
+        SyncAction::UpdateGoogleEvent {
+            notion_page_id: "page".to_owned(),
+            google_event_id: "event".to_owned(),
+            google_calendar_id: "calendar".to_owned(),
+        }
+    }
+
+    fn update_notion() -> SyncAction {
+        SyncAction::UpdateNotionPage {
+            notion_page_id: "page".to_owned(),
+            google_event_id: "event".to_owned(),
+            google_calendar_id: "calendar".to_owned(),
+        }
+    }
+
+    #[test]
+    fn one_side_changed() {
+        let notion_changed = LinkedItem {
+            google_updated: time("2023-04-30T10:00:00Z"),
+            ..both_changed()
+        };
+        let google_changed = LinkedItem {
+            notion_last_edited: time("2023-04-30T10:00:00Z"),
+            ..both_changed()
+        };
+        let neither_changed = LinkedItem {
+            notion_last_edited: time("2023-04-30T10:00:00Z"),
+            google_updated: time("2023-04-30T10:00:00Z"),
+            ..both_changed()
+        };
+
+        for strategy in [
+            ConflictStrategy::NotionWins,
+            ConflictStrategy::GoogleWins,
+            ConflictStrategy::MostRecentWins,
+            ConflictStrategy::Skip,
+        ] {
+            assert_eq!(
+                vec![update_google()],
+                compute_sync_actions(&[notion_changed.clone()], last_sync(), strategy)
+            );
+            assert_eq!(
+                vec![update_notion()],
+                compute_sync_actions(&[google_changed.clone()], last_sync(), strategy)
+            );
+            assert_eq!(
+                Vec::<SyncAction>::new(),
+                compute_sync_actions(&[neither_changed.clone()], last_sync(), strategy)
+            );
+        }
+    }
+
+    #[test]
+    fn conflict_notion_wins() {
+        assert_eq!(
+            vec![update_google()],
+            compute_sync_actions(&[both_changed()], last_sync(), ConflictStrategy::NotionWins)
+        );
+    }
+
+    #[test]
+    fn conflict_google_wins() {
+        assert_eq!(
+            vec![update_notion()],
+            compute_sync_actions(&[both_changed()], last_sync(), ConflictStrategy::GoogleWins)
+        );
+    }
+
+    #[test]
+    fn conflict_most_recent_wins() {
+        assert_eq!(
+            vec![update_notion()],
+            compute_sync_actions(
+                &[both_changed()],
+                last_sync(),
+                ConflictStrategy::MostRecentWins
+            )
+        );
+
+        let notion_more_recent = LinkedItem {
+            // same instant in a different offset, so a tie, which Notion wins
+            notion_last_edited: time("2023-05-02T11:00:00+01:00"),
+            ..both_changed()
+        };
+        assert_eq!(
+            vec![update_google()],
+            compute_sync_actions(
+                &[notion_more_recent],
+                last_sync(),
+                ConflictStrategy::MostRecentWins
+            )
+        );
+    }
+
+    #[test]
+    fn conflict_skip() {
+        assert_eq!(
+            Vec::<SyncAction>::new(),
+            compute_sync_actions(&[both_changed()], last_sync(), ConflictStrategy::Skip)
+        );
+    }
+
+    #[test]
+    fn actions_for_several_calendars() {
+        // One Notion database synced with a work and a personal calendar
+        let items = [
+            LinkedItem {
+                notion_page_id: "meeting".to_owned(),
+                google_event_id: "work-event".to_owned(),
+                google_calendar_id: "work@group.calendar.google.com".to_owned(),
+                google_updated: time("2023-04-30T10:00:00Z"),
+                ..both_changed()
+            },
+            LinkedItem {
+                notion_page_id: "dentist".to_owned(),
+                google_event_id: "personal-event".to_owned(),
+                google_calendar_id: "primary".to_owned(),
+                notion_last_edited: time("2023-04-30T10:00:00Z"),
+                ..both_changed()
+            },
+        ];
+
+        assert_eq!(
+            vec![
+                SyncAction::UpdateGoogleEvent {
+                    notion_page_id: "meeting".to_owned(),
+                    google_event_id: "work-event".to_owned(),
+                    google_calendar_id: "work@group.calendar.google.com".to_owned(),
+                },
+                SyncAction::UpdateNotionPage {
+                    notion_page_id: "dentist".to_owned(),
+                    google_event_id: "personal-event".to_owned(),
+                    google_calendar_id: "primary".to_owned(),
+                },
+            ],
+            compute_sync_actions(&items, last_sync(), ConflictStrategy::NotionWins)
+        );
+    }
+
+    /// Records every action, failing those for `failing_page`
+    #[derive(Default)]
+    struct RecordingSink {
+        applied: Mutex<Vec<SyncAction>>,
+        failing_page: Option<&'static str>,
+    }
+
+    impl SyncSink for RecordingSink {
+        fn apply(&self, action: SyncAction) -> SinkFuture<'_> {
+            Box::pin(async move {
+                let (SyncAction::UpdateGoogleEvent { notion_page_id, .. }
+                | SyncAction::UpdateNotionPage { notion_page_id, .. }) = &action;
+                if self.failing_page == Some(notion_page_id.as_str()) {
+                    anyhow::bail!("Notion unavailable");
+                }
+                self.applied.lock().unwrap().push(action);
+                Ok(SinkOutcome::Applied)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn actions_applied_to_sink() {
+        let items = [
+            LinkedItem {
+                notion_page_id: "notion-changed".to_owned(),
+                google_updated: time("2023-04-30T10:00:00Z"),
+                ..both_changed()
+            },
+            LinkedItem {
+                notion_page_id: "unchanged".to_owned(),
+                notion_last_edited: time("2023-04-30T10:00:00Z"),
+                google_updated: time("2023-04-30T10:00:00Z"),
+                ..both_changed()
+            },
+            LinkedItem {
+                notion_page_id: "conflict".to_owned(),
+                ..both_changed()
+            },
+        ];
+        let actions = compute_sync_actions(&items, last_sync(), ConflictStrategy::MostRecentWins);
+
+        let sink = RecordingSink::default();
+        assert_eq!(
+            AppliedActions {
+                applied: 2,
+                skipped: 0,
+                failed: 0
+            },
+            apply_sync_actions(&sink, actions.clone()).await
+        );
+        assert_eq!(
+            vec![
+                SyncAction::UpdateGoogleEvent {
+                    notion_page_id: "notion-changed".to_owned(),
+                    google_event_id: "event".to_owned(),
+                    google_calendar_id: "calendar".to_owned(),
+                },
+                SyncAction::UpdateNotionPage {
+                    notion_page_id: "conflict".to_owned(),
+                    google_event_id: "event".to_owned(),
+                    google_calendar_id: "calendar".to_owned(),
+                },
+            ],
+            *sink.applied.lock().unwrap()
+        );
+
+        let failing_sink = RecordingSink {
+            failing_page: Some("notion-changed"),
+            ..Default::default()
+        };
+        assert_eq!(
+            AppliedActions {
+                applied: 1,
+                skipped: 0,
+                failed: 1
+            },
+            apply_sync_actions(&failing_sink, actions).await
+        );
+        assert_eq!(1, failing_sink.applied.lock().unwrap().len());
+    }
+
+    fn page(id: &str, last_edited_time: &str, title: &str) -> NotionPageObject {
+        serde_json::from_value(serde_json::json!({
+            "object": "page",
+            "id": id,
+            "created_time": "2022-10-24T22:54:00.000Z",
+            "last_edited_time": last_edited_time,
+            "created_by": { "object": "user", "id": "user" },
+            "last_edited_by": { "object": "user", "id": "user" },
+            "icon": null,
+            "parent": { "type": "database_id", "database_id": "database" },
+            "archived": false,
+            "properties": {
+                "Name": { "id": "title", "type": "title", "title": [{ "plain_text": title }] }
+            },
+            "url": "https://www.notion.so/page",
+        }))
+        .unwrap()
+    }
+
+    fn event(id: &str, notion_page_id: Option<&str>, status: &str) -> GoogleCalendarEvent {
+        let mut event = serde_json::json!({
+            "id": id,
+            "summary": "From Google",
+            "updated": "2023-05-02T10:00:00.000Z",
+            "status": status,
+        });
+        if let Some(notion_page_id) = notion_page_id {
+            event["extendedProperties"] =
+                serde_json::json!({ "private": { "notionPageId": notion_page_id } });
+        }
+        serde_json::from_value(event).unwrap()
+    }
+
+    #[test]
+    fn events_linked_to_their_pages() {
+        let pages = [
+            page(
+                "b55c9c91-384d-452b-81db-d1ef79372b75",
+                "2023-05-02T09:00:00.000Z",
+                "Write the sync",
+            ),
+            page("other", "2023-05-02T09:00:00.000Z", "Other"),
+        ];
+        let events = [
+            // the page id without dashes
+            (
+                "calendar".to_owned(),
+                event(
+                    "event",
+                    Some("b55c9c91384d452b81dbd1ef79372b75"),
+                    "confirmed",
+                ),
+            ),
+            ("calendar".to_owned(), event("unlinked", None, "confirmed")),
+            (
+                "calendar".to_owned(),
+                event("deleted-page", Some("deleted"), "confirmed"),
+            ),
+            (
+                "calendar".to_owned(),
+                event("cancelled", Some("other"), "cancelled"),
+            ),
+        ];
+
+        assert_eq!(
+            vec![LinkedItem {
+                notion_page_id: "b55c9c91-384d-452b-81db-d1ef79372b75".to_owned(),
+                ..both_changed()
+            }],
+            link_items(&pages, &events)
+        );
+    }
+
+    #[tokio::test]
+    async fn api_sink_writes_to_notion_and_google() {
+        let notion = FakeHttpServer::start([
+            (
+                "GET /v1/pages/page",
+                200,
+                serde_json::to_value(page("page", "2023-05-02T09:00:00.000Z", "Write the sync"))
+                    .unwrap(),
+            ),
+            (
+                "PATCH /v1/pages/page",
+                200,
+                serde_json::to_value(page("page", "2023-05-02T11:00:00.000Z", "From Google"))
+                    .unwrap(),
+            ),
+        ]);
+        let google = FakeHttpServer::start([(
+            "PATCH /calendar/v3/calendars/calendar/events/event",
+            200,
+            serde_json::json!({}),
+        )]);
+        let notion_client =
+            NotionClientUnauthenticated::new().with_base_url(notion.base_url().to_owned() + "v1/");
+        let google_api = GoogleCalendarApi::with_base_url(
+            reqwest::Url::parse(&(google.base_url().to_owned() + "calendar/v3/")).unwrap(),
+        );
+        let pages = [page("page", "2023-05-02T09:00:00.000Z", "Write the sync")];
+        let events = [(
+            "calendar".to_owned(),
+            event("event", Some("page"), "confirmed"),
+        )];
+        let sink = ApiSyncSink {
+            notion_client: &notion_client,
+            notion_token: "secret_token",
+            notion_title_id: "title",
+            google_client: &reqwest::Client::new(),
+            google_api: &google_api,
+            google_token: "access_token",
+            pages: &pages,
+            events: &events,
+        };
+
+        assert_eq!(
+            AppliedActions {
+                applied: 2,
+                skipped: 0,
+                failed: 0
+            },
+            apply_sync_actions(&sink, vec![update_google(), update_notion()]).await
+        );
+
+        let google_requests = google.requests();
+        assert_eq!(1, google_requests.len());
+        assert_eq!(
+            serde_json::json!({ "summary": "Write the sync" }),
+            google_requests[0].body
+        );
+        let notion_requests = notion.requests();
+        assert_eq!(
+            vec!["GET /v1/pages/page", "PATCH /v1/pages/page"],
+            notion_requests
+                .iter()
+                .map(|request| request.route.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            serde_json::json!({
+                "properties": {
+                    "title": { "title": [{ "text": { "content": "From Google" } }] }
+                }
+            }),
+            notion_requests[1].body
+        );
+    }
+
+    #[tokio::test]
+    async fn api_sink_skips_notion_page_edited_since_fetched() {
+        let notion = FakeHttpServer::start([(
+            "GET /v1/pages/page",
+            200,
+            serde_json::to_value(page("page", "2023-05-02T10:00:00.000Z", "Edited again")).unwrap(),
+        )]);
+        let notion_client =
+            NotionClientUnauthenticated::new().with_base_url(notion.base_url().to_owned() + "v1/");
+        let google_api = GoogleCalendarApi::default();
+        let pages = [page("page", "2023-05-02T09:00:00.000Z", "Write the sync")];
+        let events = [(
+            "calendar".to_owned(),
+            event("event", Some("page"), "confirmed"),
+        )];
+        let sink = ApiSyncSink {
+            notion_client: &notion_client,
+            notion_token: "secret_token",
+            notion_title_id: "title",
+            google_client: &reqwest::Client::new(),
+            google_api: &google_api,
+            google_token: "access_token",
+            pages: &pages,
+            events: &events,
+        };
+
+        assert_eq!(
+            AppliedActions {
+                applied: 0,
+                skipped: 1,
+                failed: 0
+            },
+            apply_sync_actions(&sink, vec![update_notion()]).await
+        );
+        assert_eq!(
+            vec!["GET /v1/pages/page"],
+            notion
+                .requests()
+                .iter()
+                .map(|request| request.route.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+}
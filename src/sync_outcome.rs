@@ -0,0 +1,117 @@
+//! A single structured event per sync job, so that per-user sync stats can be aggregated from the
+//! logs.
+
+use std::time::Duration;
+
+use tracing::{event, Level};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncJobResult {
+    Success,
+    /// Nothing could be synced, e.g. the user hasn't connected Google Calendar
+    Skipped,
+    Error,
+}
+impl SyncJobResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Skipped => "skipped",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncJobOutcome {
+    pub user_id: String,
+    pub notion_pages_seen: usize,
+    pub calendar_events_seen: usize,
+    pub actions_applied: usize,
+    pub outcome: SyncJobResult,
+    pub duration: Duration,
+}
+
+/// Emit the outcome of a sync job as a single event
+pub fn record_sync_job_outcome(outcome: &SyncJobOutcome) {
+    event!(
+        Level::INFO,
+        user_id = outcome.user_id.as_str(),
+        notion_pages_seen = outcome.notion_pages_seen,
+        calendar_events_seen = outcome.calendar_events_seen,
+        actions_applied = outcome.actions_applied,
+        outcome = outcome.outcome.as_str(),
+        duration_ms = u64::try_from(outcome.duration.as_millis()).unwrap_or(u64::MAX),
+        "sync job finished"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::{layer::SubscriberExt, Layer};
+
+    use super::*;
+
+    type CapturedEvents = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+    /// Captures the fields of every event
+    struct CaptureLayer(CapturedEvents);
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_owned(), format!("{value:?}"));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_owned(), value.to_owned());
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for CaptureLayer {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = HashMap::new();
+            event.record(&mut FieldVisitor(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    #[test]
+    fn sync_job_outcome_event_fields() {
+        let captured = CapturedEvents::default();
+        let subscriber = tracing_subscriber::registry().with(CaptureLayer(captured.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            record_sync_job_outcome(&SyncJobOutcome {
+                user_id: "user-1".to_owned(),
+                notion_pages_seen: 12,
+                calendar_events_seen: 4,
+                actions_applied: 2,
+                outcome: SyncJobResult::Success,
+                duration: Duration::from_millis(1500),
+            });
+        });
+
+        let captured = captured.lock().unwrap();
+        assert_eq!(1, captured.len());
+
+        let fields = &captured[0];
+        assert_eq!("sync job finished", fields["message"]);
+        assert_eq!("user-1", fields["user_id"]);
+        assert_eq!("12", fields["notion_pages_seen"]);
+        assert_eq!("4", fields["calendar_events_seen"]);
+        assert_eq!("2", fields["actions_applied"]);
+        assert_eq!("success", fields["outcome"]);
+        assert_eq!("1500", fields["duration_ms"]);
+    }
+}
@@ -0,0 +1,23 @@
+use hello_rust_backend::aws::{load_client_with, ConnectionPoolSettings};
+
+/// Requires DynamoDB Local to be running, e.g.
+/// `docker run -p 8000:8000 amazon/dynamodb-local`
+#[tokio::test]
+#[ignore]
+async fn test_connect_to_dynamodb_local() -> Result<(), Box<dyn std::error::Error>> {
+    let endpoint_url =
+        std::env::var("DYNAMODB_LOCAL_URL").unwrap_or_else(|_| "http://localhost:8000".to_owned());
+
+    let dynamo_db_client = load_client_with(
+        Some("eu-west-2".to_owned()),
+        Some(endpoint_url),
+        ConnectionPoolSettings::default(),
+    )
+    .await;
+
+    let tables = dynamo_db_client.list_tables().send().await?;
+
+    println!("Tables in DynamoDB Local: {:?}", tables.table_names());
+
+    Ok(())
+}
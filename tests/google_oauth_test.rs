@@ -20,7 +20,7 @@ async fn test_google_oauth_token_refresh() -> Result<(), Box<dyn std::error::Err
         .expect("should be a record with this user_id");
 
     if let Some(google_refresh_token) = &one_user_record.google_refresh_token {
-        let mut google_token = GoogleToken::new(google_refresh_token);
+        let mut google_token = GoogleToken::new(google_refresh_token.expose_secret());
 
         _ = google_token
             .refresh_token(
@@ -31,7 +31,7 @@ async fn test_google_oauth_token_refresh() -> Result<(), Box<dyn std::error::Err
 
         let access_token = &google_token.access_token.unwrap().access_token;
 
-        assert!(access_token.len() > 10);
+        assert!(access_token.expose_secret().len() > 10);
         println!("Access token refresh was successful!");
 
         Ok(())